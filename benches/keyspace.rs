@@ -0,0 +1,74 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rustis::keyspace::Keyspace;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Commands each thread runs per iteration, half SET and half GET
+const COMMANDS: usize = 10_000;
+const KEYS: usize = 1_000;
+
+/*
+Runs SET and GET from several threads at once against a keyspace split into one shard, which
+works like a single lock over everything, and against one split into 16. With one shard the
+threads take turns, so throughput stays flat as threads are added. With 16 they mostly lock
+different shards, so it should grow with the number of cores.
+*/
+fn bench_threads(c: &mut Criterion) {
+    let keys: Arc<Vec<Vec<u8>>> = Arc::new((0..KEYS).map(|i| format!("key:{i}").into_bytes()).collect());
+
+    for shards in [1, 16] {
+        let mut group = c.benchmark_group(format!("set_get/{shards}_shards"));
+
+        for threads in [1, 2, 4, 8] {
+            group.throughput(Throughput::Elements((threads * COMMANDS) as u64));
+            group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, &threads| {
+                b.iter_custom(|iters| {
+                    let keyspace = Arc::new(Keyspace::new(shards));
+                    let mut elapsed = Duration::ZERO;
+
+                    for _ in 0..iters {
+                        elapsed += run(&keyspace, &keys, threads);
+                    }
+
+                    elapsed
+                });
+            });
+        }
+
+        group.finish();
+    }
+}
+
+fn run(keyspace: &Arc<Keyspace>, keys: &Arc<Vec<Vec<u8>>>, threads: usize) -> Duration {
+    let start = Instant::now();
+
+    let handles: Vec<_> = (0..threads)
+        .map(|thread| {
+            let keyspace = Arc::clone(keyspace);
+            let keys = Arc::clone(keys);
+
+            thread::spawn(move || {
+                for i in 0..COMMANDS {
+                    let key = &keys[(thread * 7919 + i) % KEYS];
+                    let mut kv = keyspace.lock([&key[..]]);
+
+                    if i % 2 == 0 {
+                        kv.set(key, b"value");
+                    } else {
+                        kv.get(key).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    start.elapsed()
+}
+
+criterion_group!(benches, bench_threads);
+criterion_main!(benches);
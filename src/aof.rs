@@ -0,0 +1,426 @@
+use crate::kvstore::{self, Entry, Value};
+use crate::packetreader::{pexpireat_args, Frame, RequestPacket};
+use crate::stream::{Stream, StreamId};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/* Append-only file format:
+
+Every command that changed the store, written one after another as RESP arrays:
+
+*<ARGC>\r\n
+$<LEN>\r\n
+<COMMAND>\r\n
+$<LEN>\r\n
+<DATA1>\r\n
+...
+
+*/
+
+// How often the append-only file is flushed to disk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    // After every write, the safest and slowest
+    Always,
+    // Once a second from a background thread, losing at most a second of writes on a crash
+    EverySec,
+    // Whenever the operating system decides to
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySec),
+            "no" => Ok(FsyncPolicy::Never),
+            _ => Err(format!("unknown fsync policy '{}', expected always, everysec or no", s)),
+        }
+    }
+}
+
+pub struct Aof {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    file: File,
+    path: PathBuf,
+    policy: FsyncPolicy,
+    // Whether there are writes that haven't been fsynced yet
+    dirty: bool,
+    // Writes made while a rewrite is running, which get added to the end of the new file
+    rewrite_buffer: Option<Vec<u8>>,
+}
+
+impl Aof {
+    pub fn open(path: PathBuf, policy: FsyncPolicy) -> io::Result<Self> {
+        let file = open_append(&path)?;
+
+        let inner = Inner {
+            file,
+            path,
+            policy,
+            dirty: false,
+            rewrite_buffer: None,
+        };
+        let inner = Arc::new(Mutex::new(inner));
+
+        if policy == FsyncPolicy::EverySec {
+            let inner = Arc::clone(&inner);
+
+            thread::spawn(move || loop {
+                thread::sleep(Duration::from_secs(1));
+
+                let mut inner = inner.lock().unwrap();
+                if inner.dirty {
+                    if let Err(e) = inner.file.sync_data() {
+                        println!("error: append-only fsync failed: {}", e);
+                    }
+                    inner.dirty = false;
+                }
+            });
+        }
+
+        Ok(Aof { inner })
+    }
+
+    // Logs a command that changed the store
+    pub fn append(&self, args: &[Vec<u8>]) -> io::Result<()> {
+        let buf = encode_command(args);
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.file.write_all(&buf)?;
+
+        if let Some(rewrite_buffer) = &mut inner.rewrite_buffer {
+            rewrite_buffer.extend_from_slice(&buf);
+        }
+
+        match inner.policy {
+            FsyncPolicy::Always => inner.file.sync_data()?,
+            FsyncPolicy::EverySec => inner.dirty = true,
+            FsyncPolicy::Never => (),
+        }
+
+        Ok(())
+    }
+
+    // Replaces the log with the shortest set of commands that rebuilds `entries`. The new file is
+    // written on another thread, so the caller can release its lock in the meantime. Returns
+    // false if a rewrite is already running.
+    pub fn bgrewrite<'a>(&self, entries: impl IntoIterator<Item = Entry<'a>>) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.rewrite_buffer.is_some() {
+            return false;
+        }
+        inner.rewrite_buffer = Some(Vec::new());
+
+        let buf = rewrite(entries);
+        let path = inner.path.clone();
+        drop(inner);
+
+        let inner = Arc::clone(&self.inner);
+
+        thread::spawn(move || {
+            let tmp_path = path.with_extension("rewrite");
+
+            let result = write_rewrite(&tmp_path, &buf).and_then(|mut file| {
+                let mut inner = inner.lock().unwrap();
+
+                // Catch the new file up with everything written since the rewrite started
+                let rewrite_buffer = inner.rewrite_buffer.take().unwrap_or_default();
+                file.write_all(&rewrite_buffer)?;
+                file.sync_data()?;
+
+                fs::rename(&tmp_path, &path)?;
+                inner.file = open_append(&path)?;
+                Ok(())
+            });
+
+            if let Err(e) = result {
+                println!("error: append-only rewrite failed: {}", e);
+                inner.lock().unwrap().rewrite_buffer = None;
+            }
+        });
+
+        true
+    }
+}
+
+// Reads the commands in an append-only file, passing each to `apply`. A command cut off at
+// the end of the file, as left by a crash mid-write, is ignored. Returns how many commands
+// were applied.
+pub fn replay<F>(path: &Path, mut apply: F) -> io::Result<usize>
+where
+    F: FnMut(RequestPacket),
+{
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut pos = 0;
+    let mut count = 0;
+
+    while pos < data.len() {
+        match Frame::read(&data[pos..]) {
+            Frame::Complete(packet, _, used) => {
+                apply(packet);
+                pos += used;
+                count += 1;
+            },
+            Frame::Incomplete => {
+                println!("Ignoring truncated command at the end of the append-only file");
+                break;
+            },
+            Frame::Malformed(error) => {
+                let error = format!("bad append-only file at byte {}: {}", pos, error);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+            },
+        }
+    }
+
+    Ok(count)
+}
+
+pub fn encode_command(args: &[Vec<u8>]) -> Vec<u8> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+
+    buf
+}
+
+fn rewrite<'a>(entries: impl IntoIterator<Item = Entry<'a>>) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    let now = kvstore::unix_millis();
+
+    for (key, value, ttl) in entries {
+        for args in value_commands(key, value) {
+            buf.extend_from_slice(&encode_command(&args));
+        }
+
+        if let Some(ttl) = ttl {
+            let expires_at = now.saturating_add(ttl.as_millis().try_into().unwrap_or(i64::MAX));
+            buf.extend_from_slice(&encode_command(&pexpireat_args(key, expires_at)));
+        }
+    }
+
+    buf
+}
+
+// How many elements each command rebuilding a collection adds, so a big collection doesn't
+// turn into a command with more arguments than can be read back
+const REWRITE_CHUNK: usize = 64;
+
+// The commands that rebuild a value from nothing
+fn value_commands(key: &[u8], value: &Value) -> Vec<Vec<Vec<u8>>> {
+    match value {
+        Value::Str(value) => vec![vec![b"set".to_vec(), key.to_vec(), value.clone()]],
+        Value::List(list) => chunked(b"rpush", key, list.iter().map(|value| vec![value.clone()])),
+        Value::Hash(hash) => chunked(b"hset", key, hash.iter().map(|(field, value)| vec![field.clone(), value.clone()])),
+        Value::Set(set) => chunked(b"sadd", key, set.iter().map(|member| vec![member.clone()])),
+        Value::ZSet(zset) => chunked(
+            b"zadd",
+            key,
+            zset.iter().map(|(member, score)| vec![score.to_string().into_bytes(), member.to_vec()]),
+        ),
+        Value::Stream(stream) => stream_commands(key, stream),
+    }
+}
+
+// Splits a collection's elements, each given as the arguments that add it, between as many
+// `command`s as it takes to hold them `REWRITE_CHUNK` at a time
+fn chunked(command: &[u8], key: &[u8], elements: impl Iterator<Item = Vec<Vec<u8>>>) -> Vec<Vec<Vec<u8>>> {
+    let elements: Vec<_> = elements.collect();
+
+    elements
+        .chunks(REWRITE_CHUNK)
+        .map(|chunk| {
+            let mut args = vec![command.to_vec(), key.to_vec()];
+            args.extend(chunk.iter().flatten().cloned());
+            args
+        })
+        .collect()
+}
+
+// Streams are rebuilt one XADD per entry with the entry's own ID. A stream that has been
+// trimmed empty still remembers its last ID, so it's brought back by adding a placeholder
+// entry with that ID and trimming it away again. Consumer groups are created at the ID they
+// last delivered, then each pending entry is forced back onto its consumer with the time it was
+// delivered and its delivery count. Pending entries that have since been trimmed from the stream
+// can't be claimed, so they're left out.
+fn stream_commands(key: &[u8], stream: &Stream) -> Vec<Vec<Vec<u8>>> {
+    let xadd = |id: StreamId, fields: &[(Vec<u8>, Vec<u8>)]| {
+        let mut args = vec![b"xadd".to_vec(), key.to_vec(), id.to_string().into_bytes()];
+        for (field, value) in fields {
+            args.push(field.clone());
+            args.push(value.clone());
+        }
+        args
+    };
+
+    let mut commands = if stream.is_empty() {
+        vec![
+            xadd(stream.last_id(), &[(Vec::new(), Vec::new())]),
+            vec![b"xtrim".to_vec(), key.to_vec(), b"maxlen".to_vec(), b"0".to_vec()],
+        ]
+    } else {
+        stream.iter().map(|(id, fields)| xadd(id, fields)).collect()
+    };
+
+    for (name, group) in stream.groups() {
+        commands.push(vec![
+            b"xgroup".to_vec(),
+            b"create".to_vec(),
+            key.to_vec(),
+            name.to_vec(),
+            group.last_delivered().to_string().into_bytes(),
+        ]);
+
+        for (id, entry) in group.pending().filter(|(id, _)| stream.get(*id).is_some()) {
+            commands.push(vec![
+                b"xclaim".to_vec(),
+                key.to_vec(),
+                name.to_vec(),
+                entry.consumer.clone(),
+                b"0".to_vec(),
+                id.to_string().into_bytes(),
+                b"time".to_vec(),
+                entry.delivered_at.to_string().into_bytes(),
+                b"retrycount".to_vec(),
+                entry.deliveries.to_string().into_bytes(),
+                b"force".to_vec(),
+                b"justid".to_vec(),
+            ]);
+        }
+    }
+
+    commands
+}
+
+fn write_rewrite(path: &Path, buf: &[u8]) -> io::Result<File> {
+    let mut file = File::create(path)?;
+    file.write_all(buf)?;
+    Ok(file)
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::kvstore::{KvStore, Ttl};
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rustis-{}-{}.aof", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn append_and_replay() {
+        let path = temp_path("replay");
+        let aof = Aof::open(path.clone(), FsyncPolicy::Always).unwrap();
+
+        aof.append(&[b"set".to_vec(), b"a".to_vec(), b"1\r\n2".to_vec()]).unwrap();
+        aof.append(&[b"setex".to_vec(), b"b".to_vec(), b"100".to_vec(), b"2".to_vec()]).unwrap();
+        aof.append(&pexpireat_args(b"a", kvstore::unix_millis() + 100_000)).unwrap();
+
+        let mut kv = KvStore::new();
+        let count = replay(&path, |packet| match packet {
+            RequestPacket::Set { key, value, .. } => kv.set(&key, &value),
+            RequestPacket::SetEx { key, value, ttl } => kv.setex(&key, &value, ttl),
+            RequestPacket::ExpireAt { key, unix_millis } => assert!(kv.expire_at(&key, unix_millis)),
+            _ => panic!("unexpected packet"),
+        }).unwrap();
+
+        assert_eq!(count, 3);
+        assert_eq!(kv.get(b"a"), Ok(Some(b"1\r\n2".to_vec())));
+        assert!(matches!(kv.ttl(b"a"), Ttl::Expires(ttl) if ttl.as_secs() >= 98));
+        assert!(matches!(kv.ttl(b"b"), Ttl::Expires(_)));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn replay_ignores_truncated_tail() {
+        let path = temp_path("truncated");
+        let mut buf = encode_command(&[b"set".to_vec(), b"a".to_vec(), b"1".to_vec()]);
+        buf.extend_from_slice(b"*3\r\n$3\r\nset\r\n$1\r\nb");
+        fs::write(&path, buf).unwrap();
+
+        let count = replay(&path, |_| ()).unwrap();
+
+        assert_eq!(count, 1);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rewrite_compacts() {
+        let path = temp_path("rewrite");
+        let aof = Aof::open(path.clone(), FsyncPolicy::Never).unwrap();
+
+        let mut kv = KvStore::new();
+        for i in 0..10 {
+            let value = i.to_string().into_bytes();
+            kv.set(b"a", &value);
+            aof.append(&[b"set".to_vec(), b"a".to_vec(), value]).unwrap();
+        }
+
+        assert!(aof.bgrewrite(kv.iter()));
+
+        // Wait for the background rewrite to swap the file in
+        while aof.inner.lock().unwrap().rewrite_buffer.is_some() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(replay(&path, |_| ()).unwrap(), 1);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rewrite_splits_big_collections() {
+        let path = temp_path("chunks");
+
+        let mut kv = KvStore::new();
+        let values: Vec<_> = (0..200).map(|i| i.to_string().into_bytes()).collect();
+        kv.push(b"list", values.clone(), false).unwrap();
+        kv.hset(b"hash", values.iter().map(|value| (value.clone(), value.clone())).collect()).unwrap();
+        fs::write(&path, rewrite(kv.iter())).unwrap();
+
+        let mut replayed = KvStore::new();
+        let count = replay(&path, |packet| match packet {
+            RequestPacket::RPush { key, values } => assert!(replayed.push(&key, values, false).is_ok()),
+            RequestPacket::HSet { key, fields } => assert!(replayed.hset(&key, fields).is_ok()),
+            _ => panic!("unexpected packet"),
+        }).unwrap();
+
+        // At 64 elements a command, each collection takes 4 commands
+        assert_eq!(count, 8);
+        assert_eq!(replayed.lrange(b"list", 0, -1), Ok(values));
+        assert_eq!(replayed.hlen(b"hash"), Ok(200));
+
+        fs::remove_file(path).unwrap();
+    }
+}
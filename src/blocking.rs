@@ -0,0 +1,156 @@
+use crate::reply::Reply;
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+// Clients waiting in BLPOP, BRPOP or BLMOVE for a value to be pushed onto a list. Each key has
+// a queue of the clients waiting on it, so values go to whoever has been waiting longest.
+#[derive(Default)]
+pub struct BlockedClients {
+    queues: HashMap<Vec<u8>, VecDeque<u64>>,
+    waiters: HashMap<u64, Waiter>,
+    next_id: u64,
+    // Keys that have been pushed to since they were last served, while someone was waiting
+    ready: Vec<Vec<u8>>,
+}
+
+// What a waiting client does with the first value it gets
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockedPop {
+    Pop { front: bool },
+    Move { destination: Vec<u8>, from_front: bool, to_front: bool },
+}
+
+struct Waiter {
+    keys: Vec<Vec<u8>>,
+    pop: BlockedPop,
+    sender: Sender<Reply>,
+    // Lets the client know its reply is there to be taken
+    wake: Box<dyn Fn() + Send>,
+}
+
+impl BlockedClients {
+    pub fn new() -> Self {
+        BlockedClients::default()
+    }
+
+    // Queues a client up behind everyone already waiting on each of `keys`. Returns the ID to
+    // stop it waiting with, and where its reply arrives once it gets a value, which is followed
+    // by a call to `wake`.
+    pub fn block(&mut self, keys: Vec<Vec<u8>>, pop: BlockedPop, wake: impl Fn() + Send + 'static) -> (u64, Receiver<Reply>) {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        for key in &keys {
+            let queue = self.queues.entry(key.clone()).or_default();
+            // The same key can be given twice, but it only needs one place in the queue
+            if !queue.contains(&id) {
+                queue.push_back(id);
+            }
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        self.waiters.insert(id, Waiter { keys, pop, sender, wake: Box::new(wake) });
+
+        (id, receiver)
+    }
+
+    // Takes a client out of the queues of every key it was waiting on, if it's still waiting
+    pub fn unblock(&mut self, id: u64) {
+        let Some(waiter) = self.waiters.remove(&id) else {
+            return;
+        };
+
+        for key in waiter.keys {
+            if let Some(queue) = self.queues.get_mut(&key) {
+                queue.retain(|waiting| *waiting != id);
+                if queue.is_empty() {
+                    self.queues.remove(&key);
+                }
+            }
+        }
+    }
+
+    // Notes that a value was pushed onto `key`, so anyone waiting on it can be served
+    pub fn signal(&mut self, key: &[u8]) {
+        if self.queues.contains_key(key) && !self.ready.iter().any(|ready| ready == key) {
+            self.ready.push(key.to_vec());
+        }
+    }
+
+    // Takes the next key that's been pushed to, in the order they were pushed to
+    pub fn next_ready(&mut self) -> Option<Vec<u8>> {
+        if self.ready.is_empty() {
+            None
+        } else {
+            Some(self.ready.remove(0))
+        }
+    }
+
+    // The client that has been waiting on `key` the longest, and what it wants done
+    pub fn first(&self, key: &[u8]) -> Option<(u64, &BlockedPop)> {
+        let id = *self.queues.get(key)?.front()?;
+
+        Some((id, &self.waiters[&id].pop))
+    }
+
+    // Sends a waiting client its reply, which stops it waiting on any of its keys
+    pub fn wake(&mut self, id: u64, reply: Reply) {
+        if let Some(waiter) = self.waiters.get(&id) {
+            // The client might have hung up, in which case nobody is left to tell
+            if waiter.sender.send(reply).is_ok() {
+                (waiter.wake)();
+            }
+        }
+
+        self.unblock(id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn longest_waiting_first() {
+        let mut blocked = BlockedClients::new();
+        let pop = BlockedPop::Pop { front: true };
+
+        let woken = Arc::new(AtomicBool::new(false));
+        let first_woken = Arc::clone(&woken);
+
+        let (first, first_receiver) = blocked.block(vec![b"a".to_vec(), b"b".to_vec()], pop.clone(), move || {
+            first_woken.store(true, Ordering::SeqCst);
+        });
+        let (second, _) = blocked.block(vec![b"b".to_vec()], pop.clone(), || ());
+
+        // Nobody waits on c, so pushing to it doesn't make it ready
+        blocked.signal(b"c");
+        blocked.signal(b"b");
+        blocked.signal(b"b");
+        assert_eq!(blocked.next_ready(), Some(b"b".to_vec()));
+        assert_eq!(blocked.next_ready(), None);
+
+        assert_eq!(blocked.first(b"b"), Some((first, &pop)));
+        blocked.wake(first, Reply::Integer(1));
+        assert_eq!(first_receiver.try_recv(), Ok(Reply::Integer(1)));
+        assert!(woken.load(Ordering::SeqCst));
+
+        // Being woken takes the client off every key it was waiting on
+        assert_eq!(blocked.first(b"a"), None);
+        assert_eq!(blocked.first(b"b"), Some((second, &pop)));
+
+        blocked.unblock(second);
+        assert!(blocked.is_empty());
+        assert_eq!(blocked.first(b"b"), None);
+    }
+}
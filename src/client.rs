@@ -1,6 +1,7 @@
 use std::net::TcpStream;
 use std::io::{Read, Write, self};
 use clap::crate_version;
+use rustis::reply::Reply;
 
 pub struct Client {
     stream: TcpStream,
@@ -15,7 +16,7 @@ impl Client {
     pub fn new(host: &str, port: u16) -> Self {
         let connection_url = format!("{}:{}", host, port);
         let stream = TcpStream::connect(&connection_url)
-            .expect(&format!("Failed to connect to {}", &connection_url));
+            .unwrap_or_else(|_| panic!("Failed to connect to {}", &connection_url));
 
         Client {
             stream,
@@ -26,8 +27,8 @@ impl Client {
         let command = "publish";
 
         self.send(command, vec![
-            channel.trim(),
-            message.trim(),
+            channel.trim().as_bytes(),
+            message.trim().as_bytes(),
         ])?;
 
         self.echo_response(EchoType::Once)?;
@@ -35,25 +36,51 @@ impl Client {
         Ok(())
     }
 
-    pub fn send_subscribe(&mut self, channel: &str) -> io::Result<()> {
+    pub fn send_subscribe(&mut self, channels: Vec<&str>) -> io::Result<()> {
         let command = "subscribe";
 
-        self.send(command, vec![
-            channel.trim(),
-        ])?;
+        self.send(command, channels.iter().map(|channel| channel.trim().as_bytes()).collect())?;
 
         self.echo_response(EchoType::Loop)?;
 
         Ok(())
     }
 
-    pub fn send_set(&mut self, key: &str, value: &str) -> io::Result<()> {
+    pub fn send_psubscribe(&mut self, patterns: Vec<&str>) -> io::Result<()> {
+        let command = "psubscribe";
+
+        self.send(command, patterns.iter().map(|pattern| pattern.trim().as_bytes()).collect())?;
+
+        self.echo_response(EchoType::Loop)?;
+
+        Ok(())
+    }
+
+    // Sends a PUBSUB subcommand, such as "numsub", with its arguments
+    pub fn send_pubsub(&mut self, subcommand: &str, args: Vec<&str>) -> io::Result<()> {
+        let command = "pubsub";
+
+        let mut all_args = vec![subcommand.as_bytes()];
+        all_args.extend(args.iter().map(|arg| arg.trim().as_bytes()));
+
+        self.send(command, all_args)?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    // `options` are passed along after the value, such as ["nx", "px", "3000"]
+    pub fn send_set(&mut self, key: &str, value: &str, options: Vec<String>) -> io::Result<()> {
         let command = "set";
 
-        self.send(command, vec![
-            key.trim(),
-            value.trim(),
-        ])?;
+        let mut args = vec![
+            key.trim().as_bytes(),
+            value.trim().as_bytes(),
+        ];
+        args.extend(options.iter().map(|option| option.as_bytes()));
+
+        self.send(command, args)?;
 
         self.echo_response(EchoType::Once)?;
 
@@ -66,9 +93,9 @@ impl Client {
         let ttl = &ttl.to_string();
 
         self.send(command, vec![
-            key.trim(),
-            ttl,
-            value.trim(),
+            key.trim().as_bytes(),
+            ttl.as_bytes(),
+            value.trim().as_bytes(),
         ])?;
 
         self.echo_response(EchoType::Once)?;
@@ -80,7 +107,131 @@ impl Client {
         let command = "get";
 
         self.send(command, vec![
-            key.trim(),
+            key.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_del(&mut self, keys: Vec<&str>) -> io::Result<()> {
+        let command = "del";
+
+        self.send(command, keys.iter().map(|key| key.trim().as_bytes()).collect())?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_exists(&mut self, keys: Vec<&str>) -> io::Result<()> {
+        let command = "exists";
+
+        self.send(command, keys.iter().map(|key| key.trim().as_bytes()).collect())?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_keys(&mut self, pattern: &str) -> io::Result<()> {
+        let command = "keys";
+
+        self.send(command, vec![
+            pattern.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_scan(&mut self, cursor: u64, pattern: Option<&str>, count: Option<usize>) -> io::Result<()> {
+        let command = "scan";
+
+        let cursor = &cursor.to_string();
+        let count = count.map(|count| count.to_string());
+
+        let mut args = vec![
+            cursor.as_bytes(),
+        ];
+        if let Some(pattern) = pattern {
+            args.extend([&b"match"[..], pattern.trim().as_bytes()]);
+        }
+        if let Some(count) = &count {
+            args.extend([&b"count"[..], count.as_bytes()]);
+        }
+
+        self.send(command, args)?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_incr(&mut self, key: &str) -> io::Result<()> {
+        let command = "incr";
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_decr(&mut self, key: &str) -> io::Result<()> {
+        let command = "decr";
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_incrby(&mut self, key: &str, increment: i64) -> io::Result<()> {
+        let command = "incrby";
+
+        let increment = &increment.to_string();
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+            increment.as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_decrby(&mut self, key: &str, decrement: i64) -> io::Result<()> {
+        let command = "decrby";
+
+        let decrement = &decrement.to_string();
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+            decrement.as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_incrbyfloat(&mut self, key: &str, increment: f64) -> io::Result<()> {
+        let command = "incrbyfloat";
+
+        let increment = &increment.to_string();
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+            increment.as_bytes(),
         ])?;
 
         self.echo_response(EchoType::Once)?;
@@ -92,7 +243,23 @@ impl Client {
         let command = "ttl";
 
         self.send(command, vec![
-            key.trim(),
+            key.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_psetex(&mut self, key: &str, value: &str, ttl: u64) -> io::Result<()> {
+        let command = "psetex";
+
+        let ttl = &ttl.to_string();
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+            ttl.as_bytes(),
+            value.trim().as_bytes(),
         ])?;
 
         self.echo_response(EchoType::Once)?;
@@ -100,13 +267,564 @@ impl Client {
         Ok(())
     }
 
-    pub fn send(&mut self, command: &str, lines: Vec<&str>) -> io::Result<()> {
+    pub fn send_pttl(&mut self, key: &str) -> io::Result<()> {
+        let command = "pttl";
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_expire(&mut self, key: &str, ttl: i64) -> io::Result<()> {
+        let command = "expire";
+
+        let ttl = &ttl.to_string();
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+            ttl.as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_pexpire(&mut self, key: &str, ttl: i64) -> io::Result<()> {
+        let command = "pexpire";
+
+        let ttl = &ttl.to_string();
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+            ttl.as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_expireat(&mut self, key: &str, timestamp: i64) -> io::Result<()> {
+        let command = "expireat";
+
+        let timestamp = &timestamp.to_string();
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+            timestamp.as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_pexpireat(&mut self, key: &str, timestamp: i64) -> io::Result<()> {
+        let command = "pexpireat";
+
+        let timestamp = &timestamp.to_string();
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+            timestamp.as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_persist(&mut self, key: &str) -> io::Result<()> {
+        let command = "persist";
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    // Sends EVAL or EVALSHA, whose first argument is the script or its SHA1
+    pub fn send_eval(&mut self, command: &str, script: &str, numkeys: usize, args: Vec<&str>) -> io::Result<()> {
+        let numkeys = numkeys.to_string();
+
+        let mut all_args = vec![
+            script.as_bytes(),
+            numkeys.as_bytes(),
+        ];
+        all_args.extend(args.iter().map(|arg| arg.as_bytes()));
+
+        self.send(command, all_args)?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_script_load(&mut self, script: &str) -> io::Result<()> {
+        let command = "script";
+
+        self.send(command, vec![
+            &b"load"[..],
+            script.as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_info(&mut self) -> io::Result<()> {
+        let command = "info";
+
+        self.send(command, vec![])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_hset(&mut self, key: &str, field: &str, value: &str) -> io::Result<()> {
+        let command = "hset";
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+            field.trim().as_bytes(),
+            value.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_hget(&mut self, key: &str, field: &str) -> io::Result<()> {
+        let command = "hget";
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+            field.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_hdel(&mut self, key: &str, field: &str) -> io::Result<()> {
+        let command = "hdel";
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+            field.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_hexists(&mut self, key: &str, field: &str) -> io::Result<()> {
+        let command = "hexists";
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+            field.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_hlen(&mut self, key: &str) -> io::Result<()> {
+        let command = "hlen";
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_hgetall(&mut self, key: &str) -> io::Result<()> {
+        let command = "hgetall";
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_hincrby(&mut self, key: &str, field: &str, increment: i64) -> io::Result<()> {
+        let command = "hincrby";
+
+        let increment = &increment.to_string();
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+            field.trim().as_bytes(),
+            increment.as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_zadd(&mut self, key: &str, score: &str, member: &str) -> io::Result<()> {
+        let command = "zadd";
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+            score.trim().as_bytes(),
+            member.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_zrem(&mut self, key: &str, member: &str) -> io::Result<()> {
+        let command = "zrem";
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+            member.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_zscore(&mut self, key: &str, member: &str) -> io::Result<()> {
+        let command = "zscore";
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+            member.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_zincrby(&mut self, key: &str, increment: &str, member: &str) -> io::Result<()> {
+        let command = "zincrby";
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+            increment.trim().as_bytes(),
+            member.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_zrank(&mut self, key: &str, member: &str) -> io::Result<()> {
+        let command = "zrank";
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+            member.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_zrange(&mut self, key: &str, start: i64, stop: i64, withscores: bool, rev: bool) -> io::Result<()> {
+        let command = if rev { "zrevrange" } else { "zrange" };
+
+        let start = &start.to_string();
+        let stop = &stop.to_string();
+
+        let mut args = vec![
+            key.trim().as_bytes(),
+            start.as_bytes(),
+            stop.as_bytes(),
+        ];
+        if withscores {
+            args.push(b"withscores");
+        }
+
+        self.send(command, args)?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_zrangebyscore(&mut self, key: &str, min: &str, max: &str, withscores: bool, limit: Option<(i64, i64)>) -> io::Result<()> {
+        let command = "zrangebyscore";
+
+        let limit = limit.map(|(offset, count)| (offset.to_string(), count.to_string()));
+
+        let mut args = vec![
+            key.trim().as_bytes(),
+            min.trim().as_bytes(),
+            max.trim().as_bytes(),
+        ];
+        if withscores {
+            args.push(b"withscores");
+        }
+        if let Some((offset, count)) = &limit {
+            args.extend([&b"limit"[..], offset.as_bytes(), count.as_bytes()]);
+        }
+
+        self.send(command, args)?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_zcard(&mut self, key: &str) -> io::Result<()> {
+        let command = "zcard";
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_xadd(&mut self, key: &str, id: &str, fields: Vec<&str>, maxlen: Option<usize>) -> io::Result<()> {
+        let command = "xadd";
+
+        let maxlen = maxlen.map(|maxlen| maxlen.to_string());
+
+        let mut args = vec![key.trim().as_bytes()];
+        if let Some(maxlen) = &maxlen {
+            args.extend([&b"maxlen"[..], maxlen.as_bytes()]);
+        }
+        args.push(id.trim().as_bytes());
+        args.extend(fields.iter().map(|field| field.as_bytes()));
+
+        self.send(command, args)?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_xrange(&mut self, key: &str, start: &str, end: &str, count: Option<usize>, rev: bool) -> io::Result<()> {
+        let command = if rev { "xrevrange" } else { "xrange" };
+
+        let count = count.map(|count| count.to_string());
+
+        let mut args = vec![key.trim().as_bytes()];
+        if rev {
+            args.extend([end.trim().as_bytes(), start.trim().as_bytes()]);
+        } else {
+            args.extend([start.trim().as_bytes(), end.trim().as_bytes()]);
+        }
+        if let Some(count) = &count {
+            args.extend([&b"count"[..], count.as_bytes()]);
+        }
+
+        self.send(command, args)?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_xread(&mut self, key: &str, id: &str, count: Option<usize>, block: Option<u64>) -> io::Result<()> {
+        let command = "xread";
+
+        let count = count.map(|count| count.to_string());
+        let block = block.map(|block| block.to_string());
+
+        let mut args = Vec::new();
+        if let Some(count) = &count {
+            args.extend([&b"count"[..], count.as_bytes()]);
+        }
+        if let Some(block) = &block {
+            args.extend([&b"block"[..], block.as_bytes()]);
+        }
+        args.extend([&b"streams"[..], key.trim().as_bytes(), id.trim().as_bytes()]);
+
+        self.send(command, args)?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_xlen(&mut self, key: &str) -> io::Result<()> {
+        let command = "xlen";
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_xtrim(&mut self, key: &str, maxlen: usize) -> io::Result<()> {
+        let command = "xtrim";
+
+        let maxlen = maxlen.to_string();
+
+        self.send(command, vec![
+            key.trim().as_bytes(),
+            &b"maxlen"[..],
+            maxlen.as_bytes(),
+        ])?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_xgroup_create(&mut self, key: &str, group: &str, id: &str, mkstream: bool) -> io::Result<()> {
+        let command = "xgroup";
+
+        let mut args = vec![&b"create"[..], key.trim().as_bytes(), group.trim().as_bytes(), id.trim().as_bytes()];
+        if mkstream {
+            args.push(b"mkstream");
+        }
+
+        self.send(command, args)?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn send_xreadgroup(&mut self, group: &str, consumer: &str, key: &str, id: &str, count: Option<usize>, block: Option<u64>, noack: bool) -> io::Result<()> {
+        let command = "xreadgroup";
+
+        let count = count.map(|count| count.to_string());
+        let block = block.map(|block| block.to_string());
+
+        let mut args = vec![&b"group"[..], group.trim().as_bytes(), consumer.trim().as_bytes()];
+        if let Some(count) = &count {
+            args.extend([&b"count"[..], count.as_bytes()]);
+        }
+        if let Some(block) = &block {
+            args.extend([&b"block"[..], block.as_bytes()]);
+        }
+        if noack {
+            args.push(b"noack");
+        }
+        args.extend([&b"streams"[..], key.trim().as_bytes(), id.trim().as_bytes()]);
+
+        self.send(command, args)?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_xack(&mut self, key: &str, group: &str, ids: Vec<&str>) -> io::Result<()> {
+        let command = "xack";
+
+        let mut args = vec![key.trim().as_bytes(), group.trim().as_bytes()];
+        args.extend(ids.iter().map(|id| id.trim().as_bytes()));
+
+        self.send(command, args)?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_xpending(&mut self, key: &str, group: &str, count: Option<usize>, consumer: Option<&str>) -> io::Result<()> {
+        let command = "xpending";
+
+        let count = count.map(|count| count.to_string());
+
+        let mut args = vec![key.trim().as_bytes(), group.trim().as_bytes()];
+        if let Some(count) = &count {
+            args.extend([&b"-"[..], b"+", count.as_bytes()]);
+            if let Some(consumer) = consumer {
+                args.push(consumer.trim().as_bytes());
+            }
+        }
+
+        self.send(command, args)?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send_xclaim(&mut self, key: &str, group: &str, consumer: &str, min_idle: u64, ids: Vec<&str>, just_id: bool) -> io::Result<()> {
+        let command = "xclaim";
+
+        let min_idle = min_idle.to_string();
+
+        let mut args = vec![key.trim().as_bytes(), group.trim().as_bytes(), consumer.trim().as_bytes(), min_idle.as_bytes()];
+        args.extend(ids.iter().map(|id| id.trim().as_bytes()));
+        if just_id {
+            args.push(b"justid");
+        }
+
+        self.send(command, args)?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn send_xautoclaim(&mut self, key: &str, group: &str, consumer: &str, min_idle: u64, start: &str, count: Option<usize>, just_id: bool) -> io::Result<()> {
+        let command = "xautoclaim";
+
+        let min_idle = min_idle.to_string();
+        let count = count.map(|count| count.to_string());
+
+        let mut args = vec![key.trim().as_bytes(), group.trim().as_bytes(), consumer.trim().as_bytes(), min_idle.as_bytes(), start.trim().as_bytes()];
+        if let Some(count) = &count {
+            args.extend([&b"count"[..], count.as_bytes()]);
+        }
+        if just_id {
+            args.push(b"justid");
+        }
+
+        self.send(command, args)?;
+
+        self.echo_response(EchoType::Once)?;
+
+        Ok(())
+    }
+
+    pub fn send(&mut self, command: &str, args: Vec<&[u8]>) -> io::Result<()> {
         let version = crate_version!();
-        let version_line = format!("Rustis {}", version);
-        let message = format!("{}\n{}\n{}\n\n", version_line, command, lines.join("\n"));
+        let mut message = format!("Rustis/2 {}\n{}\n", version, args.len() + 1).into_bytes();
+
+        for arg in std::iter::once(command.as_bytes()).chain(args) {
+            message.extend_from_slice(format!("{}\n", arg.len()).as_bytes());
+            message.extend_from_slice(arg);
+            message.push(b'\n');
+        }
 
-        let bytes = message.as_bytes();
-        self.stream.write_all(bytes)?;
+        self.stream.write_all(&message)?;
         Ok(())
     }
 
@@ -114,7 +832,6 @@ impl Client {
         let mut buffer = [0; 1024];
         let mut data = Vec::new();
 
-
         loop {
             let bytes_read = self.stream.read(&mut buffer)?;
             // End of stream
@@ -124,15 +841,29 @@ impl Client {
 
             data.extend_from_slice(&buffer[..bytes_read]);
 
-            if let Some(index) = data.windows(2).position(|window| window == b"\n\n") {
-                // Process the packet
-                let packet = &data[..=index-1];
-                println!("{}", String::from_utf8_lossy(&packet));
+            // Replies are preceded by a line holding their length
+            while let Some(newline) = data.iter().position(|byte| *byte == b'\n') {
+                let len = String::from_utf8_lossy(&data[..newline])
+                    .parse::<usize>()
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid reply length"))?;
 
-                data.drain(..=index+1);
-                if let EchoType::Once = echo_type {
+                let end = newline + 1 + len;
+                if data.len() < end {
                     break;
                 }
+
+                // Arrays come with each element's length, they're printed one element per line
+                let packet = &data[newline + 1..end];
+                match Reply::decode_binary(packet) {
+                    Some(reply) => io::stdout().write_all(&reply.to_display())?,
+                    None => io::stdout().write_all(packet)?,
+                }
+                println!();
+
+                data.drain(..end);
+                if let EchoType::Once = echo_type {
+                    return Ok(());
+                }
             }
         }
 
@@ -0,0 +1,129 @@
+// Glob-style pattern matching, as used by KEYS and SCAN:
+//
+// *        any run of bytes, including none
+// ?        any single byte
+// [abc]    one of the listed bytes, [^abc] for any other byte, [a-z] for a range
+// \x       the byte x, even if it's one of the special characters above
+
+// Whether the whole of `text` matches `pattern`
+pub fn matches(pattern: &[u8], text: &[u8]) -> bool {
+    let mut p = 0;
+    let mut t = 0;
+
+    // Where to carry on from if the text stops matching after the last `*`: the position in
+    // the pattern after it, and the next position in the text for it to swallow
+    let mut backtrack = None;
+
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            backtrack = Some((p, t + 1));
+            continue;
+        }
+
+        if let Some(len) = match_one(&pattern[p..], text[t]) {
+            p += len;
+            t += 1;
+            continue;
+        }
+
+        match backtrack {
+            Some((star_p, star_t)) => {
+                p = star_p;
+                t = star_t;
+                backtrack = Some((star_p, star_t + 1));
+            },
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// Matches a single byte against the start of the pattern, returning how much of the pattern
+// was used if it matches
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    match pattern {
+        [] => None,
+        [b'?', ..] => Some(1),
+        [b'[', rest @ ..] => {
+            let (matched, len) = match_class(rest, c);
+            matched.then_some(len + 1)
+        },
+        [b'\\', escaped, ..] => (*escaped == c).then_some(2),
+        [literal, ..] => (*literal == c).then_some(1),
+    }
+}
+
+// Matches a byte against a class like `[^a-z]`, given the pattern after the `[`. Returns
+// whether it matched and how much of the pattern the class used, including the `]`. A class
+// with no closing `]` runs to the end of the pattern.
+fn match_class(pattern: &[u8], c: u8) -> (bool, usize) {
+    let (negated, mut i) = match pattern.first() {
+        Some(b'^') => (true, 1),
+        _ => (false, 0),
+    };
+
+    let mut matched = false;
+
+    while i < pattern.len() && pattern[i] != b']' {
+        match pattern[i..] {
+            [b'\\', escaped, ..] => {
+                matched |= escaped == c;
+                i += 2;
+            },
+            [start, b'-', end, ..] if end != b']' => {
+                let (low, high) = if start <= end { (start, end) } else { (end, start) };
+                matched |= (low..=high).contains(&c);
+                i += 3;
+            },
+            [literal, ..] => {
+                matched |= literal == c;
+                i += 1;
+            },
+            [] => unreachable!(),
+        }
+    }
+
+    let len = (i + 1).min(pattern.len());
+
+    (matched != negated, len)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(matches(b"*", b""));
+        assert!(matches(b"*", b"anything"));
+        assert!(matches(b"user:*", b"user:42"));
+        assert!(!matches(b"user:*", b"session:42"));
+        assert!(matches(b"*:*:name", b"user:42:name"));
+        assert!(!matches(b"*:*:name", b"user:42:email"));
+        assert!(matches(b"h?llo", b"hello"));
+        assert!(!matches(b"h?llo", b"hllo"));
+        assert!(matches(b"a*b*c", b"aXXbYYbZc"));
+        assert!(!matches(b"a*b*c", b"aXXbYY"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches(b"h[ae]llo", b"hello"));
+        assert!(matches(b"h[ae]llo", b"hallo"));
+        assert!(!matches(b"h[ae]llo", b"hillo"));
+        assert!(matches(b"h[^e]llo", b"hallo"));
+        assert!(!matches(b"h[^e]llo", b"hello"));
+        assert!(matches(b"h[a-b]llo", b"hbllo"));
+        assert!(!matches(b"h[a-b]llo", b"hcllo"));
+        assert!(matches(b"[a-]", b"-"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(matches(b"a\\*b", b"a*b"));
+        assert!(!matches(b"a\\*b", b"aXb"));
+        assert!(matches(b"[\\]]", b"]"));
+    }
+}
@@ -0,0 +1,408 @@
+use crate::kvstore::{self, AutoClaim, Entry, Hash, KvError, KvStore, Set, SetOp, SetOptions, Ttl, Value};
+use crate::sortedset::ScoreBound;
+use crate::stream::{ClaimOptions, ConsumerGroup, Fields, GroupReadFrom, NewId, ReadFrom, StreamId};
+use std::ops::Bound;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+/* The keyspace is split into shards, each a KvStore with its own lock, so commands on keys in
+different shards run at the same time.
+
+Each shard holds a contiguous range of `kvstore::scan_hash`, the order SCAN walks keys in. That
+way a SCAN cursor is still just a position in that order, and a walk goes through the shards
+one after another.
+
+A command locks every shard its keys are in before it starts, and holds them until it's done,
+so it sees and changes its keys all at once. Shards are always locked in index order, which
+means two commands can never each be holding a shard the other is waiting for.
+
+*/
+pub struct Keyspace {
+    shards: Vec<Mutex<KvStore>>,
+}
+
+impl Keyspace {
+    pub fn new(shards: usize) -> Self {
+        assert!(shards > 0, "a keyspace needs at least one shard");
+
+        Keyspace {
+            shards: (0..shards).map(|_| Mutex::new(KvStore::new())).collect(),
+        }
+    }
+
+    // Spreads the keys of a store, as loaded from a snapshot, across `shards` shards
+    pub fn from_store(mut kv: KvStore, shards: usize) -> Self {
+        let keyspace = Keyspace::new(shards);
+
+        for (key, value, ttl) in kv.drain() {
+            let shard = keyspace.shard_of(&key);
+            keyspace.shards[shard].lock().unwrap().restore(key, value, ttl);
+        }
+
+        keyspace
+    }
+
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn shard_of(&self, key: &[u8]) -> usize {
+        self.shard_at(kvstore::scan_hash(key))
+    }
+
+    // The shard whose range of hashes `hash` falls in
+    fn shard_at(&self, hash: u64) -> usize {
+        ((hash as u128 * self.shards.len() as u128) >> 64) as usize
+    }
+
+    // The first hash in a shard's range
+    fn shard_start(&self, shard: usize) -> u64 {
+        ((shard as u128) << 64).div_ceil(self.shards.len() as u128) as u64
+    }
+
+    // Locks the shards `keys` are in, which are the only keys the returned view can be used with
+    pub fn lock<'a>(&self, keys: impl IntoIterator<Item = &'a [u8]>) -> Shards<'_> {
+        let mut wanted = vec![false; self.shards.len()];
+        for key in keys {
+            wanted[self.shard_of(key)] = true;
+        }
+
+        self.lock_shards(|shard| wanted[shard])
+    }
+
+    // Locks every shard, for commands that can touch any key
+    pub fn lock_all(&self) -> Shards<'_> {
+        self.lock_shards(|_| true)
+    }
+
+    fn lock_shards(&self, wanted: impl Fn(usize) -> bool) -> Shards<'_> {
+        // Going through them in order is what keeps this from deadlocking
+        let locked = self.shards
+            .iter()
+            .enumerate()
+            .map(|(shard, store)| wanted(shard).then(|| store.lock().unwrap()))
+            .collect();
+
+        Shards { keyspace: self, locked }
+    }
+
+    // See `KvStore::set_loading`
+    pub fn set_loading(&self, loading: bool) {
+        for shard in &self.shards {
+            shard.lock().unwrap().set_loading(loading);
+        }
+    }
+
+    // One step of active expiry on a single shard, see `KvStore::expire_sample`
+    pub fn expire_sample(&self, shard: usize, count: usize) -> (usize, usize) {
+        self.shards[shard].lock().unwrap().expire_sample(count)
+    }
+}
+
+// Commands on a single key go straight to the shard holding it
+macro_rules! route {
+    ($($(#[$attr:meta])* fn $name:ident(key $(, $arg:ident: $ty:ty)*) -> $ret:ty;)*) => {
+        $(
+            $(#[$attr])*
+            pub fn $name(&self, key: &[u8] $(, $arg: $ty)*) -> $ret {
+                self.shard(key).$name(key $(, $arg)*)
+            }
+        )*
+    };
+}
+
+macro_rules! route_mut {
+    ($($(#[$attr:meta])* fn $name:ident(key $(, $arg:ident: $ty:ty)*) -> $ret:ty;)*) => {
+        $(
+            $(#[$attr])*
+            pub fn $name(&mut self, key: &[u8] $(, $arg: $ty)*) -> $ret {
+                self.shard_mut(key).$name(key $(, $arg)*)
+            }
+        )*
+    };
+}
+
+// Some of a keyspace's shards, locked for as long as this is held. It works like a KvStore
+// holding just the keys in those shards, and panics if it's used with any other key.
+pub struct Shards<'a> {
+    keyspace: &'a Keyspace,
+    // Indexed by shard, `None` for the ones that aren't locked
+    locked: Vec<Option<MutexGuard<'a, KvStore>>>,
+}
+
+impl Shards<'_> {
+    fn shard(&self, key: &[u8]) -> &KvStore {
+        self.locked[self.keyspace.shard_of(key)]
+            .as_deref()
+            .expect("key used without locking its shard")
+    }
+
+    fn shard_mut(&mut self, key: &[u8]) -> &mut KvStore {
+        self.locked[self.keyspace.shard_of(key)]
+            .as_deref_mut()
+            .expect("key used without locking its shard")
+    }
+
+    fn stores(&self) -> impl Iterator<Item = &KvStore> {
+        self.locked.iter().filter_map(|store| store.as_deref())
+    }
+
+    route! {
+        fn get(key) -> Result<Option<Vec<u8>>, KvError>;
+        fn version(key) -> u64;
+        fn ttl(key) -> Ttl;
+        fn lrange(key, start: i64, stop: i64) -> Result<Vec<Vec<u8>>, KvError>;
+        fn llen(key) -> Result<usize, KvError>;
+        fn lindex(key, index: i64) -> Result<Option<Vec<u8>>, KvError>;
+        fn hget(key, field: &[u8]) -> Result<Option<Vec<u8>>, KvError>;
+        fn hexists(key, field: &[u8]) -> Result<bool, KvError>;
+        fn hlen(key) -> Result<usize, KvError>;
+        fn hgetall(key) -> Result<Hash, KvError>;
+        fn smembers(key) -> Result<Set, KvError>;
+        fn sismember(key, member: &[u8]) -> Result<bool, KvError>;
+        fn scard(key) -> Result<usize, KvError>;
+        fn zscore(key, member: &[u8]) -> Result<Option<f64>, KvError>;
+        fn zrank(key, member: &[u8]) -> Result<Option<usize>, KvError>;
+        fn zrange(key, start: i64, stop: i64, rev: bool) -> Result<Vec<(Vec<u8>, f64)>, KvError>;
+        fn zrangebyscore(key, min: ScoreBound, max: ScoreBound, limit: Option<(usize, usize)>) -> Result<Vec<(Vec<u8>, f64)>, KvError>;
+        fn zcard(key) -> Result<usize, KvError>;
+        fn xrange(key, start: Bound<StreamId>, end: Bound<StreamId>, count: Option<usize>, rev: bool) -> Result<Vec<(StreamId, Fields)>, KvError>;
+        fn xread(key, id: StreamId, count: Option<usize>) -> Result<Vec<(StreamId, Fields)>, KvError>;
+        fn xlast_id(key) -> Result<StreamId, KvError>;
+        fn xlen(key) -> Result<usize, KvError>;
+        fn xgroup(key, group: &[u8]) -> Result<&ConsumerGroup, KvError>;
+    }
+
+    route_mut! {
+        fn watch(key) -> u64;
+        fn set_with(key, value: Vec<u8>, options: SetOptions) -> Result<(bool, Option<Vec<u8>>), KvError>;
+        fn incrby(key, increment: i64) -> Result<i64, KvError>;
+        fn incrbyfloat(key, increment: f64) -> Result<f64, KvError>;
+        fn expire(key, ttl: i64) -> bool;
+        fn expire_at(key, at: i64) -> bool;
+        fn persist(key) -> bool;
+        fn push(key, values: Vec<Vec<u8>>, front: bool) -> Result<usize, KvError>;
+        fn pop(key, front: bool, count: usize) -> Result<Option<Vec<Vec<u8>>>, KvError>;
+        fn ltrim(key, start: i64, stop: i64) -> Result<(), KvError>;
+        fn hset(key, fields: Vec<(Vec<u8>, Vec<u8>)>) -> Result<usize, KvError>;
+        fn hdel(key, fields: &[Vec<u8>]) -> Result<usize, KvError>;
+        fn hincrby(key, field: &[u8], increment: i64) -> Result<i64, KvError>;
+        fn sadd(key, members: Vec<Vec<u8>>) -> Result<usize, KvError>;
+        fn srem(key, members: &[Vec<u8>]) -> Result<usize, KvError>;
+        fn zadd(key, members: Vec<(f64, Vec<u8>)>) -> Result<usize, KvError>;
+        fn zrem(key, members: &[Vec<u8>]) -> Result<usize, KvError>;
+        fn zincrby(key, increment: f64, member: &[u8]) -> Result<f64, KvError>;
+        fn xadd(key, id: NewId, fields: Fields, maxlen: Option<usize>) -> Result<StreamId, KvError>;
+        fn xtrim(key, maxlen: usize) -> Result<usize, KvError>;
+        fn xsetid(key, id: StreamId) -> Result<(), KvError>;
+        fn xgroup_create(key, group: &[u8], from: ReadFrom, mkstream: bool) -> Result<(), KvError>;
+        fn xgroup_destroy(key, group: &[u8]) -> Result<bool, KvError>;
+        fn xgroup_setid(key, group: &[u8], from: ReadFrom) -> Result<(), KvError>;
+        fn xreadgroup(key, group: &[u8], consumer: &[u8], from: GroupReadFrom, count: Option<usize>, noack: bool) -> Result<Vec<(StreamId, Option<Fields>)>, KvError>;
+        fn xack(key, group: &[u8], ids: &[StreamId]) -> Result<usize, KvError>;
+        #[allow(clippy::type_complexity)]
+        fn xclaim(key, group: &[u8], consumer: &[u8], min_idle: u64, ids: &[StreamId], options: ClaimOptions) -> Result<(Vec<(StreamId, Fields)>, Vec<StreamId>), KvError>;
+        #[allow(clippy::too_many_arguments)]
+        fn xautoclaim(key, group: &[u8], consumer: &[u8], min_idle: u64, start: StreamId, count: usize, just_id: bool) -> Result<AutoClaim, KvError>;
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.shard_mut(key).set(key, value);
+    }
+
+    pub fn unwatch(&mut self, key: &[u8]) {
+        self.shard_mut(key).unwatch(key);
+    }
+
+    pub fn setex(&mut self, key: &[u8], value: &[u8], ttl: Duration) {
+        self.shard_mut(key).setex(key, value, ttl);
+    }
+
+    pub fn restore(&mut self, key: Vec<u8>, value: Value, ttl: Option<Duration>) {
+        self.shard_mut(&key).restore(key, value, ttl);
+    }
+
+    // The list is popped in one shard and pushed in another, but both are locked throughout, so
+    // nobody sees the value in neither or both
+    pub fn lmove(&mut self, source: &[u8], destination: &[u8], from_front: bool, to_front: bool) -> Result<Option<Vec<u8>>, KvError> {
+        if self.keyspace.shard_of(source) == self.keyspace.shard_of(destination) {
+            return self.shard_mut(source).lmove(source, destination, from_front, to_front);
+        }
+
+        // Checked up front, so a destination that isn't a list doesn't lose the value
+        self.llen(destination)?;
+
+        let Some(value) = self.pop(source, from_front, 1)?.and_then(|mut values| values.pop()) else {
+            return Ok(None);
+        };
+
+        self.push(destination, vec![value.clone()], to_front)?;
+        Ok(Some(value))
+    }
+
+    pub fn setop(&self, op: SetOp, keys: &[Vec<u8>]) -> Result<Set, KvError> {
+        let Some(first) = keys.first() else {
+            return Ok(Set::new());
+        };
+
+        // Sets in the same shard can be combined without copying them
+        let shard = self.keyspace.shard_of(first);
+        if keys.iter().all(|key| self.keyspace.shard_of(key) == shard) {
+            return self.shard(first).setop(op, keys);
+        }
+
+        let sets = keys
+            .iter()
+            .map(|key| self.smembers(key))
+            .collect::<Result<Vec<_>, KvError>>()?;
+
+        Ok(kvstore::combine_sets(op, &sets.iter().collect::<Vec<_>>()))
+    }
+
+    pub fn setopstore(&mut self, op: SetOp, destination: &[u8], keys: &[Vec<u8>]) -> Result<usize, KvError> {
+        let set = self.setop(op, keys)?;
+        Ok(self.shard_mut(destination).store_set(destination, set))
+    }
+
+    pub fn del(&mut self, keys: &[Vec<u8>]) -> usize {
+        keys.iter()
+            .map(|key| self.shard_mut(key).del(std::slice::from_ref(key)))
+            .sum()
+    }
+
+    pub fn exists(&self, keys: &[Vec<u8>]) -> usize {
+        keys.iter()
+            .map(|key| self.shard(key).exists(std::slice::from_ref(key)))
+            .sum()
+    }
+
+    // The keys from every locked shard
+    pub fn keys(&self, pattern: &[u8]) -> Vec<Vec<u8>> {
+        self.stores().flat_map(|store| store.keys(pattern)).collect()
+    }
+
+    // Scans the shard the cursor is in, carrying on into the next ones until `count` keys have
+    // been found. Every shard must be locked.
+    pub fn scan(&self, cursor: u64, pattern: Option<&[u8]>, count: usize) -> (u64, Vec<Vec<u8>>) {
+        let mut shard = self.keyspace.shard_at(cursor);
+        let mut cursor = cursor;
+        let mut keys = Vec::new();
+
+        loop {
+            let store = self.locked[shard].as_deref().expect("scan without locking every shard");
+            let (next, found) = store.scan(cursor, pattern, count);
+            keys.extend(found);
+
+            if next != 0 {
+                return (next, keys);
+            }
+
+            shard += 1;
+            if shard == self.locked.len() {
+                return (0, keys);
+            }
+
+            cursor = self.keyspace.shard_start(shard);
+            if keys.len() >= count {
+                return (cursor, keys);
+            }
+        }
+    }
+
+    // Every live key in the locked shards, with its value and remaining time to live
+    pub fn iter(&self) -> impl Iterator<Item = Entry<'_>> {
+        self.stores().flat_map(KvStore::iter)
+    }
+
+    pub fn len(&self) -> usize {
+        self.stores().map(KvStore::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stores().all(KvStore::is_empty)
+    }
+
+    pub fn expires(&self) -> usize {
+        self.stores().map(KvStore::expires).sum()
+    }
+
+    pub fn expired_keys(&self) -> u64 {
+        self.stores().map(KvStore::expired_keys).sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shards_cover_every_hash() {
+        let keyspace = Keyspace::new(3);
+
+        assert_eq!(keyspace.shard_at(1), 0);
+        assert_eq!(keyspace.shard_at(u64::MAX), 2);
+
+        for shard in 0..3 {
+            let start = keyspace.shard_start(shard);
+            assert_eq!(keyspace.shard_at(start), shard);
+            if shard > 0 {
+                assert_eq!(keyspace.shard_at(start - 1), shard - 1);
+            }
+        }
+    }
+
+    #[test]
+    fn scan_walks_every_shard() {
+        let keyspace = Keyspace::new(4);
+        let keys: Vec<_> = (0..50).map(|i| format!("key{}", i).into_bytes()).collect();
+
+        let mut kv = keyspace.lock_all();
+        for key in &keys {
+            kv.set(key, b"v");
+        }
+
+        let mut seen = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next, found) = kv.scan(cursor, None, 5);
+            seen.extend(found);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+
+        seen.sort();
+        let mut expected = keys.clone();
+        expected.sort();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn multi_key_commands_across_shards() {
+        let keyspace = Keyspace::new(8);
+
+        // Find two keys that live in different shards
+        let a = b"a".to_vec();
+        let b = (0..)
+            .map(|i| format!("b{}", i).into_bytes())
+            .find(|key| keyspace.shard_of(key) != keyspace.shard_of(&a))
+            .unwrap();
+
+        let mut kv = keyspace.lock([&a[..], &b[..]]);
+        kv.push(&a, vec![b"1".to_vec(), b"2".to_vec()], false).unwrap();
+
+        assert_eq!(kv.lmove(&a, &b, true, true), Ok(Some(b"1".to_vec())));
+        assert_eq!(kv.lrange(&a, 0, -1), Ok(vec![b"2".to_vec()]));
+        assert_eq!(kv.lrange(&b, 0, -1), Ok(vec![b"1".to_vec()]));
+
+        assert_eq!(kv.exists(&[a.clone(), b.clone()]), 2);
+        assert_eq!(kv.del(&[a.clone(), b.clone()]), 2);
+        assert!(kv.is_empty());
+
+        kv.sadd(&a, vec![b"x".to_vec(), b"y".to_vec()]).unwrap();
+        kv.sadd(&b, vec![b"y".to_vec()]).unwrap();
+        assert_eq!(kv.setopstore(SetOp::Diff, &b, &[a.clone(), b.clone()]), Ok(1));
+        assert_eq!(kv.smembers(&b), Ok(Set::from([b"x".to_vec()])));
+    }
+}
@@ -1,139 +1,1779 @@
-use std::collections::HashMap;
-use std::time::{Instant, Duration};
+use crate::glob;
+use crate::sortedset::{ScoreBound, SortedSet};
+use crate::stream::{ClaimOptions, ConsumerGroup, Fields, GroupReadFrom, IdError, NewId, ReadFrom, Stream, StreamId};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::hash::{Hash as _, Hasher};
+use std::ops::Bound;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+#[derive(Clone)]
 pub struct KvStore {
-    map: HashMap<String, Val>,
-    last_check_time: Instant,
+    map: HashMap<Vec<u8>, Val>,
+    // Every key in `map`, ordered by `scan_hash`, so SCAN can pick up where it left off
+    index: BTreeSet<(u64, Vec<u8>)>,
+    // The keys in `index` that have a time to live, which the active expiry cycle walks through
+    volatile: BTreeSet<(u64, Vec<u8>)>,
+    // Where the next active expiry sample starts
+    expire_cursor: u64,
+    // How many keys have been removed because they expired
+    expired_keys: u64,
+    // The version given to the last key that changed, see `version`
+    last_version: u64,
+    // Keys that connections are WATCHing, with how many are and the version stamped when the
+    // key was last removed, which is the version it has while it's missing
+    watched: HashMap<Vec<u8>, (usize, u64)>,
+    // Set while the append-only file is replayed, see `set_loading`
+    loading: bool,
 }
 
+pub type Hash = HashMap<Vec<u8>, Vec<u8>>;
+pub type Set = HashSet<Vec<u8>>;
+// A live key as it's saved, with its value and remaining time to live
+pub type Entry<'a> = (&'a [u8], &'a Value, Option<Duration>);
+
+// The value held by a key, along with which commands can be used on it
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Str(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(Hash),
+    Set(Set),
+    ZSet(SortedSet),
+    Stream(Stream),
+}
+
+// How the sets at several keys are combined
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetOp {
+    // Members in every set
+    Inter,
+    // Members in any set
+    Union,
+    // Members of the first set that aren't in any of the others
+    Diff,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum KvError {
+    // The key holds a different type of value than the command works on
+    WrongType,
+    // A value used as a number isn't one
+    NotInteger,
+    // Adding to a number would take it out of range
+    Overflow,
+    // A value used as a float isn't one
+    NotFloat,
+    // Adding to a float gave NaN or infinity
+    NotFinite,
+    // Adding to a score gave NaN, as with inf + -inf
+    NaN,
+    // A new stream entry was given the ID 0-0
+    StreamIdZero,
+    // A new stream entry was given an ID that doesn't come after the stream's last one
+    StreamIdTooSmall,
+    // A stream's last ID was set below the ID of its newest entry
+    StreamIdBelowTop,
+    // The command needs a key that doesn't exist
+    NoSuchKey,
+    // There's no stream with that consumer group
+    NoGroup,
+    // A consumer group with that name already exists
+    BusyGroup,
+}
+
+impl From<IdError> for KvError {
+    fn from(error: IdError) -> Self {
+        match error {
+            IdError::Zero => KvError::StreamIdZero,
+            IdError::TooSmall => KvError::StreamIdTooSmall,
+        }
+    }
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvError::WrongType => write!(f, "WRONGTYPE Operation against a key holding the wrong kind of value"),
+            KvError::NotInteger => write!(f, "value is not an integer or out of range"),
+            KvError::Overflow => write!(f, "increment or decrement would overflow"),
+            KvError::NotFloat => write!(f, "value is not a valid float"),
+            KvError::NotFinite => write!(f, "increment would produce NaN or Infinity"),
+            KvError::NaN => write!(f, "resulting score is not a number (NaN)"),
+            KvError::StreamIdZero => write!(f, "The ID specified in XADD must be greater than 0-0"),
+            KvError::StreamIdTooSmall => write!(f, "The ID specified in XADD is equal or smaller than the target stream top item"),
+            KvError::StreamIdBelowTop => write!(f, "The ID specified in XSETID is smaller than the target stream top item"),
+            KvError::NoSuchKey => write!(f, "no such key"),
+            KvError::NoGroup => write!(f, "NOGROUP No such key or consumer group"),
+            KvError::BusyGroup => write!(f, "BUSYGROUP Consumer Group name already exists"),
+        }
+    }
+}
+
+// Options for SET beyond writing the value
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SetOptions {
+    // Only write if the key does or doesn't already exist
+    pub condition: Option<SetCondition>,
+    // What happens to the key's time to live, which is removed if this is `None`
+    pub expiry: Option<SetExpiry>,
+    // Reply with the value the key held before
+    pub get: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetCondition {
+    // NX, for when the key doesn't exist
+    Missing,
+    // XX, for when it does
+    Exists,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetExpiry {
+    // KEEPTTL, leaving the key's time to live as it was
+    Keep,
+    // EX or PX, expiring after this long
+    After(Duration),
+    // EXAT or PXAT, expiring at a unix time in milliseconds
+    At(i64),
+}
+
+// How long a key has left to live
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ttl {
+    // There's no such key
+    Missing,
+    // The key never expires
+    Persistent,
+    // The key expires after this long
+    Expires(Duration),
+}
+
+// What XAUTOCLAIM did, see `KvStore::xautoclaim`
+#[derive(Debug, PartialEq)]
+pub struct AutoClaim {
+    pub next: StreamId,
+    pub claimed: Vec<(StreamId, Fields)>,
+    pub deleted: Vec<StreamId>,
+}
+
+#[derive(Clone)]
 struct Val {
-    val: String,
-    ttl: Option<Duration>,
-    created_at: Instant,
+    val: Value,
+    expires_at: Option<Instant>,
+    // Bumped every time the key might have changed
+    version: u64,
+}
+
+// When something that expires after `ttl` runs out. One too far off for the clock to hold never
+// does, which commands avoid by turning away TTLs that big.
+fn expiry_after(ttl: Duration) -> Option<Instant> {
+    Instant::now().checked_add(ttl)
 }
 
 impl Val {
-    pub fn new(val: &str, ttl: Option<u64>) -> Self {
+    pub fn new(val: Value, ttl: Option<Duration>) -> Self {
         Val {
-            val: val.to_string(),
-            ttl: ttl.map_or(None, |ttl| Some(Duration::from_secs(ttl))),
-            created_at: Instant::now(),
+            val,
+            expires_at: ttl.and_then(expiry_after),
+            version: 0,
         }
     }
+
+    // Time left before the value expires, `None` if it never does or already has
+    fn remaining(&self) -> Option<Duration> {
+        self.expires_at.and_then(|expires_at| expires_at.checked_duration_since(Instant::now()))
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| Instant::now() >= expires_at)
+    }
+}
+
+impl Default for KvStore {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl KvStore {
     pub fn new() -> Self {
         KvStore {
             map: HashMap::new(),
-            last_check_time: Instant::now(),
+            index: BTreeSet::new(),
+            volatile: BTreeSet::new(),
+            expire_cursor: 0,
+            expired_keys: 0,
+            last_version: 0,
+            watched: HashMap::new(),
+            loading: false,
         }
     }
 
-    pub fn set(&mut self, key: &str, value: &str) {
-        let val = Val::new(value, None);
-        self.map.insert(key.to_string(), val);
+    /* While loading, keys don't expire. The append-only file is replayed some time after it
+    was written, so a TTL that has run out since then would otherwise drop a key partway
+    through, and the commands after it would run on a key that was there the first time around.
+    Expiry times that have already passed are kept instead, and those keys expire once loading
+    is done. */
+    pub fn set_loading(&mut self, loading: bool) {
+        self.loading = loading;
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) {
+        let val = Val::new(Value::Str(value.to_vec()), None);
+        self.insert(key.to_vec(), val);
     }
 
-    pub fn setex(&mut self, key: &str, value: &str, ttl: u64) {
-        let val = Val::new(value, Some(ttl));
-        self.map.insert(key.to_string(), val);
+    pub fn setex(&mut self, key: &[u8], value: &[u8], ttl: Duration) {
+        let val = Val::new(Value::Str(value.to_vec()), Some(ttl));
+        self.insert(key.to_vec(), val);
     }
 
-    pub fn get(&self, key: &str) -> Option<String> {
-        let val = self.map.get(&key.to_string());
+    // Sets a key's value subject to `options`, returning whether it was written along with
+    // the value it held before if `options.get` is set
+    pub fn set_with(&mut self, key: &[u8], value: Vec<u8>, options: SetOptions) -> Result<(bool, Option<Vec<u8>>), KvError> {
+        let old = if options.get { self.get(key)? } else { None };
+        let exists = self.live(key).is_some();
 
-        if let Some(val) = val {
-            if let Some(ttl) = val.ttl {
-                let expired = val.created_at.elapsed() > ttl;
-                if expired {
-                    None
-                } else {
-                    Some(val.val.clone())
+        let write = match options.condition {
+            Some(SetCondition::Missing) => !exists,
+            Some(SetCondition::Exists) => exists,
+            None => true,
+        };
+        if !write {
+            return Ok((false, old));
+        }
+
+        let expires_at = match options.expiry {
+            Some(SetExpiry::Keep) => self.live(key).and_then(|val| val.expires_at),
+            Some(SetExpiry::After(ttl)) => expiry_after(ttl),
+            Some(SetExpiry::At(at)) => {
+                let ttl = at.saturating_sub(unix_millis());
+
+                // A time that has already passed leaves nothing to write
+                if ttl <= 0 && !self.loading {
+                    self.remove(key);
+                    return Ok((true, old));
                 }
-            } else {
-                Some(val.val.clone())
+                self.expiry_in(ttl)
+            },
+            None => None,
+        };
+
+        self.insert(key.to_vec(), Val { val: Value::Str(value), expires_at, version: 0 });
+
+        Ok((true, old))
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, KvError> {
+        match self.live(key) {
+            Some(Val { val: Value::Str(val), .. }) => Ok(Some(val.clone())),
+            Some(_) => Err(KvError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    // Adds to the integer stored at a key, treating a missing key as 0, and returns the new
+    // value. The key keeps its time to live.
+    pub fn incrby(&mut self, key: &[u8], increment: i64) -> Result<i64, KvError> {
+        let current = match self.string(key)? {
+            Some(value) => parse_integer(value)?,
+            None => 0,
+        };
+
+        let new = current.checked_add(increment).ok_or(KvError::Overflow)?;
+        self.replace_string(key, new.to_string().into_bytes());
+
+        Ok(new)
+    }
+
+    // Adds to the float stored at a key, treating a missing key as 0, and returns the new
+    // value. The key keeps its time to live.
+    pub fn incrbyfloat(&mut self, key: &[u8], increment: f64) -> Result<f64, KvError> {
+        let current = match self.string(key)? {
+            Some(value) => parse_float(value)?,
+            None => 0.0,
+        };
+
+        let new = current + increment;
+        if !new.is_finite() {
+            return Err(KvError::NotFinite);
+        }
+        self.replace_string(key, new.to_string().into_bytes());
+
+        Ok(new)
+    }
+
+    // A number that changes whenever the key is written, deleted or expires, for WATCH to
+    // tell whether a key changed between two points. A missing key keeps the version stamped
+    // when it was last removed while watched, so one that's created and deleted again doesn't
+    // look untouched. Missing keys that nobody watches are 0.
+    pub fn version(&self, key: &[u8]) -> u64 {
+        match self.live(key) {
+            Some(val) => val.version,
+            None => self.watched.get(key).map_or(0, |(_, removed)| *removed),
+        }
+    }
+
+    // Starts keeping track of when `key` is removed, until it's unwatched as many times as it
+    // was watched. Returns its version.
+    pub fn watch(&mut self, key: &[u8]) -> u64 {
+        self.watched.entry(key.to_vec()).or_default().0 += 1;
+        self.version(key)
+    }
+
+    pub fn unwatch(&mut self, key: &[u8]) {
+        if let Some((watchers, _)) = self.watched.get_mut(key) {
+            *watchers -= 1;
+            if *watchers == 0 {
+                self.watched.remove(key);
             }
+        }
+    }
+
+    pub fn ttl(&self, key: &[u8]) -> Ttl {
+        match self.live(key) {
+            Some(val) => val.remaining().map_or(Ttl::Persistent, Ttl::Expires),
+            None => Ttl::Missing,
+        }
+    }
+
+    // Sets a key to expire after `ttl` milliseconds, keeping its value. A TTL of 0 or less
+    // deletes the key straight away. Returns false if there's no such key.
+    pub fn expire(&mut self, key: &[u8], ttl: i64) -> bool {
+        if self.live_mut(key).is_none() {
+            return false;
+        }
+
+        if ttl <= 0 && !self.loading {
+            self.remove(key);
+        } else {
+            let expires_at = self.expiry_in(ttl);
+            self.set_expiry(key, expires_at);
+        }
+
+        true
+    }
+
+    // When something expiring in `ttl` milliseconds runs out. Only while loading can the time
+    // have passed already, which leaves it expiring as soon as loading is done.
+    fn expiry_in(&self, ttl: i64) -> Option<Instant> {
+        if ttl <= 0 {
+            Some(Instant::now())
         } else {
-            None
+            expiry_after(Duration::from_millis(ttl as u64))
         }
+    }
+
+    // Sets a key to expire at a unix time in milliseconds, deleting it straight away if that
+    // time has passed. Returns false if there's no such key.
+    pub fn expire_at(&mut self, key: &[u8], at: i64) -> bool {
+        self.expire(key, at.saturating_sub(unix_millis()))
+    }
 
+    // Stops a key from expiring, returning false if it doesn't exist or has no TTL
+    pub fn persist(&mut self, key: &[u8]) -> bool {
+        match self.live_mut(key) {
+            Some(Val { expires_at: Some(_), .. }) => {
+                self.set_expiry(key, None);
+                true
+            },
+            _ => false,
+        }
     }
 
-    pub fn ttl(&self, key: &str) -> Option<u64> {
-        let val = self.map.get(&key.to_string());
+    // Adds values to the front or back of a list, creating it if needed, and returns the
+    // list's new length
+    pub fn push(&mut self, key: &[u8], values: Vec<Vec<u8>>, front: bool) -> Result<usize, KvError> {
+        let Value::List(list) = self.live_or_insert(key, Value::List(VecDeque::new())) else {
+            return Err(KvError::WrongType);
+        };
 
-        if let Some(val) = val {
-            if let Some(ttl) = val.ttl {
-                let elapsed = val.created_at.elapsed();
-                let expired = elapsed > ttl;
-                if expired {
-                    None
-                } else {
-                    let time_remaining = ttl - elapsed;
-                    Some(time_remaining.as_secs())
-                }
+        for value in values {
+            if front {
+                list.push_front(value);
             } else {
-                None
+                list.push_back(value);
             }
+        }
+
+        Ok(list.len())
+    }
+
+    // Removes up to `count` values from the front or back of a list, `None` if there is no list
+    pub fn pop(&mut self, key: &[u8], front: bool, count: usize) -> Result<Option<Vec<Vec<u8>>>, KvError> {
+        let Some(list) = self.list_mut(key)? else {
+            return Ok(None);
+        };
+
+        let count = count.min(list.len());
+        let values = if front {
+            list.drain(..count).collect()
         } else {
-            None
+            list.drain(list.len() - count..).rev().collect()
+        };
+
+        self.remove_if_empty(key);
+        Ok(Some(values))
+    }
+
+    // Pops a value off one end of `source` and pushes it onto one end of `destination`, which
+    // can be the same list. `None` if there is no source list.
+    pub fn lmove(&mut self, source: &[u8], destination: &[u8], from_front: bool, to_front: bool) -> Result<Option<Vec<u8>>, KvError> {
+        // Checked up front, so a destination that isn't a list doesn't lose the value
+        self.list(destination)?;
+
+        let Some(value) = self.pop(source, from_front, 1)?.and_then(|mut values| values.pop()) else {
+            return Ok(None);
+        };
+
+        self.push(destination, vec![value.clone()], to_front)?;
+        Ok(Some(value))
+    }
+
+    pub fn lrange(&self, key: &[u8], start: i64, stop: i64) -> Result<Vec<Vec<u8>>, KvError> {
+        let Some(list) = self.list(key)? else {
+            return Ok(Vec::new());
+        };
+
+        match range_bounds(start, stop, list.len()) {
+            Some((start, stop)) => Ok(list.range(start..=stop).cloned().collect()),
+            None => Ok(Vec::new()),
         }
     }
 
-    pub fn collect_garbage(&mut self) {
-        let mut old_keys = Vec::new();
-        for (key, val) in &self.map {
-            if let Some(ttl) = val.ttl {
-                if val.created_at.elapsed() >= ttl {
-                    old_keys.push(key.to_string());
-                }
-            }
+    pub fn llen(&self, key: &[u8]) -> Result<usize, KvError> {
+        Ok(self.list(key)?.map_or(0, VecDeque::len))
+    }
+
+    pub fn lindex(&self, key: &[u8], index: i64) -> Result<Option<Vec<u8>>, KvError> {
+        let Some(list) = self.list(key)? else {
+            return Ok(None);
+        };
+
+        let index = if index < 0 { list.len() as i64 + index } else { index };
+        if index < 0 {
+            return Ok(None);
         }
 
-        for old_key in old_keys {
-            self.map.remove(&old_key);
+        Ok(list.get(index as usize).cloned())
+    }
+
+    // Keeps only the values between `start` and `stop`, inclusive
+    pub fn ltrim(&mut self, key: &[u8], start: i64, stop: i64) -> Result<(), KvError> {
+        let Some(list) = self.list_mut(key)? else {
+            return Ok(());
+        };
+
+        match range_bounds(start, stop, list.len()) {
+            Some((start, stop)) => {
+                list.truncate(stop + 1);
+                list.drain(..start);
+            },
+            None => list.clear(),
         }
-        self.last_check_time = Instant::now();
+
+        self.remove_if_empty(key);
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    // Sets fields of a hash, creating it if needed, and returns how many fields are new
+    pub fn hset(&mut self, key: &[u8], fields: Vec<(Vec<u8>, Vec<u8>)>) -> Result<usize, KvError> {
+        let Value::Hash(hash) = self.live_or_insert(key, Value::Hash(Hash::new())) else {
+            return Err(KvError::WrongType);
+        };
 
-    #[test]
-    fn test_set() {
-        let mut kv = KvStore::new();
+        let added = fields
+            .into_iter()
+            .filter(|(field, value)| hash.insert(field.clone(), value.clone()).is_none())
+            .count();
 
-        kv.set("test", "value");
+        Ok(added)
+    }
+
+    pub fn hget(&self, key: &[u8], field: &[u8]) -> Result<Option<Vec<u8>>, KvError> {
+        Ok(self.hash(key)?.and_then(|hash| hash.get(field)).cloned())
+    }
 
-        let result = kv.get("test");
+    // Removes fields from a hash and returns how many of them existed
+    pub fn hdel(&mut self, key: &[u8], fields: &[Vec<u8>]) -> Result<usize, KvError> {
+        let Some(hash) = self.hash_mut(key)? else {
+            return Ok(0);
+        };
 
-        if let Some(val) = result {
-            assert_eq!(&val[..], "value");
+        let removed = fields
+            .iter()
+            .filter(|field| hash.remove(*field).is_some())
+            .count();
+
+        self.remove_if_empty(key);
+        Ok(removed)
+    }
+
+    pub fn hexists(&self, key: &[u8], field: &[u8]) -> Result<bool, KvError> {
+        Ok(self.hash(key)?.is_some_and(|hash| hash.contains_key(field)))
+    }
+
+    pub fn hlen(&self, key: &[u8]) -> Result<usize, KvError> {
+        Ok(self.hash(key)?.map_or(0, HashMap::len))
+    }
+
+    pub fn hgetall(&self, key: &[u8]) -> Result<Hash, KvError> {
+        Ok(self.hash(key)?.cloned().unwrap_or_default())
+    }
+
+    // Adds to the integer held in a hash field, treating a missing field as 0, and returns
+    // the new value
+    pub fn hincrby(&mut self, key: &[u8], field: &[u8], increment: i64) -> Result<i64, KvError> {
+        let Value::Hash(hash) = self.live_or_insert(key, Value::Hash(Hash::new())) else {
+            return Err(KvError::WrongType);
+        };
+
+        let current = match hash.get(field) {
+            Some(value) => parse_integer(value)?,
+            None => 0,
+        };
+
+        let new = current.checked_add(increment).ok_or(KvError::Overflow)?;
+        hash.insert(field.to_vec(), new.to_string().into_bytes());
+
+        Ok(new)
+    }
+
+    // Adds members to a set, creating it if needed, and returns how many weren't already in it
+    pub fn sadd(&mut self, key: &[u8], members: Vec<Vec<u8>>) -> Result<usize, KvError> {
+        let Value::Set(set) = self.live_or_insert(key, Value::Set(Set::new())) else {
+            return Err(KvError::WrongType);
+        };
+
+        Ok(members.into_iter().filter(|member| set.insert(member.clone())).count())
+    }
+
+    // Removes members from a set and returns how many of them were in it
+    pub fn srem(&mut self, key: &[u8], members: &[Vec<u8>]) -> Result<usize, KvError> {
+        let Some(set) = self.members_mut(key)? else {
+            return Ok(0);
+        };
+
+        let removed = members
+            .iter()
+            .filter(|member| set.remove(*member))
+            .count();
+
+        self.remove_if_empty(key);
+        Ok(removed)
+    }
+
+    pub fn smembers(&self, key: &[u8]) -> Result<Set, KvError> {
+        Ok(self.members(key)?.cloned().unwrap_or_default())
+    }
+
+    pub fn sismember(&self, key: &[u8], member: &[u8]) -> Result<bool, KvError> {
+        Ok(self.members(key)?.is_some_and(|set| set.contains(member)))
+    }
+
+    pub fn scard(&self, key: &[u8]) -> Result<usize, KvError> {
+        Ok(self.members(key)?.map_or(0, HashSet::len))
+    }
+
+    // Combines the sets at `keys`, where a missing key counts as an empty set
+    pub fn setop(&self, op: SetOp, keys: &[Vec<u8>]) -> Result<Set, KvError> {
+        let empty = Set::new();
+        let sets = keys
+            .iter()
+            .map(|key| Ok(self.members(key)?.unwrap_or(&empty)))
+            .collect::<Result<Vec<_>, KvError>>()?;
+
+        Ok(combine_sets(op, &sets))
+    }
+
+    // Combines the sets at `keys` and stores the result at `destination`, replacing whatever
+    // was there. Returns the size of the new set.
+    pub fn setopstore(&mut self, op: SetOp, destination: &[u8], keys: &[Vec<u8>]) -> Result<usize, KvError> {
+        let set = self.setop(op, keys)?;
+        Ok(self.store_set(destination, set))
+    }
+
+    // Replaces whatever is at `key` with a set, deleting the key if the set is empty. Returns
+    // the size of the set.
+    pub fn store_set(&mut self, key: &[u8], set: Set) -> usize {
+        let len = set.len();
+
+        if set.is_empty() {
+            self.remove(key);
         } else {
-            assert!(false);
+            self.insert(key.to_vec(), Val::new(Value::Set(set), None));
         }
+
+        len
     }
 
-    #[test]
-    fn test_setex() {
-        let mut kv = KvStore::new();
+    // Adds members to a sorted set or updates their scores, creating the set if needed, and
+    // returns how many members are new
+    pub fn zadd(&mut self, key: &[u8], members: Vec<(f64, Vec<u8>)>) -> Result<usize, KvError> {
+        let Value::ZSet(zset) = self.live_or_insert(key, Value::ZSet(SortedSet::new())) else {
+            return Err(KvError::WrongType);
+        };
+
+        Ok(members.into_iter().filter(|(score, member)| zset.insert(member.clone(), *score)).count())
+    }
 
-        kv.setex("test", "value", 10);
+    // Removes members from a sorted set and returns how many of them were in it
+    pub fn zrem(&mut self, key: &[u8], members: &[Vec<u8>]) -> Result<usize, KvError> {
+        let Some(zset) = self.zset_mut(key)? else {
+            return Ok(0);
+        };
 
-        let result = kv.get("test");
+        let removed = members
+            .iter()
+            .filter(|member| zset.remove(member))
+            .count();
 
-        if let Some(val) = result {
-            assert_eq!(&val[..], "value");
+        self.remove_if_empty(key);
+        Ok(removed)
+    }
+
+    pub fn zscore(&self, key: &[u8], member: &[u8]) -> Result<Option<f64>, KvError> {
+        Ok(self.zset(key)?.and_then(|zset| zset.score(member)))
+    }
+
+    // Adds to a member's score, treating a missing member as 0, and returns the new score
+    pub fn zincrby(&mut self, key: &[u8], increment: f64, member: &[u8]) -> Result<f64, KvError> {
+        if let Some(zset) = self.zset(key)? {
+            let score = zset.score(member).unwrap_or(0.0) + increment;
+            if score.is_nan() {
+                return Err(KvError::NaN);
+            }
+        }
+
+        let Value::ZSet(zset) = self.live_or_insert(key, Value::ZSet(SortedSet::new())) else {
+            return Err(KvError::WrongType);
+        };
+
+        let score = zset.score(member).unwrap_or(0.0) + increment;
+        zset.insert(member.to_vec(), score);
+
+        Ok(score)
+    }
+
+    pub fn zrank(&self, key: &[u8], member: &[u8]) -> Result<Option<usize>, KvError> {
+        Ok(self.zset(key)?.and_then(|zset| zset.rank(member)))
+    }
+
+    // Members between two ranks, inclusive, counting from the lowest score or from the
+    // highest if `rev` is set
+    pub fn zrange(&self, key: &[u8], start: i64, stop: i64, rev: bool) -> Result<Vec<(Vec<u8>, f64)>, KvError> {
+        let Some(zset) = self.zset(key)? else {
+            return Ok(Vec::new());
+        };
+
+        let Some((start, stop)) = range_bounds(start, stop, zset.len()) else {
+            return Ok(Vec::new());
+        };
+
+        let members: Box<dyn Iterator<Item = (&[u8], f64)>> = if rev {
+            Box::new(zset.iter().rev())
+        } else {
+            Box::new(zset.iter())
+        };
+
+        Ok(members
+            .skip(start)
+            .take(stop - start + 1)
+            .map(|(member, score)| (member.to_vec(), score))
+            .collect())
+    }
+
+    // Members with scores between `min` and `max`, skipping `offset` of them and returning at
+    // most `count` if a limit is given
+    pub fn zrangebyscore(&self, key: &[u8], min: ScoreBound, max: ScoreBound, limit: Option<(usize, usize)>) -> Result<Vec<(Vec<u8>, f64)>, KvError> {
+        let Some(zset) = self.zset(key)? else {
+            return Ok(Vec::new());
+        };
+
+        let (offset, count) = limit.unwrap_or((0, usize::MAX));
+
+        Ok(zset
+            .range_by_score(min, max)
+            .skip(offset)
+            .take(count)
+            .map(|(member, score)| (member.to_vec(), score))
+            .collect())
+    }
+
+    pub fn zcard(&self, key: &[u8]) -> Result<usize, KvError> {
+        Ok(self.zset(key)?.map_or(0, SortedSet::len))
+    }
+
+    // Appends an entry to a stream, creating the stream if needed, and returns the entry's
+    // ID. With `maxlen`, the oldest entries are then trimmed to leave at most that many.
+    pub fn xadd(&mut self, key: &[u8], id: NewId, fields: Fields, maxlen: Option<usize>) -> Result<StreamId, KvError> {
+        // The ID is checked first, so a bad one doesn't leave an empty stream behind
+        let now = unix_millis() as u64;
+        let id = match self.stream(key)? {
+            Some(stream) => stream.next_id(id, now)?,
+            None => Stream::new().next_id(id, now)?,
+        };
+
+        let Value::Stream(stream) = self.live_or_insert(key, Value::Stream(Stream::new())) else {
+            return Err(KvError::WrongType);
+        };
+
+        stream.append(id, fields);
+        if let Some(maxlen) = maxlen {
+            stream.trim(maxlen);
+        }
+
+        Ok(id)
+    }
+
+    // Entries with IDs between `start` and `end`, newest first if `rev`, returning at most
+    // `count` if given
+    pub fn xrange(&self, key: &[u8], start: Bound<StreamId>, end: Bound<StreamId>, count: Option<usize>, rev: bool) -> Result<Vec<(StreamId, Fields)>, KvError> {
+        let Some(stream) = self.stream(key)? else {
+            return Ok(Vec::new());
+        };
+
+        let entries: Box<dyn Iterator<Item = (StreamId, &Fields)>> = if rev {
+            Box::new(stream.range(start, end).rev())
+        } else {
+            Box::new(stream.range(start, end))
+        };
+
+        Ok(entries
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| (id, fields.clone()))
+            .collect())
+    }
+
+    // Entries added after `id`, oldest first
+    pub fn xread(&self, key: &[u8], id: StreamId, count: Option<usize>) -> Result<Vec<(StreamId, Fields)>, KvError> {
+        self.xrange(key, Bound::Excluded(id), Bound::Unbounded, count, false)
+    }
+
+    // The ID of the newest entry a stream has had, 0-0 if there's no stream at the key
+    pub fn xlast_id(&self, key: &[u8]) -> Result<StreamId, KvError> {
+        Ok(self.stream(key)?.map_or(StreamId::MIN, Stream::last_id))
+    }
+
+    pub fn xlen(&self, key: &[u8]) -> Result<usize, KvError> {
+        Ok(self.stream(key)?.map_or(0, Stream::len))
+    }
+
+    // Removes the oldest entries of a stream until at most `maxlen` are left, returning how
+    // many were removed. Unlike other collections, streams are kept once they're empty, so
+    // they don't forget their last ID.
+    pub fn xtrim(&mut self, key: &[u8], maxlen: usize) -> Result<usize, KvError> {
+        Ok(self.stream_mut(key)?.map_or(0, |stream| stream.trim(maxlen)))
+    }
+
+    // Sets the last ID of a stream, which can't go below its newest entry
+    pub fn xsetid(&mut self, key: &[u8], id: StreamId) -> Result<(), KvError> {
+        let stream = self.stream_mut(key)?.ok_or(KvError::NoSuchKey)?;
+
+        stream.set_last_id(id).map_err(|_| KvError::StreamIdBelowTop)
+    }
+
+    // Adds a consumer group to a stream, which hands out the entries after `from`. With
+    // `mkstream`, a missing stream is created empty rather than being an error.
+    pub fn xgroup_create(&mut self, key: &[u8], group: &[u8], from: ReadFrom, mkstream: bool) -> Result<(), KvError> {
+        if self.stream(key)?.is_none() && !mkstream {
+            return Err(KvError::NoSuchKey);
+        }
+
+        let Value::Stream(stream) = self.live_or_insert(key, Value::Stream(Stream::new())) else {
+            return Err(KvError::WrongType);
+        };
+
+        let last_delivered = match from {
+            ReadFrom::After(id) => id,
+            ReadFrom::New => stream.last_id(),
+        };
+
+        if stream.create_group(group, last_delivered) {
+            Ok(())
         } else {
-            assert!(false);
+            Err(KvError::BusyGroup)
+        }
+    }
+
+    pub fn xgroup_destroy(&mut self, key: &[u8], group: &[u8]) -> Result<bool, KvError> {
+        Ok(self.stream_mut(key)?.is_some_and(|stream| stream.destroy_group(group)))
+    }
+
+    pub fn xgroup_setid(&mut self, key: &[u8], group: &[u8], from: ReadFrom) -> Result<(), KvError> {
+        let stream = self.stream_mut(key)?.ok_or(KvError::NoGroup)?;
+
+        let id = match from {
+            ReadFrom::After(id) => id,
+            ReadFrom::New => stream.last_id(),
+        };
+
+        stream.group_mut(group).ok_or(KvError::NoGroup)?.set_last_delivered(id);
+        Ok(())
+    }
+
+    // Reads a stream as `consumer` in a group: `>` hands over entries no one in the group has
+    // had yet, and an ID goes back over the consumer's own pending entries after it. Pending
+    // entries that have since been trimmed from the stream come back without their fields.
+    pub fn xreadgroup(&mut self, key: &[u8], group: &[u8], consumer: &[u8], from: GroupReadFrom, count: Option<usize>, noack: bool) -> Result<Vec<(StreamId, Option<Fields>)>, KvError> {
+        let stream = self.stream_mut(key)?.ok_or(KvError::NoGroup)?;
+
+        let entries = match from {
+            GroupReadFrom::Undelivered => stream
+                .read_group(group, consumer, count, noack, unix_millis() as u64)
+                .map(|entries| entries.into_iter().map(|(id, fields)| (id, Some(fields))).collect()),
+            GroupReadFrom::Pending(after) => stream.read_pending(group, consumer, after, count),
+        };
+
+        entries.ok_or(KvError::NoGroup)
+    }
+
+    // Acknowledges entries for a group, returning how many of them were pending
+    pub fn xack(&mut self, key: &[u8], group: &[u8], ids: &[StreamId]) -> Result<usize, KvError> {
+        Ok(self.stream_mut(key)?
+            .and_then(|stream| stream.ack(group, ids))
+            .unwrap_or(0))
+    }
+
+    // A stream's consumer group, for looking through its pending entries
+    pub fn xgroup(&self, key: &[u8], group: &[u8]) -> Result<&ConsumerGroup, KvError> {
+        self.stream(key)?
+            .and_then(|stream| stream.group(group))
+            .ok_or(KvError::NoGroup)
+    }
+
+    // Gives `consumer` the pending entries among `ids` that have been idle for at least
+    // `min_idle` milliseconds. Returns the claimed entries, and the IDs that were dropped from
+    // the pending list because they've been trimmed from the stream.
+    #[allow(clippy::type_complexity)]
+    pub fn xclaim(&mut self, key: &[u8], group: &[u8], consumer: &[u8], min_idle: u64, ids: &[StreamId], options: ClaimOptions) -> Result<(Vec<(StreamId, Fields)>, Vec<StreamId>), KvError> {
+        let stream = self.stream_mut(key)?.ok_or(KvError::NoGroup)?;
+
+        let (claimed, deleted) = stream
+            .claim(group, consumer, min_idle, ids, options, unix_millis() as u64)
+            .ok_or(KvError::NoGroup)?;
+
+        Ok((entries_with_ids(stream, claimed), deleted))
+    }
+
+    // Claims up to `count` entries idle for at least `min_idle` milliseconds, walking the
+    // pending list from `start`. Returns the ID to carry on from next time, which is 0-0 once
+    // the whole list has been walked, the claimed entries, and the IDs dropped from the
+    // pending list because they've been trimmed from the stream.
+    #[allow(clippy::too_many_arguments)]
+    pub fn xautoclaim(&mut self, key: &[u8], group: &[u8], consumer: &[u8], min_idle: u64, start: StreamId, count: usize, just_id: bool) -> Result<AutoClaim, KvError> {
+        let stream = self.stream_mut(key)?.ok_or(KvError::NoGroup)?;
+
+        let (next, claimed, deleted) = stream
+            .autoclaim(group, consumer, min_idle, start, count, just_id, unix_millis() as u64)
+            .ok_or(KvError::NoGroup)?;
+
+        Ok(AutoClaim { next, claimed: entries_with_ids(stream, claimed), deleted })
+    }
+
+    // Deletes keys, returning how many of them existed
+    pub fn del(&mut self, keys: &[Vec<u8>]) -> usize {
+        keys.iter()
+            .filter(|key| self.live_mut(key).is_some() && self.remove(key).is_some())
+            .count()
+    }
+
+    // Counts how many of the keys exist, counting a key again each time it's repeated
+    pub fn exists(&self, keys: &[Vec<u8>]) -> usize {
+        keys.iter()
+            .filter(|key| self.live(key).is_some())
+            .count()
+    }
+
+    // Every live key matching a glob pattern
+    pub fn keys(&self, pattern: &[u8]) -> Vec<Vec<u8>> {
+        self.iter()
+            .filter(|(key, _, _)| glob::matches(pattern, key))
+            .map(|(key, _, _)| key.to_vec())
+            .collect()
+    }
+
+    // Looks at around `count` keys starting from `cursor`, returning the ones that are live and
+    // match `pattern`, along with the cursor to pass in next time, which is 0 once every key
+    // has been seen. Start with a cursor of 0. Keys that exist for the whole walk are returned
+    // at least once, even if other keys are added or removed between calls.
+    pub fn scan(&self, cursor: u64, pattern: Option<&[u8]>, count: usize) -> (u64, Vec<Vec<u8>>) {
+        let mut entries = self.index.range((cursor, Vec::new())..).peekable();
+        let mut keys = Vec::new();
+        let mut seen = 0;
+
+        while let Some((hash, key)) = entries.next() {
+            seen += 1;
+
+            let live = self.live(key).is_some();
+            if live && pattern.is_none_or(|pattern| glob::matches(pattern, key)) {
+                keys.push(key.clone());
+            }
+
+            // Keys with the same hash share a cursor, so they have to be returned together
+            match entries.peek() {
+                Some((next, _)) if seen >= count && next != hash => return (*next, keys),
+                Some(_) => (),
+                None => break,
+            }
+        }
+
+        (0, keys)
+    }
+
+    // Iterates over every live key, with its value and remaining time to live
+    pub fn iter(&self) -> impl Iterator<Item = Entry<'_>> {
+        self.map
+            .iter()
+            .filter(|(_, val)| !val.is_expired())
+            .map(|(key, val)| (&key[..], &val.val, val.remaining()))
+    }
+
+    // Empties the store, handing back every live key with its value and remaining time to live
+    pub fn drain(&mut self) -> impl Iterator<Item = (Vec<u8>, Value, Option<Duration>)> + '_ {
+        self.index.clear();
+        self.volatile.clear();
+
+        self.map
+            .drain()
+            .filter(|(_, val)| !val.is_expired())
+            .map(|(key, val)| {
+                let ttl = val.remaining();
+                (key, val.val, ttl)
+            })
+    }
+
+    // Puts back a key read from a snapshot, keeping whatever was left of its time to live
+    pub fn restore(&mut self, key: Vec<u8>, value: Value, ttl: Option<Duration>) {
+        self.insert(key, Val::new(value, ttl));
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    // Looks up a key, treating it as missing if it has expired
+    fn live(&self, key: &[u8]) -> Option<&Val> {
+        self.map
+            .get(key)
+            .filter(|val| self.loading || !val.is_expired())
+    }
+
+    // Looks up a key to change it, removing it first if it has expired
+    fn live_mut(&mut self, key: &[u8]) -> Option<&mut Val> {
+        if !self.loading && self.map.get(key).is_some_and(Val::is_expired) {
+            self.remove(key);
+            self.expired_keys += 1;
         }
 
-        let ttl = kv.ttl("test");
+        let val = self.map.get_mut(key)?;
+
+        // The caller may change the value, so anyone watching the key has to assume it did
+        self.last_version += 1;
+        val.version = self.last_version;
+
+        Some(val)
+    }
+
+    // Looks up a key to change it, first creating it with `empty` if it doesn't exist
+    fn live_or_insert(&mut self, key: &[u8], empty: Value) -> &mut Value {
+        if self.live_mut(key).is_none() {
+            self.insert(key.to_vec(), Val::new(empty, None));
+        }
+
+        &mut self.map.get_mut(key).unwrap().val
+    }
+
+    fn string(&self, key: &[u8]) -> Result<Option<&Vec<u8>>, KvError> {
+        match self.live(key) {
+            Some(Val { val: Value::Str(value), .. }) => Ok(Some(value)),
+            Some(_) => Err(KvError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    // Changes the string at a key without touching its time to live
+    fn replace_string(&mut self, key: &[u8], value: Vec<u8>) {
+        match self.live_mut(key) {
+            Some(val) => val.val = Value::Str(value),
+            None => self.set(key, &value),
+        }
+    }
+
+    fn list(&self, key: &[u8]) -> Result<Option<&VecDeque<Vec<u8>>>, KvError> {
+        match self.live(key) {
+            Some(Val { val: Value::List(list), .. }) => Ok(Some(list)),
+            Some(_) => Err(KvError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn list_mut(&mut self, key: &[u8]) -> Result<Option<&mut VecDeque<Vec<u8>>>, KvError> {
+        match self.live_mut(key) {
+            Some(Val { val: Value::List(list), .. }) => Ok(Some(list)),
+            Some(_) => Err(KvError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn hash(&self, key: &[u8]) -> Result<Option<&Hash>, KvError> {
+        match self.live(key) {
+            Some(Val { val: Value::Hash(hash), .. }) => Ok(Some(hash)),
+            Some(_) => Err(KvError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn hash_mut(&mut self, key: &[u8]) -> Result<Option<&mut Hash>, KvError> {
+        match self.live_mut(key) {
+            Some(Val { val: Value::Hash(hash), .. }) => Ok(Some(hash)),
+            Some(_) => Err(KvError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn members(&self, key: &[u8]) -> Result<Option<&Set>, KvError> {
+        match self.live(key) {
+            Some(Val { val: Value::Set(set), .. }) => Ok(Some(set)),
+            Some(_) => Err(KvError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn members_mut(&mut self, key: &[u8]) -> Result<Option<&mut Set>, KvError> {
+        match self.live_mut(key) {
+            Some(Val { val: Value::Set(set), .. }) => Ok(Some(set)),
+            Some(_) => Err(KvError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn zset(&self, key: &[u8]) -> Result<Option<&SortedSet>, KvError> {
+        match self.live(key) {
+            Some(Val { val: Value::ZSet(zset), .. }) => Ok(Some(zset)),
+            Some(_) => Err(KvError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn zset_mut(&mut self, key: &[u8]) -> Result<Option<&mut SortedSet>, KvError> {
+        match self.live_mut(key) {
+            Some(Val { val: Value::ZSet(zset), .. }) => Ok(Some(zset)),
+            Some(_) => Err(KvError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn stream(&self, key: &[u8]) -> Result<Option<&Stream>, KvError> {
+        match self.live(key) {
+            Some(Val { val: Value::Stream(stream), .. }) => Ok(Some(stream)),
+            Some(_) => Err(KvError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    fn stream_mut(&mut self, key: &[u8]) -> Result<Option<&mut Stream>, KvError> {
+        match self.live_mut(key) {
+            Some(Val { val: Value::Stream(stream), .. }) => Ok(Some(stream)),
+            Some(_) => Err(KvError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    // Collections are deleted once their last element is removed
+    fn remove_if_empty(&mut self, key: &[u8]) {
+        let empty = match self.map.get(key) {
+            Some(Val { val: Value::List(list), .. }) => list.is_empty(),
+            Some(Val { val: Value::Hash(hash), .. }) => hash.is_empty(),
+            Some(Val { val: Value::Set(set), .. }) => set.is_empty(),
+            Some(Val { val: Value::ZSet(zset), .. }) => zset.is_empty(),
+            _ => false,
+        };
+
+        if empty {
+            self.remove(key);
+        }
+    }
+
+    // Every change to the set of keys goes through `insert` and `remove`, which keep `index`
+    // in step with `map`
+    fn insert(&mut self, key: Vec<u8>, mut val: Val) {
+        self.last_version += 1;
+        val.version = self.last_version;
+
+        let entry = (scan_hash(&key), key.clone());
+
+        if val.expires_at.is_some() {
+            self.volatile.insert(entry.clone());
+        } else {
+            self.volatile.remove(&entry);
+        }
+
+        if !self.map.contains_key(&key) {
+            self.index.insert(entry);
+        }
+
+        self.map.insert(key, val);
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Val> {
+        let val = self.map.remove(key)?;
+
+        if let Some((_, removed)) = self.watched.get_mut(key) {
+            self.last_version += 1;
+            *removed = self.last_version;
+        }
+
+        let entry = (scan_hash(key), key.to_vec());
+        self.index.remove(&entry);
+        if val.expires_at.is_some() {
+            self.volatile.remove(&entry);
+        }
+
+        Some(val)
+    }
+
+    // Changes when an existing key expires
+    fn set_expiry(&mut self, key: &[u8], expires_at: Option<Instant>) {
+        let Some(val) = self.map.get_mut(key) else {
+            return;
+        };
+        self.last_version += 1;
+        val.version = self.last_version;
+        val.expires_at = expires_at;
+
+        let entry = (scan_hash(key), key.to_vec());
+        if expires_at.is_some() {
+            self.volatile.insert(entry);
+        } else {
+            self.volatile.remove(&entry);
+        }
+    }
+
+    // One step of active expiry: checks the next `count` keys that have a time to live,
+    // removing any that have expired. Returns how many keys were checked and how many were
+    // removed, so the caller can tell whether it's worth doing another step straight away.
+    pub fn expire_sample(&mut self, count: usize) -> (usize, usize) {
+        let sample: Vec<_> = self.volatile
+            .range((self.expire_cursor, Vec::new())..)
+            .take(count)
+            .cloned()
+            .collect();
+
+        // Start again from the beginning once the end is reached
+        self.expire_cursor = match sample.last() {
+            Some((hash, _)) if sample.len() == count => hash.wrapping_add(1),
+            _ => 0,
+        };
+
+        let mut expired = 0;
+        for (_, key) in &sample {
+            if self.map.get(key).is_some_and(Val::is_expired) {
+                self.remove(key);
+                expired += 1;
+            }
+        }
+        self.expired_keys += expired as u64;
+
+        (sample.len(), expired)
+    }
+
+    // How many keys have a time to live, including expired ones that haven't been removed yet
+    pub fn expires(&self) -> usize {
+        self.volatile.len()
+    }
+
+    // How many keys have been removed because they expired, either when they were next used
+    // or by active expiry
+    pub fn expired_keys(&self) -> u64 {
+        self.expired_keys
+    }
+}
+
+// Combines sets the way SINTER, SUNION and SDIFF do, in the order they were given
+pub fn combine_sets(op: SetOp, sets: &[&Set]) -> Set {
+    let Some((first, rest)) = sets.split_first() else {
+        return Set::new();
+    };
+
+    match op {
+        SetOp::Inter => first
+            .iter()
+            .filter(|member| rest.iter().all(|set| set.contains(*member)))
+            .cloned()
+            .collect(),
+        SetOp::Union => sets
+            .iter()
+            .flat_map(|set| set.iter())
+            .cloned()
+            .collect(),
+        SetOp::Diff => first
+            .iter()
+            .filter(|member| !rest.iter().any(|set| set.contains(*member)))
+            .cloned()
+            .collect(),
+    }
+}
+
+fn entries_with_ids(stream: &Stream, ids: Vec<StreamId>) -> Vec<(StreamId, Fields)> {
+    ids.into_iter()
+        .filter_map(|id| Some((id, stream.get(id)?.clone())))
+        .collect()
+}
+
+// The current unix time in milliseconds
+pub fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+// Orders keys for SCAN. Cursors are positions in this order, so it has to stay the same for
+// as long as the server runs. 0 is left out, since it means the walk is over.
+pub fn scan_hash(key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish().max(1)
+}
+
+fn parse_integer(value: &[u8]) -> Result<i64, KvError> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .ok_or(KvError::NotInteger)
+}
+
+fn parse_float(value: &[u8]) -> Result<f64, KvError> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse::<f64>().ok())
+        .filter(|value| value.is_finite())
+        .ok_or(KvError::NotFloat)
+}
+
+// Turns an inclusive range that may count back from the end (with negative indexes) into
+// positions in a collection of `len` elements, `None` if the range is empty
+fn range_bounds(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+
+    if start > stop || start >= len {
+        None
+    } else {
+        Some((start as usize, stop as usize))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_set() {
+        let mut kv = KvStore::new();
+
+        kv.set(b"test", b"value");
+
+        let result = kv.get(b"test").unwrap();
+
+        if let Some(val) = result {
+            assert_eq!(&val[..], b"value");
+        } else {
+            panic!("missing value");
+        }
+    }
+
+    #[test]
+    fn test_setex() {
+        let mut kv = KvStore::new();
+
+        kv.setex(b"test", b"value", Duration::from_secs(10));
+
+        let result = kv.get(b"test").unwrap();
+
+        if let Some(val) = result {
+            assert_eq!(&val[..], b"value");
+        } else {
+            panic!("missing value");
+        }
+
+        let Ttl::Expires(ttl) = kv.ttl(b"test") else {
+            panic!("missing ttl");
+        };
+
+        assert!(ttl.as_secs() >= 9);
+    }
+
+    #[test]
+    fn test_set_options() {
+        let mut kv = KvStore::new();
+
+        let nx = SetOptions { condition: Some(SetCondition::Missing), ..Default::default() };
+        let xx = SetOptions { condition: Some(SetCondition::Exists), ..Default::default() };
+
+        assert_eq!(kv.set_with(b"lock", b"a".to_vec(), xx), Ok((false, None)));
+        assert_eq!(kv.set_with(b"lock", b"a".to_vec(), nx), Ok((true, None)));
+        assert_eq!(kv.set_with(b"lock", b"b".to_vec(), nx), Ok((false, None)));
+        assert_eq!(kv.get(b"lock"), Ok(Some(b"a".to_vec())));
+
+        let px = SetOptions {
+            expiry: Some(SetExpiry::After(Duration::from_millis(5000))),
+            get: true,
+            ..xx
+        };
+        assert_eq!(kv.set_with(b"lock", b"b".to_vec(), px), Ok((true, Some(b"a".to_vec()))));
+        assert!(matches!(kv.ttl(b"lock"), Ttl::Expires(_)));
+
+        let keepttl = SetOptions { expiry: Some(SetExpiry::Keep), ..Default::default() };
+        kv.set_with(b"lock", b"c".to_vec(), keepttl).unwrap();
+        assert!(matches!(kv.ttl(b"lock"), Ttl::Expires(_)));
+
+        kv.set_with(b"lock", b"d".to_vec(), SetOptions::default()).unwrap();
+        assert_eq!(kv.ttl(b"lock"), Ttl::Persistent);
+
+        let past = SetOptions { expiry: Some(SetExpiry::At(unix_millis() - 1000)), ..Default::default() };
+        assert_eq!(kv.set_with(b"lock", b"e".to_vec(), past), Ok((true, None)));
+        assert_eq!(kv.get(b"lock"), Ok(None));
+
+        kv.push(b"list", vec![b"a".to_vec()], false).unwrap();
+        let get = SetOptions { get: true, ..Default::default() };
+        assert_eq!(kv.set_with(b"list", b"a".to_vec(), get), Err(KvError::WrongType));
+
+        // An expired key that hasn't been removed yet has no TTL left to keep
+        kv.setex(b"lock", b"f", Duration::from_millis(1));
+        std::thread::sleep(Duration::from_millis(5));
+        let keepttl_nx = SetOptions { condition: Some(SetCondition::Missing), ..keepttl };
+        assert_eq!(kv.set_with(b"lock", b"g".to_vec(), keepttl_nx), Ok((true, None)));
+        assert_eq!(kv.get(b"lock"), Ok(Some(b"g".to_vec())));
+        assert_eq!(kv.ttl(b"lock"), Ttl::Persistent);
+    }
+
+    #[test]
+    fn test_version() {
+        let mut kv = KvStore::new();
+
+        let missing = kv.version(b"key");
+
+        kv.set(b"key", b"1");
+        let version = kv.version(b"key");
+        assert_ne!(version, 0);
+
+        // Reads and failed conditional writes leave the version alone
+        kv.get(b"key").unwrap();
+        let nx = SetOptions { condition: Some(SetCondition::Missing), ..Default::default() };
+        kv.set_with(b"key", b"2".to_vec(), nx).unwrap();
+        assert_eq!(kv.version(b"key"), version);
+
+        kv.incrby(b"key", 1).unwrap();
+        assert_ne!(kv.version(b"key"), version);
+
+        let version = kv.version(b"key");
+        kv.expire(b"key", 10_000);
+        assert_ne!(kv.version(b"key"), version);
+
+        let version = kv.watch(b"key");
+        kv.del(&[b"key".to_vec()]);
+        assert_ne!(kv.version(b"key"), version);
+
+        // Deleting another key leaves a missing key's version alone
+        let version = kv.version(b"key");
+        kv.set(b"other", b"1");
+        kv.del(&[b"other".to_vec()]);
+        assert_eq!(kv.version(b"key"), version);
+
+        // Creating and deleting a watched missing key changes its version
+        kv.set(b"key", b"1");
+        kv.del(&[b"key".to_vec()]);
+        assert_ne!(kv.version(b"key"), version);
+
+        kv.unwatch(b"key");
+        assert_eq!(kv.version(b"key"), missing);
+        assert!(kv.watched.is_empty());
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut kv = KvStore::new();
+
+        assert_eq!(kv.ttl(b"list"), Ttl::Missing);
+        assert!(!kv.expire(b"list", 1000));
+
+        kv.push(b"list", vec![b"a".to_vec()], false).unwrap();
+        assert_eq!(kv.ttl(b"list"), Ttl::Persistent);
+        assert!(!kv.persist(b"list"));
+
+        assert!(kv.expire(b"list", 1500));
+        assert!(matches!(kv.ttl(b"list"), Ttl::Expires(ttl) if ttl > Duration::from_millis(1000)));
+        assert_eq!(kv.expires(), 1);
+
+        assert!(kv.persist(b"list"));
+        assert_eq!(kv.ttl(b"list"), Ttl::Persistent);
+        assert_eq!(kv.expires(), 0);
+
+        assert!(kv.expire_at(b"list", unix_millis() + 60_000));
+        assert!(matches!(kv.ttl(b"list"), Ttl::Expires(ttl) if ttl.as_secs() >= 59));
+
+        // A time in the past deletes the key
+        assert!(kv.expire_at(b"list", unix_millis() - 1000));
+        assert_eq!(kv.ttl(b"list"), Ttl::Missing);
+        assert!(kv.is_empty());
+    }
+
+    #[test]
+    fn expiry_waits_for_loading() {
+        let mut kv = KvStore::new();
+        kv.set_loading(true);
+
+        // As replayed from `SET k 5 PX 1000` then `INCRBY k 1`, long after they were logged
+        let past = SetOptions { expiry: Some(SetExpiry::At(unix_millis() - 1000)), ..Default::default() };
+        kv.set_with(b"k", b"5".to_vec(), past).unwrap();
+        assert_eq!(kv.incrby(b"k", 1), Ok(6));
+
+        kv.push(b"list", vec![b"a".to_vec()], false).unwrap();
+        assert!(kv.expire_at(b"list", unix_millis() - 1000));
+        assert_eq!(kv.llen(b"list"), Ok(1));
+
+        kv.set_loading(false);
+        assert_eq!(kv.get(b"k"), Ok(None));
+        assert_eq!(kv.llen(b"list"), Ok(0));
+    }
+
+    #[test]
+    fn test_list() {
+        let mut kv = KvStore::new();
+
+        assert_eq!(kv.push(b"list", vec![b"b".to_vec(), b"a".to_vec()], true), Ok(2));
+        assert_eq!(kv.push(b"list", vec![b"c".to_vec()], false), Ok(3));
+
+        assert_eq!(kv.lrange(b"list", 0, -1).unwrap(), vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(kv.lrange(b"list", -2, 10).unwrap(), vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(kv.lindex(b"list", -1), Ok(Some(b"c".to_vec())));
+        assert_eq!(kv.llen(b"list"), Ok(3));
+
+        assert_eq!(kv.pop(b"list", false, 2), Ok(Some(vec![b"c".to_vec(), b"b".to_vec()])));
+        assert_eq!(kv.pop(b"list", true, 1), Ok(Some(vec![b"a".to_vec()])));
+
+        // Popping the last value deletes the list
+        assert!(kv.is_empty());
+        assert_eq!(kv.pop(b"list", true, 1), Ok(None));
+    }
+
+    #[test]
+    fn test_ltrim() {
+        let mut kv = KvStore::new();
+
+        let values = (0..5).map(|i| i.to_string().into_bytes()).collect();
+        kv.push(b"list", values, false).unwrap();
+
+        kv.ltrim(b"list", 1, -2).unwrap();
+        assert_eq!(kv.lrange(b"list", 0, -1).unwrap(), vec![b"1".to_vec(), b"2".to_vec(), b"3".to_vec()]);
+
+        kv.ltrim(b"list", 5, 10).unwrap();
+        assert!(kv.is_empty());
+    }
+
+    #[test]
+    fn test_lmove() {
+        let mut kv = KvStore::new();
+        kv.push(b"src", vec![b"a".to_vec(), b"b".to_vec()], false).unwrap();
+        kv.set(b"string", b"value");
+
+        assert_eq!(kv.lmove(b"src", b"dst", true, false), Ok(Some(b"a".to_vec())));
+        assert_eq!(kv.lmove(b"src", b"src", false, true), Ok(Some(b"b".to_vec())));
+        assert_eq!(kv.lmove(b"src", b"string", true, true), Err(KvError::WrongType));
+        assert_eq!(kv.lrange(b"src", 0, -1), Ok(vec![b"b".to_vec()]));
+        assert_eq!(kv.lmove(b"missing", b"dst", true, true), Ok(None));
+        assert_eq!(kv.lrange(b"dst", 0, -1), Ok(vec![b"a".to_vec()]));
+    }
+
+    #[test]
+    fn test_wrong_type() {
+        let mut kv = KvStore::new();
+
+        kv.set(b"string", b"value");
+        kv.push(b"list", vec![b"value".to_vec()], false).unwrap();
+
+        assert_eq!(kv.push(b"string", vec![b"value".to_vec()], false), Err(KvError::WrongType));
+        assert_eq!(kv.llen(b"string"), Err(KvError::WrongType));
+        assert_eq!(kv.get(b"list"), Err(KvError::WrongType));
+    }
+
+    #[test]
+    fn test_hash() {
+        let mut kv = KvStore::new();
+
+        let fields = vec![
+            (b"name".to_vec(), b"rustis".to_vec()),
+            (b"visits".to_vec(), b"1".to_vec()),
+        ];
+        assert_eq!(kv.hset(b"hash", fields), Ok(2));
+        assert_eq!(kv.hset(b"hash", vec![(b"name".to_vec(), b"redis".to_vec())]), Ok(0));
+
+        assert_eq!(kv.hget(b"hash", b"name"), Ok(Some(b"redis".to_vec())));
+        assert_eq!(kv.hexists(b"hash", b"missing"), Ok(false));
+        assert_eq!(kv.hlen(b"hash"), Ok(2));
+        assert_eq!(kv.hgetall(b"hash").unwrap().len(), 2);
+
+        assert_eq!(kv.hdel(b"hash", &[b"name".to_vec(), b"missing".to_vec()]), Ok(1));
+        assert_eq!(kv.hdel(b"hash", &[b"visits".to_vec()]), Ok(1));
+        assert!(kv.is_empty());
+    }
+
+    #[test]
+    fn test_hincrby() {
+        let mut kv = KvStore::new();
+
+        assert_eq!(kv.hincrby(b"hash", b"count", 5), Ok(5));
+        assert_eq!(kv.hincrby(b"hash", b"count", -7), Ok(-2));
+
+        kv.hset(b"hash", vec![(b"name".to_vec(), b"rustis".to_vec())]).unwrap();
+        assert_eq!(kv.hincrby(b"hash", b"name", 1), Err(KvError::NotInteger));
+
+        kv.hset(b"hash", vec![(b"big".to_vec(), i64::MAX.to_string().into_bytes())]).unwrap();
+        assert_eq!(kv.hincrby(b"hash", b"big", 1), Err(KvError::Overflow));
+    }
+
+    #[test]
+    fn test_set_members() {
+        let mut kv = KvStore::new();
+
+        assert_eq!(kv.sadd(b"set", vec![b"a".to_vec(), b"b".to_vec(), b"a".to_vec()]), Ok(2));
+        assert_eq!(kv.sadd(b"set", vec![b"b".to_vec(), b"c".to_vec()]), Ok(1));
+
+        assert_eq!(kv.scard(b"set"), Ok(3));
+        assert_eq!(kv.sismember(b"set", b"c"), Ok(true));
+        assert_eq!(kv.sismember(b"set", b"d"), Ok(false));
+
+        assert_eq!(kv.srem(b"set", &[b"a".to_vec(), b"d".to_vec()]), Ok(1));
+        assert_eq!(kv.smembers(b"set").unwrap(), Set::from([b"b".to_vec(), b"c".to_vec()]));
+
+        kv.srem(b"set", &[b"b".to_vec(), b"c".to_vec()]).unwrap();
+        assert!(kv.is_empty());
+    }
+
+    #[test]
+    fn test_setop() {
+        let mut kv = KvStore::new();
+
+        kv.sadd(b"a", vec![b"1".to_vec(), b"2".to_vec(), b"3".to_vec()]).unwrap();
+        kv.sadd(b"b", vec![b"2".to_vec(), b"3".to_vec(), b"4".to_vec()]).unwrap();
+
+        let keys = [b"a".to_vec(), b"b".to_vec()];
+        assert_eq!(kv.setop(SetOp::Inter, &keys).unwrap(), Set::from([b"2".to_vec(), b"3".to_vec()]));
+        assert_eq!(kv.setop(SetOp::Union, &keys).unwrap().len(), 4);
+        assert_eq!(kv.setop(SetOp::Diff, &keys).unwrap(), Set::from([b"1".to_vec()]));
+
+        // A missing key is an empty set
+        let keys = [b"a".to_vec(), b"missing".to_vec()];
+        assert!(kv.setop(SetOp::Inter, &keys).unwrap().is_empty());
+
+        kv.set(b"string", b"value");
+        assert_eq!(kv.setopstore(SetOp::Union, b"string", &[b"a".to_vec(), b"b".to_vec()]), Ok(4));
+        assert_eq!(kv.scard(b"string"), Ok(4));
+
+        assert_eq!(kv.setopstore(SetOp::Inter, b"string", &[b"a".to_vec(), b"missing".to_vec()]), Ok(0));
+        assert_eq!(kv.get(b"string"), Ok(None));
+    }
+
+    #[test]
+    fn test_zset() {
+        let mut kv = KvStore::new();
+
+        let members = vec![(1.0, b"a".to_vec()), (3.0, b"c".to_vec()), (2.0, b"b".to_vec())];
+        assert_eq!(kv.zadd(b"zset", members), Ok(3));
+        assert_eq!(kv.zadd(b"zset", vec![(0.5, b"c".to_vec())]), Ok(0));
+
+        assert_eq!(kv.zscore(b"zset", b"c"), Ok(Some(0.5)));
+        assert_eq!(kv.zrank(b"zset", b"a"), Ok(Some(1)));
+        assert_eq!(kv.zincrby(b"zset", 10.0, b"a"), Ok(11.0));
+        assert_eq!(kv.zcard(b"zset"), Ok(3));
+
+        let members = |range: Vec<(Vec<u8>, f64)>| -> Vec<Vec<u8>> {
+            range.into_iter().map(|(member, _)| member).collect()
+        };
+        assert_eq!(members(kv.zrange(b"zset", 0, -1, false).unwrap()), vec![b"c".to_vec(), b"b".to_vec(), b"a".to_vec()]);
+        assert_eq!(members(kv.zrange(b"zset", 0, 0, true).unwrap()), vec![b"a".to_vec()]);
+
+        let min = "(0.5".parse().unwrap();
+        let max = "+inf".parse().unwrap();
+        assert_eq!(members(kv.zrangebyscore(b"zset", min, max, None).unwrap()), vec![b"b".to_vec(), b"a".to_vec()]);
+        assert_eq!(members(kv.zrangebyscore(b"zset", min, max, Some((1, 5))).unwrap()), vec![b"a".to_vec()]);
+
+        assert_eq!(kv.zrem(b"zset", &[b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]), Ok(3));
+        assert!(kv.is_empty());
+    }
+
+    #[test]
+    fn test_stream() {
+        let mut kv = KvStore::new();
+        let fields = |value: &str| vec![(b"event".to_vec(), value.as_bytes().to_vec())];
+
+        // A bad ID doesn't create the stream
+        assert_eq!(kv.xadd(b"stream", NewId::Exact(StreamId::MIN), fields("a"), None), Err(KvError::StreamIdZero));
+        assert!(kv.is_empty());
+
+        assert_eq!(kv.xadd(b"stream", NewId::Exact(StreamId::new(1, 0)), fields("a"), None), Ok(StreamId::new(1, 0)));
+        assert_eq!(kv.xadd(b"stream", NewId::Exact(StreamId::new(1, 0)), fields("b"), None), Err(KvError::StreamIdTooSmall));
+
+        let id = kv.xadd(b"stream", NewId::Auto, fields("b"), None).unwrap();
+        assert!(id > StreamId::new(1, 0));
+        assert_eq!(kv.xlast_id(b"stream"), Ok(id));
+
+        let all = kv.xrange(b"stream", Bound::Unbounded, Bound::Unbounded, None, false).unwrap();
+        assert_eq!(all, vec![(StreamId::new(1, 0), fields("a")), (id, fields("b"))]);
+        assert_eq!(kv.xrange(b"stream", Bound::Unbounded, Bound::Unbounded, Some(1), true).unwrap(), vec![(id, fields("b"))]);
+        assert_eq!(kv.xread(b"stream", StreamId::new(1, 0), None).unwrap(), vec![(id, fields("b"))]);
+        assert!(kv.xread(b"stream", id, None).unwrap().is_empty());
+
+        // Trimming everything keeps the stream and its last ID
+        assert_eq!(kv.xtrim(b"stream", 0), Ok(2));
+        assert_eq!(kv.xlen(b"stream"), Ok(0));
+        assert_eq!(kv.xlast_id(b"stream"), Ok(id));
+        assert_eq!(kv.xadd(b"stream", NewId::Exact(id), fields("c"), None), Err(KvError::StreamIdTooSmall));
+
+        kv.set(b"string", b"value");
+        assert_eq!(kv.xlen(b"string"), Err(KvError::WrongType));
+    }
+
+    #[test]
+    fn test_zincrby_nan() {
+        let mut kv = KvStore::new();
+
+        kv.zadd(b"zset", vec![(f64::INFINITY, b"a".to_vec())]).unwrap();
+
+        assert_eq!(kv.zincrby(b"zset", f64::NEG_INFINITY, b"a"), Err(KvError::NaN));
+        assert_eq!(kv.zscore(b"zset", b"a"), Ok(Some(f64::INFINITY)));
+    }
+
+    #[test]
+    fn test_incrby() {
+        let mut kv = KvStore::new();
+
+        assert_eq!(kv.incrby(b"counter", 5), Ok(5));
+        assert_eq!(kv.incrby(b"counter", -7), Ok(-2));
+        assert_eq!(kv.get(b"counter"), Ok(Some(b"-2".to_vec())));
+
+        kv.setex(b"limited", b"10", Duration::from_secs(100));
+        assert_eq!(kv.incrby(b"limited", 1), Ok(11));
+        assert!(matches!(kv.ttl(b"limited"), Ttl::Expires(_)));
+
+        kv.set(b"max", i64::MAX.to_string().as_bytes());
+        assert_eq!(kv.incrby(b"max", 1), Err(KvError::Overflow));
+
+        kv.set(b"word", b"abc");
+        assert_eq!(kv.incrby(b"word", 1), Err(KvError::NotInteger));
+        assert_eq!(kv.get(b"word"), Ok(Some(b"abc".to_vec())));
+    }
+
+    #[test]
+    fn test_incrbyfloat() {
+        let mut kv = KvStore::new();
+
+        assert_eq!(kv.incrbyfloat(b"float", 1.5), Ok(1.5));
+        kv.setex(b"float", b"10", Duration::from_secs(100));
+        assert_eq!(kv.incrbyfloat(b"float", 0.25), Ok(10.25));
+        assert_eq!(kv.get(b"float"), Ok(Some(b"10.25".to_vec())));
+        assert!(matches!(kv.ttl(b"float"), Ttl::Expires(_)));
+
+        assert_eq!(kv.incrbyfloat(b"float", f64::INFINITY), Err(KvError::NotFinite));
+        assert_eq!(kv.incrbyfloat(b"missing", f64::INFINITY), Err(KvError::NotFinite));
+        assert_eq!(kv.get(b"missing"), Ok(None));
+
+        kv.set(b"word", b"abc");
+        assert_eq!(kv.incrbyfloat(b"word", 1.0), Err(KvError::NotFloat));
+    }
+
+    #[test]
+    fn test_del_exists() {
+        let mut kv = KvStore::new();
+
+        kv.set(b"a", b"1");
+        kv.push(b"b", vec![b"1".to_vec()], false).unwrap();
+
+        let keys = [b"a".to_vec(), b"a".to_vec(), b"b".to_vec(), b"c".to_vec()];
+        assert_eq!(kv.exists(&keys), 3);
+        assert_eq!(kv.del(&keys), 2);
+        assert_eq!(kv.exists(&keys), 0);
+        assert!(kv.is_empty());
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut kv = KvStore::new();
+
+        kv.set(b"user:1", b"a");
+        kv.set(b"user:2", b"b");
+        kv.set(b"session:1", b"c");
+
+        let mut keys = kv.keys(b"user:*");
+        keys.sort();
+        assert_eq!(keys, vec![b"user:1".to_vec(), b"user:2".to_vec()]);
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut kv = KvStore::new();
+        for i in 0..100 {
+            kv.set(format!("key:{}", i).as_bytes(), b"value");
+        }
+
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        let mut calls = 0;
+        loop {
+            let (next, keys) = kv.scan(cursor, None, 10);
+            seen.extend(keys);
+            calls += 1;
+
+            // Keys removed partway through shouldn't stop the rest being found
+            kv.del(&[format!("key:{}", calls).into_bytes()]);
+
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+
+        assert!(calls >= 10);
+        for i in 11..100 {
+            assert!(seen.contains(format!("key:{}", i).as_bytes()));
+        }
+
+        let (_, keys) = kv.scan(0, Some(b"key:9?"), 1000);
+        assert_eq!(keys.len(), 10);
+    }
+
+    #[test]
+    fn test_expire_sample() {
+        let mut kv = KvStore::new();
+        for i in 0..50 {
+            kv.setex(format!("expired:{}", i).as_bytes(), b"value", Duration::from_secs(0));
+            kv.setex(format!("live:{}", i).as_bytes(), b"value", Duration::from_secs(100));
+        }
+        kv.set(b"plain", b"value");
+
+        assert_eq!(kv.expires(), 100);
+
+        let mut sampled = 0;
+        let mut expired = 0;
+        while sampled < 100 {
+            let (count, removed) = kv.expire_sample(20);
+            sampled += count;
+            expired += removed;
+        }
 
-        assert_eq!(ttl.is_some(), true);
-        assert_eq!(ttl.unwrap() >= 9, true);
+        assert_eq!(expired, 50);
+        assert_eq!(kv.expired_keys(), 50);
+        assert_eq!(kv.expires(), 50);
+        assert_eq!(kv.len(), 51);
     }
 }
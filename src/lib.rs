@@ -2,3 +2,12 @@ pub mod pubsub;
 pub mod threadpool;
 pub mod packetreader;
 pub mod kvstore;
+pub mod keyspace;
+pub mod reply;
+pub mod snapshot;
+pub mod aof;
+pub mod sortedset;
+pub mod stream;
+pub mod blocking;
+pub mod glob;
+pub mod scripting;
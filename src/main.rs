@@ -3,20 +3,51 @@ mod client;
 
 use clap::{arg, command, Command};
 use std::io;
+use std::path::PathBuf;
+use rustis::aof::FsyncPolicy;
+use rustis::pubsub::OverflowPolicy;
 
 fn main() -> io::Result<()> {
     let matches = command!()
         .subcommand(
             Command::new("server")
                 .about("Runs the server")
-                .arg(arg!(-t --threads <N> "number of tcp threads")
-                     .default_value("10")
-                     .value_parser(clap::value_parser!(u16)))
+                .arg(arg!(-t --threads <N> "number of event loop threads")
+                     .default_value("4")
+                     .value_parser(clap::value_parser!(u16).range(1..)))
+                .arg(arg!(--shards <N> "number of separately locked parts to split the keyspace into")
+                     .default_value("16")
+                     .value_parser(clap::value_parser!(u16).range(1..)))
                 .arg(arg!(--host <host> "The host to connect to")
                      .default_value("127.0.0.1"))
                 .arg(arg!(-p --port <port> "The port to connect to")
                      .default_value("7878")
                      .value_parser(clap::value_parser!(u16)))
+                .arg(arg!(--dir <dir> "Directory to keep the snapshot file in")
+                     .default_value(".")
+                     .value_parser(clap::value_parser!(PathBuf)))
+                .arg(arg!(--dbfilename <name> "Name of the snapshot file")
+                     .default_value("dump.rdb"))
+                .arg(arg!(--"snapshot-interval" <seconds> "Take a background snapshot every N seconds")
+                     .value_parser(clap::value_parser!(u64)))
+                .arg(arg!(--appendonly "Log every write to an append-only file"))
+                .arg(arg!(--appendfilename <name> "Name of the append-only file")
+                     .default_value("appendonly.aof"))
+                .arg(arg!(--appendfsync <policy> "When to fsync the append-only file: always, everysec or no")
+                     .default_value("everysec")
+                     .value_parser(clap::value_parser!(FsyncPolicy)))
+                .arg(arg!(--hz <times> "How many times a second to look for expired keys to remove")
+                     .default_value("10")
+                     .value_parser(clap::value_parser!(u32).range(1..=500)))
+                .arg(arg!(--"lua-time-limit" <ms> "Milliseconds a script can run before it's stopped")
+                     .default_value("5000")
+                     .value_parser(clap::value_parser!(u64).range(1..)))
+                .arg(arg!(--"subscriber-queue" <messages> "How many messages can wait to be sent to each subscriber")
+                     .default_value("1024")
+                     .value_parser(clap::value_parser!(u64).range(1..)))
+                .arg(arg!(--"subscriber-overflow" <policy> "What to do when a subscriber's queue is full: drop-oldest, drop-newest or disconnect")
+                     .default_value("disconnect")
+                     .value_parser(clap::value_parser!(OverflowPolicy)))
         )
         .subcommand(
             Command::new("client")
@@ -34,14 +65,48 @@ fn main() -> io::Result<()> {
                 )
                 .subcommand(
                     Command::new("subscribe")
-                        .about("Subscribe to a channel")
-                        .arg(arg!(<channel> "Channel to subscribe to"))
+                        .about("Subscribe to one or more channels")
+                        .arg(arg!(<channel> ... "Channels to subscribe to"))
+                )
+                .subcommand(
+                    Command::new("psubscribe")
+                        .about("Subscribe to every channel matching glob patterns")
+                        .arg(arg!(<pattern> ... "Patterns to match, such as 'orders.*'"))
+                )
+                .subcommand(
+                    Command::new("pubsub-channels")
+                        .about("List the channels that have subscribers")
+                        .arg(arg!([pattern] "Only list channels matching a glob pattern"))
+                )
+                .subcommand(
+                    Command::new("pubsub-numsub")
+                        .about("Count the subscribers of channels, not counting pattern subscriptions")
+                        .arg(arg!([channel] ... "Channels to count the subscribers of"))
+                )
+                .subcommand(
+                    Command::new("pubsub-numpat")
+                        .about("Count the patterns that have subscribers")
+                )
+                .subcommand(
+                    Command::new("pubsub-dropped")
+                        .about("Count the messages each channel has dropped because subscribers fell behind")
                 )
                 .subcommand(
                     Command::new("set")
                         .about("Set a key's value in the KV store")
                         .arg(arg!(<key> "Key to set"))
                         .arg(arg!(<value> "New value"))
+                        .arg(arg!(--nx "Only set the key if it doesn't exist")
+                             .conflicts_with("xx"))
+                        .arg(arg!(--xx "Only set the key if it already exists"))
+                        .arg(arg!(--get "Reply with the key's old value"))
+                        .arg(arg!(--keepttl "Keep the key's expiration time")
+                             .conflicts_with_all(["ex", "px"]))
+                        .arg(arg!(--ex <seconds> "Expire the key after a number of seconds")
+                             .conflicts_with("px")
+                             .value_parser(clap::value_parser!(u64)))
+                        .arg(arg!(--px <milliseconds> "Expire the key after a number of milliseconds")
+                             .value_parser(clap::value_parser!(u64)))
                 )
                 .subcommand(
                     Command::new("setex")
@@ -51,6 +116,114 @@ fn main() -> io::Result<()> {
                         .arg(arg!(<ttl> "Expiration time to live")
                              .value_parser(clap::value_parser!(u64)))
                 )
+                .subcommand(
+                    Command::new("psetex")
+                        .about("Set a key's value in the KV store with an expiration time in milliseconds")
+                        .arg(arg!(<key> "Key to set"))
+                        .arg(arg!(<value> "New value"))
+                        .arg(arg!(<ttl> "Expiration time to live in milliseconds")
+                             .value_parser(clap::value_parser!(u64)))
+                )
+                .subcommand(
+                    Command::new("pttl")
+                        .about("Get a key's remaining expiration time in the KV store in milliseconds")
+                        .arg(arg!(<key> "Key to get the ttl of"))
+                )
+                .subcommand(
+                    Command::new("expire")
+                        .about("Set a key in the KV store to expire after a number of seconds")
+                        .arg(arg!(<key> "Key to expire"))
+                        .arg(arg!(<ttl> "Seconds to live, deleting the key if 0 or less")
+                             .allow_negative_numbers(true)
+                             .value_parser(clap::value_parser!(i64)))
+                )
+                .subcommand(
+                    Command::new("pexpire")
+                        .about("Set a key in the KV store to expire after a number of milliseconds")
+                        .arg(arg!(<key> "Key to expire"))
+                        .arg(arg!(<ttl> "Milliseconds to live, deleting the key if 0 or less")
+                             .allow_negative_numbers(true)
+                             .value_parser(clap::value_parser!(i64)))
+                )
+                .subcommand(
+                    Command::new("expireat")
+                        .about("Set a key in the KV store to expire at a unix time in seconds")
+                        .arg(arg!(<key> "Key to expire"))
+                        .arg(arg!(<timestamp> "Unix time to expire at")
+                             .allow_negative_numbers(true)
+                             .value_parser(clap::value_parser!(i64)))
+                )
+                .subcommand(
+                    Command::new("pexpireat")
+                        .about("Set a key in the KV store to expire at a unix time in milliseconds")
+                        .arg(arg!(<key> "Key to expire"))
+                        .arg(arg!(<timestamp> "Unix time in milliseconds to expire at")
+                             .allow_negative_numbers(true)
+                             .value_parser(clap::value_parser!(i64)))
+                )
+                .subcommand(
+                    Command::new("persist")
+                        .about("Stop a key in the KV store from expiring")
+                        .arg(arg!(<key> "Key to keep"))
+                )
+                .subcommand(
+                    Command::new("del")
+                        .about("Delete keys from the KV store")
+                        .arg(arg!(<key> ... "Keys to delete"))
+                )
+                .subcommand(
+                    Command::new("exists")
+                        .about("Count how many of the given keys exist in the KV store")
+                        .arg(arg!(<key> ... "Keys to look for"))
+                )
+                .subcommand(
+                    Command::new("keys")
+                        .about("List every key in the KV store matching a glob pattern")
+                        .arg(arg!(<pattern> "Pattern to match, such as 'user:*'"))
+                )
+                .subcommand(
+                    Command::new("scan")
+                        .about("List some of the keys in the KV store, continuing from a cursor")
+                        .arg(arg!(<cursor> "Cursor returned by the last scan, or 0 to start")
+                             .value_parser(clap::value_parser!(u64)))
+                        .arg(arg!(--match <PATTERN> "Only list keys matching a glob pattern"))
+                        .arg(arg!(--count <COUNT> "Roughly how many keys to look at")
+                             .value_parser(clap::value_parser!(usize)))
+                )
+                .subcommand(
+                    Command::new("incr")
+                        .about("Add 1 to the integer stored at a key in the KV store")
+                        .arg(arg!(<key> "Key of the integer"))
+                )
+                .subcommand(
+                    Command::new("decr")
+                        .about("Subtract 1 from the integer stored at a key in the KV store")
+                        .arg(arg!(<key> "Key of the integer"))
+                )
+                .subcommand(
+                    Command::new("incrby")
+                        .about("Add to the integer stored at a key in the KV store")
+                        .arg(arg!(<key> "Key of the integer"))
+                        .arg(arg!(<increment> "Amount to add, which may be negative")
+                             .allow_negative_numbers(true)
+                             .value_parser(clap::value_parser!(i64)))
+                )
+                .subcommand(
+                    Command::new("decrby")
+                        .about("Subtract from the integer stored at a key in the KV store")
+                        .arg(arg!(<key> "Key of the integer"))
+                        .arg(arg!(<decrement> "Amount to subtract, which may be negative")
+                             .allow_negative_numbers(true)
+                             .value_parser(clap::value_parser!(i64)))
+                )
+                .subcommand(
+                    Command::new("incrbyfloat")
+                        .about("Add to the float stored at a key in the KV store")
+                        .arg(arg!(<key> "Key of the float"))
+                        .arg(arg!(<increment> "Amount to add, which may be negative")
+                             .allow_negative_numbers(true)
+                             .value_parser(clap::value_parser!(f64)))
+                )
                 .subcommand(
                     Command::new("get")
                         .about("Get a key's value in the KV store")
@@ -61,16 +234,306 @@ fn main() -> io::Result<()> {
                         .about("Get a key's remaining expiration time in the KV store")
                         .arg(arg!(<key> "Key to get the ttl of"))
                 )
+                .subcommand(
+                    Command::new("hset")
+                        .about("Set a field of a hash in the KV store")
+                        .arg(arg!(<key> "Key of the hash"))
+                        .arg(arg!(<field> "Field to set"))
+                        .arg(arg!(<value> "New value"))
+                )
+                .subcommand(
+                    Command::new("hget")
+                        .about("Get a field of a hash in the KV store")
+                        .arg(arg!(<key> "Key of the hash"))
+                        .arg(arg!(<field> "Field to get the value of"))
+                )
+                .subcommand(
+                    Command::new("hdel")
+                        .about("Delete a field of a hash in the KV store")
+                        .arg(arg!(<key> "Key of the hash"))
+                        .arg(arg!(<field> "Field to delete"))
+                )
+                .subcommand(
+                    Command::new("hexists")
+                        .about("Check whether a hash in the KV store has a field")
+                        .arg(arg!(<key> "Key of the hash"))
+                        .arg(arg!(<field> "Field to look for"))
+                )
+                .subcommand(
+                    Command::new("hlen")
+                        .about("Get the number of fields in a hash in the KV store")
+                        .arg(arg!(<key> "Key of the hash"))
+                )
+                .subcommand(
+                    Command::new("hgetall")
+                        .about("Get every field and value of a hash in the KV store")
+                        .arg(arg!(<key> "Key of the hash"))
+                )
+                .subcommand(
+                    Command::new("hincrby")
+                        .about("Add to the integer in a field of a hash in the KV store")
+                        .arg(arg!(<key> "Key of the hash"))
+                        .arg(arg!(<field> "Field to add to"))
+                        .arg(arg!(<increment> "Amount to add, which may be negative")
+                             .allow_negative_numbers(true)
+                             .value_parser(clap::value_parser!(i64)))
+                )
+                .subcommand(
+                    Command::new("zadd")
+                        .about("Add a member to a sorted set in the KV store, or change its score")
+                        .arg(arg!(<key> "Key of the sorted set"))
+                        .arg(arg!(<score> "Score of the member, which may be inf or -inf")
+                             .allow_hyphen_values(true))
+                        .arg(arg!(<member> "Member to add"))
+                )
+                .subcommand(
+                    Command::new("zrem")
+                        .about("Remove a member from a sorted set in the KV store")
+                        .arg(arg!(<key> "Key of the sorted set"))
+                        .arg(arg!(<member> "Member to remove"))
+                )
+                .subcommand(
+                    Command::new("zscore")
+                        .about("Get the score of a member of a sorted set in the KV store")
+                        .arg(arg!(<key> "Key of the sorted set"))
+                        .arg(arg!(<member> "Member to look up"))
+                )
+                .subcommand(
+                    Command::new("zincrby")
+                        .about("Add to the score of a member of a sorted set in the KV store")
+                        .arg(arg!(<key> "Key of the sorted set"))
+                        .arg(arg!(<increment> "Amount to add, which may be negative")
+                             .allow_hyphen_values(true))
+                        .arg(arg!(<member> "Member to add to"))
+                )
+                .subcommand(
+                    Command::new("zrank")
+                        .about("Get the position of a member of a sorted set in the KV store, from the lowest score")
+                        .arg(arg!(<key> "Key of the sorted set"))
+                        .arg(arg!(<member> "Member to look up"))
+                )
+                .subcommand(
+                    Command::new("zrange")
+                        .about("Get the members of a sorted set in the KV store between two positions, from the lowest score")
+                        .arg(arg!(<key> "Key of the sorted set"))
+                        .arg(arg!(<start> "First position, counting from the end if negative")
+                             .allow_negative_numbers(true)
+                             .value_parser(clap::value_parser!(i64)))
+                        .arg(arg!(<stop> "Last position, counting from the end if negative")
+                             .allow_negative_numbers(true)
+                             .value_parser(clap::value_parser!(i64)))
+                        .arg(arg!(--withscores "Include the score of each member"))
+                )
+                .subcommand(
+                    Command::new("zrevrange")
+                        .about("Get the members of a sorted set in the KV store between two positions, from the highest score")
+                        .arg(arg!(<key> "Key of the sorted set"))
+                        .arg(arg!(<start> "First position, counting from the end if negative")
+                             .allow_negative_numbers(true)
+                             .value_parser(clap::value_parser!(i64)))
+                        .arg(arg!(<stop> "Last position, counting from the end if negative")
+                             .allow_negative_numbers(true)
+                             .value_parser(clap::value_parser!(i64)))
+                        .arg(arg!(--withscores "Include the score of each member"))
+                )
+                .subcommand(
+                    Command::new("zrangebyscore")
+                        .about("Get the members of a sorted set in the KV store with scores in a range")
+                        .arg(arg!(<key> "Key of the sorted set"))
+                        .arg(arg!(<min> "Lowest score, or (score to leave it out, or -inf")
+                             .allow_hyphen_values(true))
+                        .arg(arg!(<max> "Highest score, or (score to leave it out, or +inf")
+                             .allow_hyphen_values(true))
+                        .arg(arg!(--withscores "Include the score of each member"))
+                        .arg(arg!(--limit <OFFSET_COUNT> "Skip OFFSET members and return at most COUNT")
+                             .num_args(2)
+                             .allow_negative_numbers(true)
+                             .value_parser(clap::value_parser!(i64)))
+                )
+                .subcommand(
+                    Command::new("zcard")
+                        .about("Get the number of members in a sorted set in the KV store")
+                        .arg(arg!(<key> "Key of the sorted set"))
+                )
+                .subcommand(
+                    Command::new("xadd")
+                        .about("Add an entry to a stream in the KV store")
+                        .arg(arg!(<key> "Key of the stream"))
+                        .arg(arg!(<id> "ID of the entry, or * to have one picked"))
+                        .arg(arg!(<field_value> ... "Fields of the entry, each followed by its value")
+                             .allow_hyphen_values(true))
+                        .arg(arg!(--maxlen <MAXLEN> "Trim the oldest entries to leave at most this many")
+                             .value_parser(clap::value_parser!(usize)))
+                )
+                .subcommand(
+                    Command::new("xrange")
+                        .about("Get the entries of a stream in the KV store between two IDs, oldest first")
+                        .arg(arg!(<key> "Key of the stream"))
+                        .arg(arg!(<start> "First ID, or (ID to leave it out, or - for the first entry")
+                             .allow_hyphen_values(true))
+                        .arg(arg!(<end> "Last ID, or (ID to leave it out, or + for the last entry"))
+                        .arg(arg!(--count <COUNT> "Return at most this many entries")
+                             .value_parser(clap::value_parser!(usize)))
+                )
+                .subcommand(
+                    Command::new("xrevrange")
+                        .about("Get the entries of a stream in the KV store between two IDs, newest first")
+                        .arg(arg!(<key> "Key of the stream"))
+                        .arg(arg!(<end> "Last ID, or (ID to leave it out, or + for the last entry"))
+                        .arg(arg!(<start> "First ID, or (ID to leave it out, or - for the first entry")
+                             .allow_hyphen_values(true))
+                        .arg(arg!(--count <COUNT> "Return at most this many entries")
+                             .value_parser(clap::value_parser!(usize)))
+                )
+                .subcommand(
+                    Command::new("xread")
+                        .about("Get the entries of a stream in the KV store added after an ID")
+                        .arg(arg!(<key> "Key of the stream"))
+                        .arg(arg!(<id> "ID to read after, or $ for entries added from now on"))
+                        .arg(arg!(--count <COUNT> "Return at most this many entries")
+                             .value_parser(clap::value_parser!(usize)))
+                        .arg(arg!(--block <MILLISECONDS> "Wait this long for an entry if there aren't any yet, 0 to wait forever")
+                             .value_parser(clap::value_parser!(u64)))
+                )
+                .subcommand(
+                    Command::new("xlen")
+                        .about("Get the number of entries in a stream in the KV store")
+                        .arg(arg!(<key> "Key of the stream"))
+                )
+                .subcommand(
+                    Command::new("xtrim")
+                        .about("Remove the oldest entries of a stream in the KV store")
+                        .arg(arg!(<key> "Key of the stream"))
+                        .arg(arg!(<maxlen> "How many entries to leave")
+                             .value_parser(clap::value_parser!(usize)))
+                )
+                .subcommand(
+                    Command::new("xgroup-create")
+                        .about("Create a consumer group on a stream in the KV store")
+                        .arg(arg!(<key> "Key of the stream"))
+                        .arg(arg!(<group> "Name of the group"))
+                        .arg(arg!(<id> "Last ID the group has seen, or $ to only see entries added from now on"))
+                        .arg(arg!(--mkstream "Create the stream if it doesn't exist"))
+                )
+                .subcommand(
+                    Command::new("xreadgroup")
+                        .about("Read the entries of a stream in the KV store as a consumer of a group")
+                        .arg(arg!(<group> "Name of the group"))
+                        .arg(arg!(<consumer> "Name of the consumer"))
+                        .arg(arg!(<key> "Key of the stream"))
+                        .arg(arg!(<id> "> for entries no consumer has been given yet, or an ID to go over the consumer's pending entries after it"))
+                        .arg(arg!(--count <COUNT> "Return at most this many entries")
+                             .value_parser(clap::value_parser!(usize)))
+                        .arg(arg!(--block <MILLISECONDS> "Wait this long for an entry if there aren't any yet, 0 to wait forever")
+                             .value_parser(clap::value_parser!(u64)))
+                        .arg(arg!(--noack "Don't keep the entries pending until they're acknowledged"))
+                )
+                .subcommand(
+                    Command::new("xack")
+                        .about("Acknowledge entries a consumer group was given from a stream in the KV store")
+                        .arg(arg!(<key> "Key of the stream"))
+                        .arg(arg!(<group> "Name of the group"))
+                        .arg(arg!(<id> ... "IDs of the entries"))
+                )
+                .subcommand(
+                    Command::new("xpending")
+                        .about("Get the entries a consumer group hasn't acknowledged yet from a stream in the KV store")
+                        .arg(arg!(<key> "Key of the stream"))
+                        .arg(arg!(<group> "Name of the group"))
+                        .arg(arg!(--count <COUNT> "List up to this many entries instead of a summary")
+                             .value_parser(clap::value_parser!(usize)))
+                        .arg(arg!(--consumer <CONSUMER> "Only list the entries of this consumer")
+                             .requires("count"))
+                )
+                .subcommand(
+                    Command::new("xclaim")
+                        .about("Take over pending entries of a stream in the KV store from another consumer")
+                        .arg(arg!(<key> "Key of the stream"))
+                        .arg(arg!(<group> "Name of the group"))
+                        .arg(arg!(<consumer> "Consumer to give the entries to"))
+                        .arg(arg!(<min_idle> "Only take entries that have been pending for at least this many milliseconds")
+                             .value_parser(clap::value_parser!(u64)))
+                        .arg(arg!(<id> ... "IDs of the entries"))
+                        .arg(arg!(--justid "Only return the IDs of the entries"))
+                )
+                .subcommand(
+                    Command::new("xautoclaim")
+                        .about("Take over pending entries of a stream in the KV store that have been idle for a while")
+                        .arg(arg!(<key> "Key of the stream"))
+                        .arg(arg!(<group> "Name of the group"))
+                        .arg(arg!(<consumer> "Consumer to give the entries to"))
+                        .arg(arg!(<min_idle> "Only take entries that have been pending for at least this many milliseconds")
+                             .value_parser(clap::value_parser!(u64)))
+                        .arg(arg!(<start> "ID to start looking from, 0-0 for the first pending entry"))
+                        .arg(arg!(--count <COUNT> "Take at most this many entries")
+                             .value_parser(clap::value_parser!(usize)))
+                        .arg(arg!(--justid "Only return the IDs of the entries"))
+                )
+                .subcommand(
+                    Command::new("eval")
+                        .about("Run a Lua script on the server")
+                        .arg(arg!(<script> "Source of the script"))
+                        .arg(arg!(<numkeys> "How many of the arguments are keys")
+                             .value_parser(clap::value_parser!(usize)))
+                        .arg(arg!([arg] ... "Keys, then other arguments, for the script")
+                             .allow_hyphen_values(true))
+                )
+                .subcommand(
+                    Command::new("evalsha")
+                        .about("Run a Lua script the server already has, by its SHA1")
+                        .arg(arg!(<sha1> "SHA1 of the script"))
+                        .arg(arg!(<numkeys> "How many of the arguments are keys")
+                             .value_parser(clap::value_parser!(usize)))
+                        .arg(arg!([arg] ... "Keys, then other arguments, for the script")
+                             .allow_hyphen_values(true))
+                )
+                .subcommand(
+                    Command::new("script-load")
+                        .about("Send a Lua script to the server to run later with evalsha")
+                        .arg(arg!(<script> "Source of the script"))
+                )
+                .subcommand(
+                    Command::new("info")
+                        .about("Get stats about the server, such as how many keys have expired")
+                )
         )
         .get_matches();
 
     if let Some(matches) = matches.subcommand_matches("server") {
         let threads = matches
             .get_one::<u16>("threads").unwrap();
+        let shards = matches.get_one::<u16>("shards").unwrap();
         let host = matches.get_one::<String>("host").unwrap();
         let port = matches.get_one::<u16>("port").unwrap();
+        let dir = matches.get_one::<PathBuf>("dir").unwrap();
+        let dbfilename = matches.get_one::<String>("dbfilename").unwrap();
+        let snapshot_interval = matches.get_one::<u64>("snapshot-interval");
+        let appendonly = matches.get_flag("appendonly");
+        let appendfilename = matches.get_one::<String>("appendfilename").unwrap();
+        let appendfsync = matches.get_one::<FsyncPolicy>("appendfsync").unwrap();
+        let hz = matches.get_one::<u32>("hz").unwrap();
+        let lua_time_limit = matches.get_one::<u64>("lua-time-limit").unwrap();
+        let subscriber_queue = matches.get_one::<u64>("subscriber-queue").unwrap();
+        let subscriber_overflow = matches.get_one::<OverflowPolicy>("subscriber-overflow").unwrap();
+
+        let config = server::Config {
+            host: host.to_string(),
+            port: *port,
+            threads: *threads,
+            shards: (*shards).into(),
+            dir: dir.to_path_buf(),
+            dbfilename: dbfilename.to_string(),
+            snapshot_interval: snapshot_interval.copied(),
+            appendonly,
+            appendfilename: appendfilename.to_string(),
+            appendfsync: *appendfsync,
+            hz: *hz,
+            lua_time_limit: *lua_time_limit,
+            subscriber_queue: *subscriber_queue as usize,
+            subscriber_overflow: *subscriber_overflow,
+        };
 
-        server::start_server(host, *port, *threads)
+        server::start_server(config)
             .expect("Failed to start Rustis!");
     } else if let Some(matches) = matches.subcommand_matches("client") {
         let host = matches.get_one::<String>("host").unwrap();
@@ -84,20 +547,123 @@ fn main() -> io::Result<()> {
 
             client.send_publish(channel, message)?;
         } else if let Some(matches) = matches.subcommand_matches("subscribe") {
-            let channel = matches.get_one::<String>("channel").unwrap();
+            let channels = matches.get_many::<String>("channel").unwrap();
 
-            client.send_subscribe(channel)?;
+            client.send_subscribe(channels.map(String::as_str).collect())?;
+        } else if let Some(matches) = matches.subcommand_matches("psubscribe") {
+            let patterns = matches.get_many::<String>("pattern").unwrap();
+
+            client.send_psubscribe(patterns.map(String::as_str).collect())?;
+        } else if let Some(matches) = matches.subcommand_matches("pubsub-channels") {
+            let pattern = matches.get_one::<String>("pattern");
+
+            client.send_pubsub("channels", pattern.into_iter().map(String::as_str).collect())?;
+        } else if let Some(matches) = matches.subcommand_matches("pubsub-numsub") {
+            let channels = matches.get_many::<String>("channel").unwrap_or_default();
+
+            client.send_pubsub("numsub", channels.map(String::as_str).collect())?;
+        } else if matches.subcommand_matches("pubsub-numpat").is_some() {
+            client.send_pubsub("numpat", vec![])?;
+        } else if matches.subcommand_matches("pubsub-dropped").is_some() {
+            client.send_pubsub("dropped", vec![])?;
         } else if let Some(matches) = matches.subcommand_matches("set") {
             let key = matches.get_one::<String>("key").unwrap();
             let value = matches.get_one::<String>("value").unwrap();
 
-            client.send_set(key, value)?;
+            let mut options = Vec::new();
+            for flag in ["nx", "xx", "get", "keepttl"] {
+                if matches.get_flag(flag) {
+                    options.push(flag.to_string());
+                }
+            }
+            for option in ["ex", "px"] {
+                if let Some(ttl) = matches.get_one::<u64>(option) {
+                    options.extend([option.to_string(), ttl.to_string()]);
+                }
+            }
+
+            client.send_set(key, value, options)?;
         } else if let Some(matches) = matches.subcommand_matches("setex") {
             let key = matches.get_one::<String>("key").unwrap();
             let value = matches.get_one::<String>("value").unwrap();
             let ttl = matches.get_one::<u64>("ttl").unwrap();
 
             client.send_setex(key, value, *ttl)?;
+        } else if let Some(matches) = matches.subcommand_matches("psetex") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let value = matches.get_one::<String>("value").unwrap();
+            let ttl = matches.get_one::<u64>("ttl").unwrap();
+
+            client.send_psetex(key, value, *ttl)?;
+        } else if let Some(matches) = matches.subcommand_matches("pttl") {
+            let key = matches.get_one::<String>("key").unwrap();
+
+            client.send_pttl(key)?;
+        } else if let Some(matches) = matches.subcommand_matches("expire") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let ttl = matches.get_one::<i64>("ttl").unwrap();
+
+            client.send_expire(key, *ttl)?;
+        } else if let Some(matches) = matches.subcommand_matches("pexpire") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let ttl = matches.get_one::<i64>("ttl").unwrap();
+
+            client.send_pexpire(key, *ttl)?;
+        } else if let Some(matches) = matches.subcommand_matches("expireat") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let timestamp = matches.get_one::<i64>("timestamp").unwrap();
+
+            client.send_expireat(key, *timestamp)?;
+        } else if let Some(matches) = matches.subcommand_matches("pexpireat") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let timestamp = matches.get_one::<i64>("timestamp").unwrap();
+
+            client.send_pexpireat(key, *timestamp)?;
+        } else if let Some(matches) = matches.subcommand_matches("persist") {
+            let key = matches.get_one::<String>("key").unwrap();
+
+            client.send_persist(key)?;
+        } else if let Some(matches) = matches.subcommand_matches("del") {
+            let keys = matches.get_many::<String>("key").unwrap();
+
+            client.send_del(keys.map(String::as_str).collect())?;
+        } else if let Some(matches) = matches.subcommand_matches("exists") {
+            let keys = matches.get_many::<String>("key").unwrap();
+
+            client.send_exists(keys.map(String::as_str).collect())?;
+        } else if let Some(matches) = matches.subcommand_matches("keys") {
+            let pattern = matches.get_one::<String>("pattern").unwrap();
+
+            client.send_keys(pattern)?;
+        } else if let Some(matches) = matches.subcommand_matches("scan") {
+            let cursor = matches.get_one::<u64>("cursor").unwrap();
+            let pattern = matches.get_one::<String>("match");
+            let count = matches.get_one::<usize>("count");
+
+            client.send_scan(*cursor, pattern.map(String::as_str), count.copied())?;
+        } else if let Some(matches) = matches.subcommand_matches("incr") {
+            let key = matches.get_one::<String>("key").unwrap();
+
+            client.send_incr(key)?;
+        } else if let Some(matches) = matches.subcommand_matches("decr") {
+            let key = matches.get_one::<String>("key").unwrap();
+
+            client.send_decr(key)?;
+        } else if let Some(matches) = matches.subcommand_matches("incrby") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let increment = matches.get_one::<i64>("increment").unwrap();
+
+            client.send_incrby(key, *increment)?;
+        } else if let Some(matches) = matches.subcommand_matches("decrby") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let decrement = matches.get_one::<i64>("decrement").unwrap();
+
+            client.send_decrby(key, *decrement)?;
+        } else if let Some(matches) = matches.subcommand_matches("incrbyfloat") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let increment = matches.get_one::<f64>("increment").unwrap();
+
+            client.send_incrbyfloat(key, *increment)?;
         } else if let Some(matches) = matches.subcommand_matches("get") {
             let key = matches.get_one::<String>("key").unwrap();
 
@@ -106,6 +672,200 @@ fn main() -> io::Result<()> {
             let key = matches.get_one::<String>("key").unwrap();
 
             client.send_ttl(key)?;
+        } else if let Some(matches) = matches.subcommand_matches("hset") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let field = matches.get_one::<String>("field").unwrap();
+            let value = matches.get_one::<String>("value").unwrap();
+
+            client.send_hset(key, field, value)?;
+        } else if let Some(matches) = matches.subcommand_matches("hget") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let field = matches.get_one::<String>("field").unwrap();
+
+            client.send_hget(key, field)?;
+        } else if let Some(matches) = matches.subcommand_matches("hdel") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let field = matches.get_one::<String>("field").unwrap();
+
+            client.send_hdel(key, field)?;
+        } else if let Some(matches) = matches.subcommand_matches("hexists") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let field = matches.get_one::<String>("field").unwrap();
+
+            client.send_hexists(key, field)?;
+        } else if let Some(matches) = matches.subcommand_matches("hlen") {
+            let key = matches.get_one::<String>("key").unwrap();
+
+            client.send_hlen(key)?;
+        } else if let Some(matches) = matches.subcommand_matches("hgetall") {
+            let key = matches.get_one::<String>("key").unwrap();
+
+            client.send_hgetall(key)?;
+        } else if let Some(matches) = matches.subcommand_matches("hincrby") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let field = matches.get_one::<String>("field").unwrap();
+            let increment = matches.get_one::<i64>("increment").unwrap();
+
+            client.send_hincrby(key, field, *increment)?;
+        } else if let Some(matches) = matches.subcommand_matches("zadd") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let score = matches.get_one::<String>("score").unwrap();
+            let member = matches.get_one::<String>("member").unwrap();
+
+            client.send_zadd(key, score, member)?;
+        } else if let Some(matches) = matches.subcommand_matches("zrem") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let member = matches.get_one::<String>("member").unwrap();
+
+            client.send_zrem(key, member)?;
+        } else if let Some(matches) = matches.subcommand_matches("zscore") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let member = matches.get_one::<String>("member").unwrap();
+
+            client.send_zscore(key, member)?;
+        } else if let Some(matches) = matches.subcommand_matches("zincrby") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let increment = matches.get_one::<String>("increment").unwrap();
+            let member = matches.get_one::<String>("member").unwrap();
+
+            client.send_zincrby(key, increment, member)?;
+        } else if let Some(matches) = matches.subcommand_matches("zrank") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let member = matches.get_one::<String>("member").unwrap();
+
+            client.send_zrank(key, member)?;
+        } else if let Some(matches) = matches.subcommand_matches("zrange") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let start = matches.get_one::<i64>("start").unwrap();
+            let stop = matches.get_one::<i64>("stop").unwrap();
+            let withscores = matches.get_flag("withscores");
+
+            client.send_zrange(key, *start, *stop, withscores, false)?;
+        } else if let Some(matches) = matches.subcommand_matches("zrevrange") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let start = matches.get_one::<i64>("start").unwrap();
+            let stop = matches.get_one::<i64>("stop").unwrap();
+            let withscores = matches.get_flag("withscores");
+
+            client.send_zrange(key, *start, *stop, withscores, true)?;
+        } else if let Some(matches) = matches.subcommand_matches("zrangebyscore") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let min = matches.get_one::<String>("min").unwrap();
+            let max = matches.get_one::<String>("max").unwrap();
+            let withscores = matches.get_flag("withscores");
+            let limit = matches
+                .get_many::<i64>("limit")
+                .map(|mut limit| (*limit.next().unwrap(), *limit.next().unwrap()));
+
+            client.send_zrangebyscore(key, min, max, withscores, limit)?;
+        } else if let Some(matches) = matches.subcommand_matches("zcard") {
+            let key = matches.get_one::<String>("key").unwrap();
+
+            client.send_zcard(key)?;
+        } else if let Some(matches) = matches.subcommand_matches("xadd") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let id = matches.get_one::<String>("id").unwrap();
+            let fields = matches.get_many::<String>("field_value").unwrap();
+            let maxlen = matches.get_one::<usize>("maxlen");
+
+            client.send_xadd(key, id, fields.map(String::as_str).collect(), maxlen.copied())?;
+        } else if let Some(matches) = matches.subcommand_matches("xrange") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let start = matches.get_one::<String>("start").unwrap();
+            let end = matches.get_one::<String>("end").unwrap();
+            let count = matches.get_one::<usize>("count");
+
+            client.send_xrange(key, start, end, count.copied(), false)?;
+        } else if let Some(matches) = matches.subcommand_matches("xrevrange") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let start = matches.get_one::<String>("start").unwrap();
+            let end = matches.get_one::<String>("end").unwrap();
+            let count = matches.get_one::<usize>("count");
+
+            client.send_xrange(key, start, end, count.copied(), true)?;
+        } else if let Some(matches) = matches.subcommand_matches("xread") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let id = matches.get_one::<String>("id").unwrap();
+            let count = matches.get_one::<usize>("count");
+            let block = matches.get_one::<u64>("block");
+
+            client.send_xread(key, id, count.copied(), block.copied())?;
+        } else if let Some(matches) = matches.subcommand_matches("xlen") {
+            let key = matches.get_one::<String>("key").unwrap();
+
+            client.send_xlen(key)?;
+        } else if let Some(matches) = matches.subcommand_matches("xtrim") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let maxlen = matches.get_one::<usize>("maxlen").unwrap();
+
+            client.send_xtrim(key, *maxlen)?;
+        } else if let Some(matches) = matches.subcommand_matches("xgroup-create") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let group = matches.get_one::<String>("group").unwrap();
+            let id = matches.get_one::<String>("id").unwrap();
+            let mkstream = matches.get_flag("mkstream");
+
+            client.send_xgroup_create(key, group, id, mkstream)?;
+        } else if let Some(matches) = matches.subcommand_matches("xreadgroup") {
+            let group = matches.get_one::<String>("group").unwrap();
+            let consumer = matches.get_one::<String>("consumer").unwrap();
+            let key = matches.get_one::<String>("key").unwrap();
+            let id = matches.get_one::<String>("id").unwrap();
+            let count = matches.get_one::<usize>("count");
+            let block = matches.get_one::<u64>("block");
+            let noack = matches.get_flag("noack");
+
+            client.send_xreadgroup(group, consumer, key, id, count.copied(), block.copied(), noack)?;
+        } else if let Some(matches) = matches.subcommand_matches("xack") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let group = matches.get_one::<String>("group").unwrap();
+            let ids = matches.get_many::<String>("id").unwrap();
+
+            client.send_xack(key, group, ids.map(String::as_str).collect())?;
+        } else if let Some(matches) = matches.subcommand_matches("xpending") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let group = matches.get_one::<String>("group").unwrap();
+            let count = matches.get_one::<usize>("count");
+            let consumer = matches.get_one::<String>("consumer");
+
+            client.send_xpending(key, group, count.copied(), consumer.map(String::as_str))?;
+        } else if let Some(matches) = matches.subcommand_matches("xclaim") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let group = matches.get_one::<String>("group").unwrap();
+            let consumer = matches.get_one::<String>("consumer").unwrap();
+            let min_idle = matches.get_one::<u64>("min_idle").unwrap();
+            let ids = matches.get_many::<String>("id").unwrap();
+            let just_id = matches.get_flag("justid");
+
+            client.send_xclaim(key, group, consumer, *min_idle, ids.map(String::as_str).collect(), just_id)?;
+        } else if let Some(matches) = matches.subcommand_matches("xautoclaim") {
+            let key = matches.get_one::<String>("key").unwrap();
+            let group = matches.get_one::<String>("group").unwrap();
+            let consumer = matches.get_one::<String>("consumer").unwrap();
+            let min_idle = matches.get_one::<u64>("min_idle").unwrap();
+            let start = matches.get_one::<String>("start").unwrap();
+            let count = matches.get_one::<usize>("count");
+            let just_id = matches.get_flag("justid");
+
+            client.send_xautoclaim(key, group, consumer, *min_idle, start, count.copied(), just_id)?;
+        } else if let Some(matches) = matches.subcommand_matches("eval") {
+            let script = matches.get_one::<String>("script").unwrap();
+            let numkeys = matches.get_one::<usize>("numkeys").unwrap();
+            let args = matches.get_many::<String>("arg").unwrap_or_default();
+
+            client.send_eval("eval", script, *numkeys, args.map(String::as_str).collect())?;
+        } else if let Some(matches) = matches.subcommand_matches("evalsha") {
+            let sha = matches.get_one::<String>("sha1").unwrap();
+            let numkeys = matches.get_one::<usize>("numkeys").unwrap();
+            let args = matches.get_many::<String>("arg").unwrap_or_default();
+
+            client.send_eval("evalsha", sha, *numkeys, args.map(String::as_str).collect())?;
+        } else if let Some(matches) = matches.subcommand_matches("script-load") {
+            let script = matches.get_one::<String>("script").unwrap();
+
+            client.send_script_load(script)?;
+        } else if matches.subcommand_matches("info").is_some() {
+            client.send_info()?;
         }
     };
     Ok(())
@@ -1,5 +1,11 @@
 use regex::Regex;
 use clap::crate_version;
+use std::str::{self, FromStr};
+use crate::kvstore::{self, SetCondition, SetExpiry, SetOp, SetOptions};
+use std::time::Duration;
+use crate::sortedset::{self, ScoreBound};
+use crate::stream::{self, ClaimOptions, Fields, GroupReadFrom, NewId, PendingRange, ReadFrom, StreamId};
+use std::ops::Bound;
 
 /* Packet format:
 
@@ -10,176 +16,1918 @@ Rustis <VERSION>
 <DATA3>
 ...
 
+RESP2 packets are also accepted, as sent by redis-cli and Redis client libraries:
+
+*<ARGC>\r\n
+$<LEN>\r\n
+<COMMAND>\r\n
+$<LEN>\r\n
+<DATA1>\r\n
+...
+
+as well as the length-prefixed binary format described below.
+
 */
 
-// Represents a packet sent by the client
-#[derive(Debug)]
-pub enum RequestPacket {
-    Publish {
-        channel: String,
-        message: String,
-    },
-    Subscribe {
-        channel: String,
-    },
-    Set {
-        key: String,
-        value: String,
-    },
-    SetEx {
-        key: String,
-        value: String,
-        ttl: u64,
-    },
-    Get {
-        key: String,
-    },
-    Ttl {
-        key: String,
-    },
-    Unknown,
-    Invalid {
-        error: String,
-    },
-}
+// The wire format a packet arrived in, replies are written back in the same format
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Text,
+    Binary,
+    Resp,
+}
+
+// Represents a packet sent by the client
+#[derive(Debug)]
+pub enum RequestPacket {
+    Publish {
+        channel: String,
+        message: Vec<u8>,
+    },
+    Subscribe {
+        channels: Vec<String>,
+    },
+    PSubscribe {
+        patterns: Vec<String>,
+    },
+    // No channels means every channel the connection is subscribed to
+    Unsubscribe {
+        channels: Vec<String>,
+    },
+    PUnsubscribe {
+        patterns: Vec<String>,
+    },
+    PubSubChannels {
+        pattern: Option<String>,
+    },
+    PubSubNumSub {
+        channels: Vec<String>,
+    },
+    PubSubNumPat,
+    PubSubDropped,
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        options: SetOptions,
+    },
+    SetEx {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    },
+    Get {
+        key: Vec<u8>,
+    },
+    Ttl {
+        key: Vec<u8>,
+    },
+    PTtl {
+        key: Vec<u8>,
+    },
+    Expire {
+        key: Vec<u8>,
+        // In milliseconds, deleting the key if it's 0 or less
+        ttl: i64,
+    },
+    ExpireAt {
+        key: Vec<u8>,
+        unix_millis: i64,
+    },
+    Persist {
+        key: Vec<u8>,
+    },
+    Ping {
+        message: Option<Vec<u8>>,
+    },
+    Del {
+        keys: Vec<Vec<u8>>,
+    },
+    Exists {
+        keys: Vec<Vec<u8>>,
+    },
+    Keys {
+        pattern: Vec<u8>,
+    },
+    Scan {
+        cursor: u64,
+        pattern: Option<Vec<u8>>,
+        count: usize,
+    },
+    IncrBy {
+        key: Vec<u8>,
+        increment: i64,
+    },
+    IncrByFloat {
+        key: Vec<u8>,
+        increment: f64,
+    },
+    LPush {
+        key: Vec<u8>,
+        values: Vec<Vec<u8>>,
+    },
+    RPush {
+        key: Vec<u8>,
+        values: Vec<Vec<u8>>,
+    },
+    LPop {
+        key: Vec<u8>,
+        count: Option<usize>,
+    },
+    RPop {
+        key: Vec<u8>,
+        count: Option<usize>,
+    },
+    LRange {
+        key: Vec<u8>,
+        start: i64,
+        stop: i64,
+    },
+    LLen {
+        key: Vec<u8>,
+    },
+    LIndex {
+        key: Vec<u8>,
+        index: i64,
+    },
+    LTrim {
+        key: Vec<u8>,
+        start: i64,
+        stop: i64,
+    },
+    LMove {
+        source: Vec<u8>,
+        destination: Vec<u8>,
+        from_front: bool,
+        to_front: bool,
+    },
+    BLPop {
+        keys: Vec<Vec<u8>>,
+        // How long to wait for a value, where zero waits forever
+        timeout: Duration,
+    },
+    BRPop {
+        keys: Vec<Vec<u8>>,
+        timeout: Duration,
+    },
+    BLMove {
+        source: Vec<u8>,
+        destination: Vec<u8>,
+        from_front: bool,
+        to_front: bool,
+        timeout: Duration,
+    },
+    HSet {
+        key: Vec<u8>,
+        fields: Vec<(Vec<u8>, Vec<u8>)>,
+    },
+    HGet {
+        key: Vec<u8>,
+        field: Vec<u8>,
+    },
+    HDel {
+        key: Vec<u8>,
+        fields: Vec<Vec<u8>>,
+    },
+    HExists {
+        key: Vec<u8>,
+        field: Vec<u8>,
+    },
+    HLen {
+        key: Vec<u8>,
+    },
+    HGetAll {
+        key: Vec<u8>,
+    },
+    HIncrBy {
+        key: Vec<u8>,
+        field: Vec<u8>,
+        increment: i64,
+    },
+    SAdd {
+        key: Vec<u8>,
+        members: Vec<Vec<u8>>,
+    },
+    SRem {
+        key: Vec<u8>,
+        members: Vec<Vec<u8>>,
+    },
+    SMembers {
+        key: Vec<u8>,
+    },
+    SIsMember {
+        key: Vec<u8>,
+        member: Vec<u8>,
+    },
+    SCard {
+        key: Vec<u8>,
+    },
+    SInter {
+        keys: Vec<Vec<u8>>,
+    },
+    SUnion {
+        keys: Vec<Vec<u8>>,
+    },
+    SDiff {
+        keys: Vec<Vec<u8>>,
+    },
+    SInterStore {
+        destination: Vec<u8>,
+        keys: Vec<Vec<u8>>,
+    },
+    SUnionStore {
+        destination: Vec<u8>,
+        keys: Vec<Vec<u8>>,
+    },
+    SDiffStore {
+        destination: Vec<u8>,
+        keys: Vec<Vec<u8>>,
+    },
+    ZAdd {
+        key: Vec<u8>,
+        members: Vec<(f64, Vec<u8>)>,
+    },
+    ZRem {
+        key: Vec<u8>,
+        members: Vec<Vec<u8>>,
+    },
+    ZScore {
+        key: Vec<u8>,
+        member: Vec<u8>,
+    },
+    ZIncrBy {
+        key: Vec<u8>,
+        increment: f64,
+        member: Vec<u8>,
+    },
+    ZRank {
+        key: Vec<u8>,
+        member: Vec<u8>,
+    },
+    ZRange {
+        key: Vec<u8>,
+        start: i64,
+        stop: i64,
+        withscores: bool,
+    },
+    ZRevRange {
+        key: Vec<u8>,
+        start: i64,
+        stop: i64,
+        withscores: bool,
+    },
+    ZRangeByScore {
+        key: Vec<u8>,
+        min: ScoreBound,
+        max: ScoreBound,
+        withscores: bool,
+        // Offset and count
+        limit: Option<(usize, usize)>,
+    },
+    ZCard {
+        key: Vec<u8>,
+    },
+    XAdd {
+        key: Vec<u8>,
+        id: NewId,
+        fields: Fields,
+        maxlen: Option<usize>,
+    },
+    XRange {
+        key: Vec<u8>,
+        start: Bound<StreamId>,
+        end: Bound<StreamId>,
+        count: Option<usize>,
+    },
+    XRevRange {
+        key: Vec<u8>,
+        end: Bound<StreamId>,
+        start: Bound<StreamId>,
+        count: Option<usize>,
+    },
+    XRead {
+        streams: Vec<(Vec<u8>, ReadFrom)>,
+        count: Option<usize>,
+        // How long to wait for new entries if there aren't any yet, where 0 waits forever
+        block: Option<Duration>,
+    },
+    XLen {
+        key: Vec<u8>,
+    },
+    XTrim {
+        key: Vec<u8>,
+        maxlen: usize,
+    },
+    XSetId {
+        key: Vec<u8>,
+        id: StreamId,
+    },
+    XGroupCreate {
+        key: Vec<u8>,
+        group: Vec<u8>,
+        id: ReadFrom,
+        // Create the stream if it doesn't exist
+        mkstream: bool,
+    },
+    XGroupSetId {
+        key: Vec<u8>,
+        group: Vec<u8>,
+        id: ReadFrom,
+    },
+    XGroupDestroy {
+        key: Vec<u8>,
+        group: Vec<u8>,
+    },
+    XReadGroup {
+        group: Vec<u8>,
+        consumer: Vec<u8>,
+        streams: Vec<(Vec<u8>, GroupReadFrom)>,
+        count: Option<usize>,
+        // How long to wait for new entries if there aren't any yet, where 0 waits forever
+        block: Option<Duration>,
+        // Don't keep the entries pending, as if they were acknowledged straight away
+        noack: bool,
+    },
+    XAck {
+        key: Vec<u8>,
+        group: Vec<u8>,
+        ids: Vec<StreamId>,
+    },
+    XPending {
+        key: Vec<u8>,
+        group: Vec<u8>,
+        // Lists the entries in the range rather than summing them up
+        range: Option<PendingRange>,
+    },
+    XClaim {
+        key: Vec<u8>,
+        group: Vec<u8>,
+        consumer: Vec<u8>,
+        // Milliseconds an entry has to have been idle for to be claimed
+        min_idle: u64,
+        ids: Vec<StreamId>,
+        options: ClaimOptions,
+    },
+    XAutoClaim {
+        key: Vec<u8>,
+        group: Vec<u8>,
+        consumer: Vec<u8>,
+        min_idle: u64,
+        start: StreamId,
+        count: usize,
+        just_id: bool,
+    },
+    Multi,
+    Exec,
+    Discard,
+    Watch {
+        keys: Vec<Vec<u8>>,
+    },
+    Unwatch,
+    Eval {
+        script: Vec<u8>,
+        keys: Vec<Vec<u8>>,
+        args: Vec<Vec<u8>>,
+    },
+    EvalSha {
+        sha: String,
+        keys: Vec<Vec<u8>>,
+        args: Vec<Vec<u8>>,
+    },
+    ScriptLoad {
+        script: Vec<u8>,
+    },
+    ScriptExists {
+        shas: Vec<String>,
+    },
+    ScriptFlush,
+    Info,
+    Save,
+    BgSave,
+    BgRewriteAof,
+    Unknown,
+    Invalid {
+        error: String,
+    },
+}
+
+// Result of trying to pull one packet off the front of a connection's buffer
+#[derive(Debug)]
+pub enum Frame {
+    // A full packet, along with how many bytes of the buffer it used
+    Complete(RequestPacket, Protocol, usize),
+    // More bytes are needed before a packet can be read
+    Incomplete,
+    // The buffer can never become a valid packet, the connection should be dropped
+    Malformed(String),
+}
+
+impl Frame {
+    pub fn read(data: &[u8]) -> Self {
+        match data.first() {
+            None => Frame::Incomplete,
+            Some(b'*') => Self::read_resp(data),
+            Some(_) => {
+                // Both native formats start with a header line, which tells them apart
+                match read_line(data, 0) {
+                    Some((header, _)) if header.starts_with(BINARY_HEADER) => Self::read_binary(data),
+                    Some(_) => Self::read_text(data),
+                    None => Frame::Incomplete,
+                }
+            },
+        }
+    }
+
+    fn read_text(data: &[u8]) -> Self {
+        if let Some(index) = data.windows(2).position(|window| window == b"\n\n") {
+            let packet = String::from_utf8_lossy(&data[..index]);
+            Frame::Complete(RequestPacket::new(packet.to_string()), Protocol::Text, index + 2)
+        } else {
+            Frame::Incomplete
+        }
+    }
+
+    fn read_binary(data: &[u8]) -> Self {
+        let Some((header, mut pos)) = read_line(data, 0) else {
+            return Frame::Incomplete;
+        };
+
+        let version = str::from_utf8(&header[BINARY_HEADER.len()..]).unwrap_or_default();
+        if version != crate_version!() {
+            return Frame::Malformed(String::from("version mismatch"));
+        }
+
+        let argc = match read_number(data, pos) {
+            Ok(Some((argc, next))) => {
+                pos = next;
+                argc
+            },
+            Ok(None) => return Frame::Incomplete,
+            Err(error) => return Frame::Malformed(error),
+        };
+        if argc > MAX_ARGS {
+            return Frame::Malformed(String::from("too many arguments"));
+        }
+
+        let mut args = Vec::with_capacity(argc.min(PREALLOCATED_ARGS));
+        for _ in 0..argc {
+            let (len, start) = match read_number(data, pos) {
+                Ok(Some(header)) => header,
+                Ok(None) => return Frame::Incomplete,
+                Err(error) => return Frame::Malformed(error),
+            };
+
+            let Some(end) = start.checked_add(len).filter(|_| len <= MAX_ARG_LEN) else {
+                return Frame::Malformed(String::from("argument too long"));
+            };
+            if data.len() < end + 1 {
+                return Frame::Incomplete;
+            }
+            if data[end] != b'\n' {
+                return Frame::Malformed(String::from("expected newline after argument"));
+            }
+
+            args.push(data[start..end].to_vec());
+            pos = end + 1;
+        }
+
+        Frame::Complete(RequestPacket::from_arg_list(args), Protocol::Binary, pos)
+    }
+
+    fn read_resp(data: &[u8]) -> Self {
+        let (argc, mut pos) = match read_resp_line(data, 0, b'*') {
+            Ok(Some(header)) => header,
+            Ok(None) => return Frame::Incomplete,
+            Err(error) => return Frame::Malformed(error),
+        };
+        if argc > MAX_ARGS {
+            return Frame::Malformed(String::from("too many arguments"));
+        }
+
+        // Most of the arguments might never turn up, so only a few are made room for up front
+        let mut args = Vec::with_capacity(argc.min(PREALLOCATED_ARGS));
+        for _ in 0..argc {
+            let (len, start) = match read_resp_line(data, pos, b'$') {
+                Ok(Some(header)) => header,
+                Ok(None) => return Frame::Incomplete,
+                Err(error) => return Frame::Malformed(error),
+            };
+
+            let Some(end) = start.checked_add(len).filter(|_| len <= MAX_ARG_LEN) else {
+                return Frame::Malformed(String::from("argument too long"));
+            };
+            if data.len() < end + 2 {
+                return Frame::Incomplete;
+            }
+            if &data[end..end + 2] != b"\r\n" {
+                return Frame::Malformed(String::from("expected CRLF after bulk string"));
+            }
+
+            args.push(data[start..end].to_vec());
+            pos = end + 2;
+        }
+
+        Frame::Complete(RequestPacket::from_arg_list(args), Protocol::Resp, pos)
+    }
+}
+
+/* Binary packet format:
+
+Rustis/2 <VERSION>
+<ARGC>
+<LEN1>
+<COMMAND>
+<LEN2>
+<DATA1>
+...
+
+Every argument is preceded by its length in bytes, so arguments may hold any bytes at all.
+
+*/
+pub const BINARY_HEADER: &[u8] = b"Rustis/2 ";
+
+// The most arguments a packet can have, and the longest any one of them can be. The lengths come
+// from the client, so they're checked before anything is allocated for them.
+const MAX_ARGS: usize = 1024 * 1024;
+const MAX_ARG_LEN: usize = 512 * 1024 * 1024;
+const PREALLOCATED_ARGS: usize = 64;
+
+// Reads the line starting at `pos`, returning it without its newline and the position
+// just past it
+fn read_line(data: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let rest = &data[pos..];
+    let end = rest.iter().position(|byte| *byte == b'\n')?;
+
+    Some((&rest[..end], pos + end + 1))
+}
+
+// Reads a line holding a single number, as used by the binary format's lengths
+fn read_number(data: &[u8], pos: usize) -> Result<Option<(usize, usize)>, String> {
+    let Some((line, next)) = read_line(data, pos) else {
+        return Ok(None);
+    };
+
+    let number = str::from_utf8(line)
+        .ok()
+        .and_then(|number| number.parse::<usize>().ok());
+
+    match number {
+        Some(number) => Ok(Some((number, next))),
+        None => Err(String::from("invalid length")),
+    }
+}
+
+// Reads a `<prefix><number>\r\n` line starting at `pos`, returning the number and the
+// position just past the line
+fn read_resp_line(data: &[u8], pos: usize, prefix: u8) -> Result<Option<(usize, usize)>, String> {
+    let rest = &data[pos..];
+
+    let Some(end) = rest.windows(2).position(|window| window == b"\r\n") else {
+        return Ok(None);
+    };
+
+    if rest[0] != prefix {
+        return Err(format!("expected '{}'", prefix as char));
+    }
+
+    let number = str::from_utf8(&rest[1..end])
+        .ok()
+        .and_then(|number| number.parse::<usize>().ok());
+
+    match number {
+        Some(number) => Ok(Some((number, pos + end + 2))),
+        None => Err(String::from("invalid length")),
+    }
+}
+
+// Builds the argument list for a command that takes a key followed by some values
+fn command_args(command: &str, key: &[u8], values: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let mut args = vec![command.as_bytes().to_vec(), key.to_vec()];
+    args.extend(values.iter().cloned());
+    args
+}
+
+// LEFT and RIGHT pick the front and back of a list
+fn parse_end(arg: &[u8]) -> Result<bool, String> {
+    if arg.eq_ignore_ascii_case(b"left") {
+        Ok(true)
+    } else if arg.eq_ignore_ascii_case(b"right") {
+        Ok(false)
+    } else {
+        Err(String::from("expected LEFT or RIGHT"))
+    }
+}
+
+fn end_arg(front: bool) -> Vec<u8> {
+    if front { b"left".to_vec() } else { b"right".to_vec() }
+}
+
+// Blocking timeouts are in seconds and can have a fractional part
+fn parse_timeout(arg: &[u8]) -> Result<Duration, String> {
+    str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse::<f64>().ok())
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| String::from("invalid timeout"))
+}
+
+// What to log for a SET that wrote its value. NX and XX are left out, since whether the key
+// existed was already settled when it ran, and checking again on replay could go the other way.
+pub fn set_commands(key: &[u8], value: &[u8], options: &SetOptions) -> Vec<Vec<Vec<u8>>> {
+    let mut args = vec![b"set".to_vec(), key.to_vec(), value.to_vec()];
+
+    match options.expiry {
+        // See `write_commands`
+        Some(SetExpiry::At(at)) if at <= kvstore::unix_millis() => return vec![vec![b"del".to_vec(), key.to_vec()]],
+        Some(SetExpiry::At(at)) => args.extend([b"pxat".to_vec(), at.to_string().into_bytes()]),
+        Some(SetExpiry::After(ttl)) => args.extend([b"pxat".to_vec(), expires_at(ttl).to_string().into_bytes()]),
+        Some(SetExpiry::Keep) => args.push(b"keepttl".to_vec()),
+        None => (),
+    }
+
+    vec![args]
+}
+
+// Turns a TTL into milliseconds, turning away ones that run out too far in the future to be given
+// as a unix time
+fn ttl_millis(ttl: i64, scale: i64) -> Result<i64, String> {
+    ttl.checked_mul(scale)
+        .filter(|ttl| kvstore::unix_millis().checked_add(*ttl).is_some())
+        .ok_or_else(|| String::from("invalid expire time"))
+}
+
+// The unix time in milliseconds that a TTL starting now runs out at
+fn expires_at(ttl: Duration) -> i64 {
+    kvstore::unix_millis().saturating_add(ttl.as_millis().try_into().unwrap_or(i64::MAX))
+}
+
+pub fn pexpireat_args(key: &[u8], unix_millis: i64) -> Vec<Vec<u8>> {
+    vec![b"pexpireat".to_vec(), key.to_vec(), unix_millis.to_string().into_bytes()]
+}
+
+// Walks through a command's arguments, naming whichever one is missing or invalid
+struct Args {
+    args: std::vec::IntoIter<Vec<u8>>,
+}
+
+impl Args {
+    fn new(args: Vec<Vec<u8>>) -> Self {
+        Args { args: args.into_iter() }
+    }
+
+    fn next(&mut self, name: &str) -> Result<Vec<u8>, String> {
+        self.args
+            .next()
+            .ok_or_else(|| format!("missing {}", name))
+    }
+
+    fn next_string(&mut self, name: &str) -> Result<String, String> {
+        self.next(name)
+            .map(|arg| String::from_utf8_lossy(&arg).to_string())
+    }
+
+    fn next_parsed<T: FromStr>(&mut self, name: &str) -> Result<T, String> {
+        let arg = self.next(name)?;
+
+        str::from_utf8(&arg)
+            .ok()
+            .and_then(|arg| arg.parse::<T>().ok())
+            .ok_or_else(|| format!("invalid {}", name))
+    }
+
+    fn next_score(&mut self, name: &str) -> Result<f64, String> {
+        let arg = self.next(name)?;
+
+        str::from_utf8(&arg)
+            .ok()
+            .and_then(sortedset::parse_score)
+            .ok_or_else(|| format!("invalid {}", name))
+    }
+
+    fn peek(&self) -> Option<&[u8]> {
+        self.args.as_slice().first().map(Vec::as_slice)
+    }
+
+    fn optional(&mut self) -> Option<Vec<u8>> {
+        self.args.next()
+    }
+
+    fn optional_parsed<T: FromStr>(&mut self, name: &str) -> Result<Option<T>, String> {
+        match self.args.next() {
+            Some(arg) => str::from_utf8(&arg)
+                .ok()
+                .and_then(|arg| arg.parse::<T>().ok())
+                .map(Some)
+                .ok_or_else(|| format!("invalid {}", name)),
+            None => Ok(None),
+        }
+    }
+
+    // Takes all the remaining arguments, if there are any
+    fn remaining(&mut self) -> Vec<Vec<u8>> {
+        self.args.by_ref().collect()
+    }
+
+    fn remaining_strings(&mut self) -> Vec<String> {
+        self.args
+            .by_ref()
+            .map(|arg| String::from_utf8_lossy(&arg).to_string())
+            .collect()
+    }
+
+    // Takes all the remaining arguments, of which there must be at least one
+    fn rest(&mut self, name: &str) -> Result<Vec<Vec<u8>>, String> {
+        let rest: Vec<_> = self.args.by_ref().collect();
+
+        if rest.is_empty() {
+            Err(format!("missing {}", name))
+        } else {
+            Ok(rest)
+        }
+    }
+
+    fn rest_strings(&mut self, name: &str) -> Result<Vec<String>, String> {
+        let rest = self.rest(name)?;
+
+        Ok(rest.iter().map(|arg| String::from_utf8_lossy(arg).to_string()).collect())
+    }
+}
+
+impl RequestPacket {
+    pub fn new(buf: String) -> Self {
+        let lines: Vec<_> = buf.split('\n').collect();
+
+        let packet_version = if let Some(line1) = lines.first() {
+            let re = Regex::new(r"^Rustis (\d{1,3}\.\d{1,4}\.\d{1,4})$").unwrap();
+
+            re.captures(line1).map(|caps| caps.get(1).unwrap().as_str())
+        } else {
+            None
+        };
+
+        let current_version = crate_version!();
+
+        if let Some(packet_version) = packet_version {
+            if packet_version != current_version {
+                return RequestPacket::Invalid {
+                    error: String::from("version mismatch"),
+                }
+            }
+        } else {
+            return RequestPacket::Invalid {
+                error: String::from("missing headers")
+            };
+        }
+
+        let Some(command) = lines.get(1) else {
+            return RequestPacket::Unknown;
+        };
+
+        let mut args: Vec<Vec<u8>> = lines[2..].iter().map(|line| line.as_bytes().to_vec()).collect();
+
+        // The last argument of these commands is a value, which may itself span several lines
+        let arity = match *command {
+            "publish" | "set" => 2,
+            "setex" | "psetex" => 3,
+            _ => usize::MAX,
+        };
+
+        if args.len() > arity {
+            let value = args.split_off(arity - 1).join(&b'\n');
+            args.push(value);
+        }
+
+        RequestPacket::from_args(command, args)
+    }
+
+    // The commands to log to the append-only file for commands that change the store, which
+    // is empty for commands that don't. Times to live are logged as the unix time the key
+    // expires at, so replaying the file later doesn't give keys extra time. Keys are kept until
+    // the whole file has been replayed, so a time that had already passed when the command ran,
+    // which deleted the key there and then, is logged as a DEL.
+    pub fn write_commands(&self) -> Vec<Vec<Vec<u8>>> {
+        let now = kvstore::unix_millis();
+
+        let args = match self {
+            // Only logged if it wrote anything, which the server knows once it's run, see
+            // `set_commands`
+            RequestPacket::Set { .. } => return Vec::new(),
+            RequestPacket::SetEx { key, value, ttl } => {
+                return vec![
+                    vec![b"set".to_vec(), key.clone(), value.clone()],
+                    pexpireat_args(key, expires_at(*ttl)),
+                ];
+            },
+            RequestPacket::Expire { key, ttl } if *ttl <= 0 => vec![b"del".to_vec(), key.clone()],
+            RequestPacket::Expire { key, ttl } => pexpireat_args(key, now.saturating_add(*ttl)),
+            RequestPacket::ExpireAt { key, unix_millis } if *unix_millis <= now => vec![b"del".to_vec(), key.clone()],
+            RequestPacket::ExpireAt { key, unix_millis } => pexpireat_args(key, *unix_millis),
+            RequestPacket::Persist { key } => vec![b"persist".to_vec(), key.clone()],
+            RequestPacket::Del { keys } => {
+                let mut args = vec![b"del".to_vec()];
+                args.extend(keys.iter().cloned());
+                args
+            },
+            RequestPacket::IncrBy { key, increment } => {
+                vec![b"incrby".to_vec(), key.clone(), increment.to_string().into_bytes()]
+            },
+            RequestPacket::IncrByFloat { key, increment } => {
+                vec![b"incrbyfloat".to_vec(), key.clone(), increment.to_string().into_bytes()]
+            },
+            RequestPacket::LPush { key, values } => command_args("lpush", key, values),
+            RequestPacket::RPush { key, values } => command_args("rpush", key, values),
+            RequestPacket::LPop { key, count } => {
+                vec![b"lpop".to_vec(), key.clone(), count.unwrap_or(1).to_string().into_bytes()]
+            },
+            RequestPacket::RPop { key, count } => {
+                vec![b"rpop".to_vec(), key.clone(), count.unwrap_or(1).to_string().into_bytes()]
+            },
+            RequestPacket::LTrim { key, start, stop } => {
+                vec![b"ltrim".to_vec(), key.clone(), start.to_string().into_bytes(), stop.to_string().into_bytes()]
+            },
+            RequestPacket::LMove { source, destination, from_front, to_front } => {
+                vec![b"lmove".to_vec(), source.clone(), destination.clone(), end_arg(*from_front), end_arg(*to_front)]
+            },
+            // BLPOP, BRPOP and BLMOVE log themselves as the pop or move they ended up doing,
+            // which might happen later, in some other client's command
+            RequestPacket::HSet { key, fields } => {
+                let fields: Vec<_> = fields
+                    .iter()
+                    .flat_map(|(field, value)| [field.clone(), value.clone()])
+                    .collect();
+                command_args("hset", key, &fields)
+            },
+            RequestPacket::HDel { key, fields } => command_args("hdel", key, fields),
+            RequestPacket::HIncrBy { key, field, increment } => {
+                vec![b"hincrby".to_vec(), key.clone(), field.clone(), increment.to_string().into_bytes()]
+            },
+            RequestPacket::SAdd { key, members } => command_args("sadd", key, members),
+            RequestPacket::SRem { key, members } => command_args("srem", key, members),
+            RequestPacket::SInterStore { destination, keys } => command_args("sinterstore", destination, keys),
+            RequestPacket::SUnionStore { destination, keys } => command_args("sunionstore", destination, keys),
+            RequestPacket::SDiffStore { destination, keys } => command_args("sdiffstore", destination, keys),
+            RequestPacket::ZAdd { key, members } => {
+                let members: Vec<_> = members
+                    .iter()
+                    .flat_map(|(score, member)| [score.to_string().into_bytes(), member.clone()])
+                    .collect();
+                command_args("zadd", key, &members)
+            },
+            RequestPacket::ZRem { key, members } => command_args("zrem", key, members),
+            RequestPacket::ZIncrBy { key, increment, member } => {
+                vec![b"zincrby".to_vec(), key.clone(), increment.to_string().into_bytes(), member.clone()]
+            },
+            // XADD, XREADGROUP, XCLAIM and XAUTOCLAIM log themselves, since what they do
+            // depends on the time they ran at
+            RequestPacket::XTrim { key, maxlen } => {
+                vec![b"xtrim".to_vec(), key.clone(), b"maxlen".to_vec(), maxlen.to_string().into_bytes()]
+            },
+            RequestPacket::XSetId { key, id } => vec![b"xsetid".to_vec(), key.clone(), id.to_string().into_bytes()],
+            RequestPacket::XGroupCreate { key, group, id, mkstream } => {
+                let mut args = vec![b"xgroup".to_vec(), b"create".to_vec(), key.clone(), group.clone(), id.to_string().into_bytes()];
+                if *mkstream {
+                    args.push(b"mkstream".to_vec());
+                }
+                args
+            },
+            RequestPacket::XGroupSetId { key, group, id } => {
+                vec![b"xgroup".to_vec(), b"setid".to_vec(), key.clone(), group.clone(), id.to_string().into_bytes()]
+            },
+            RequestPacket::XGroupDestroy { key, group } => {
+                vec![b"xgroup".to_vec(), b"destroy".to_vec(), key.clone(), group.clone()]
+            },
+            RequestPacket::XAck { key, group, ids } => {
+                let mut args = vec![b"xack".to_vec(), key.clone(), group.clone()];
+                args.extend(ids.iter().map(|id| id.to_string().into_bytes()));
+                args
+            },
+            _ => return Vec::new(),
+        };
+
+        vec![args]
+    }
+
+    // The keys a command reads or writes, so the server knows which shards to lock. `None` means
+    // it can touch any key, like KEYS, or a script, which can run commands on keys it wasn't given.
+    pub fn keys(&self) -> Option<Vec<&[u8]>> {
+        let keys = match self {
+            RequestPacket::Set { key, .. }
+            | RequestPacket::SetEx { key, .. }
+            | RequestPacket::Get { key, .. }
+            | RequestPacket::Ttl { key, .. }
+            | RequestPacket::PTtl { key, .. }
+            | RequestPacket::Expire { key, .. }
+            | RequestPacket::ExpireAt { key, .. }
+            | RequestPacket::Persist { key, .. }
+            | RequestPacket::IncrBy { key, .. }
+            | RequestPacket::IncrByFloat { key, .. }
+            | RequestPacket::LPush { key, .. }
+            | RequestPacket::RPush { key, .. }
+            | RequestPacket::LPop { key, .. }
+            | RequestPacket::RPop { key, .. }
+            | RequestPacket::LRange { key, .. }
+            | RequestPacket::LLen { key, .. }
+            | RequestPacket::LIndex { key, .. }
+            | RequestPacket::LTrim { key, .. }
+            | RequestPacket::HSet { key, .. }
+            | RequestPacket::HGet { key, .. }
+            | RequestPacket::HDel { key, .. }
+            | RequestPacket::HExists { key, .. }
+            | RequestPacket::HLen { key, .. }
+            | RequestPacket::HGetAll { key, .. }
+            | RequestPacket::HIncrBy { key, .. }
+            | RequestPacket::SAdd { key, .. }
+            | RequestPacket::SRem { key, .. }
+            | RequestPacket::SMembers { key, .. }
+            | RequestPacket::SIsMember { key, .. }
+            | RequestPacket::SCard { key, .. }
+            | RequestPacket::ZAdd { key, .. }
+            | RequestPacket::ZRem { key, .. }
+            | RequestPacket::ZScore { key, .. }
+            | RequestPacket::ZIncrBy { key, .. }
+            | RequestPacket::ZRank { key, .. }
+            | RequestPacket::ZRange { key, .. }
+            | RequestPacket::ZRevRange { key, .. }
+            | RequestPacket::ZRangeByScore { key, .. }
+            | RequestPacket::ZCard { key, .. }
+            | RequestPacket::XAdd { key, .. }
+            | RequestPacket::XRange { key, .. }
+            | RequestPacket::XRevRange { key, .. }
+            | RequestPacket::XLen { key, .. }
+            | RequestPacket::XTrim { key, .. }
+            | RequestPacket::XSetId { key, .. }
+            | RequestPacket::XGroupCreate { key, .. }
+            | RequestPacket::XGroupSetId { key, .. }
+            | RequestPacket::XGroupDestroy { key, .. }
+            | RequestPacket::XAck { key, .. }
+            | RequestPacket::XPending { key, .. }
+            | RequestPacket::XClaim { key, .. }
+            | RequestPacket::XAutoClaim { key, .. } => vec![&key[..]],
+            RequestPacket::Del { keys }
+            | RequestPacket::Exists { keys }
+            | RequestPacket::SInter { keys }
+            | RequestPacket::SUnion { keys }
+            | RequestPacket::SDiff { keys }
+            | RequestPacket::BLPop { keys, .. }
+            | RequestPacket::BRPop { keys, .. }
+            | RequestPacket::Watch { keys } => keys.iter().map(Vec::as_slice).collect(),
+            RequestPacket::SInterStore { destination, keys }
+            | RequestPacket::SUnionStore { destination, keys }
+            | RequestPacket::SDiffStore { destination, keys } => {
+                std::iter::once(destination).chain(keys).map(Vec::as_slice).collect()
+            },
+            RequestPacket::LMove { source, destination, .. }
+            | RequestPacket::BLMove { source, destination, .. } => vec![&source[..], &destination[..]],
+            RequestPacket::XRead { streams, .. } => streams.iter().map(|(key, _)| &key[..]).collect(),
+            RequestPacket::XReadGroup { streams, .. } => streams.iter().map(|(key, _)| &key[..]).collect(),
+            RequestPacket::Keys { .. }
+            | RequestPacket::Scan { .. }
+            | RequestPacket::Eval { .. }
+            | RequestPacket::EvalSha { .. }
+            | RequestPacket::Info
+            | RequestPacket::Save
+            | RequestPacket::BgSave
+            | RequestPacket::BgRewriteAof => return None,
+            _ => Vec::new(),
+        };
+
+        Some(keys)
+    }
+
+    // Builds a packet from a full argument list, where the first argument is the command
+    pub fn from_arg_list(mut args: Vec<Vec<u8>>) -> Self {
+        if args.is_empty() {
+            return RequestPacket::Unknown;
+        }
+
+        let command = args.remove(0);
+        RequestPacket::from_args(&String::from_utf8_lossy(&command), args)
+    }
+
+    pub fn from_args(command: &str, args: Vec<Vec<u8>>) -> Self {
+        let mut args = Args::new(args);
+
+        let packet = match command.to_lowercase().as_str() {
+            "publish" => Self::parse_publish(&mut args),
+            "subscribe" => Self::parse_subscribe(&mut args),
+            "psubscribe" => Self::parse_psubscribe(&mut args),
+            "unsubscribe" => Ok(RequestPacket::Unsubscribe { channels: args.remaining_strings() }),
+            "punsubscribe" => Ok(RequestPacket::PUnsubscribe { patterns: args.remaining_strings() }),
+            "pubsub" => Self::parse_pubsub(&mut args),
+            "set" => Self::parse_set(&mut args),
+            "setex" => Self::parse_setex(&mut args, 1000),
+            "psetex" => Self::parse_setex(&mut args, 1),
+            "get" => Self::parse_get(&mut args),
+            "ttl" => Self::parse_ttl(&mut args),
+            "pttl" => Self::parse_pttl(&mut args),
+            "expire" => Self::parse_expire(&mut args, 1000),
+            "pexpire" => Self::parse_expire(&mut args, 1),
+            "expireat" => Self::parse_expireat(&mut args, 1000),
+            "pexpireat" => Self::parse_expireat(&mut args, 1),
+            "persist" => Self::parse_persist(&mut args),
+            "ping" => Ok(RequestPacket::Ping { message: args.optional() }),
+            "del" => Self::parse_del(&mut args),
+            "exists" => Self::parse_exists(&mut args),
+            "keys" => Self::parse_keys(&mut args),
+            "scan" => Self::parse_scan(&mut args),
+            "incr" => Self::parse_incr(&mut args, 1),
+            "decr" => Self::parse_incr(&mut args, -1),
+            "incrby" => Self::parse_incrby(&mut args),
+            "decrby" => Self::parse_decrby(&mut args),
+            "incrbyfloat" => Self::parse_incrbyfloat(&mut args),
+            "lpush" => Self::parse_push(&mut args, true),
+            "rpush" => Self::parse_push(&mut args, false),
+            "lpop" => Self::parse_pop(&mut args, true),
+            "rpop" => Self::parse_pop(&mut args, false),
+            "lrange" => Self::parse_lrange(&mut args),
+            "llen" => Self::parse_llen(&mut args),
+            "lindex" => Self::parse_lindex(&mut args),
+            "ltrim" => Self::parse_ltrim(&mut args),
+            "lmove" => Self::parse_lmove(&mut args, false),
+            "blmove" => Self::parse_lmove(&mut args, true),
+            "blpop" => Self::parse_blocking_pop(&mut args, true),
+            "brpop" => Self::parse_blocking_pop(&mut args, false),
+            "hset" => Self::parse_hset(&mut args),
+            "hget" => Self::parse_hget(&mut args),
+            "hdel" => Self::parse_hdel(&mut args),
+            "hexists" => Self::parse_hexists(&mut args),
+            "hlen" => Self::parse_hlen(&mut args),
+            "hgetall" => Self::parse_hgetall(&mut args),
+            "hincrby" => Self::parse_hincrby(&mut args),
+            "sadd" => Self::parse_sadd(&mut args),
+            "srem" => Self::parse_srem(&mut args),
+            "smembers" => Self::parse_smembers(&mut args),
+            "sismember" => Self::parse_sismember(&mut args),
+            "scard" => Self::parse_scard(&mut args),
+            "sinter" => Self::parse_setop(&mut args, SetOp::Inter),
+            "sunion" => Self::parse_setop(&mut args, SetOp::Union),
+            "sdiff" => Self::parse_setop(&mut args, SetOp::Diff),
+            "sinterstore" => Self::parse_setopstore(&mut args, SetOp::Inter),
+            "sunionstore" => Self::parse_setopstore(&mut args, SetOp::Union),
+            "sdiffstore" => Self::parse_setopstore(&mut args, SetOp::Diff),
+            "zadd" => Self::parse_zadd(&mut args),
+            "zrem" => Self::parse_zrem(&mut args),
+            "zscore" => Self::parse_zscore(&mut args),
+            "zincrby" => Self::parse_zincrby(&mut args),
+            "zrank" => Self::parse_zrank(&mut args),
+            "zrange" => Self::parse_zrange(&mut args, false),
+            "zrevrange" => Self::parse_zrange(&mut args, true),
+            "zrangebyscore" => Self::parse_zrangebyscore(&mut args),
+            "zcard" => Self::parse_zcard(&mut args),
+            "xadd" => Self::parse_xadd(&mut args),
+            "xrange" => Self::parse_xrange(&mut args, false),
+            "xrevrange" => Self::parse_xrange(&mut args, true),
+            "xread" => Self::parse_xread(&mut args),
+            "xlen" => Self::parse_xlen(&mut args),
+            "xtrim" => Self::parse_xtrim(&mut args),
+            "xsetid" => Self::parse_xsetid(&mut args),
+            "xgroup" => Self::parse_xgroup(&mut args),
+            "xreadgroup" => Self::parse_xreadgroup(&mut args),
+            "xack" => Self::parse_xack(&mut args),
+            "xpending" => Self::parse_xpending(&mut args),
+            "xclaim" => Self::parse_xclaim(&mut args),
+            "xautoclaim" => Self::parse_xautoclaim(&mut args),
+            "multi" => Ok(RequestPacket::Multi),
+            "exec" => Ok(RequestPacket::Exec),
+            "discard" => Ok(RequestPacket::Discard),
+            "watch" => Self::parse_watch(&mut args),
+            "unwatch" => Ok(RequestPacket::Unwatch),
+            "eval" => Self::parse_eval(&mut args),
+            "evalsha" => Self::parse_evalsha(&mut args),
+            "script" => Self::parse_script(&mut args),
+            "info" => Ok(RequestPacket::Info),
+            "save" => Ok(RequestPacket::Save),
+            "bgsave" => Ok(RequestPacket::BgSave),
+            "bgrewriteaof" => Ok(RequestPacket::BgRewriteAof),
+            _ => Ok(RequestPacket::Unknown),
+        };
+
+        packet.unwrap_or_else(|error| RequestPacket::Invalid { error })
+    }
+
+    fn parse_publish(args: &mut Args) -> Result<Self, String> {
+        let channel = args.next_string("channel")?;
+        let message = args.next("message")?;
+
+        Ok(RequestPacket::Publish { channel, message })
+    }
+
+    fn parse_subscribe(args: &mut Args) -> Result<Self, String> {
+        let channels = args.rest_strings("channel")?;
+
+        Ok(RequestPacket::Subscribe { channels })
+    }
+
+    fn parse_psubscribe(args: &mut Args) -> Result<Self, String> {
+        let patterns = args.rest_strings("pattern")?;
+
+        Ok(RequestPacket::PSubscribe { patterns })
+    }
+
+    fn parse_pubsub(args: &mut Args) -> Result<Self, String> {
+        let subcommand = args.next_string("subcommand")?;
+
+        match subcommand.to_lowercase().as_str() {
+            "channels" => {
+                let pattern = args.optional().map(|pattern| String::from_utf8_lossy(&pattern).to_string());
+                Ok(RequestPacket::PubSubChannels { pattern })
+            },
+            "numsub" => Ok(RequestPacket::PubSubNumSub { channels: args.remaining_strings() }),
+            "numpat" => Ok(RequestPacket::PubSubNumPat),
+            "dropped" => Ok(RequestPacket::PubSubDropped),
+            _ => Err(format!("unknown pubsub subcommand {}", subcommand)),
+        }
+    }
+
+    fn parse_set(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let value = args.next("value")?;
+
+        let mut options = SetOptions::default();
+
+        while let Some(option) = args.optional() {
+            let option = String::from_utf8_lossy(&option).to_lowercase();
+
+            let (condition, expiry) = match option.as_str() {
+                "nx" => (Some(SetCondition::Missing), None),
+                "xx" => (Some(SetCondition::Exists), None),
+                "get" => {
+                    options.get = true;
+                    continue;
+                },
+                "keepttl" => (None, Some(SetExpiry::Keep)),
+                "ex" | "px" => {
+                    let ttl: i64 = args.next_parsed("expire time")?;
+                    if ttl <= 0 {
+                        return Err(String::from("invalid expire time"));
+                    }
+
+                    let ttl = ttl_millis(ttl, if option == "ex" { 1000 } else { 1 })?;
+                    (None, Some(SetExpiry::After(Duration::from_millis(ttl as u64))))
+                },
+                "exat" | "pxat" => {
+                    let at: i64 = args.next_parsed("expire time")?;
+                    let scale = if option == "exat" { 1000 } else { 1 };
+                    let at = at.checked_mul(scale).ok_or("invalid expire time")?;
+
+                    (None, Some(SetExpiry::At(at)))
+                },
+                _ => return Err(String::from("syntax error")),
+            };
+
+            // Only one condition and one kind of expiry can be given
+            if condition.is_some() {
+                if options.condition.is_some() {
+                    return Err(String::from("syntax error"));
+                }
+                options.condition = condition;
+            }
+            if expiry.is_some() {
+                if options.expiry.is_some() {
+                    return Err(String::from("syntax error"));
+                }
+                options.expiry = expiry;
+            }
+        }
+
+        Ok(RequestPacket::Set { key, value, options })
+    }
+
+    // `scale` turns the TTL into milliseconds, 1000 for SETEX or 1 for PSETEX
+    fn parse_setex(args: &mut Args, scale: i64) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let ttl: i64 = args.next_parsed("expire time")?;
+        let value = args.next("value")?;
+
+        if ttl <= 0 {
+            return Err(String::from("invalid expire time"));
+        }
+        let ttl = ttl_millis(ttl, scale)?;
+
+        Ok(RequestPacket::SetEx { key, ttl: Duration::from_millis(ttl as u64), value })
+    }
+
+    fn parse_get(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+
+        Ok(RequestPacket::Get { key })
+    }
+
+    fn parse_ttl(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+
+        Ok(RequestPacket::Ttl { key })
+    }
+
+    fn parse_pttl(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+
+        Ok(RequestPacket::PTtl { key })
+    }
+
+    // `scale` turns the TTL into milliseconds, 1000 for EXPIRE or 1 for PEXPIRE
+    fn parse_expire(args: &mut Args, scale: i64) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let ttl: i64 = args.next_parsed("expire time")?;
+        let ttl = ttl_millis(ttl, scale)?;
+
+        Ok(RequestPacket::Expire { key, ttl })
+    }
+
+    // `scale` turns the time into milliseconds, 1000 for EXPIREAT or 1 for PEXPIREAT
+    fn parse_expireat(args: &mut Args, scale: i64) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let time: i64 = args.next_parsed("timestamp")?;
+        let unix_millis = time.checked_mul(scale).ok_or("invalid timestamp")?;
+
+        Ok(RequestPacket::ExpireAt { key, unix_millis })
+    }
+
+    fn parse_persist(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+
+        Ok(RequestPacket::Persist { key })
+    }
+
+    fn parse_del(args: &mut Args) -> Result<Self, String> {
+        let keys = args.rest("key")?;
+
+        Ok(RequestPacket::Del { keys })
+    }
+
+    fn parse_exists(args: &mut Args) -> Result<Self, String> {
+        let keys = args.rest("key")?;
+
+        Ok(RequestPacket::Exists { keys })
+    }
+
+    fn parse_keys(args: &mut Args) -> Result<Self, String> {
+        let pattern = args.next("pattern")?;
+
+        Ok(RequestPacket::Keys { pattern })
+    }
+
+    fn parse_scan(args: &mut Args) -> Result<Self, String> {
+        let cursor = args.next_parsed("cursor")?;
+
+        let mut pattern = None;
+        let mut count = 10;
+
+        while let Some(option) = args.optional() {
+            if option.eq_ignore_ascii_case(b"match") {
+                pattern = Some(args.next("pattern")?);
+            } else if option.eq_ignore_ascii_case(b"count") {
+                count = args.next_parsed("count")?;
+                if count == 0 {
+                    return Err(String::from("invalid count"));
+                }
+            } else {
+                return Err(String::from("syntax error"));
+            }
+        }
+
+        Ok(RequestPacket::Scan { cursor, pattern, count })
+    }
+
+    fn parse_watch(args: &mut Args) -> Result<Self, String> {
+        let keys = args.rest("key")?;
+
+        Ok(RequestPacket::Watch { keys })
+    }
+
+    fn parse_eval(args: &mut Args) -> Result<Self, String> {
+        let script = args.next("script")?;
+        let keys = Self::parse_script_keys(args)?;
+
+        Ok(RequestPacket::Eval { script, keys, args: args.remaining() })
+    }
+
+    fn parse_evalsha(args: &mut Args) -> Result<Self, String> {
+        let sha = args.next_string("sha1")?;
+        let keys = Self::parse_script_keys(args)?;
+
+        Ok(RequestPacket::EvalSha { sha, keys, args: args.remaining() })
+    }
+
+    // The `numkeys key...` that follows the script in EVAL and EVALSHA, before its other arguments
+    fn parse_script_keys(args: &mut Args) -> Result<Vec<Vec<u8>>, String> {
+        let numkeys = args.next_parsed("numkeys")?;
+
+        let keys = (0..numkeys)
+            .map(|_| args.next("key"))
+            .collect::<Result<_, _>>()?;
+
+        Ok(keys)
+    }
+
+    fn parse_script(args: &mut Args) -> Result<Self, String> {
+        let subcommand = args.next_string("subcommand")?;
+
+        match subcommand.to_lowercase().as_str() {
+            "load" => Ok(RequestPacket::ScriptLoad { script: args.next("script")? }),
+            "exists" => Ok(RequestPacket::ScriptExists { shas: args.rest_strings("sha1")? }),
+            "flush" => Ok(RequestPacket::ScriptFlush),
+            _ => Err(format!("unknown script subcommand {}", subcommand)),
+        }
+    }
+
+    fn parse_incr(args: &mut Args, increment: i64) -> Result<Self, String> {
+        let key = args.next("key")?;
+
+        Ok(RequestPacket::IncrBy { key, increment })
+    }
+
+    fn parse_incrby(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let increment = args.next_parsed("increment")?;
+
+        Ok(RequestPacket::IncrBy { key, increment })
+    }
+
+    fn parse_decrby(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let decrement: i64 = args.next_parsed("decrement")?;
+        let increment = decrement
+            .checked_neg()
+            .ok_or("decrement would overflow")?;
+
+        Ok(RequestPacket::IncrBy { key, increment })
+    }
+
+    fn parse_incrbyfloat(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let increment = args.next_parsed("increment")?;
+
+        Ok(RequestPacket::IncrByFloat { key, increment })
+    }
+
+    fn parse_push(args: &mut Args, front: bool) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let values = args.rest("value")?;
+
+        if front {
+            Ok(RequestPacket::LPush { key, values })
+        } else {
+            Ok(RequestPacket::RPush { key, values })
+        }
+    }
+
+    fn parse_pop(args: &mut Args, front: bool) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let count = args.optional_parsed("count")?;
+
+        if front {
+            Ok(RequestPacket::LPop { key, count })
+        } else {
+            Ok(RequestPacket::RPop { key, count })
+        }
+    }
+
+    fn parse_lrange(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let start = args.next_parsed("start")?;
+        let stop = args.next_parsed("stop")?;
+
+        Ok(RequestPacket::LRange { key, start, stop })
+    }
+
+    fn parse_llen(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+
+        Ok(RequestPacket::LLen { key })
+    }
+
+    fn parse_lindex(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let index = args.next_parsed("index")?;
+
+        Ok(RequestPacket::LIndex { key, index })
+    }
+
+    fn parse_ltrim(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let start = args.next_parsed("start")?;
+        let stop = args.next_parsed("stop")?;
+
+        Ok(RequestPacket::LTrim { key, start, stop })
+    }
+
+    fn parse_lmove(args: &mut Args, blocking: bool) -> Result<Self, String> {
+        let source = args.next("source")?;
+        let destination = args.next("destination")?;
+        let from_front = parse_end(&args.next("wherefrom")?)?;
+        let to_front = parse_end(&args.next("whereto")?)?;
+
+        if !blocking {
+            return Ok(RequestPacket::LMove { source, destination, from_front, to_front });
+        }
+
+        let timeout = parse_timeout(&args.next("timeout")?)?;
+        Ok(RequestPacket::BLMove { source, destination, from_front, to_front, timeout })
+    }
+
+    // The timeout comes after the keys, so it's the last argument
+    fn parse_blocking_pop(args: &mut Args, front: bool) -> Result<Self, String> {
+        let mut keys = args.rest("key")?;
+        let timeout = parse_timeout(&keys.pop().unwrap())?;
+
+        if keys.is_empty() {
+            return Err(String::from("missing key"));
+        }
+
+        if front {
+            Ok(RequestPacket::BLPop { keys, timeout })
+        } else {
+            Ok(RequestPacket::BRPop { keys, timeout })
+        }
+    }
+
+    fn parse_hset(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+
+        let mut fields = vec![(args.next("field")?, args.next("value")?)];
+        while let Some(field) = args.optional() {
+            fields.push((field, args.next("value")?));
+        }
+
+        Ok(RequestPacket::HSet { key, fields })
+    }
+
+    fn parse_hget(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let field = args.next("field")?;
+
+        Ok(RequestPacket::HGet { key, field })
+    }
+
+    fn parse_hdel(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let fields = args.rest("field")?;
+
+        Ok(RequestPacket::HDel { key, fields })
+    }
+
+    fn parse_hexists(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let field = args.next("field")?;
+
+        Ok(RequestPacket::HExists { key, field })
+    }
+
+    fn parse_hlen(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+
+        Ok(RequestPacket::HLen { key })
+    }
+
+    fn parse_hgetall(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+
+        Ok(RequestPacket::HGetAll { key })
+    }
+
+    fn parse_hincrby(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let field = args.next("field")?;
+        let increment = args.next_parsed("increment")?;
+
+        Ok(RequestPacket::HIncrBy { key, field, increment })
+    }
+
+    fn parse_sadd(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let members = args.rest("member")?;
+
+        Ok(RequestPacket::SAdd { key, members })
+    }
+
+    fn parse_srem(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let members = args.rest("member")?;
+
+        Ok(RequestPacket::SRem { key, members })
+    }
+
+    fn parse_smembers(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+
+        Ok(RequestPacket::SMembers { key })
+    }
+
+    fn parse_sismember(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let member = args.next("member")?;
+
+        Ok(RequestPacket::SIsMember { key, member })
+    }
+
+    fn parse_scard(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+
+        Ok(RequestPacket::SCard { key })
+    }
+
+    fn parse_setop(args: &mut Args, op: SetOp) -> Result<Self, String> {
+        let keys = args.rest("key")?;
+
+        match op {
+            SetOp::Inter => Ok(RequestPacket::SInter { keys }),
+            SetOp::Union => Ok(RequestPacket::SUnion { keys }),
+            SetOp::Diff => Ok(RequestPacket::SDiff { keys }),
+        }
+    }
+
+    fn parse_setopstore(args: &mut Args, op: SetOp) -> Result<Self, String> {
+        let destination = args.next("destination")?;
+        let keys = args.rest("key")?;
+
+        match op {
+            SetOp::Inter => Ok(RequestPacket::SInterStore { destination, keys }),
+            SetOp::Union => Ok(RequestPacket::SUnionStore { destination, keys }),
+            SetOp::Diff => Ok(RequestPacket::SDiffStore { destination, keys }),
+        }
+    }
+
+    fn parse_zadd(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
 
+        let mut members = vec![(args.next_score("score")?, args.next("member")?)];
+        while let Some(score) = args.optional() {
+            let score = str::from_utf8(&score)
+                .ok()
+                .and_then(sortedset::parse_score)
+                .ok_or("invalid score")?;
+            members.push((score, args.next("member")?));
+        }
 
-impl RequestPacket {
-    pub fn new(buf: String) -> Self {
-        let lines: Vec<_> = buf.split("\n").collect();
+        Ok(RequestPacket::ZAdd { key, members })
+    }
 
-        let packet_version = if let Some(line1) = lines.get(0) {
-            let re = Regex::new(r"^Rustis (\d{1,3}\.\d{1,4}\.\d{1,4})$").unwrap();
+    fn parse_zrem(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let members = args.rest("member")?;
+
+        Ok(RequestPacket::ZRem { key, members })
+    }
+
+    fn parse_zscore(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let member = args.next("member")?;
+
+        Ok(RequestPacket::ZScore { key, member })
+    }
 
-            if let Some(caps) = re.captures(line1) {
-                Some(caps.get(1).unwrap().as_str())
+    fn parse_zincrby(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let increment = args.next_score("increment")?;
+        let member = args.next("member")?;
+
+        Ok(RequestPacket::ZIncrBy { key, increment, member })
+    }
+
+    fn parse_zrank(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let member = args.next("member")?;
+
+        Ok(RequestPacket::ZRank { key, member })
+    }
+
+    fn parse_zrange(args: &mut Args, rev: bool) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let start = args.next_parsed("start")?;
+        let stop = args.next_parsed("stop")?;
+
+        let withscores = match args.optional() {
+            Some(option) if option.eq_ignore_ascii_case(b"withscores") => true,
+            Some(_) => return Err(String::from("syntax error")),
+            None => false,
+        };
+
+        if rev {
+            Ok(RequestPacket::ZRevRange { key, start, stop, withscores })
+        } else {
+            Ok(RequestPacket::ZRange { key, start, stop, withscores })
+        }
+    }
+
+    fn parse_zrangebyscore(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let min = args.next_parsed("min")?;
+        let max = args.next_parsed("max")?;
+
+        let mut withscores = false;
+        let mut limit = None;
+
+        while let Some(option) = args.optional() {
+            if option.eq_ignore_ascii_case(b"withscores") {
+                withscores = true;
+            } else if option.eq_ignore_ascii_case(b"limit") {
+                let offset = args.next_parsed("offset")?;
+                // A negative count returns everything after the offset
+                let count: i64 = args.next_parsed("count")?;
+                limit = Some((offset, usize::try_from(count).unwrap_or(usize::MAX)));
             } else {
-                None
+                return Err(String::from("syntax error"));
             }
+        }
+
+        Ok(RequestPacket::ZRangeByScore { key, min, max, withscores, limit })
+    }
+
+    fn parse_zcard(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+
+        Ok(RequestPacket::ZCard { key })
+    }
+
+    fn parse_xadd(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+
+        let maxlen = if args.peek().is_some_and(|arg| arg.eq_ignore_ascii_case(b"maxlen")) {
+            args.next("maxlen")?;
+            Some(Self::parse_maxlen(args)?)
         } else {
             None
         };
 
-        let current_version = crate_version!();
+        let id = args.next_parsed("ID")?;
 
-        if let Some(packet_version) = packet_version {
-            if packet_version != current_version {
-                return RequestPacket::Invalid {
-                    error: String::from("version mismatch"),
-                }
-            }
+        let values = args.rest("field")?;
+        if values.len() % 2 != 0 {
+            return Err(String::from("wrong number of arguments for 'xadd' command"));
+        }
+
+        let fields = values
+            .chunks(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect();
+
+        Ok(RequestPacket::XAdd { key, id, fields, maxlen })
+    }
+
+    // The length after MAXLEN. Trimming is always exact, so `~` is taken but does the same as `=`.
+    fn parse_maxlen(args: &mut Args) -> Result<usize, String> {
+        if matches!(args.peek(), Some(b"=" | b"~")) {
+            args.next("maxlen")?;
+        }
+
+        args.next_parsed("maxlen")
+    }
+
+    fn parse_xrange(args: &mut Args, rev: bool) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let first = args.next_string(if rev { "end" } else { "start" })?;
+        let second = args.next_string(if rev { "start" } else { "end" })?;
+
+        let (start, end) = if rev { (second, first) } else { (first, second) };
+        let start = stream::parse_start(&start).ok_or("invalid start ID")?;
+        let end = stream::parse_end(&end).ok_or("invalid end ID")?;
+
+        let count = match args.optional() {
+            Some(option) if option.eq_ignore_ascii_case(b"count") => Some(args.next_parsed("count")?),
+            Some(_) => return Err(String::from("syntax error")),
+            None => None,
+        };
+
+        if rev {
+            Ok(RequestPacket::XRevRange { key, end, start, count })
         } else {
-            return RequestPacket::Invalid {
-                error: String::from("missing headers")
-            };
+            Ok(RequestPacket::XRange { key, start, end, count })
         }
+    }
 
-        let command = lines.get(1);
-
-        if let Some(command) = command {
-            match *command {
-                "publish" => {
-                    let channel = lines.get(2);
-                    let message = lines.get(3..);
-                    if let Some(channel) = channel {
-                        if let Some(message) = message {
-                            let message = message.join("\n");
-                            RequestPacket::Publish{ channel: channel.to_string(), message: message.to_string() }
-                        } else {
-                            RequestPacket::Invalid {
-                                error: String::from("missing message")
-                            }
-                        }
-                    } else {
-                        RequestPacket::Invalid {
-                            error: String::from("missing channel")
-                        }
-                    }
-                },
-                "subscribe" => { 
-                    let channel = lines.get(2);
-                    if let Some(channel) = channel {
-                        RequestPacket::Subscribe { channel: channel.to_string() } 
-                    } else {
-                        RequestPacket::Invalid {
-                            error: String::from("missing channel")
-                        }
-                    }
-                },
-                "set" => {
-                    let key = lines.get(2);
-                    let value = lines.get(3..);
-                    if let Some(key) = key {
-                        if let Some(value) = value {
-                            let value = value.join("\n");
-                            RequestPacket::Set{ key: key.to_string(), value: value.to_string() }
-                        } else {
-                            RequestPacket::Invalid {
-                                error: String::from("missing value")
-                            }
-                        }
-                    } else {
-                        RequestPacket::Invalid {
-                            error: String::from("missing key")
-                        }
-                    }
-                },
-                "setex" => {
-                    let key = lines.get(2);
-                    let ttl = lines.get(3);
-                    let value = lines.get(4..);
-                    if let Some(key) = key {
-                        if let Some(ttl) = ttl {
-                            if let Ok(ttl) = ttl.parse::<u64>() {
-                                if let Some(value) = value {
-                                    let value = value.join("\n");
-                                    RequestPacket::SetEx{ key: key.to_string(), ttl, value: value.to_string() }
-                                } else {
-                                    RequestPacket::Invalid {
-                                        error: String::from("missing value")
-                                    }
-                                }
-                            } else {
-                                RequestPacket::Invalid {
-                                    error: String::from("invalid ttl")
-                                }
-                            }
-                        } else {
-                            RequestPacket::Invalid {
-                                error: String::from("missing ttl")
-                            }
-                        }
-                    } else {
-                        RequestPacket::Invalid {
-                            error: String::from("missing key")
-                        }
-                    }
-                },
-                "get" => {
-                    let key = lines.get(2);
-                    if let Some(key) = key {
-                        RequestPacket::Get{ key: key.to_string() }
-                    } else {
-                        RequestPacket::Invalid {
-                            error: String::from("missing key")
-                        }
-                    }
-                },
-                "ttl" => {
-                    let key = lines.get(2);
-                    if let Some(key) = key {
-                        RequestPacket::Ttl{ key: key.to_string() }
-                    } else {
-                        RequestPacket::Invalid {
-                            error: String::from("missing key")
-                        }
-                    }
-                },
-                _ => RequestPacket::Unknown,
+    fn parse_xread(args: &mut Args) -> Result<Self, String> {
+        let mut count = None;
+        let mut block = None;
+
+        loop {
+            let option = args.next("STREAMS")?;
+
+            if option.eq_ignore_ascii_case(b"count") {
+                count = Some(args.next_parsed("count")?);
+            } else if option.eq_ignore_ascii_case(b"block") {
+                block = Some(Duration::from_millis(args.next_parsed("block")?));
+            } else if option.eq_ignore_ascii_case(b"streams") {
+                break;
+            } else {
+                return Err(String::from("syntax error"));
+            }
+        }
+
+        let streams = Self::parse_streams(args)?;
+
+        Ok(RequestPacket::XRead { streams, count, block })
+    }
+
+    // The keys after STREAMS, followed by where to read each of them from
+    fn parse_streams<T: FromStr>(args: &mut Args) -> Result<Vec<(Vec<u8>, T)>, String> {
+        let mut keys = args.rest("key")?;
+        if keys.len() % 2 != 0 {
+            return Err(String::from("unbalanced list of streams: for each stream key an ID must be specified"));
+        }
+
+        let ids = keys.split_off(keys.len() / 2);
+        keys.into_iter()
+            .zip(ids)
+            .map(|(key, id)| {
+                let id = str::from_utf8(&id).ok().and_then(|id| id.parse().ok());
+                id.map(|id| (key, id)).ok_or_else(|| String::from("invalid ID"))
+            })
+            .collect()
+    }
+
+    fn parse_xlen(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+
+        Ok(RequestPacket::XLen { key })
+    }
+
+    fn parse_xtrim(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+
+        match args.next("MAXLEN")? {
+            strategy if strategy.eq_ignore_ascii_case(b"maxlen") => {
+                let maxlen = Self::parse_maxlen(args)?;
+                Ok(RequestPacket::XTrim { key, maxlen })
+            },
+            _ => Err(String::from("syntax error")),
+        }
+    }
+
+    fn parse_xsetid(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let id = args.next_parsed("ID")?;
+
+        Ok(RequestPacket::XSetId { key, id })
+    }
+
+    fn parse_xgroup(args: &mut Args) -> Result<Self, String> {
+        let subcommand = args.next_string("subcommand")?;
+        let key = args.next("key")?;
+        let group = args.next("group")?;
+
+        match subcommand.to_lowercase().as_str() {
+            "create" => {
+                let id = args.next_parsed("ID")?;
+                let mkstream = match args.optional() {
+                    Some(option) if option.eq_ignore_ascii_case(b"mkstream") => true,
+                    Some(_) => return Err(String::from("syntax error")),
+                    None => false,
+                };
+                Ok(RequestPacket::XGroupCreate { key, group, id, mkstream })
+            },
+            "setid" => {
+                let id = args.next_parsed("ID")?;
+                Ok(RequestPacket::XGroupSetId { key, group, id })
+            },
+            "destroy" => Ok(RequestPacket::XGroupDestroy { key, group }),
+            _ => Err(format!("unknown xgroup subcommand {}", subcommand)),
+        }
+    }
+
+    fn parse_xreadgroup(args: &mut Args) -> Result<Self, String> {
+        if !args.next("GROUP")?.eq_ignore_ascii_case(b"group") {
+            return Err(String::from("syntax error"));
+        }
+
+        let group = args.next("group")?;
+        let consumer = args.next("consumer")?;
+
+        let mut count = None;
+        let mut block = None;
+        let mut noack = false;
+
+        loop {
+            let option = args.next("STREAMS")?;
+
+            if option.eq_ignore_ascii_case(b"count") {
+                count = Some(args.next_parsed("count")?);
+            } else if option.eq_ignore_ascii_case(b"block") {
+                block = Some(Duration::from_millis(args.next_parsed("block")?));
+            } else if option.eq_ignore_ascii_case(b"noack") {
+                noack = true;
+            } else if option.eq_ignore_ascii_case(b"streams") {
+                break;
+            } else {
+                return Err(String::from("syntax error"));
             }
+        }
+
+        let streams = Self::parse_streams(args)?;
+
+        Ok(RequestPacket::XReadGroup { group, consumer, streams, count, block, noack })
+    }
+
+    fn parse_xack(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let group = args.next("group")?;
+        let ids = Self::parse_ids(args.rest("ID")?)?;
+
+        Ok(RequestPacket::XAck { key, group, ids })
+    }
+
+    fn parse_ids(ids: Vec<Vec<u8>>) -> Result<Vec<StreamId>, String> {
+        ids.iter()
+            .map(|id| {
+                str::from_utf8(id)
+                    .ok()
+                    .and_then(|id| id.parse().ok())
+                    .ok_or_else(|| String::from("invalid ID"))
+            })
+            .collect()
+    }
+
+    fn parse_xpending(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let group = args.next("group")?;
+
+        if args.peek().is_none() {
+            return Ok(RequestPacket::XPending { key, group, range: None });
+        }
+
+        let min_idle = if args.peek().is_some_and(|arg| arg.eq_ignore_ascii_case(b"idle")) {
+            args.next("IDLE")?;
+            Some(args.next_parsed("min-idle-time")?)
         } else {
-            RequestPacket::Unknown
+            None
+        };
+
+        let start = stream::parse_start(&args.next_string("start")?).ok_or("invalid start ID")?;
+        let end = stream::parse_end(&args.next_string("end")?).ok_or("invalid end ID")?;
+        let count = args.next_parsed("count")?;
+        let consumer = args.optional();
+
+        let range = PendingRange { min_idle, start, end, count, consumer };
+
+        Ok(RequestPacket::XPending { key, group, range: Some(range) })
+    }
+
+    fn parse_xclaim(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let group = args.next("group")?;
+        let consumer = args.next("consumer")?;
+        let min_idle = args.next_parsed("min-idle-time")?;
+
+        // IDs run up to the first option
+        let mut ids = vec![args.next_parsed("ID")?];
+        while let Some(id) = args.peek().and_then(|id| str::from_utf8(id).ok()?.parse().ok()) {
+            args.next("ID")?;
+            ids.push(id);
+        }
+
+        let mut options = ClaimOptions::default();
+
+        while let Some(option) = args.optional() {
+            if option.eq_ignore_ascii_case(b"idle") {
+                options.idle = Some(args.next_parsed("idle")?);
+            } else if option.eq_ignore_ascii_case(b"time") {
+                options.time = Some(args.next_parsed("time")?);
+            } else if option.eq_ignore_ascii_case(b"retrycount") {
+                options.retry_count = Some(args.next_parsed("retrycount")?);
+            } else if option.eq_ignore_ascii_case(b"force") {
+                options.force = true;
+            } else if option.eq_ignore_ascii_case(b"justid") {
+                options.just_id = true;
+            } else if option.eq_ignore_ascii_case(b"lastid") {
+                options.last_id = Some(args.next_parsed("lastid")?);
+            } else {
+                return Err(String::from("syntax error"));
+            }
+        }
+
+        Ok(RequestPacket::XClaim { key, group, consumer, min_idle, ids, options })
+    }
+
+    fn parse_xautoclaim(args: &mut Args) -> Result<Self, String> {
+        let key = args.next("key")?;
+        let group = args.next("group")?;
+        let consumer = args.next("consumer")?;
+        let min_idle = args.next_parsed("min-idle-time")?;
+        let start = stream::parse_start(&args.next_string("start")?)
+            .and_then(|start| match start {
+                Bound::Included(start) => Some(start),
+                Bound::Excluded(start) => start.next(),
+                Bound::Unbounded => Some(StreamId::MIN),
+            })
+            .ok_or("invalid start ID")?;
+
+        let mut count = 100;
+        let mut just_id = false;
+
+        while let Some(option) = args.optional() {
+            if option.eq_ignore_ascii_case(b"count") {
+                count = args.next_parsed("count")?;
+            } else if option.eq_ignore_ascii_case(b"justid") {
+                just_id = true;
+            } else {
+                return Err(String::from("syntax error"));
+            }
+        }
+
+        if count == 0 {
+            return Err(String::from("COUNT must be > 0"));
         }
+
+        Ok(RequestPacket::XAutoClaim { key, group, consumer, min_idle, start, count, just_id })
     }
 }
 
@@ -198,12 +1946,52 @@ mod test {
 
         let packet = RequestPacket::new(buf);
 
+        assert!(matches!(packet, RequestPacket::Publish { .. }));
+    }
+
+    #[test]
+    fn multiline_value() {
+        let mut buf = String::new();
+        let version = crate_version!();
+        buf.push_str(&format!("Rustis {}\n", version));
+        buf.push_str("setex\n");
+        buf.push_str("key\n");
+        buf.push_str("10\n");
+        buf.push_str("line 1\nline 2");
+
+        let packet = RequestPacket::new(buf);
+
         match packet {
-            RequestPacket::Publish { .. } => assert!(true),
-            _ => assert!(false),
+            RequestPacket::SetEx { key, ttl, value } => {
+                assert_eq!(key, b"key");
+                assert_eq!(ttl, Duration::from_secs(10));
+                assert_eq!(value, b"line 1\nline 2");
+            },
+            _ => panic!("unexpected packet"),
         }
     }
 
+    #[test]
+    fn expire_out_of_range() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+        let invalid = |packet| matches!(packet, RequestPacket::Invalid { error } if error == "invalid expire time");
+
+        assert!(invalid(RequestPacket::from_args("setex", args(&["k", "18446744073709551615", "v"]))));
+        assert!(invalid(RequestPacket::from_args("setex", args(&["k", "9223372036854775807", "v"]))));
+        assert!(invalid(RequestPacket::from_args("psetex", args(&["k", "9223372036854775807", "v"]))));
+        assert!(invalid(RequestPacket::from_args("expire", args(&["k", "9223372036854775807"]))));
+        assert!(invalid(RequestPacket::from_args("pexpire", args(&["k", "9223372036854775807"]))));
+        assert!(invalid(RequestPacket::from_args("set", args(&["k", "v", "EX", "18446744073709551615"]))));
+        assert!(invalid(RequestPacket::from_args("set", args(&["k", "v", "PX", "9223372036854775807"]))));
+
+        // Deleting straight away with a negative TTL is still fine, and is logged as a DEL
+        let expire = RequestPacket::from_args("expire", args(&["k", "-1"]));
+        assert!(matches!(expire, RequestPacket::Expire { ttl: -1000, .. }));
+        assert_eq!(expire.write_commands(), vec![args(&["del", "k"])]);
+        let past = SetOptions { expiry: Some(SetExpiry::At(1)), ..Default::default() };
+        assert_eq!(set_commands(b"k", b"v", &past), vec![args(&["del", "k"])]);
+    }
+
     #[test]
     fn invalid_version() {
         let mut buf = String::new();
@@ -215,10 +2003,7 @@ mod test {
 
         let packet = RequestPacket::new(buf);
 
-        match packet {
-            RequestPacket::Invalid { .. } => assert!(true),
-            _ => assert!(false),
-        }
+        assert!(matches!(packet, RequestPacket::Invalid { .. }));
     }
 
     #[test]
@@ -230,9 +2015,224 @@ mod test {
 
         let packet = RequestPacket::new(buf);
 
-        match packet {
-            RequestPacket::Unknown => assert!(true),
-            _ => assert!(false),
+        assert!(matches!(packet, RequestPacket::Unknown));
+    }
+
+    #[test]
+    fn resp_packet() {
+        let buf = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nva\r\nl\r\n";
+
+        match Frame::read(buf) {
+            Frame::Complete(RequestPacket::Set { key, value, .. }, Protocol::Resp, used) => {
+                assert_eq!(key, b"key");
+                assert_eq!(value, b"va\r\nl");
+                assert_eq!(used, buf.len());
+            },
+            _ => panic!("unexpected packet"),
+        }
+    }
+
+    #[test]
+    fn resp_incomplete() {
+        let buf = b"*2\r\n$3\r\nGET\r\n$3\r\nke";
+
+        assert!(matches!(Frame::read(buf), Frame::Incomplete));
+    }
+
+    #[test]
+    fn resp_malformed() {
+        let buf = b"*1\r\n:3\r\n";
+
+        assert!(matches!(Frame::read(buf), Frame::Malformed(_)));
+    }
+
+    #[test]
+    fn resp_oversized() {
+        assert!(matches!(Frame::read(b"*99999999999999999\r\n"), Frame::Malformed(_)));
+        assert!(matches!(Frame::read(b"*2\r\n$18446744073709551614\r\nab\r\n"), Frame::Malformed(_)));
+        assert!(matches!(Frame::read(b"*1\r\n$536870913\r\n"), Frame::Malformed(_)));
+    }
+
+    #[test]
+    fn binary_packet() {
+        let mut buf = Vec::new();
+        let version = crate_version!();
+        buf.extend_from_slice(format!("Rustis/2 {}\n", version).as_bytes());
+        buf.extend_from_slice(b"3\n");
+        buf.extend_from_slice(b"3\nset\n");
+        buf.extend_from_slice(b"3\nkey\n");
+        buf.extend_from_slice(b"5\n\n\n\xff\x00\n\n");
+
+        match Frame::read(&buf) {
+            Frame::Complete(RequestPacket::Set { key, value, .. }, Protocol::Binary, used) => {
+                assert_eq!(key, b"key");
+                assert_eq!(value, b"\n\n\xff\x00\n");
+                assert_eq!(used, buf.len());
+            },
+            _ => panic!("unexpected packet"),
+        }
+    }
+
+    #[test]
+    fn binary_incomplete() {
+        let mut buf = Vec::new();
+        let version = crate_version!();
+        buf.extend_from_slice(format!("Rustis/2 {}\n", version).as_bytes());
+        buf.extend_from_slice(b"2\n");
+        buf.extend_from_slice(b"3\nget\n");
+        buf.extend_from_slice(b"3\nke");
+
+        assert!(matches!(Frame::read(&buf), Frame::Incomplete));
+    }
+
+    #[test]
+    fn binary_oversized() {
+        let header = format!("Rustis/2 {}\n", crate_version!()).into_bytes();
+
+        let too_many = [&header[..], b"99999999999999999\n"].concat();
+        assert!(matches!(Frame::read(&too_many), Frame::Malformed(_)));
+
+        let too_long = [&header[..], b"2\n18446744073709551614\nab\n"].concat();
+        assert!(matches!(Frame::read(&too_long), Frame::Malformed(_)));
+    }
+
+    #[test]
+    fn set_options() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+
+        match RequestPacket::from_args("set", args(&["lock", "token", "NX", "px", "3000", "get"])) {
+            RequestPacket::Set { options, .. } => {
+                assert_eq!(options.condition, Some(SetCondition::Missing));
+                assert_eq!(options.expiry, Some(SetExpiry::After(Duration::from_millis(3000))));
+                assert!(options.get);
+
+                // The condition was settled when it ran, so it isn't logged
+                let logged = &set_commands(b"lock", b"token", &options)[0];
+                assert_eq!(logged[..4], args(&["set", "lock", "token", "pxat"])[..]);
+                assert_eq!(logged.len(), 5);
+            },
+            _ => panic!("unexpected packet"),
+        }
+
+        let conflicting = RequestPacket::from_args("set", args(&["lock", "token", "nx", "xx"]));
+        assert!(matches!(conflicting, RequestPacket::Invalid { .. }));
+
+        let conflicting = RequestPacket::from_args("set", args(&["lock", "token", "keepttl", "ex", "10"]));
+        assert!(matches!(conflicting, RequestPacket::Invalid { .. }));
+    }
+
+    #[test]
+    fn eval_args() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+
+        match RequestPacket::from_args("eval", args(&["return 1", "2", "a", "b", "c"])) {
+            RequestPacket::Eval { keys, args, .. } => {
+                assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec()]);
+                assert_eq!(args, vec![b"c".to_vec()]);
+            },
+            _ => panic!("unexpected packet"),
+        }
+
+        let missing_keys = RequestPacket::from_args("eval", args(&["return 1", "3", "a"]));
+        assert!(matches!(missing_keys, RequestPacket::Invalid { .. }));
+    }
+
+    #[test]
+    fn blocking_pop_args() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+
+        match RequestPacket::from_args("blpop", args(&["a", "b", "0.5"])) {
+            RequestPacket::BLPop { keys, timeout } => {
+                assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec()]);
+                assert_eq!(timeout, Duration::from_millis(500));
+            },
+            _ => panic!("unexpected packet"),
+        }
+
+        match RequestPacket::from_args("blmove", args(&["src", "dst", "RIGHT", "left", "0"])) {
+            RequestPacket::BLMove { from_front, to_front, timeout, .. } => {
+                assert!(!from_front && to_front);
+                assert_eq!(timeout, Duration::ZERO);
+            },
+            _ => panic!("unexpected packet"),
+        }
+
+        for (command, arguments) in [("brpop", &["a", "-1"][..]), ("brpop", &["5"]), ("lmove", &["a", "b", "up", "left"])] {
+            assert!(matches!(RequestPacket::from_args(command, args(arguments)), RequestPacket::Invalid { .. }));
+        }
+    }
+
+    #[test]
+    fn stream_args() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+
+        match RequestPacket::from_args("xadd", args(&["events", "MAXLEN", "~", "100", "*", "type", "click"])) {
+            RequestPacket::XAdd { id, fields, maxlen, .. } => {
+                assert_eq!(id, NewId::Auto);
+                assert_eq!(fields, vec![(b"type".to_vec(), b"click".to_vec())]);
+                assert_eq!(maxlen, Some(100));
+            },
+            _ => panic!("unexpected packet"),
+        }
+
+        let missing_value = RequestPacket::from_args("xadd", args(&["events", "*", "type"]));
+        assert!(matches!(missing_value, RequestPacket::Invalid { .. }));
+
+        match RequestPacket::from_args("xread", args(&["count", "2", "BLOCK", "0", "streams", "a", "b", "5-1", "$"])) {
+            RequestPacket::XRead { streams, count, block } => {
+                assert_eq!(streams, vec![(b"a".to_vec(), ReadFrom::After(StreamId::new(5, 1))), (b"b".to_vec(), ReadFrom::New)]);
+                assert_eq!(count, Some(2));
+                assert_eq!(block, Some(Duration::ZERO));
+            },
+            _ => panic!("unexpected packet"),
+        }
+
+        let unbalanced = RequestPacket::from_args("xread", args(&["streams", "a", "b", "0"]));
+        assert!(matches!(unbalanced, RequestPacket::Invalid { .. }));
+    }
+
+    #[test]
+    fn command_keys() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+
+        let keys = |packet: RequestPacket| packet.keys().map(|keys| keys.iter().map(|key| key.to_vec()).collect::<Vec<_>>());
+
+        assert_eq!(keys(RequestPacket::from_args("get", args(&["a"]))), Some(vec![b"a".to_vec()]));
+        assert_eq!(keys(RequestPacket::from_args("sunionstore", args(&["dst", "a", "b"]))), Some(vec![b"dst".to_vec(), b"a".to_vec(), b"b".to_vec()]));
+        assert_eq!(keys(RequestPacket::from_args("xread", args(&["streams", "a", "b", "0", "$"]))), Some(vec![b"a".to_vec(), b"b".to_vec()]));
+        assert_eq!(keys(RequestPacket::from_args("publish", args(&["channel", "message"]))), Some(Vec::new()));
+        assert_eq!(keys(RequestPacket::from_args("keys", args(&["*"]))), None);
+        assert_eq!(keys(RequestPacket::from_args("eval", args(&["return 1", "0"]))), None);
+    }
+
+    #[test]
+    fn consumer_group_args() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+
+        match RequestPacket::from_args("xreadgroup", args(&["GROUP", "g", "alice", "NOACK", "streams", "a", "b", ">", "0"])) {
+            RequestPacket::XReadGroup { group, consumer, streams, noack, block, .. } => {
+                assert_eq!((group, consumer), (b"g".to_vec(), b"alice".to_vec()));
+                assert_eq!(streams, vec![(b"a".to_vec(), GroupReadFrom::Undelivered), (b"b".to_vec(), GroupReadFrom::Pending(StreamId::MIN))]);
+                assert!(noack);
+                assert_eq!(block, None);
+            },
+            _ => panic!("unexpected packet"),
         }
+
+        match RequestPacket::from_args("xclaim", args(&["s", "g", "bob", "1000", "1-0", "2-0", "RETRYCOUNT", "3", "JUSTID"])) {
+            RequestPacket::XClaim { min_idle, ids, options, .. } => {
+                assert_eq!(min_idle, 1000);
+                assert_eq!(ids, vec![StreamId::new(1, 0), StreamId::new(2, 0)]);
+                assert_eq!(options.retry_count, Some(3));
+                assert!(options.just_id && !options.force);
+            },
+            _ => panic!("unexpected packet"),
+        }
+
+        let no_ids = RequestPacket::from_args("xclaim", args(&["s", "g", "bob", "1000", "FORCE"]));
+        assert!(matches!(no_ids, RequestPacket::Invalid { .. }));
+
+        let zero_count = RequestPacket::from_args("xautoclaim", args(&["s", "g", "bob", "0", "0-0", "COUNT", "0"]));
+        assert!(matches!(zero_count, RequestPacket::Invalid { .. }));
     }
 }
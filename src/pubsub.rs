@@ -1,70 +1,370 @@
-use crate::threadpool::ThreadPool;
-use std::collections::HashMap;
-use std::sync::{mpsc, Arc, Mutex};
+use crate::glob;
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
 
+// Subscribers to exact channel names, and to glob patterns that are matched against the
+// channel of every published message
 pub struct PubSub {
-    subscribers: Arc<Mutex<HashMap<String, Vec<Subscriber>>>>,
-    next_sub_id: Mutex<usize>,
-    workers: ThreadPool,
+    channels: HashMap<String, Vec<Subscriber>>,
+    patterns: HashMap<String, Vec<Subscriber>>,
+    next_sub_id: usize,
+    // How many messages each subscriber can have waiting, and what happens past that
+    queue_limit: usize,
+    overflow: OverflowPolicy,
+    // Messages that were published to each channel but never reached a subscriber
+    dropped: HashMap<String, u64>,
+}
+
+// What happens to a subscriber that has `queue_limit` messages waiting when another arrives
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    // Make room by dropping the message that has waited longest
+    DropOldest,
+    // Drop the message that just arrived
+    DropNewest,
+    // Drop everything waiting and disconnect the client
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(format!("unknown overflow policy '{}', expected drop-oldest, drop-newest or disconnect", s)),
+        }
+    }
+}
+
+// A published message as a subscriber receives it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub channel: String,
+    // The pattern the subscription was for, if it was made with PSUBSCRIBE
+    pub pattern: Option<String>,
+    pub payload: Vec<u8>,
+}
+
+impl Default for PubSub {
+    fn default() -> Self {
+        Self::new(1024, OverflowPolicy::Disconnect)
+    }
 }
 
 impl PubSub {
-    pub fn new() -> Self {
-        let subscribers = Arc::new(Mutex::new(HashMap::new()));
-        let next_sub_id = Mutex::new(0);
-        let workers = ThreadPool::new(8);
+    pub fn new(queue_limit: usize, overflow: OverflowPolicy) -> Self {
+        PubSub {
+            channels: HashMap::new(),
+            patterns: HashMap::new(),
+            next_sub_id: 0,
+            queue_limit,
+            overflow,
+            dropped: HashMap::new(),
+        }
+    }
+
+    // Sends a message to everyone subscribed to the channel or a pattern matching it, returning
+    // how many subscriptions it reached. Sending never blocks: a subscriber that has fallen too
+    // far behind has messages dropped, or is disconnected, depending on the overflow policy.
+    // Subscribers whose receiver has gone away are removed along the way.
+    pub fn publish(&mut self, channel: String, message: Vec<u8>) -> usize {
+        let mut receivers = 0;
+        let dropped = &mut self.dropped;
+
+        if let Some(list) = self.channels.get_mut(&channel) {
+            list.retain(|subscriber| {
+                let message = Message { channel: channel.clone(), pattern: None, payload: message.clone() };
+                deliver(subscriber, message, &mut receivers, dropped)
+            });
+
+            if list.is_empty() {
+                self.channels.remove(&channel);
+            }
+        }
 
-        PubSub { subscribers, next_sub_id, workers }
+        self.patterns.retain(|pattern, list| {
+            if glob::matches(pattern.as_bytes(), channel.as_bytes()) {
+                list.retain(|subscriber| {
+                    let message = Message { channel: channel.clone(), pattern: Some(pattern.clone()), payload: message.clone() };
+                    deliver(subscriber, message, &mut receivers, dropped)
+                });
+            }
+
+            !list.is_empty()
+        });
+
+        receivers
     }
 
-    pub fn publish(&mut self, channel: String, message: String) {
-        let subscribers = self.subscribers.clone();
+    // How many messages published to each channel never reached a subscriber, for channels
+    // that have dropped any
+    pub fn dropped(&self) -> &HashMap<String, u64> {
+        &self.dropped
+    }
 
-        self.workers.execute(move || {
-            let subscribers = subscribers.lock().unwrap();
+    // Channels with at least one subscriber, optionally only those matching a glob pattern
+    pub fn channels(&self, pattern: Option<&str>) -> Vec<String> {
+        self.channels
+            .keys()
+            .filter(|channel| pattern.is_none_or(|pattern| glob::matches(pattern.as_bytes(), channel.as_bytes())))
+            .cloned()
+            .collect()
+    }
 
-            match subscribers.get(&channel) {
-                Some(subscribers) => {
-                    for subscriber in subscribers {
-                        let _result = subscriber.send(message.clone());
-                    }
-                },
-                None => (),
-            };
+    // How many subscribers a channel has, not counting pattern subscriptions
+    pub fn numsub(&self, channel: &str) -> usize {
+        self.channels.get(channel).map_or(0, Vec::len)
+    }
+
+    // How many patterns have at least one subscriber
+    pub fn numpat(&self) -> usize {
+        self.patterns.len()
+    }
+
+    // Creates a subscriber that can be subscribed to any number of channels and patterns,
+    // along with the receiver all of their messages arrive on
+    pub fn subscriber(&mut self) -> (Subscriber, Receiver) {
+        let queue = Arc::new(Queue {
+            state: Mutex::new(QueueState::default()),
+            ready: Condvar::new(),
+            limit: self.queue_limit,
+            overflow: self.overflow,
         });
+
+        let subscriber = Subscriber {
+            id: self.next_sub_id,
+            sender: Arc::new(Sender { queue: Arc::clone(&queue) }),
+        };
+        self.next_sub_id += 1;
+
+        (subscriber, Receiver { queue })
     }
 
-    pub fn subscribe(&mut self, channel: String) -> Result<mpsc::Receiver<String>, ()> {
-        let (sender, receiver) = mpsc::channel();
+    // Returns false if the subscriber was already subscribed to the channel
+    pub fn subscribe(&mut self, subscriber: &Subscriber, channel: String) -> bool {
+        add(self.channels.entry(channel).or_default(), subscriber)
+    }
 
-        let mut next_sub_id = self.next_sub_id.lock().unwrap();
-        let subscriber = Subscriber::new(*next_sub_id, sender);
-        *next_sub_id += 1;
+    // Subscribes to every channel matching a glob pattern, like `orders.*`
+    pub fn psubscribe(&mut self, subscriber: &Subscriber, pattern: String) -> bool {
+        add(self.patterns.entry(pattern).or_default(), subscriber)
+    }
+
+    // Returns false if the subscriber wasn't subscribed to the channel
+    pub fn unsubscribe(&mut self, id: usize, channel: &str) -> bool {
+        remove(&mut self.channels, id, channel)
+    }
+
+    pub fn punsubscribe(&mut self, id: usize, pattern: &str) -> bool {
+        remove(&mut self.patterns, id, pattern)
+    }
+}
+
+fn add(list: &mut Vec<Subscriber>, subscriber: &Subscriber) -> bool {
+    if list.iter().any(|other| other.id == subscriber.id) {
+        return false;
+    }
+
+    list.push(subscriber.clone());
+    true
+}
+
+fn remove(map: &mut HashMap<String, Vec<Subscriber>>, id: usize, name: &str) -> bool {
+    let Some(list) = map.get_mut(name) else {
+        return false;
+    };
+
+    let len = list.len();
+    list.retain(|subscriber| subscriber.id != id);
+    let removed = list.len() < len;
+
+    if list.is_empty() {
+        map.remove(name);
+    }
 
-        let mut subscribers = self.subscribers.lock().unwrap();
+    removed
+}
+
+// Counts a message as received or dropped, returning false if the subscriber is gone
+fn deliver(subscriber: &Subscriber, message: Message, receivers: &mut usize, dropped: &mut HashMap<String, u64>) -> bool {
+    let (lost, still_subscribed) = match subscriber.send(message) {
+        Delivery::Delivered => {
+            *receivers += 1;
+            return true;
+        },
+        Delivery::DeliveredDropping(oldest) => {
+            *receivers += 1;
+            (vec![oldest], true)
+        },
+        Delivery::Dropped(message) => (vec![message], true),
+        Delivery::Disconnected(messages) => (messages, false),
+        Delivery::Closed => return false,
+    };
+
+    for message in lost {
+        *dropped.entry(message.channel).or_default() += 1;
+    }
+
+    still_subscribed
+}
+
+// What happened to a message sent to a subscriber
+enum Delivery {
+    Delivered,
+    // Delivered, making room by dropping the oldest waiting message
+    DeliveredDropping(Message),
+    // Dropped because the subscriber's queue was full
+    Dropped(Message),
+    // The queue was full so the subscriber was disconnected, losing these messages
+    Disconnected(Vec<Message>),
+    // The subscriber's receiver has gone away
+    Closed,
+}
 
-        let subscriber_list = subscribers.entry(channel).or_insert(Vec::new());
+// The messages waiting for a subscriber, shared between its sender and receiver
+struct Queue {
+    state: Mutex<QueueState>,
+    // Signalled when a message arrives or the queue closes
+    ready: Condvar,
+    limit: usize,
+    overflow: OverflowPolicy,
+}
 
-        subscriber_list.push(subscriber);
+#[derive(Default)]
+struct QueueState {
+    messages: VecDeque<Message>,
+    // Whether the subscriber has been dropped everywhere, so nothing more will arrive
+    senders_gone: bool,
+    receiver_gone: bool,
+    // Whether the subscriber was disconnected for falling behind
+    fell_behind: bool,
+    // Called whenever a message arrives or the subscriber falls behind, for receivers that
+    // don't wait in `recv`
+    on_ready: Option<Arc<dyn Fn() + Send + Sync>>,
+}
 
-        Ok(receiver)
+impl QueueState {
+    fn closed(&self) -> bool {
+        self.receiver_gone || self.fell_behind
     }
 }
 
-struct Subscriber {
-    #[allow(dead_code)]
+// A handle to a subscriber, which may be subscribed to many channels and patterns. All its
+// messages arrive on the one `Receiver` it was created with.
+#[derive(Clone)]
+pub struct Subscriber {
     id: usize,
-    sender: mpsc::Sender<String>,
+    sender: Arc<Sender>,
 }
 
 impl Subscriber {
-    pub fn new(id: usize, sender: mpsc::Sender<String>) -> Self {
-        Subscriber { id, sender }
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    fn send(&self, message: Message) -> Delivery {
+        let queue = &self.sender.queue;
+        let mut state = queue.state.lock().unwrap();
+
+        if state.closed() {
+            return Delivery::Closed;
+        }
+
+        let delivery = if state.messages.len() < queue.limit {
+            state.messages.push_back(message);
+            Delivery::Delivered
+        } else {
+            match queue.overflow {
+                OverflowPolicy::DropOldest => {
+                    state.messages.push_back(message);
+                    match state.messages.pop_front() {
+                        Some(oldest) => Delivery::DeliveredDropping(oldest),
+                        None => Delivery::Delivered,
+                    }
+                },
+                OverflowPolicy::DropNewest => Delivery::Dropped(message),
+                OverflowPolicy::Disconnect => {
+                    state.fell_behind = true;
+                    let mut lost: Vec<_> = state.messages.drain(..).collect();
+                    lost.push(message);
+                    Delivery::Disconnected(lost)
+                },
+            }
+        };
+
+        let on_ready = state.on_ready.clone();
+        drop(state);
+
+        queue.ready.notify_one();
+        if let Some(on_ready) = on_ready {
+            on_ready();
+        }
+
+        delivery
     }
+}
 
-    pub fn send(&self, message: String) -> Result<(), ()> {
-        let _result = self.sender.send(message);
-        Ok(())
+// Shared by every clone of a subscriber, and closes the queue once the last one is dropped
+struct Sender {
+    queue: Arc<Queue>,
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().senders_gone = true;
+        self.queue.ready.notify_one();
+    }
+}
+
+pub struct Receiver {
+    queue: Arc<Queue>,
+}
+
+impl Receiver {
+    // Waits for the next message, or returns `None` once the subscriber has been dropped
+    // everywhere or disconnected for falling behind
+    pub fn recv(&self) -> Option<Message> {
+        let mut state = self.queue.state.lock().unwrap();
+
+        loop {
+            if state.fell_behind {
+                return None;
+            }
+            if let Some(message) = state.messages.pop_front() {
+                return Some(message);
+            }
+            if state.senders_gone {
+                return None;
+            }
+
+            state = self.queue.ready.wait(state).unwrap();
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<Message> {
+        self.queue.state.lock().unwrap().messages.pop_front()
+    }
+
+    // Whether the subscriber was disconnected because its queue filled up
+    pub fn fell_behind(&self) -> bool {
+        self.queue.state.lock().unwrap().fell_behind
+    }
+
+    // Sets what to do when a message arrives or the subscriber is disconnected for falling
+    // behind, which runs on the publisher's thread. Lets a receiver be checked with `try_recv`
+    // only when there's something to find, instead of parking a thread in `recv`.
+    pub fn on_ready(&self, f: impl Fn() + Send + Sync + 'static) {
+        self.queue.state.lock().unwrap().on_ready = Some(Arc::new(f));
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().receiver_gone = true;
     }
 }
 
@@ -74,14 +374,116 @@ mod test {
 
     #[test]
     fn test() {
-        let mut ps = PubSub::new();
+        let mut ps = PubSub::default();
 
-        let receiver = ps.subscribe(String::from("test")).unwrap();
+        let (subscriber, receiver) = ps.subscriber();
+        ps.subscribe(&subscriber, String::from("test"));
 
-        let _ = ps.publish(String::from("test"), String::from("Hello world!"));
+        ps.publish(String::from("test"), b"Hello world!".to_vec());
 
         let message = receiver.recv().unwrap();
 
-        assert_eq!(&message, "Hello world!");
+        assert_eq!(message.payload, b"Hello world!");
+        assert_eq!(message.pattern, None);
+    }
+
+    #[test]
+    fn patterns() {
+        let mut ps = PubSub::default();
+
+        let (orders_subscriber, orders) = ps.subscriber();
+        ps.psubscribe(&orders_subscriber, String::from("orders.*"));
+        let (everything_subscriber, everything) = ps.subscriber();
+        ps.psubscribe(&everything_subscriber, String::from("*"));
+
+        assert_eq!(ps.publish(String::from("users.new"), b"alice".to_vec()), 1);
+        assert_eq!(ps.publish(String::from("orders.new"), b"42".to_vec()), 2);
+
+        let message = orders.recv().unwrap();
+        assert_eq!(message.channel, "orders.new");
+        assert_eq!(message.pattern.as_deref(), Some("orders.*"));
+        assert_eq!(message.payload, b"42");
+
+        let channels: Vec<_> = (0..2).map(|_| everything.recv().unwrap().channel).collect();
+        assert!(channels.contains(&String::from("users.new")));
+        assert!(channels.contains(&String::from("orders.new")));
+    }
+
+    #[test]
+    fn unsubscribe() {
+        let mut ps = PubSub::default();
+
+        let (subscriber, receiver) = ps.subscriber();
+        assert!(ps.subscribe(&subscriber, String::from("a")));
+        assert!(!ps.subscribe(&subscriber, String::from("a")));
+        assert!(ps.subscribe(&subscriber, String::from("b")));
+
+        assert!(ps.unsubscribe(subscriber.id(), "a"));
+        assert!(!ps.unsubscribe(subscriber.id(), "a"));
+
+        assert_eq!(ps.publish(String::from("a"), b"dropped".to_vec()), 0);
+        assert_eq!(ps.publish(String::from("b"), b"kept".to_vec()), 1);
+
+        assert_eq!(receiver.recv().unwrap().payload, b"kept");
+        assert!(receiver.try_recv().is_none());
+    }
+
+    #[test]
+    fn dead_subscribers() {
+        let mut ps = PubSub::default();
+
+        let (subscriber, receiver) = ps.subscriber();
+        ps.subscribe(&subscriber, String::from("a"));
+        ps.psubscribe(&subscriber, String::from("*"));
+        drop(receiver);
+
+        assert_eq!(ps.publish(String::from("a"), b"nobody".to_vec()), 0);
+
+        assert!(ps.channels.is_empty());
+        assert!(ps.patterns.is_empty());
+    }
+
+    #[test]
+    fn introspection() {
+        let mut ps = PubSub::default();
+
+        let (first, _first_receiver) = ps.subscriber();
+        let (second, _second_receiver) = ps.subscriber();
+        ps.subscribe(&first, String::from("orders.new"));
+        ps.subscribe(&second, String::from("orders.new"));
+        ps.subscribe(&second, String::from("users.new"));
+        ps.psubscribe(&second, String::from("orders.*"));
+
+        let mut channels = ps.channels(None);
+        channels.sort();
+        assert_eq!(channels, vec![String::from("orders.new"), String::from("users.new")]);
+        assert_eq!(ps.channels(Some("users.*")), vec![String::from("users.new")]);
+
+        assert_eq!(ps.numsub("orders.new"), 2);
+        assert_eq!(ps.numsub("nobody"), 0);
+        assert_eq!(ps.numpat(), 1);
+    }
+
+    #[test]
+    fn overflow() {
+        let publish_three = |overflow| {
+            let mut ps = PubSub::new(2, overflow);
+            let (subscriber, receiver) = ps.subscriber();
+            ps.subscribe(&subscriber, String::from("a"));
+
+            let receivers: Vec<_> = [b"1", b"2", b"3"]
+                .iter()
+                .map(|message| ps.publish(String::from("a"), message.to_vec()))
+                .collect();
+            let received: Vec<_> = std::iter::from_fn(|| receiver.try_recv())
+                .map(|message| message.payload)
+                .collect();
+
+            (receivers, received, ps.dropped().get("a").copied(), receiver.fell_behind())
+        };
+
+        assert_eq!(publish_three(OverflowPolicy::DropOldest), (vec![1, 1, 1], vec![b"2".to_vec(), b"3".to_vec()], Some(1), false));
+        assert_eq!(publish_three(OverflowPolicy::DropNewest), (vec![1, 1, 0], vec![b"1".to_vec(), b"2".to_vec()], Some(1), false));
+        assert_eq!(publish_three(OverflowPolicy::Disconnect), (vec![1, 1, 0], vec![], Some(3), true));
     }
 }
@@ -0,0 +1,252 @@
+use crate::kvstore::KvError;
+use crate::packetreader::Protocol;
+
+// Represents a reply sent back to the client
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    // Acknowledges a command, written as the given word in the text format and as OK in RESP
+    Ack(&'static str),
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    pub fn encode(&self, protocol: Protocol) -> Vec<u8> {
+        match protocol {
+            Protocol::Text => {
+                let mut buf = self.to_text(false);
+                buf.extend_from_slice(b"\n\n");
+                buf
+            },
+            Protocol::Binary => {
+                let text = self.to_binary();
+                let mut buf = format!("{}\n", text.len()).into_bytes();
+                buf.extend_from_slice(&text);
+                buf
+            },
+            Protocol::Resp => {
+                let mut buf = Vec::new();
+                self.write_resp(&mut buf);
+                buf
+            },
+        }
+    }
+
+    /* Text format:
+
+    A found value is written as `1\n<VALUE>` and a missing one as `0`, everything else is
+    written as-is. Array elements are written one per line without the found marker.
+
+    */
+    fn to_text(&self, nested: bool) -> Vec<u8> {
+        match self {
+            Reply::Ack(word) => word.as_bytes().to_vec(),
+            Reply::Status(status) => status.as_bytes().to_vec(),
+            Reply::Error(error) => error.as_bytes().to_vec(),
+            Reply::Integer(n) => n.to_string().into_bytes(),
+            Reply::Bulk(val) if nested => val.clone(),
+            Reply::Bulk(val) => [b"1\n", &val[..]].concat(),
+            Reply::Nil if nested => Vec::new(),
+            Reply::Nil => b"0".to_vec(),
+            Reply::Array(items) => items
+                .iter()
+                .map(|item| item.to_text(true))
+                .collect::<Vec<_>>()
+                .join(&b'\n'),
+        }
+    }
+
+    // What the client prints for a reply, which is the text format without its blank line
+    pub fn to_display(&self) -> Vec<u8> {
+        self.to_text(false)
+    }
+
+    /* Binary format:
+
+    Everything but arrays is sent as in the text format. Arrays are sent as `*<COUNT>\n`
+    followed by their elements, each of which can hold any bytes:
+
+    <LEN>\n<VALUE>\n     a value
+    -\n                  nil
+    !<LEN>\n<ERROR>\n    an error
+    *<COUNT>\n...        a nested array
+
+    The whole reply is preceded by a line holding its length in bytes.
+
+    */
+    fn to_binary(&self) -> Vec<u8> {
+        match self {
+            Reply::Array(items) => {
+                let mut buf = format!("*{}\n", items.len()).into_bytes();
+                for item in items {
+                    item.write_binary_element(&mut buf);
+                }
+                buf
+            },
+            reply => reply.to_text(false),
+        }
+    }
+
+    fn write_binary_element(&self, buf: &mut Vec<u8>) {
+        let (marker, value) = match self {
+            Reply::Nil => return buf.extend_from_slice(b"-\n"),
+            Reply::Array(_) => return buf.extend_from_slice(&self.to_binary()),
+            Reply::Error(error) => ("!", error.as_bytes().to_vec()),
+            reply => ("", reply.to_text(true)),
+        };
+
+        buf.extend_from_slice(format!("{}{}\n", marker, value.len()).as_bytes());
+        buf.extend_from_slice(&value);
+        buf.push(b'\n');
+    }
+
+    // Reads an array sent in the binary format back into a reply, giving `None` for anything
+    // that isn't one
+    pub fn decode_binary(data: &[u8]) -> Option<Reply> {
+        match read_binary_element(data)? {
+            (reply @ Reply::Array(_), used) if used == data.len() => Some(reply),
+            _ => None,
+        }
+    }
+
+    fn write_resp(&self, buf: &mut Vec<u8>) {
+        match self {
+            Reply::Ack(_) => buf.extend_from_slice(b"+OK\r\n"),
+            Reply::Status(status) => buf.extend_from_slice(format!("+{}\r\n", status).as_bytes()),
+            Reply::Error(error) if has_error_code(error) => {
+                buf.extend_from_slice(format!("-{}\r\n", error).as_bytes())
+            },
+            Reply::Error(error) => buf.extend_from_slice(format!("-ERR {}\r\n", error).as_bytes()),
+            Reply::Integer(n) => buf.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(val) => {
+                buf.extend_from_slice(format!("${}\r\n", val.len()).as_bytes());
+                buf.extend_from_slice(val);
+                buf.extend_from_slice(b"\r\n");
+            },
+            Reply::Nil => buf.extend_from_slice(b"$-1\r\n"),
+            Reply::Array(items) => {
+                buf.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.write_resp(buf);
+                }
+            },
+        }
+    }
+}
+
+impl From<KvError> for Reply {
+    fn from(error: KvError) -> Self {
+        Reply::Error(error.to_string())
+    }
+}
+
+// Errors that start with an upper case code, like WRONGTYPE, keep it in RESP instead of
+// getting the generic ERR
+fn has_error_code(error: &str) -> bool {
+    let code = error.split(' ').next().unwrap_or_default();
+
+    code.len() > 1 && code.chars().all(|c| c.is_ascii_uppercase())
+}
+
+// Reads one array element off the front of `data`, along with how many bytes it took up
+fn read_binary_element(data: &[u8]) -> Option<(Reply, usize)> {
+    match data.first()? {
+        b'-' => (data.get(1) == Some(&b'\n')).then_some((Reply::Nil, 2)),
+        b'*' => {
+            let (count, mut pos) = read_binary_length(data, 1)?;
+
+            let mut items = Vec::new();
+            for _ in 0..count {
+                let (item, used) = read_binary_element(&data[pos..])?;
+                items.push(item);
+                pos += used;
+            }
+
+            Some((Reply::Array(items), pos))
+        },
+        marker => {
+            let error = *marker == b'!';
+            let (len, start) = read_binary_length(data, error as usize)?;
+
+            let end = start.checked_add(len)?;
+            if data.get(end) != Some(&b'\n') {
+                return None;
+            }
+
+            let value = data[start..end].to_vec();
+            let reply = if error {
+                Reply::Error(String::from_utf8_lossy(&value).into_owned())
+            } else {
+                Reply::Bulk(value)
+            };
+
+            Some((reply, end + 1))
+        },
+    }
+}
+
+// Reads the number on the line starting at `pos`, returning it and the position just past it
+fn read_binary_length(data: &[u8], pos: usize) -> Option<(usize, usize)> {
+    let end = pos + data[pos..].iter().position(|byte| *byte == b'\n')?;
+    let len = std::str::from_utf8(&data[pos..end]).ok()?.parse().ok()?;
+
+    Some((len, end + 1))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn text_encoding() {
+        assert_eq!(Reply::Ack("set").encode(Protocol::Text), b"set\n\n");
+        assert_eq!(Reply::Bulk(b"value".to_vec()).encode(Protocol::Text), b"1\nvalue\n\n");
+        assert_eq!(Reply::Nil.encode(Protocol::Text), b"0\n\n");
+    }
+
+    #[test]
+    fn binary_encoding() {
+        assert_eq!(Reply::Bulk(b"a\n\nb".to_vec()).encode(Protocol::Binary), b"6\n1\na\n\nb");
+        assert_eq!(Reply::Nil.encode(Protocol::Binary), b"1\n0");
+
+        // Array elements keep their newlines, and nil stays apart from an empty value
+        let reply = Reply::Array(vec![
+            Reply::Bulk(b"a\nb".to_vec()),
+            Reply::Nil,
+            Reply::Bulk(Vec::new()),
+            Reply::Error(String::from("no")),
+            Reply::Array(vec![Reply::Integer(3)]),
+        ]);
+        let encoded = reply.encode(Protocol::Binary);
+        assert_eq!(encoded, b"27\n*5\n3\na\nb\n-\n0\n\n!2\nno\n*1\n1\n3\n");
+
+        let body = &encoded[3..];
+        let decoded = Reply::Array(vec![
+            Reply::Bulk(b"a\nb".to_vec()),
+            Reply::Nil,
+            Reply::Bulk(Vec::new()),
+            Reply::Error(String::from("no")),
+            Reply::Array(vec![Reply::Bulk(b"3".to_vec())]),
+        ]);
+        assert_eq!(Reply::decode_binary(body), Some(decoded));
+        assert_eq!(Reply::decode_binary(b"1\nvalue"), None);
+    }
+
+    #[test]
+    fn resp_encoding() {
+        assert_eq!(Reply::Ack("set").encode(Protocol::Resp), b"+OK\r\n");
+        assert_eq!(Reply::Error(String::from("missing key")).encode(Protocol::Resp), b"-ERR missing key\r\n");
+        assert_eq!(Reply::Nil.encode(Protocol::Resp), b"$-1\r\n");
+        assert_eq!(Reply::from(KvError::WrongType).encode(Protocol::Resp), b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n");
+
+        let reply = Reply::Array(vec![
+            Reply::Bulk(b"message".to_vec()),
+            Reply::Integer(3),
+        ]);
+        assert_eq!(reply.encode(Protocol::Resp), b"*2\r\n$7\r\nmessage\r\n:3\r\n");
+    }
+}
@@ -0,0 +1,261 @@
+use crate::reply::Reply;
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use mlua::{HookTriggers, Lua, LuaOptions, MultiValue, StdLib, Table, Value, Variadic};
+
+// How many Lua instructions run between checks of a script's time limit
+const CHECK_INTERVAL: u32 = 1000;
+
+// Scripts loaded with SCRIPT LOAD or run with EVAL, by the hex SHA1 of their source
+#[derive(Default)]
+pub struct ScriptCache {
+    scripts: HashMap<String, Vec<u8>>,
+}
+
+impl ScriptCache {
+    pub fn new() -> Self {
+        ScriptCache::default()
+    }
+
+    // Adds a script, returning the SHA1 it can be run by
+    pub fn load(&mut self, script: Vec<u8>) -> String {
+        let sha = sha1_hex(&script);
+        self.scripts.entry(sha.clone()).or_insert(script);
+        sha
+    }
+
+    pub fn get(&self, sha: &str) -> Option<&[u8]> {
+        self.scripts.get(&sha.to_lowercase()).map(|script| &script[..])
+    }
+
+    pub fn contains(&self, sha: &str) -> bool {
+        self.get(sha).is_some()
+    }
+
+    pub fn flush(&mut self) {
+        self.scripts.clear();
+    }
+}
+
+pub fn sha1_hex(script: &[u8]) -> String {
+    sha1_smol::Sha1::from(script).digest().to_string()
+}
+
+/* Runs a Lua script, with the key and argument lists available as KEYS and ARGV.
+
+Scripts run commands with `redis.call(command, ...)`, which hands the arguments to `call` and
+raises an error if the command fails, or `redis.pcall`, which returns the error as `{err=...}`.
+Replies are converted both ways the way Redis does it: integers stay integers, bulk strings are
+Lua strings, nil is false, arrays are tables, and status replies are `{ok=...}`.
+
+Only the base, table, string, math and utf8 libraries are loaded, so scripts can't reach the
+filesystem. A script still running after `time_limit` is stopped with an error, and keeps
+getting errors after every instruction from then on so it can't catch its way out of it.
+Commands it ran before then aren't undone.
+
+*/
+pub fn run(script: &[u8], keys: Vec<Vec<u8>>, argv: Vec<Vec<u8>>, time_limit: Duration, call: impl FnMut(Vec<Vec<u8>>) -> Reply) -> Reply {
+    match eval(script, keys, argv, time_limit, call) {
+        Ok(reply) => reply,
+        Err(e) => Reply::Error(error_message(&e)),
+    }
+}
+
+fn eval(script: &[u8], keys: Vec<Vec<u8>>, argv: Vec<Vec<u8>>, time_limit: Duration, call: impl FnMut(Vec<Vec<u8>>) -> Reply) -> mlua::Result<Reply> {
+    let lua = Lua::new_with(StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8, LuaOptions::default())?;
+
+    let started = Instant::now();
+    lua.set_hook(HookTriggers::new().every_nth_instruction(CHECK_INTERVAL), move |lua, _| {
+        if started.elapsed() < time_limit {
+            return Ok(());
+        }
+
+        lua.set_hook(HookTriggers::new().every_nth_instruction(1), move |_, _| Err(timed_out(time_limit)));
+        Err(timed_out(time_limit))
+    });
+
+    let globals = lua.globals();
+    globals.set("KEYS", byte_strings(&lua, keys)?)?;
+    globals.set("ARGV", byte_strings(&lua, argv)?)?;
+
+    let call = std::cell::RefCell::new(call);
+
+    lua.scope(|scope| {
+        let redis = lua.create_table()?;
+
+        redis.set("call", scope.create_function(|lua, args: Variadic<mlua::String>| {
+            match (call.borrow_mut())(command_args(args)?) {
+                Reply::Error(error) => Err(mlua::Error::RuntimeError(error)),
+                reply => to_lua(lua, reply),
+            }
+        })?)?;
+        redis.set("pcall", scope.create_function(|lua, args: Variadic<mlua::String>| {
+            to_lua(lua, (call.borrow_mut())(command_args(args)?))
+        })?)?;
+        redis.set("error_reply", lua.create_function(|lua, error: mlua::String| {
+            reply_table(lua, "err", error)
+        })?)?;
+        redis.set("status_reply", lua.create_function(|lua, status: mlua::String| {
+            reply_table(lua, "ok", status)
+        })?)?;
+        globals.set("redis", redis)?;
+
+        let result: MultiValue = lua.load(script).set_name("@user_script").call(())?;
+
+        Ok(from_lua(result.into_iter().next().unwrap_or(Value::Nil)))
+    })
+}
+
+fn timed_out(time_limit: Duration) -> mlua::Error {
+    mlua::Error::RuntimeError(format!("script timed out after {} ms", time_limit.as_millis()))
+}
+
+// The message of the error that stopped the script, without the callback wrapping or the
+// stack traceback that follows it on the next lines
+fn error_message(error: &mlua::Error) -> String {
+    let message = match error {
+        mlua::Error::CallbackError { cause, .. } => return error_message(cause),
+        mlua::Error::RuntimeError(message) => message.clone(),
+        mlua::Error::SyntaxError { message, .. } => message.clone(),
+        error => error.to_string(),
+    };
+
+    message.lines().next().unwrap_or_default().to_string()
+}
+
+fn command_args(args: Variadic<mlua::String>) -> mlua::Result<Vec<Vec<u8>>> {
+    if args.is_empty() {
+        return Err(mlua::Error::RuntimeError(String::from("redis.call needs at least the command")));
+    }
+
+    Ok(args.iter().map(|arg| arg.as_bytes().to_vec()).collect())
+}
+
+fn byte_strings(lua: &Lua, values: Vec<Vec<u8>>) -> mlua::Result<Table<'_>> {
+    let strings = values
+        .into_iter()
+        .map(|value| lua.create_string(value))
+        .collect::<mlua::Result<Vec<_>>>()?;
+
+    lua.create_sequence_from(strings)
+}
+
+fn reply_table<'lua>(lua: &'lua Lua, field: &str, message: mlua::String<'lua>) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    table.set(field, message)?;
+    Ok(table)
+}
+
+fn to_lua(lua: &Lua, reply: Reply) -> mlua::Result<Value<'_>> {
+    let value = match reply {
+        Reply::Ack(_) => Value::Table(reply_table(lua, "ok", lua.create_string("OK")?)?),
+        Reply::Status(status) => Value::Table(reply_table(lua, "ok", lua.create_string(status)?)?),
+        Reply::Error(error) => Value::Table(reply_table(lua, "err", lua.create_string(error)?)?),
+        Reply::Integer(n) => Value::Integer(n),
+        Reply::Bulk(val) => Value::String(lua.create_string(val)?),
+        Reply::Nil => Value::Boolean(false),
+        Reply::Array(items) => {
+            let items = items
+                .into_iter()
+                .map(|item| to_lua(lua, item))
+                .collect::<mlua::Result<Vec<_>>>()?;
+            Value::Table(lua.create_sequence_from(items)?)
+        },
+    };
+
+    Ok(value)
+}
+
+// Tables are read as arrays up to their first nil, unless they're an `{err=...}` or `{ok=...}`
+fn from_lua(value: Value) -> Reply {
+    match value {
+        Value::Boolean(true) => Reply::Integer(1),
+        Value::Integer(n) => Reply::Integer(n),
+        Value::Number(n) => Reply::Integer(n as i64),
+        Value::String(s) => Reply::Bulk(s.as_bytes().to_vec()),
+        Value::Table(table) => {
+            if let Ok(mlua::Value::String(error)) = table.raw_get("err") {
+                return Reply::Error(error.to_string_lossy().to_string());
+            }
+            if let Ok(mlua::Value::String(status)) = table.raw_get("ok") {
+                return Reply::Status(status.to_string_lossy().to_string());
+            }
+
+            let items = table
+                .sequence_values::<Value>()
+                .map_while(Result::ok)
+                .map(from_lua)
+                .collect();
+            Reply::Array(items)
+        },
+        _ => Reply::Nil,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run_script(script: &str, call: impl FnMut(Vec<Vec<u8>>) -> Reply) -> Reply {
+        run(script.as_bytes(), vec![b"key".to_vec()], vec![b"arg".to_vec()], Duration::from_secs(5), call)
+    }
+
+    #[test]
+    fn conversions() {
+        let no_calls = |_| panic!("unexpected call");
+
+        assert_eq!(run_script("return 1.9", no_calls), Reply::Integer(1));
+        assert_eq!(run_script("return KEYS[1] .. ARGV[1]", no_calls), Reply::Bulk(b"keyarg".to_vec()));
+        assert_eq!(run_script("return false", no_calls), Reply::Nil);
+        assert_eq!(run_script("return {1, 'a', nil, 2}", no_calls), Reply::Array(vec![Reply::Integer(1), Reply::Bulk(b"a".to_vec())]));
+        assert_eq!(run_script("return redis.status_reply('FINE')", no_calls), Reply::Status(String::from("FINE")));
+        assert_eq!(run_script("return redis.error_reply('bad')", no_calls), Reply::Error(String::from("bad")));
+        assert_eq!(run_script("return os.time()", no_calls), Reply::Error(String::from("user_script:1: attempt to index a nil value (global 'os')")));
+    }
+
+    #[test]
+    fn calls() {
+        let mut calls = Vec::new();
+        let reply = run_script("return {redis.call('get', KEYS[1]), redis.call('incrby', 'n', 5)}", |args| {
+            calls.push(args);
+            match calls.len() {
+                1 => Reply::Nil,
+                _ => Reply::Integer(5),
+            }
+        });
+
+        assert_eq!(reply, Reply::Array(vec![Reply::Nil, Reply::Integer(5)]));
+        assert_eq!(calls, vec![vec![b"get".to_vec(), b"key".to_vec()], vec![b"incrby".to_vec(), b"n".to_vec(), b"5".to_vec()]]);
+
+        let failing = |_| Reply::Error(String::from("WRONGTYPE oops"));
+        assert_eq!(run_script("return redis.call('get', 'k')", failing), Reply::Error(String::from("WRONGTYPE oops")));
+        assert_eq!(run_script("return redis.pcall('get', 'k')", failing), Reply::Error(String::from("WRONGTYPE oops")));
+        assert_eq!(run_script("local ok = pcall(redis.call, 'get', 'k') return ok", failing), Reply::Nil);
+    }
+
+    #[test]
+    fn time_limit() {
+        let no_calls = |_| panic!("unexpected call");
+        let script = b"while true do pcall(function() while true do end end) end";
+
+        let started = Instant::now();
+        let reply = run(script, vec![], vec![], Duration::from_millis(50), no_calls);
+
+        assert_eq!(reply, Reply::Error(String::from("script timed out after 50 ms")));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn cache() {
+        let mut cache = ScriptCache::new();
+        let sha = cache.load(b"return 1".to_vec());
+
+        assert_eq!(sha, "e0e1f9fabfc9d4800c877a703b823ac0578ff8db");
+        assert!(cache.contains(&sha.to_uppercase()));
+
+        cache.flush();
+        assert!(!cache.contains(&sha));
+    }
+}
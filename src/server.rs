@@ -1,22 +1,256 @@
-use rustis::pubsub::PubSub;
+use rustis::pubsub::{Message, OverflowPolicy, PubSub, Receiver, Subscriber};
 use rustis::threadpool::ThreadPool;
-use rustis::packetreader::RequestPacket;
-use rustis::kvstore::KvStore;
+use rustis::packetreader::{set_commands, Frame, Protocol, RequestPacket};
+use rustis::reply::Reply;
+use rustis::kvstore::{self, AutoClaim, KvError, SetOp, SetOptions, Ttl};
+use rustis::keyspace::{Keyspace, Shards};
+use rustis::sortedset::ScoreBound;
+use rustis::stream::{ClaimOptions, Fields, GroupReadFrom, NewId, PendingRange, ReadFrom, StreamId};
+use rustis::snapshot::Snapshotter;
+use rustis::aof::{self, Aof, FsyncPolicy};
+use rustis::scripting::{self, ScriptCache};
+use rustis::blocking::{BlockedClients, BlockedPop};
 
+use std::collections::{HashMap, HashSet};
 use std::io::{prelude::*, self};
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::net::{SocketAddr, TcpListener};
+use std::ops::{Bound, Deref};
+use std::path::PathBuf;
+use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use std::str;
+use std::thread;
+use std::time::{Duration, Instant};
 
+use mio::net::TcpStream;
+use mio::{Events, Interest, Poll, Token, Waker};
 use socket2::{Socket, Domain, Type, TcpKeepalive};
 
-struct ServerState {
-    ps: PubSub,
-    kv: KvStore,
+/* Everything connections share. Rather than one lock around all of it, the keyspace is split
+into shards that are locked separately, and each other part has a lock of its own, so a
+publish doesn't wait on a slow read and commands on different keys don't wait on each other.
+
+A command locks the shards it needs first, see `Keyspace`, and any of the other locks after
+that. Those are only ever held for a moment, never while taking another lock.
+
+*/
+struct Server {
+    keyspace: Keyspace,
+    ps: Mutex<PubSub>,
+    snapshotter: Snapshotter,
+    aof: Option<Aof>,
+    scripts: Mutex<ScriptCache>,
+    // How long a script can run before it's stopped
+    lua_time_limit: Duration,
+    // Connections waiting in XREAD or XREADGROUP BLOCK, woken whenever an entry is added to a
+    // stream to try their read again
+    stream_waiters: Mutex<Vec<ClientHandle>>,
+    // Clients waiting in BLPOP, BRPOP or BLMOVE for something to be pushed
+    blocked: Mutex<BlockedClients>,
+    started_at: Instant,
+}
+
+impl Server {
+    // Locks the shards holding `keys`, which are the only keys the command can use
+    fn lock<'a>(&self, keys: impl IntoIterator<Item = &'a [u8]>) -> ServerState<'_> {
+        ServerState { kv: self.keyspace.lock(keys), server: self }
+    }
+
+    fn lock_all(&self) -> ServerState<'_> {
+        ServerState { kv: self.keyspace.lock_all(), server: self }
+    }
+
+    // Locks whatever `packet` needs to run
+    fn lock_for(&self, packet: &RequestPacket) -> ServerState<'_> {
+        match packet.keys() {
+            Some(keys) => self.lock(keys),
+            None => self.lock_all(),
+        }
+    }
+}
+
+// What a command runs against: the server, with the shards it needs locked until it's done
+struct ServerState<'a> {
+    kv: Shards<'a>,
+    server: &'a Server,
+}
+
+impl Deref for ServerState<'_> {
+    type Target = Server;
+
+    fn deref(&self) -> &Server {
+        self.server
+    }
+}
+
+// A connection's MULTI/EXEC state
+#[derive(Default)]
+struct Transaction {
+    // Commands queued since MULTI, or `None` outside of a transaction
+    queued: Option<Vec<RequestPacket>>,
+    // Whether a command couldn't be queued, which makes EXEC discard the transaction
+    failed: bool,
+    // Keys from WATCH, with the version each had at the time
+    watched: Vec<(Vec<u8>, u64)>,
+}
+
+// What a connection is subscribed to, once it has subscribed to anything
+struct Subscriptions {
+    subscriber: Subscriber,
+    // Where the subscriber's messages wait until the connection has room to write them
+    receiver: Receiver,
+    // The protocol of the first subscription, which messages are written in
+    protocol: Protocol,
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+}
+
+impl Subscriptions {
+    fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    fn names(&mut self, pattern: bool) -> &mut HashSet<String> {
+        if pattern {
+            &mut self.patterns
+        } else {
+            &mut self.channels
+        }
+    }
+}
+
+// A blocking command that's waiting, and the protocol to reply in once it can
+struct Blocked {
+    wait: Wait,
+    protocol: Protocol,
+    // When to give up and reply nil, or `None` to wait forever
+    deadline: Option<Instant>,
+}
+
+enum Wait {
+    // XREAD, with `$` already resolved to the IDs the streams were at when it started waiting
+    XRead {
+        streams: Vec<(Vec<u8>, StreamId)>,
+        count: Option<usize>,
+    },
+    XReadGroup {
+        group: Vec<u8>,
+        consumer: Vec<u8>,
+        streams: Vec<(Vec<u8>, GroupReadFrom)>,
+        count: Option<usize>,
+        noack: bool,
+    },
+    // BLPOP, BRPOP or BLMOVE, whose reply arrives here once a push has been handed to it
+    List {
+        id: u64,
+        receiver: mpsc::Receiver<Reply>,
+    },
+}
+
+impl Wait {
+    // The streams a blocked XREAD or XREADGROUP reads from
+    fn keys(&self) -> Vec<&[u8]> {
+        match self {
+            Wait::XRead { streams, .. } => streams.iter().map(|(key, _)| &key[..]).collect(),
+            Wait::XReadGroup { streams, .. } => streams.iter().map(|(key, _)| &key[..]).collect(),
+            Wait::List { .. } => Vec::new(),
+        }
+    }
+}
+
+// The state a connection keeps between commands
+struct Connection {
+    stream: TcpStream,
+    // How other threads wake the connection up
+    handle: ClientHandle,
+    // Bytes that have arrived but haven't been run yet, because they don't make up a whole
+    // frame or because a blocking command is waiting
+    input: Vec<u8>,
+    // Replies the socket hasn't taken yet
+    output: Vec<u8>,
+    transaction: Transaction,
+    subscriptions: Option<Subscriptions>,
+    blocked: Option<Blocked>,
+    // Whether to close the connection once its output has been written
+    closing: bool,
+}
+
+impl Connection {
+    fn write(&mut self, protocol: Protocol, reply: &Reply) {
+        write_reply(&mut self.output, protocol, reply);
+    }
+}
+
+// How other threads get an event loop's attention, either to hand it a new connection or
+// because one of its connections has something to do
+struct Notifier {
+    waker: Waker,
+    accepted: Mutex<Vec<std::net::TcpStream>>,
+    woken: Mutex<HashSet<Token>>,
+}
+
+impl Notifier {
+    fn accept(&self, stream: std::net::TcpStream) {
+        self.accepted.lock().unwrap().push(stream);
+        self.wake_loop();
+    }
+
+    fn wake(&self, token: Token) {
+        self.woken.lock().unwrap().insert(token);
+        self.wake_loop();
+    }
+
+    fn wake_loop(&self) {
+        if let Err(e) = self.waker.wake() {
+            println!("error: can't wake event loop: {}", e);
+        }
+    }
+}
+
+// A connection as seen from outside its event loop
+#[derive(Clone)]
+struct ClientHandle {
+    notifier: Arc<Notifier>,
+    token: Token,
+}
+
+impl ClientHandle {
+    fn wake(&self) {
+        self.notifier.wake(self.token);
+    }
+
+    fn is(&self, other: &ClientHandle) -> bool {
+        Arc::ptr_eq(&self.notifier, &other.notifier) && self.token == other.token
+    }
 }
 
-pub fn start_server(host: &str, port: u16, threads: u16) -> io::Result<()> {
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    // How many event loops to share connections between
+    pub threads: u16,
+    // How many separately locked shards the keyspace is split into
+    pub shards: usize,
+    // Directory the snapshot file is kept in
+    pub dir: PathBuf,
+    pub dbfilename: String,
+    // Seconds between automatic background snapshots, if any
+    pub snapshot_interval: Option<u64>,
+    // Whether every write is logged to the append-only file
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appendfsync: FsyncPolicy,
+    // How many times a second the active expiry cycle runs
+    pub hz: u32,
+    // Milliseconds a script can run before it's stopped
+    pub lua_time_limit: u64,
+    // How many messages can wait for a subscriber, and what happens to ones that don't fit
+    pub subscriber_queue: usize,
+    pub subscriber_overflow: OverflowPolicy,
+}
+
+pub fn start_server(config: Config) -> io::Result<()> {
+    let Config { host, port, threads, .. } = config;
+
     println!("Starting server with {threads} threads!");
 
     let socket = Socket::new(Domain::IPV4, Type::STREAM, None)?;
@@ -34,144 +268,1871 @@ pub fn start_server(host: &str, port: u16, threads: u16) -> io::Result<()> {
     socket.set_tcp_keepalive(&keepalive)?;
 
     let listener: TcpListener = socket.into();
-    let ps = PubSub::new();
-    let kv = KvStore::new();
-    let tcp_pool = ThreadPool::new(threads.into());
+    let ps = PubSub::new(config.subscriber_queue, config.subscriber_overflow);
+    let snapshotter = Snapshotter::new(config.dir.join(&config.dbfilename));
+    let aof_path = config.dir.join(&config.appendfilename);
+
+    // The append-only file has every write, so it's preferred over the snapshot when enabled
+    let keyspace = match snapshotter.load()? {
+        Some(kv) if !config.appendonly => {
+            println!("Loaded {} keys from snapshot", kv.len());
+            Keyspace::from_store(kv, config.shards)
+        },
+        _ => Keyspace::new(config.shards),
+    };
+    let mut server = Server {
+        keyspace,
+        ps: Mutex::new(ps),
+        snapshotter,
+        aof: None,
+        scripts: Mutex::new(ScriptCache::new()),
+        lua_time_limit: Duration::from_millis(config.lua_time_limit),
+        stream_waiters: Mutex::new(Vec::new()),
+        blocked: Mutex::new(BlockedClients::new()),
+        started_at: Instant::now(),
+    };
 
-    let state = ServerState { ps, kv };
-    let state = Arc::new(Mutex::new(state));
+    if config.appendonly {
+        server.keyspace.set_loading(true);
+        let count = aof::replay(&aof_path, |packet| {
+            let mut state = server.lock_for(&packet);
+            if let Reply::Error(error) = execute(&mut state, packet) {
+                println!("error: failed to replay command: {}", error);
+            }
+        })?;
+        server.keyspace.set_loading(false);
+        println!("Replayed {} commands from append-only file", count);
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        let state = Arc::clone(&state);
+        server.aof = Some(Aof::open(aof_path, config.appendfsync)?);
+    }
 
-        tcp_pool.execute(move || {
-            handle_connection(stream, state)
-                .expect("Can't handle!");
-        });
+    let server = Arc::new(server);
+
+    if let Some(interval) = config.snapshot_interval {
+        start_snapshots(Arc::clone(&server), Duration::from_secs(interval));
+    }
+
+    start_active_expiry(Arc::clone(&server), config.hz);
+
+    // Each thread runs an event loop, and new connections are handed to them in turn
+    let pool = ThreadPool::new(threads.into());
+    let mut notifiers = Vec::new();
+
+    for _ in 0..threads {
+        let event_loop = EventLoop::new(Arc::clone(&server))?;
+        notifiers.push(Arc::clone(&event_loop.notifier));
+
+        pool.execute(move || event_loop.run());
+    }
+
+    for (stream, notifier) in listener.incoming().zip(notifiers.iter().cycle()) {
+        match stream {
+            Ok(stream) => notifier.accept(stream),
+            Err(e) => println!("error: can't accept connection: {}", e),
+        }
     }
 
     Ok(())
 }
 
-fn handle_connection(mut stream: TcpStream, state: Arc<Mutex<ServerState>>) -> io::Result<()> {
-    let mut buffer = [0; 1024];
-    let mut data = Vec::new();
+// Takes a background snapshot every `interval` for as long as the server runs
+fn start_snapshots(server: Arc<Server>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
 
-    loop {
-        let bytes_read = stream.read(&mut buffer)?;
+        let state = server.lock_all();
+        if !state.snapshotter.bgsave(state.kv.iter()) {
+            println!("Skipping snapshot, one is already in progress");
+        }
+    });
+}
+
+// How many keys with a time to live each step of the active expiry cycle checks
+const EXPIRE_SAMPLE: usize = 20;
+
+// Removes expired keys in the background, so keys that are never used again don't sit in
+// memory forever. Each of the `hz` cycles a second goes through the shards, checking a small
+// sample of keys at a time and only locking that shard for one sample. It keeps going on a
+// shard while more than a quarter of each sample had expired, until the cycle has used a
+// quarter of the time between cycles.
+fn start_active_expiry(server: Arc<Server>, hz: u32) {
+    let interval = Duration::from_secs(1) / hz;
+    let budget = interval / 4;
+
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+
+        let started = Instant::now();
+        for shard in 0..server.keyspace.shards() {
+            loop {
+                let (sampled, expired) = server.keyspace.expire_sample(shard, EXPIRE_SAMPLE);
 
-        // End of stream
-        if bytes_read == 0 {
-            break; 
+                if expired * 4 <= sampled || started.elapsed() >= budget {
+                    break;
+                }
+            }
         }
+    });
+}
+
+// The token the waker wakes an event loop with. Connections are numbered from 1.
+const WAKER: Token = Token(0);
+
+// How much output a subscriber can have waiting before its messages are left in its queue, so
+// the queue limit still applies to clients that don't keep up
+const OUTPUT_LIMIT: usize = 64 * 1024;
+
+// Runs any number of connections on one thread. Connections are only ever read from, written
+// to or woken up by the loop that owns them, so a command that has to wait, or a subscriber
+// waiting for messages, is just a state its connection is in rather than a parked thread.
+struct EventLoop {
+    poll: Poll,
+    notifier: Arc<Notifier>,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    server: Arc<Server>,
+}
+
+impl EventLoop {
+    fn new(server: Arc<Server>) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let notifier = Arc::new(Notifier {
+            waker: Waker::new(poll.registry(), WAKER)?,
+            accepted: Mutex::new(Vec::new()),
+            woken: Mutex::new(HashSet::new()),
+        });
+
+        Ok(EventLoop { poll, notifier, connections: HashMap::new(), next_token: 1, server })
+    }
+
+    fn run(mut self) {
+        let mut events = Events::with_capacity(1024);
 
-        data.extend_from_slice(&buffer[..bytes_read]);
+        loop {
+            if let Err(e) = self.poll.poll(&mut events, self.next_timeout()) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                println!("error: event loop stopped: {}", e);
+                return;
+            }
 
-        if let Some(index) = data.windows(2).position(|window| window == b"\n\n") {
-            // Process the packet
-            let packet = &data[..=index-1];
-            process_packet(&mut stream, &state, packet)?;
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => self.handle_wakeups(),
+                    token => self.handle_io(token, event.is_readable()),
+                }
+            }
 
-            data.drain(..=index+1);
+            self.expire_blocked();
         }
     }
 
-    Ok(())
+    // How long until the next blocked command times out, if any of them can
+    fn next_timeout(&self) -> Option<Duration> {
+        let now = Instant::now();
+
+        self.connections
+            .values()
+            .filter_map(|conn| conn.blocked.as_ref()?.deadline)
+            .min()
+            .map(|deadline| deadline.saturating_duration_since(now))
+    }
+
+    fn handle_wakeups(&mut self) {
+        let accepted = std::mem::take(&mut *self.notifier.accepted.lock().unwrap());
+        for stream in accepted {
+            if let Err(e) = self.add_connection(stream) {
+                println!("error: can't add connection: {}", e);
+            }
+        }
+
+        let woken = std::mem::take(&mut *self.notifier.woken.lock().unwrap());
+        for token in woken {
+            let Some(conn) = self.connections.get_mut(&token) else {
+                continue;
+            };
+
+            resume_blocked(conn, &self.server);
+            self.finish_io(token);
+        }
+    }
+
+    fn add_connection(&mut self, stream: std::net::TcpStream) -> io::Result<()> {
+        stream.set_nonblocking(true)?;
+        let mut stream = TcpStream::from_std(stream);
+
+        let token = Token(self.next_token);
+        self.next_token += 1;
+
+        // Registering for both up front means an event arrives whenever either becomes possible
+        self.poll.registry().register(&mut stream, token, Interest::READABLE | Interest::WRITABLE)?;
+
+        let conn = Connection {
+            stream,
+            handle: ClientHandle { notifier: Arc::clone(&self.notifier), token },
+            input: Vec::new(),
+            output: Vec::new(),
+            transaction: Transaction::default(),
+            subscriptions: None,
+            blocked: None,
+            closing: false,
+        };
+        self.connections.insert(token, conn);
+
+        Ok(())
+    }
+
+    fn handle_io(&mut self, token: Token, readable: bool) {
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+
+        if readable {
+            // Whatever arrived before the client hung up still runs, and gets its replies
+            if !read_input(conn) {
+                conn.closing = true;
+            }
+            run_input(conn, &self.server);
+        }
+
+        self.finish_io(token);
+    }
+
+    // Writes out what the connection has waiting, and closes it if it's done with
+    fn finish_io(&mut self, token: Token) {
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+
+        let open = write_output(conn);
+        if !open || (conn.closing && (conn.output.is_empty() || conn.blocked.is_some())) {
+            self.close(token);
+        }
+    }
+
+    // Replies nil to blocked commands whose timeout has passed
+    fn expire_blocked(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<_> = self.connections
+            .iter()
+            .filter(|(_, conn)| conn.blocked.as_ref().and_then(|blocked| blocked.deadline).is_some_and(|deadline| deadline <= now))
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in expired {
+            let Some(conn) = self.connections.get_mut(&token) else {
+                continue;
+            };
+            let Some(blocked) = conn.blocked.take() else {
+                continue;
+            };
+
+            stop_waiting(&self.server, &conn.handle, &blocked.wait);
+
+            // A value could have been handed over after the deadline but before the lock was taken
+            let reply = match &blocked.wait {
+                Wait::List { receiver, .. } => receiver.try_recv().unwrap_or(Reply::Nil),
+                _ => Reply::Nil,
+            };
+            conn.write(blocked.protocol, &reply);
+
+            run_input(conn, &self.server);
+            self.finish_io(token);
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        let Some(mut conn) = self.connections.remove(&token) else {
+            return;
+        };
+
+        let _ = self.poll.registry().deregister(&mut conn.stream);
+
+        // Nothing can reach the client anymore, so don't leave its subscriptions behind
+        if let Some(subscriptions) = conn.subscriptions.take() {
+            let id = subscriptions.subscriber.id();
+            let mut ps = self.server.ps.lock().unwrap();
+
+            for channel in &subscriptions.channels {
+                ps.unsubscribe(id, channel);
+            }
+            for pattern in &subscriptions.patterns {
+                ps.punsubscribe(id, pattern);
+            }
+        }
+
+        if let Some(blocked) = conn.blocked.take() {
+            stop_waiting(&self.server, &conn.handle, &blocked.wait);
+        }
+
+        unwatch(&self.server, std::mem::take(&mut conn.transaction.watched));
+    }
 }
 
-fn process_packet(stream: &mut TcpStream, state: &Arc<Mutex<ServerState>>, packet: &[u8]) -> io::Result<()> {
-    let packet_str = String::from_utf8_lossy(packet);
-    let packet = RequestPacket::new(packet_str.to_string());
+// Reads everything the socket has for now, returning false once the client has hung up
+fn read_input(conn: &mut Connection) -> bool {
+    let mut buffer = [0; 4096];
 
-    dbg!(&packet);
+    loop {
+        match conn.stream.read(&mut buffer) {
+            // End of stream
+            Ok(0) => return false,
+            Ok(bytes_read) => conn.input.extend_from_slice(&buffer[..bytes_read]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return false,
+        }
+    }
+}
 
-    match packet {
-        RequestPacket::Subscribe { channel } => handle_subscribe(stream, state, channel),
-        RequestPacket::Publish { channel, message } => handle_publish(stream, state, channel, message),
-        RequestPacket::Set { key, value } => handle_set(stream, state, key, value),
-        RequestPacket::SetEx { key, ttl, value } => handle_setex(stream, state, key, ttl, value),
-        RequestPacket::Get { key } => handle_get(stream, state, key),
-        RequestPacket::Ttl { key } => handle_ttl(stream, state, key),
-        RequestPacket::Invalid { error } => {
-            write_message(stream, &error)
-        },
-        RequestPacket::Unknown => {
-            write_message(stream, "unknown")
-        },
+// Runs every whole frame that has arrived, stopping early if a command has to wait
+fn run_input(conn: &mut Connection, server: &Server) {
+    while conn.blocked.is_none() {
+        match Frame::read(&conn.input) {
+            Frame::Complete(packet, protocol, used) => {
+                conn.input.drain(..used);
+
+                // Process the packet
+                process_packet(conn, server, packet, protocol);
+            },
+            Frame::Incomplete => break,
+            Frame::Malformed(error) => {
+                let reply = Reply::Error(format!("protocol error: {}", error));
+                conn.write(Protocol::Resp, &reply);
+                conn.input.clear();
+                conn.closing = true;
+                break;
+            },
+        }
     }
+}
 
-    Ok(())
+// Writes as much output as the socket will take, topping it up with a subscriber's messages as
+// it goes. Returns false if the connection is broken.
+fn write_output(conn: &mut Connection) -> bool {
+    loop {
+        if !forward_messages(conn) {
+            return false;
+        }
+        if conn.output.is_empty() {
+            return true;
+        }
+
+        match conn.stream.write(&conn.output) {
+            Ok(0) => return false,
+            Ok(written) => {
+                conn.output.drain(..written);
+            },
+            // The socket is full, and a writable event comes once it has room again
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => {
+                println!("error: {}", e);
+                return false;
+            },
+        }
+    }
 }
 
-fn handle_subscribe(stream: &mut TcpStream, state: &Arc<Mutex<ServerState>>, channel: String) {
-    let mut state = state.lock().unwrap();
-    let receiver = state.ps.subscribe(channel);
-    drop(state);
+// Moves a subscriber's waiting messages into its output, up to `OUTPUT_LIMIT`. Returns false
+// if it fell too far behind and was disconnected.
+fn forward_messages(conn: &mut Connection) -> bool {
+    let Some(subscriptions) = &conn.subscriptions else {
+        return true;
+    };
 
-    match receiver {
-        Ok(receiver) => {
-            loop {
-                let message = receiver.recv().unwrap();
+    if subscriptions.receiver.fell_behind() {
+        println!("Disconnecting a subscriber that fell behind");
+        return false;
+    }
+
+    while conn.output.len() < OUTPUT_LIMIT {
+        let Some(message) = subscriptions.receiver.try_recv() else {
+            break;
+        };
+
+        let reply = message_reply(subscriptions.protocol, message);
+        write_reply(&mut conn.output, subscriptions.protocol, &reply);
+    }
 
-                let _ = write_message(stream, &message[..]);
+    true
+}
+
+// Tries a blocked command again after something woke its connection. If it can go ahead, it
+// replies, and the commands that arrived while it waited get to run.
+fn resume_blocked(conn: &mut Connection, server: &Server) {
+    let Some(blocked) = &conn.blocked else {
+        return;
+    };
+
+    let reply = match &blocked.wait {
+        Wait::List { receiver, .. } => receiver.try_recv().ok(),
+        wait => {
+            let mut state = server.lock(wait.keys());
+            let reply = read_blocked_streams(&mut state, wait);
+            if reply.is_none() {
+                state.stream_waiters.lock().unwrap().push(conn.handle.clone());
             }
+            reply
+        },
+    };
+
+    if let Some(reply) = reply {
+        let protocol = blocked.protocol;
+        conn.blocked = None;
+        conn.write(protocol, &reply);
+
+        run_input(conn, server);
+    }
+}
+
+// Takes a blocked connection out of whatever it was waiting in
+fn stop_waiting(server: &Server, handle: &ClientHandle, wait: &Wait) {
+    match wait {
+        Wait::List { id, .. } => server.blocked.lock().unwrap().unblock(*id),
+        Wait::XRead { .. } | Wait::XReadGroup { .. } => {
+            server.stream_waiters.lock().unwrap().retain(|waiter| !waiter.is(handle));
+        },
+    }
+}
+
+// When a blocking command gives up. A timeout of 0, or one too long for the clock to hold, waits
+// forever.
+fn deadline(timeout: Duration) -> Option<Instant> {
+    (!timeout.is_zero()).then(|| Instant::now().checked_add(timeout)).flatten()
+}
+
+fn process_packet(conn: &mut Connection, server: &Server, packet: RequestPacket, protocol: Protocol) {
+    // A subscribed connection can only change what it's subscribed to, or ping
+    if conn.subscriptions.is_some() {
+        let reply = match packet {
+            RequestPacket::Subscribe { .. }
+            | RequestPacket::PSubscribe { .. }
+            | RequestPacket::Unsubscribe { .. }
+            | RequestPacket::PUnsubscribe { .. } => None,
+            RequestPacket::Ping { ref message } => Some(subscribed_ping_reply(protocol, message.clone())),
+            _ => Some(Reply::Error(String::from("only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING are allowed while subscribed"))),
+        };
+
+        if let Some(reply) = reply {
+            return conn.write(protocol, &reply);
         }
-        Err(_) => (),
     }
+
+    let transaction = &mut conn.transaction;
+
+    let reply = match packet {
+        RequestPacket::Multi => handle_multi(transaction),
+        RequestPacket::Exec => handle_exec(server, transaction),
+        RequestPacket::Discard => handle_discard(server, transaction),
+        RequestPacket::Watch { keys } => handle_watch(server, transaction, keys),
+        RequestPacket::Unwatch => handle_unwatch(server, transaction),
+        // Inside a transaction, everything else waits for EXEC
+        packet if transaction.queued.is_some() => handle_queue(transaction, packet),
+        // Subscriptions belong to the connection rather than the store, and write their own replies
+        RequestPacket::Subscribe { channels } => return handle_subscribe(conn, server, protocol, channels, false),
+        RequestPacket::PSubscribe { patterns } => return handle_subscribe(conn, server, protocol, patterns, true),
+        RequestPacket::Unsubscribe { channels } => return handle_unsubscribe(conn, server, protocol, channels, false),
+        RequestPacket::PUnsubscribe { patterns } => return handle_unsubscribe(conn, server, protocol, patterns, true),
+        // Blocking commands either reply straight away or leave the connection waiting
+        RequestPacket::XRead { streams, count, block: Some(timeout) } => {
+            return handle_blocking_xread(conn, server, protocol, streams, count, timeout)
+        },
+        RequestPacket::XReadGroup { group, consumer, streams, count, block: Some(timeout), noack } => {
+            let wait = Wait::XReadGroup { group, consumer, streams, count, noack };
+            let mut state = server.lock(wait.keys());
+            return block_on_streams(conn, &mut state, protocol, wait, timeout);
+        },
+        RequestPacket::BLPop { keys, timeout } => {
+            return handle_blocking_pop(conn, server, protocol, keys, BlockedPop::Pop { front: true }, timeout)
+        },
+        RequestPacket::BRPop { keys, timeout } => {
+            return handle_blocking_pop(conn, server, protocol, keys, BlockedPop::Pop { front: false }, timeout)
+        },
+        RequestPacket::BLMove { source, destination, from_front, to_front, timeout } => {
+            let pop = BlockedPop::Move { destination, from_front, to_front };
+            return handle_blocking_pop(conn, server, protocol, vec![source], pop, timeout);
+        },
+        packet => {
+            let mut state = server.lock_for(&packet);
+            let reply = execute(&mut state, packet);
+            drop(state);
+
+            serve_blocked(server);
+            reply
+        },
+    };
+
+    conn.write(protocol, &reply);
 }
 
-fn handle_publish(stream: &mut TcpStream, state: &Arc<Mutex<ServerState>>, channel: String, message: String) {
-    let mut state = state.lock().unwrap();
-    state
-        .ps
-        .publish(channel, message);
-    let _ = write_message(stream, "published");
+fn handle_multi(transaction: &mut Transaction) -> Reply {
+    if transaction.queued.is_some() {
+        return Reply::Error(String::from("MULTI calls can not be nested"));
+    }
+
+    transaction.queued = Some(Vec::new());
+    Reply::Ack("multi")
+}
+
+fn handle_queue(transaction: &mut Transaction, packet: RequestPacket) -> Reply {
+    let error = match &packet {
+        RequestPacket::Invalid { error } => error.clone(),
+        RequestPacket::Unknown => String::from("unknown"),
+        _ => {
+            transaction.queued.get_or_insert_with(Vec::new).push(packet);
+            return Reply::Status(String::from("QUEUED"));
+        },
+    };
+
+    transaction.failed = true;
+    Reply::Error(error)
 }
 
-fn handle_set(stream: &mut TcpStream, state: &Arc<Mutex<ServerState>>, key: String, value: String) {
-    let mut state = state.lock().unwrap();
-    state.kv.set(&key[..], &value[..]);
-    let _ = write_message(stream, "set");
+// Runs every queued command with the shards of all their keys locked the whole time, so no
+// other client sees those keys partway through. Replies with nil without running anything if a
+// watched key has changed.
+fn handle_exec(server: &Server, transaction: &mut Transaction) -> Reply {
+    let Some(queued) = transaction.queued.take() else {
+        return Reply::Error(String::from("EXEC without MULTI"));
+    };
+    let watched = std::mem::take(&mut transaction.watched);
+
+    if std::mem::take(&mut transaction.failed) {
+        unwatch(server, watched);
+        return Reply::Error(String::from("EXECABORT Transaction discarded because of previous errors."));
+    }
+
+    let mut keys: Vec<&[u8]> = watched.iter().map(|(key, _)| &key[..]).collect();
+    let mut any_key = false;
+    for packet in &queued {
+        match packet.keys() {
+            Some(packet_keys) => keys.extend(packet_keys),
+            None => any_key = true,
+        }
+    }
+
+    let mut state = if any_key { server.lock_all() } else { server.lock(keys) };
+
+    let changed = watched.iter().any(|(key, version)| state.kv.version(key) != *version);
+    for (key, _) in &watched {
+        state.kv.unwatch(key);
+    }
+    if changed {
+        return Reply::Nil;
+    }
+
+    let replies = queued.into_iter().map(|packet| execute(&mut state, packet)).collect();
+    drop(state);
+
+    serve_blocked(server);
+    Reply::Array(replies)
 }
 
-fn handle_setex(stream: &mut TcpStream, state: &Arc<Mutex<ServerState>>, key: String, ttl: u64, value: String) {
-    let mut state = state.lock().unwrap();
-    state.kv.setex(&key[..], &value[..], ttl);
-    let _ = write_message(stream, "setex");
+fn handle_discard(server: &Server, transaction: &mut Transaction) -> Reply {
+    if transaction.queued.is_none() {
+        return Reply::Error(String::from("DISCARD without MULTI"));
+    }
+
+    unwatch(server, std::mem::take(&mut transaction.watched));
+    *transaction = Transaction::default();
+    Reply::Ack("discarded")
 }
 
-fn handle_get(stream: &mut TcpStream, state: &Arc<Mutex<ServerState>>, key: String) {
-    let state = state.lock().unwrap();
-    let val = state.kv.get(&key[..]);
-    if let Some(val) = val {
-        write_message(stream, &format!("1\n{}", &val));
-    } else {
-        write_message(stream, "0");
+fn handle_watch(server: &Server, transaction: &mut Transaction, keys: Vec<Vec<u8>>) -> Reply {
+    if transaction.queued.is_some() {
+        return Reply::Error(String::from("WATCH inside MULTI is not allowed"));
+    }
+
+    let mut state = server.lock(keys.iter().map(Vec::as_slice));
+    for key in keys {
+        let version = state.kv.watch(&key);
+        transaction.watched.push((key, version));
     }
+
+    Reply::Ack("watched")
 }
 
-fn handle_ttl(stream: &mut TcpStream, state: &Arc<Mutex<ServerState>>, key: String) {
-    let state = state.lock().unwrap();
-    let ttl = state.kv.ttl(&key[..]);
-    if let Some(ttl) = ttl {
-        write_message(stream, &format!("1\n{}", &ttl));
-    } else {
-        write_message(stream, "0");
+fn handle_unwatch(server: &Server, transaction: &mut Transaction) -> Reply {
+    unwatch(server, std::mem::take(&mut transaction.watched));
+    Reply::Ack("unwatched")
+}
+
+// Lets go of a connection's watched keys, so the store stops keeping track of them for it
+fn unwatch(server: &Server, watched: Vec<(Vec<u8>, u64)>) {
+    if watched.is_empty() {
+        return;
+    }
+
+    let mut state = server.lock(watched.iter().map(|(key, _)| &key[..]));
+    for (key, _) in &watched {
+        state.kv.unwatch(key);
     }
 }
 
-fn write_message(stream: &mut TcpStream, message: &str) {
-    let message = format!("{}\n\n", message);
-    let result = stream.write_all(message.as_bytes());
+// Runs a command against the server state. Commands that change the store are logged to the
+// append-only file while their shards are still held, so commands on the same keys are logged in
+// the order they ran in.
+fn execute(state: &mut ServerState, packet: RequestPacket) -> Reply {
+    let write_commands = packet.write_commands();
 
-    match result {
-        Ok(_) => {
-            println!("messaged");
+    let reply = match packet {
+        RequestPacket::Subscribe { .. }
+        | RequestPacket::PSubscribe { .. }
+        | RequestPacket::Unsubscribe { .. }
+        | RequestPacket::PUnsubscribe { .. } => Reply::Error(String::from("subscribe is not allowed here")),
+        RequestPacket::Multi | RequestPacket::Exec | RequestPacket::Discard | RequestPacket::Watch { .. } | RequestPacket::Unwatch => {
+            Reply::Error(String::from("transactions are not allowed here"))
+        },
+        RequestPacket::Publish { channel, message } => handle_publish(state, channel, message),
+        RequestPacket::PubSubChannels { pattern } => handle_pubsub_channels(state, pattern),
+        RequestPacket::PubSubNumSub { channels } => handle_pubsub_numsub(state, channels),
+        RequestPacket::PubSubNumPat => Reply::Integer(state.ps.lock().unwrap().numpat() as i64),
+        RequestPacket::PubSubDropped => handle_pubsub_dropped(state),
+        RequestPacket::Set { key, value, options } => handle_set(state, key, value, options),
+        RequestPacket::SetEx { key, ttl, value } => handle_setex(state, key, ttl, value),
+        RequestPacket::Get { key } => handle_get(state, key),
+        RequestPacket::Ttl { key } => handle_ttl(state, key, false),
+        RequestPacket::PTtl { key } => handle_ttl(state, key, true),
+        RequestPacket::Expire { key, ttl } => handle_expire(state, key, ttl),
+        RequestPacket::ExpireAt { key, unix_millis } => handle_expireat(state, key, unix_millis),
+        RequestPacket::Persist { key } => handle_persist(state, key),
+        RequestPacket::Ping { message } => handle_ping(message),
+        RequestPacket::Del { keys } => handle_del(state, keys),
+        RequestPacket::Exists { keys } => handle_exists(state, keys),
+        RequestPacket::Keys { pattern } => handle_keys(state, pattern),
+        RequestPacket::Scan { cursor, pattern, count } => handle_scan(state, cursor, pattern, count),
+        RequestPacket::IncrBy { key, increment } => handle_incrby(state, key, increment),
+        RequestPacket::IncrByFloat { key, increment } => handle_incrbyfloat(state, key, increment),
+        RequestPacket::LPush { key, values } => handle_push(state, key, values, true),
+        RequestPacket::RPush { key, values } => handle_push(state, key, values, false),
+        RequestPacket::LPop { key, count } => handle_pop(state, key, count, true),
+        RequestPacket::RPop { key, count } => handle_pop(state, key, count, false),
+        RequestPacket::LRange { key, start, stop } => handle_lrange(state, key, start, stop),
+        RequestPacket::LLen { key } => handle_llen(state, key),
+        RequestPacket::LIndex { key, index } => handle_lindex(state, key, index),
+        RequestPacket::LTrim { key, start, stop } => handle_ltrim(state, key, start, stop),
+        RequestPacket::LMove { source, destination, from_front, to_front } => handle_lmove(state, source, destination, from_front, to_front),
+        // Scripts and transactions can't wait, so these reply nil if there's nothing to pop yet
+        RequestPacket::BLPop { keys, .. } => handle_pop_first(state, &keys, &BlockedPop::Pop { front: true }),
+        RequestPacket::BRPop { keys, .. } => handle_pop_first(state, &keys, &BlockedPop::Pop { front: false }),
+        RequestPacket::BLMove { source, destination, from_front, to_front, .. } => {
+            handle_pop_first(state, &[source], &BlockedPop::Move { destination, from_front, to_front })
+        },
+        RequestPacket::HSet { key, fields } => handle_hset(state, key, fields),
+        RequestPacket::HGet { key, field } => handle_hget(state, key, field),
+        RequestPacket::HDel { key, fields } => handle_hdel(state, key, fields),
+        RequestPacket::HExists { key, field } => handle_hexists(state, key, field),
+        RequestPacket::HLen { key } => handle_hlen(state, key),
+        RequestPacket::HGetAll { key } => handle_hgetall(state, key),
+        RequestPacket::HIncrBy { key, field, increment } => handle_hincrby(state, key, field, increment),
+        RequestPacket::SAdd { key, members } => handle_sadd(state, key, members),
+        RequestPacket::SRem { key, members } => handle_srem(state, key, members),
+        RequestPacket::SMembers { key } => handle_smembers(state, key),
+        RequestPacket::SIsMember { key, member } => handle_sismember(state, key, member),
+        RequestPacket::SCard { key } => handle_scard(state, key),
+        RequestPacket::SInter { keys } => handle_setop(state, SetOp::Inter, keys),
+        RequestPacket::SUnion { keys } => handle_setop(state, SetOp::Union, keys),
+        RequestPacket::SDiff { keys } => handle_setop(state, SetOp::Diff, keys),
+        RequestPacket::SInterStore { destination, keys } => handle_setopstore(state, SetOp::Inter, destination, keys),
+        RequestPacket::SUnionStore { destination, keys } => handle_setopstore(state, SetOp::Union, destination, keys),
+        RequestPacket::SDiffStore { destination, keys } => handle_setopstore(state, SetOp::Diff, destination, keys),
+        RequestPacket::ZAdd { key, members } => handle_zadd(state, key, members),
+        RequestPacket::ZRem { key, members } => handle_zrem(state, key, members),
+        RequestPacket::ZScore { key, member } => handle_zscore(state, key, member),
+        RequestPacket::ZIncrBy { key, increment, member } => handle_zincrby(state, key, increment, member),
+        RequestPacket::ZRank { key, member } => handle_zrank(state, key, member),
+        RequestPacket::ZRange { key, start, stop, withscores } => handle_zrange(state, key, start, stop, withscores, false),
+        RequestPacket::ZRevRange { key, start, stop, withscores } => handle_zrange(state, key, start, stop, withscores, true),
+        RequestPacket::ZRangeByScore { key, min, max, withscores, limit } => handle_zrangebyscore(state, key, min, max, withscores, limit),
+        RequestPacket::ZCard { key } => handle_zcard(state, key),
+        RequestPacket::XAdd { key, id, fields, maxlen } => handle_xadd(state, key, id, fields, maxlen),
+        RequestPacket::XRange { key, start, end, count } => handle_xrange(state, key, start, end, count, false),
+        RequestPacket::XRevRange { key, end, start, count } => handle_xrange(state, key, start, end, count, true),
+        // Scripts and transactions can't wait, so they read whatever is there already
+        RequestPacket::XRead { streams, count, .. } => handle_xread(state, streams, count),
+        RequestPacket::XLen { key } => handle_xlen(state, key),
+        RequestPacket::XTrim { key, maxlen } => handle_xtrim(state, key, maxlen),
+        RequestPacket::XSetId { key, id } => handle_xsetid(state, key, id),
+        RequestPacket::XGroupCreate { key, group, id, mkstream } => handle_xgroup_create(state, key, group, id, mkstream),
+        RequestPacket::XGroupSetId { key, group, id } => handle_xgroup_setid(state, key, group, id),
+        RequestPacket::XGroupDestroy { key, group } => handle_xgroup_destroy(state, key, group),
+        RequestPacket::XReadGroup { group, consumer, streams, count, noack, .. } => handle_xreadgroup(state, &group, &consumer, &streams, count, noack),
+        RequestPacket::XAck { key, group, ids } => handle_xack(state, key, group, ids),
+        RequestPacket::XPending { key, group, range } => handle_xpending(state, key, group, range),
+        RequestPacket::XClaim { key, group, consumer, min_idle, ids, options } => handle_xclaim(state, key, group, consumer, min_idle, ids, options),
+        RequestPacket::XAutoClaim { key, group, consumer, min_idle, start, count, just_id } => {
+            handle_xautoclaim(state, key, group, consumer, min_idle, start, count, just_id)
         },
-        Err(e) => {
-            println!("error: {}", e.to_string());
+        RequestPacket::Eval { script, keys, args } => handle_eval(state, script, keys, args),
+        RequestPacket::EvalSha { sha, keys, args } => handle_evalsha(state, sha, keys, args),
+        RequestPacket::ScriptLoad { script } => Reply::Bulk(state.scripts.lock().unwrap().load(script).into_bytes()),
+        RequestPacket::ScriptExists { shas } => handle_script_exists(state, shas),
+        RequestPacket::ScriptFlush => handle_script_flush(state),
+        RequestPacket::Info => handle_info(state),
+        RequestPacket::Save => handle_save(state),
+        RequestPacket::BgSave => handle_bgsave(state),
+        RequestPacket::BgRewriteAof => handle_bgrewriteaof(state),
+        RequestPacket::Invalid { error } => Reply::Error(error),
+        RequestPacket::Unknown => Reply::Error(String::from("unknown")),
+    };
+
+    if !matches!(reply, Reply::Error(_)) {
+        log_writes(state, write_commands);
+    }
+
+    reply
+}
+
+fn log_writes(state: &ServerState, write_commands: Vec<Vec<Vec<u8>>>) {
+    if let Some(aof) = &state.aof {
+        for args in write_commands {
+            if let Err(e) = aof.append(&args) {
+                println!("error: append-only write failed: {}", e);
+            }
+        }
+    }
+}
+
+// Subscribes the connection to channels, or patterns if `pattern` is set. The subscriber's
+// messages wait in its queue until the connection has room to write them.
+fn handle_subscribe(conn: &mut Connection, server: &Server, protocol: Protocol, names: Vec<String>, pattern: bool) {
+    let kind = if pattern { "psubscribe" } else { "subscribe" };
+    let Connection { handle, output, subscriptions, .. } = conn;
+
+    let subscriptions = subscriptions.get_or_insert_with(|| {
+        let (subscriber, receiver) = server.ps.lock().unwrap().subscriber();
+
+        let handle = handle.clone();
+        receiver.on_ready(move || handle.wake());
+
+        Subscriptions { subscriber, receiver, protocol, channels: HashSet::new(), patterns: HashSet::new() }
+    });
+
+    for name in names {
+        let mut ps = server.ps.lock().unwrap();
+        if pattern {
+            ps.psubscribe(&subscriptions.subscriber, name.clone());
+        } else {
+            ps.subscribe(&subscriptions.subscriber, name.clone());
+        }
+        drop(ps);
+
+        subscriptions.names(pattern).insert(name.clone());
+
+        // Messages are only written once the command is done, so they can't go ahead of this
+        if let Protocol::Resp = protocol {
+            let reply = subscription_reply(kind, Some(name), subscriptions.count());
+            write_reply(output, protocol, &reply);
         }
     }
 }
 
+// Unsubscribes from the given channels or patterns, or all of them if none are given, with a
+// reply for each. Once nothing is left, the connection can run other commands again.
+fn handle_unsubscribe(conn: &mut Connection, server: &Server, protocol: Protocol, names: Vec<String>, pattern: bool) {
+    let kind = if pattern { "punsubscribe" } else { "unsubscribe" };
+
+    let names = match &mut conn.subscriptions {
+        Some(subscriptions) if names.is_empty() => subscriptions.names(pattern).iter().cloned().collect(),
+        _ => names,
+    };
+
+    if names.is_empty() {
+        let count = conn.subscriptions.as_ref().map_or(0, Subscriptions::count);
+        return conn.write(protocol, &subscription_reply(kind, None, count));
+    }
+
+    for name in names {
+        let count = match &mut conn.subscriptions {
+            Some(subscriptions) => {
+                let id = subscriptions.subscriber.id();
+                let mut ps = server.ps.lock().unwrap();
+
+                if pattern {
+                    ps.punsubscribe(id, &name);
+                } else {
+                    ps.unsubscribe(id, &name);
+                }
+                subscriptions.names(pattern).remove(&name);
+
+                subscriptions.count()
+            },
+            None => 0,
+        };
+
+        conn.write(protocol, &subscription_reply(kind, Some(name), count));
+    }
+
+    // Dropping the receiver throws away any messages that were still waiting
+    if conn.subscriptions.as_ref().is_some_and(|subscriptions| subscriptions.count() == 0) {
+        conn.subscriptions = None;
+    }
+}
+
+fn subscription_reply(kind: &str, name: Option<String>, count: usize) -> Reply {
+    Reply::Array(vec![
+        Reply::Bulk(kind.as_bytes().to_vec()),
+        name.map_or(Reply::Nil, |name| Reply::Bulk(name.into_bytes())),
+        Reply::Integer(count as i64),
+    ])
+}
+
+fn message_reply(protocol: Protocol, message: Message) -> Reply {
+    let Message { channel, pattern, payload } = message;
+
+    match (protocol, pattern) {
+        // Messages are written bare, without the found marker, after the channel they were
+        // published to, and the pattern it matched for PSUBSCRIBE
+        (Protocol::Text | Protocol::Binary, None) => Reply::Array(vec![
+            Reply::Bulk(channel.into_bytes()),
+            Reply::Bulk(payload),
+        ]),
+        (Protocol::Text | Protocol::Binary, Some(pattern)) => Reply::Array(vec![
+            Reply::Bulk(pattern.into_bytes()),
+            Reply::Bulk(channel.into_bytes()),
+            Reply::Bulk(payload),
+        ]),
+        (Protocol::Resp, None) => Reply::Array(vec![
+            Reply::Bulk(b"message".to_vec()),
+            Reply::Bulk(channel.into_bytes()),
+            Reply::Bulk(payload),
+        ]),
+        (Protocol::Resp, Some(pattern)) => Reply::Array(vec![
+            Reply::Bulk(b"pmessage".to_vec()),
+            Reply::Bulk(pattern.into_bytes()),
+            Reply::Bulk(channel.into_bytes()),
+            Reply::Bulk(payload),
+        ]),
+    }
+}
+
+// Replies with each channel that has dropped messages, followed by how many it dropped
+fn handle_pubsub_dropped(state: &mut ServerState) -> Reply {
+    let ps = state.ps.lock().unwrap();
+    let mut dropped: Vec<_> = ps.dropped().iter().collect();
+    dropped.sort();
+
+    let counts = dropped
+        .into_iter()
+        .flat_map(|(channel, count)| [Reply::Bulk(channel.clone().into_bytes()), Reply::Integer(*count as i64)])
+        .collect();
+
+    Reply::Array(counts)
+}
+
+// Replies with how many subscriptions the message reached
+fn handle_publish(state: &mut ServerState, channel: String, message: Vec<u8>) -> Reply {
+    let receivers = state
+        .ps
+        .lock()
+        .unwrap()
+        .publish(channel, message);
+    Reply::Integer(receivers as i64)
+}
+
+fn handle_pubsub_channels(state: &mut ServerState, pattern: Option<String>) -> Reply {
+    let mut channels = state.ps.lock().unwrap().channels(pattern.as_deref());
+    channels.sort();
+
+    Reply::Array(channels.into_iter().map(|channel| Reply::Bulk(channel.into_bytes())).collect())
+}
+
+// Replies with each channel followed by its number of subscribers
+fn handle_pubsub_numsub(state: &mut ServerState, channels: Vec<String>) -> Reply {
+    let counts = channels
+        .into_iter()
+        .flat_map(|channel| {
+            let count = state.ps.lock().unwrap().numsub(&channel) as i64;
+            [Reply::Bulk(channel.into_bytes()), Reply::Integer(count)]
+        })
+        .collect();
+
+    Reply::Array(counts)
+}
+
+// Replies with the old value if GET was given, otherwise with whether the value was written
+fn handle_set(state: &mut ServerState, key: Vec<u8>, value: Vec<u8>, options: SetOptions) -> Reply {
+    let write_commands = set_commands(&key, &value, &options);
+
+    let written = state.kv.set_with(&key, value, options);
+    if let Ok((true, _)) = written {
+        log_writes(state, write_commands);
+    }
+
+    match written {
+        Ok((_, old)) if options.get => old.map_or(Reply::Nil, Reply::Bulk),
+        Ok((true, _)) => Reply::Ack("set"),
+        Ok((false, _)) => Reply::Nil,
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_setex(state: &mut ServerState, key: Vec<u8>, ttl: Duration, value: Vec<u8>) -> Reply {
+    state.kv.setex(&key[..], &value[..], ttl);
+    Reply::Ack("setex")
+}
+
+fn handle_get(state: &mut ServerState, key: Vec<u8>) -> Reply {
+    match state.kv.get(&key[..]) {
+        Ok(Some(val)) => Reply::Bulk(val),
+        Ok(None) => Reply::Nil,
+        Err(e) => e.into(),
+    }
+}
+
+// Replies with the time left in seconds (rounded to the nearest second), or milliseconds if `millis` is set, -1 if the key
+// never expires and -2 if there's no such key
+fn handle_ttl(state: &mut ServerState, key: Vec<u8>, millis: bool) -> Reply {
+    match state.kv.ttl(&key) {
+        Ttl::Expires(ttl) if millis => Reply::Integer(ttl.as_millis() as i64),
+        Ttl::Expires(ttl) => Reply::Integer((ttl.as_millis() as i64 + 500) / 1000),
+        Ttl::Persistent => Reply::Integer(-1),
+        Ttl::Missing => Reply::Integer(-2),
+    }
+}
+
+fn handle_expire(state: &mut ServerState, key: Vec<u8>, ttl: i64) -> Reply {
+    Reply::Integer(state.kv.expire(&key, ttl) as i64)
+}
+
+fn handle_expireat(state: &mut ServerState, key: Vec<u8>, unix_millis: i64) -> Reply {
+    Reply::Integer(state.kv.expire_at(&key, unix_millis) as i64)
+}
+
+fn handle_persist(state: &mut ServerState, key: Vec<u8>) -> Reply {
+    Reply::Integer(state.kv.persist(&key) as i64)
+}
+
+fn handle_del(state: &mut ServerState, keys: Vec<Vec<u8>>) -> Reply {
+    Reply::Integer(state.kv.del(&keys) as i64)
+}
+
+fn handle_exists(state: &mut ServerState, keys: Vec<Vec<u8>>) -> Reply {
+    Reply::Integer(state.kv.exists(&keys) as i64)
+}
+
+fn handle_keys(state: &mut ServerState, pattern: Vec<u8>) -> Reply {
+    Reply::Array(state.kv.keys(&pattern).into_iter().map(Reply::Bulk).collect())
+}
+
+// Each call only holds the lock for one batch of keys, the client calls again with the
+// returned cursor to carry on
+fn handle_scan(state: &mut ServerState, cursor: u64, pattern: Option<Vec<u8>>, count: usize) -> Reply {
+    let (cursor, keys) = state.kv.scan(cursor, pattern.as_deref(), count);
+
+    Reply::Array(vec![
+        Reply::Bulk(cursor.to_string().into_bytes()),
+        Reply::Array(keys.into_iter().map(Reply::Bulk).collect()),
+    ])
+}
+
+fn handle_incrby(state: &mut ServerState, key: Vec<u8>, increment: i64) -> Reply {
+    match state.kv.incrby(&key, increment) {
+        Ok(value) => Reply::Integer(value),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_incrbyfloat(state: &mut ServerState, key: Vec<u8>, increment: f64) -> Reply {
+    match state.kv.incrbyfloat(&key, increment) {
+        Ok(value) => Reply::Bulk(value.to_string().into_bytes()),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_push(state: &mut ServerState, key: Vec<u8>, values: Vec<Vec<u8>>, front: bool) -> Reply {
+    match state.kv.push(&key, values, front) {
+        Ok(len) => {
+            state.blocked.lock().unwrap().signal(&key);
+            Reply::Integer(len as i64)
+        },
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_pop(state: &mut ServerState, key: Vec<u8>, count: Option<usize>, front: bool) -> Reply {
+    match (state.kv.pop(&key, front, count.unwrap_or(1)), count) {
+        (Ok(Some(values)), Some(_)) => Reply::Array(values.into_iter().map(Reply::Bulk).collect()),
+        (Ok(Some(mut values)), None) => Reply::Bulk(values.remove(0)),
+        (Ok(None), _) => Reply::Nil,
+        (Err(e), _) => e.into(),
+    }
+}
+
+fn handle_lrange(state: &mut ServerState, key: Vec<u8>, start: i64, stop: i64) -> Reply {
+    match state.kv.lrange(&key, start, stop) {
+        Ok(values) => Reply::Array(values.into_iter().map(Reply::Bulk).collect()),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_llen(state: &mut ServerState, key: Vec<u8>) -> Reply {
+    match state.kv.llen(&key) {
+        Ok(len) => Reply::Integer(len as i64),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_lindex(state: &mut ServerState, key: Vec<u8>, index: i64) -> Reply {
+    match state.kv.lindex(&key, index) {
+        Ok(Some(value)) => Reply::Bulk(value),
+        Ok(None) => Reply::Nil,
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_ltrim(state: &mut ServerState, key: Vec<u8>, start: i64, stop: i64) -> Reply {
+    match state.kv.ltrim(&key, start, stop) {
+        Ok(_) => Reply::Ack("trimmed"),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_lmove(state: &mut ServerState, source: Vec<u8>, destination: Vec<u8>, from_front: bool, to_front: bool) -> Reply {
+    match state.kv.lmove(&source, &destination, from_front, to_front) {
+        Ok(Some(value)) => {
+            state.blocked.lock().unwrap().signal(&destination);
+            Reply::Bulk(value)
+        },
+        Ok(None) => Reply::Nil,
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_pop_first(state: &mut ServerState, keys: &[Vec<u8>], pop: &BlockedPop) -> Reply {
+    let server = state.server;
+    match pop_first(state, &mut server.blocked.lock().unwrap(), keys, pop) {
+        Ok(Some(reply)) => reply,
+        Ok(None) => Reply::Nil,
+        Err(e) => e.into(),
+    }
+}
+
+// Pops straight away if one of the lists has something in it. Otherwise the connection waits in
+// line behind anyone already waiting on those keys until `serve_blocked` hands it a value, or
+// replies nil once `timeout` passes. A timeout of 0 waits forever.
+fn handle_blocking_pop(conn: &mut Connection, server: &Server, protocol: Protocol, keys: Vec<Vec<u8>>, pop: BlockedPop, timeout: Duration) {
+    let mut locked: Vec<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+    if let BlockedPop::Move { destination, .. } = &pop {
+        locked.push(destination);
+    }
+    let mut state = server.lock(locked);
+    // Held until the connection is queued up, so a push can't slip in before anyone is told
+    let mut blocked = server.blocked.lock().unwrap();
+
+    match pop_first(&mut state, &mut blocked, &keys, &pop) {
+        Ok(Some(reply)) => {
+            drop(blocked);
+            drop(state);
+
+            // BLMOVE might have pushed onto a list someone else is waiting on
+            serve_blocked(server);
+            conn.write(protocol, &reply);
+        },
+        Ok(None) => {
+            let handle = conn.handle.clone();
+            let (id, receiver) = blocked.block(keys.clone(), pop, move || handle.wake());
+
+            conn.blocked = Some(Blocked { wait: Wait::List { id, receiver }, protocol, deadline: deadline(timeout) });
+        },
+        Err(e) => conn.write(protocol, &e.into()),
+    }
+}
+
+fn pop_first(state: &mut ServerState, blocked: &mut BlockedClients, keys: &[Vec<u8>], pop: &BlockedPop) -> Result<Option<Reply>, KvError> {
+    for key in keys {
+        if let Some(reply) = pop_for_blocked(state, blocked, key, pop)? {
+            return Ok(Some(reply));
+        }
+    }
+
+    Ok(None)
+}
+
+// Pops or moves a value from the list at `key`, if it has one, replying as BLPOP or BLMOVE
+// would. The blocking commands aren't logged themselves, so this logs what actually happened.
+fn pop_for_blocked(state: &mut ServerState, blocked: &mut BlockedClients, key: &[u8], pop: &BlockedPop) -> Result<Option<Reply>, KvError> {
+    let reply = match pop {
+        BlockedPop::Pop { front } => {
+            let Some(value) = state.kv.pop(key, *front, 1)?.and_then(|mut values| values.pop()) else {
+                return Ok(None);
+            };
+
+            let packet = if *front {
+                RequestPacket::LPop { key: key.to_vec(), count: None }
+            } else {
+                RequestPacket::RPop { key: key.to_vec(), count: None }
+            };
+            log_writes(state, packet.write_commands());
+
+            Reply::Array(vec![Reply::Bulk(key.to_vec()), Reply::Bulk(value)])
+        },
+        BlockedPop::Move { destination, from_front, to_front } => {
+            let Some(value) = state.kv.lmove(key, destination, *from_front, *to_front)? else {
+                return Ok(None);
+            };
+
+            let packet = RequestPacket::LMove {
+                source: key.to_vec(),
+                destination: destination.clone(),
+                from_front: *from_front,
+                to_front: *to_front,
+            };
+            log_writes(state, packet.write_commands());
+            blocked.signal(destination);
+
+            Reply::Bulk(value)
+        },
+    };
+
+    Ok(Some(reply))
+}
+
+// Hands values pushed onto lists to the clients waiting for them, longest waiting first. This
+// runs once a command has finished and let go of its shards rather than as soon as it pushes, so
+// a transaction or script that pushes and then looks at the list sees what it pushed.
+fn serve_blocked(server: &Server) {
+    loop {
+        let Some(key) = server.blocked.lock().unwrap().next_ready() else {
+            return;
+        };
+
+        loop {
+            let Some(pop) = server.blocked.lock().unwrap().first(&key).map(|(_, pop)| pop.clone()) else {
+                break;
+            };
+
+            // Shards come before the waiting clients in the lock order, so which shards to lock
+            // is worked out first, then checked again once they're held
+            let mut keys = vec![&key[..]];
+            if let BlockedPop::Move { destination, .. } = &pop {
+                keys.push(destination);
+            }
+            let mut state = server.lock(keys);
+            let mut blocked = server.blocked.lock().unwrap();
+
+            let Some((id, first)) = blocked.first(&key) else {
+                break;
+            };
+            if *first != pop {
+                continue;
+            }
+
+            match pop_for_blocked(&mut state, &mut blocked, &key, &pop) {
+                Ok(Some(reply)) => blocked.wake(id, reply),
+                Ok(None) => break,
+                // The key stopped being a list, so there's nothing to hand out after all
+                Err(_) if state.kv.llen(&key).is_err() => break,
+                // BLMOVE's destination isn't a list, which ends its wait with the error
+                Err(e) => blocked.wake(id, e.into()),
+            }
+        }
+    }
+}
+
+fn handle_hset(state: &mut ServerState, key: Vec<u8>, fields: Vec<(Vec<u8>, Vec<u8>)>) -> Reply {
+    match state.kv.hset(&key, fields) {
+        Ok(added) => Reply::Integer(added as i64),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_hget(state: &mut ServerState, key: Vec<u8>, field: Vec<u8>) -> Reply {
+    match state.kv.hget(&key, &field) {
+        Ok(Some(value)) => Reply::Bulk(value),
+        Ok(None) => Reply::Nil,
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_hdel(state: &mut ServerState, key: Vec<u8>, fields: Vec<Vec<u8>>) -> Reply {
+    match state.kv.hdel(&key, &fields) {
+        Ok(removed) => Reply::Integer(removed as i64),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_hexists(state: &mut ServerState, key: Vec<u8>, field: Vec<u8>) -> Reply {
+    match state.kv.hexists(&key, &field) {
+        Ok(exists) => Reply::Integer(exists as i64),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_hlen(state: &mut ServerState, key: Vec<u8>) -> Reply {
+    match state.kv.hlen(&key) {
+        Ok(len) => Reply::Integer(len as i64),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_hgetall(state: &mut ServerState, key: Vec<u8>) -> Reply {
+    match state.kv.hgetall(&key) {
+        Ok(fields) => Reply::Array(
+            fields
+                .into_iter()
+                .flat_map(|(field, value)| [Reply::Bulk(field), Reply::Bulk(value)])
+                .collect()
+        ),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_hincrby(state: &mut ServerState, key: Vec<u8>, field: Vec<u8>, increment: i64) -> Reply {
+    match state.kv.hincrby(&key, &field, increment) {
+        Ok(value) => Reply::Integer(value),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_sadd(state: &mut ServerState, key: Vec<u8>, members: Vec<Vec<u8>>) -> Reply {
+    match state.kv.sadd(&key, members) {
+        Ok(added) => Reply::Integer(added as i64),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_srem(state: &mut ServerState, key: Vec<u8>, members: Vec<Vec<u8>>) -> Reply {
+    match state.kv.srem(&key, &members) {
+        Ok(removed) => Reply::Integer(removed as i64),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_smembers(state: &mut ServerState, key: Vec<u8>) -> Reply {
+    match state.kv.smembers(&key) {
+        Ok(set) => Reply::Array(set.into_iter().map(Reply::Bulk).collect()),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_sismember(state: &mut ServerState, key: Vec<u8>, member: Vec<u8>) -> Reply {
+    match state.kv.sismember(&key, &member) {
+        Ok(is_member) => Reply::Integer(is_member as i64),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_scard(state: &mut ServerState, key: Vec<u8>) -> Reply {
+    match state.kv.scard(&key) {
+        Ok(len) => Reply::Integer(len as i64),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_setop(state: &mut ServerState, op: SetOp, keys: Vec<Vec<u8>>) -> Reply {
+    match state.kv.setop(op, &keys) {
+        Ok(set) => Reply::Array(set.into_iter().map(Reply::Bulk).collect()),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_setopstore(state: &mut ServerState, op: SetOp, destination: Vec<u8>, keys: Vec<Vec<u8>>) -> Reply {
+    match state.kv.setopstore(op, &destination, &keys) {
+        Ok(len) => Reply::Integer(len as i64),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_zadd(state: &mut ServerState, key: Vec<u8>, members: Vec<(f64, Vec<u8>)>) -> Reply {
+    match state.kv.zadd(&key, members) {
+        Ok(added) => Reply::Integer(added as i64),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_zrem(state: &mut ServerState, key: Vec<u8>, members: Vec<Vec<u8>>) -> Reply {
+    match state.kv.zrem(&key, &members) {
+        Ok(removed) => Reply::Integer(removed as i64),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_zscore(state: &mut ServerState, key: Vec<u8>, member: Vec<u8>) -> Reply {
+    match state.kv.zscore(&key, &member) {
+        Ok(Some(score)) => score_reply(score),
+        Ok(None) => Reply::Nil,
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_zincrby(state: &mut ServerState, key: Vec<u8>, increment: f64, member: Vec<u8>) -> Reply {
+    match state.kv.zincrby(&key, increment, &member) {
+        Ok(score) => score_reply(score),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_zrank(state: &mut ServerState, key: Vec<u8>, member: Vec<u8>) -> Reply {
+    match state.kv.zrank(&key, &member) {
+        Ok(Some(rank)) => Reply::Integer(rank as i64),
+        Ok(None) => Reply::Nil,
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_zrange(state: &mut ServerState, key: Vec<u8>, start: i64, stop: i64, withscores: bool, rev: bool) -> Reply {
+    match state.kv.zrange(&key, start, stop, rev) {
+        Ok(members) => members_reply(members, withscores),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_zrangebyscore(state: &mut ServerState, key: Vec<u8>, min: ScoreBound, max: ScoreBound, withscores: bool, limit: Option<(usize, usize)>) -> Reply {
+    match state.kv.zrangebyscore(&key, min, max, limit) {
+        Ok(members) => members_reply(members, withscores),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_zcard(state: &mut ServerState, key: Vec<u8>) -> Reply {
+    match state.kv.zcard(&key) {
+        Ok(len) => Reply::Integer(len as i64),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_xadd(state: &mut ServerState, key: Vec<u8>, id: NewId, fields: Fields, maxlen: Option<usize>) -> Reply {
+    let mut args = vec![b"xadd".to_vec(), key.clone()];
+    if let Some(maxlen) = maxlen {
+        args.extend([b"maxlen".to_vec(), maxlen.to_string().into_bytes()]);
+    }
+
+    let values: Vec<_> = fields
+        .iter()
+        .flat_map(|(field, value)| [field.clone(), value.clone()])
+        .collect();
+
+    match state.kv.xadd(&key, id, fields, maxlen) {
+        Ok(id) => {
+            // The log gets the ID that was picked, so replaying it gives every entry the same ID
+            args.push(id.to_string().into_bytes());
+            args.extend(values);
+            log_writes(state, vec![args]);
+
+            // Connections blocked on streams try their reads again, and wait again if this
+            // wasn't one of theirs
+            for waiter in std::mem::take(&mut *state.stream_waiters.lock().unwrap()) {
+                waiter.wake();
+            }
+            Reply::Bulk(id.to_string().into_bytes())
+        },
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_xrange(state: &mut ServerState, key: Vec<u8>, start: Bound<StreamId>, end: Bound<StreamId>, count: Option<usize>, rev: bool) -> Reply {
+    match state.kv.xrange(&key, start, end, count, rev) {
+        Ok(entries) => entries_reply(entries.into_iter().map(|(id, fields)| (id, Some(fields))).collect()),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_xread(state: &mut ServerState, streams: Vec<(Vec<u8>, ReadFrom)>, count: Option<usize>) -> Reply {
+    let read = resolve_read_ids(&state.kv, streams)
+        .and_then(|streams| read_streams(&state.kv, &streams, count));
+
+    match read {
+        Ok(read) => read,
+        Err(e) => e.into(),
+    }
+}
+
+// Waits until one of the streams has entries after the ID it's read from, or until `timeout`
+// has passed, which replies with nil. A timeout of 0 waits for as long as it takes.
+fn handle_blocking_xread(conn: &mut Connection, server: &Server, protocol: Protocol, streams: Vec<(Vec<u8>, ReadFrom)>, count: Option<usize>, timeout: Duration) {
+    let mut state = server.lock(streams.iter().map(|(key, _)| &key[..]));
+
+    // `$` is pinned to the last ID now, so entries added while waiting are the ones returned
+    let streams = match resolve_read_ids(&state.kv, streams) {
+        Ok(streams) => streams,
+        Err(e) => return conn.write(protocol, &e.into()),
+    };
+
+    block_on_streams(conn, &mut state, protocol, Wait::XRead { streams, count }, timeout);
+}
+
+// Replies if the read finds something already. Otherwise the connection waits, and tries again
+// each time an entry is added to a stream, until it finds something or `timeout` passes.
+fn block_on_streams(conn: &mut Connection, state: &mut ServerState, protocol: Protocol, wait: Wait, timeout: Duration) {
+    match read_blocked_streams(state, &wait) {
+        Some(reply) => conn.write(protocol, &reply),
+        None => {
+            state.stream_waiters.lock().unwrap().push(conn.handle.clone());
+            conn.blocked = Some(Blocked { wait, protocol, deadline: deadline(timeout) });
+        },
+    }
+}
+
+// Runs a waiting XREAD or XREADGROUP, giving `None` while there's still nothing to read. Reading
+// a group from an ID only looks through entries that are already pending, so it always replies.
+fn read_blocked_streams(state: &mut ServerState, wait: &Wait) -> Option<Reply> {
+    let reply = match wait {
+        Wait::XRead { streams, count } => match read_streams(&state.kv, streams, *count) {
+            Ok(read) => read,
+            Err(e) => e.into(),
+        },
+        Wait::XReadGroup { group, consumer, streams, count, noack } => {
+            handle_xreadgroup(state, group, consumer, streams, *count, *noack)
+        },
+        Wait::List { .. } => return None,
+    };
+
+    (reply != Reply::Nil).then_some(reply)
+}
+
+fn resolve_read_ids(kv: &Shards, streams: Vec<(Vec<u8>, ReadFrom)>) -> Result<Vec<(Vec<u8>, StreamId)>, KvError> {
+    streams
+        .into_iter()
+        .map(|(key, from)| {
+            let id = match from {
+                ReadFrom::After(id) => id,
+                ReadFrom::New => kv.xlast_id(&key)?,
+            };
+            Ok((key, id))
+        })
+        .collect()
+}
+
+// Replies with `[key, entries]` for each stream that has entries after its ID, or nil if none do
+fn read_streams(kv: &Shards, streams: &[(Vec<u8>, StreamId)], count: Option<usize>) -> Result<Reply, KvError> {
+    let mut replies = Vec::new();
+
+    for (key, id) in streams {
+        let entries = kv.xread(key, *id, count)?;
+        if !entries.is_empty() {
+            let entries = entries.into_iter().map(|(id, fields)| (id, Some(fields))).collect();
+            replies.push(Reply::Array(vec![Reply::Bulk(key.clone()), entries_reply(entries)]));
+        }
+    }
+
+    if replies.is_empty() {
+        Ok(Reply::Nil)
+    } else {
+        Ok(Reply::Array(replies))
+    }
+}
+
+fn handle_xlen(state: &mut ServerState, key: Vec<u8>) -> Reply {
+    match state.kv.xlen(&key) {
+        Ok(len) => Reply::Integer(len as i64),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_xtrim(state: &mut ServerState, key: Vec<u8>, maxlen: usize) -> Reply {
+    match state.kv.xtrim(&key, maxlen) {
+        Ok(removed) => Reply::Integer(removed as i64),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_xsetid(state: &mut ServerState, key: Vec<u8>, id: StreamId) -> Reply {
+    match state.kv.xsetid(&key, id) {
+        Ok(()) => Reply::Ack("set"),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_xgroup_create(state: &mut ServerState, key: Vec<u8>, group: Vec<u8>, id: ReadFrom, mkstream: bool) -> Reply {
+    match state.kv.xgroup_create(&key, &group, id, mkstream) {
+        Ok(()) => Reply::Ack("created"),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_xgroup_setid(state: &mut ServerState, key: Vec<u8>, group: Vec<u8>, id: ReadFrom) -> Reply {
+    match state.kv.xgroup_setid(&key, &group, id) {
+        Ok(()) => Reply::Ack("set"),
+        Err(e) => e.into(),
+    }
+}
+
+fn handle_xgroup_destroy(state: &mut ServerState, key: Vec<u8>, group: Vec<u8>) -> Reply {
+    match state.kv.xgroup_destroy(&key, &group) {
+        Ok(destroyed) => Reply::Integer(destroyed.into()),
+        Err(e) => e.into(),
+    }
+}
+
+// Replies with `[key, entries]` for each stream read. Streams read with `>` are left out if
+// they had nothing new, and the reply is nil if that leaves nothing.
+fn handle_xreadgroup(state: &mut ServerState, group: &[u8], consumer: &[u8], streams: &[(Vec<u8>, GroupReadFrom)], count: Option<usize>, noack: bool) -> Reply {
+    let mut replies = Vec::new();
+
+    for (key, from) in streams {
+        let entries = match state.kv.xreadgroup(key, group, consumer, *from, count, noack) {
+            Ok(entries) => entries,
+            Err(e) => return e.into(),
+        };
+
+        if *from == GroupReadFrom::Undelivered {
+            if entries.is_empty() {
+                continue;
+            }
+            log_group_delivery(state, key, group, consumer, &entries, noack);
+        }
+
+        replies.push(Reply::Array(vec![Reply::Bulk(key.clone()), entries_reply(entries)]));
+    }
+
+    if replies.is_empty() {
+        Reply::Nil
+    } else {
+        Reply::Array(replies)
+    }
+}
+
+// Logs entries newly handed out by XREADGROUP as the claims that would give them to the same
+// consumer at the same time, so replaying the log keeps their idle times
+fn log_group_delivery(state: &ServerState, key: &[u8], group: &[u8], consumer: &[u8], entries: &[(StreamId, Option<Fields>)], noack: bool) {
+    let Some((last_id, _)) = entries.last() else {
+        return;
+    };
+    let last_id = last_id.to_string().into_bytes();
+
+    let args = if noack {
+        vec![b"xgroup".to_vec(), b"setid".to_vec(), key.to_vec(), group.to_vec(), last_id]
+    } else {
+        let mut args = vec![b"xclaim".to_vec(), key.to_vec(), group.to_vec(), consumer.to_vec(), b"0".to_vec()];
+        args.extend(entries.iter().map(|(id, _)| id.to_string().into_bytes()));
+        args.extend([
+            b"time".to_vec(),
+            kvstore::unix_millis().to_string().into_bytes(),
+            b"retrycount".to_vec(),
+            b"1".to_vec(),
+            b"force".to_vec(),
+            b"justid".to_vec(),
+            b"lastid".to_vec(),
+            last_id,
+        ]);
+        args
+    };
+
+    log_writes(state, vec![args]);
+}
+
+fn handle_xack(state: &mut ServerState, key: Vec<u8>, group: Vec<u8>, ids: Vec<StreamId>) -> Reply {
+    match state.kv.xack(&key, &group, &ids) {
+        Ok(acked) => Reply::Integer(acked as i64),
+        Err(e) => e.into(),
+    }
+}
+
+// Without a range, sums up the pending entries as `[count, first ID, last ID, [[consumer,
+// count], ...]]`. With one, lists them as `[id, consumer, idle milliseconds, deliveries]`.
+fn handle_xpending(state: &mut ServerState, key: Vec<u8>, group: Vec<u8>, range: Option<PendingRange>) -> Reply {
+    let group = match state.kv.xgroup(&key, &group) {
+        Ok(group) => group,
+        Err(e) => return e.into(),
+    };
+
+    let id_reply = |id: StreamId| Reply::Bulk(id.to_string().into_bytes());
+
+    let Some(range) = range else {
+        if group.pending_len() == 0 {
+            return Reply::Array(vec![Reply::Integer(0), Reply::Nil, Reply::Nil, Reply::Nil]);
+        }
+
+        let first = group.pending().next().map(|(id, _)| id).unwrap();
+        let last = group.pending().next_back().map(|(id, _)| id).unwrap();
+        let consumers = group
+            .consumers()
+            .map(|(consumer, count)| {
+                Reply::Array(vec![Reply::Bulk(consumer.to_vec()), Reply::Bulk(count.to_string().into_bytes())])
+            })
+            .collect();
+
+        return Reply::Array(vec![
+            Reply::Integer(group.pending_len() as i64),
+            id_reply(first),
+            id_reply(last),
+            Reply::Array(consumers),
+        ]);
+    };
+
+    let now = kvstore::unix_millis() as u64;
+    let entries = group
+        .pending_range(&range, now)
+        .into_iter()
+        .map(|(id, entry)| {
+            Reply::Array(vec![
+                id_reply(id),
+                Reply::Bulk(entry.consumer.clone()),
+                Reply::Integer(now.saturating_sub(entry.delivered_at) as i64),
+                Reply::Integer(entry.deliveries as i64),
+            ])
+        })
+        .collect();
+
+    Reply::Array(entries)
+}
+
+fn handle_xclaim(state: &mut ServerState, key: Vec<u8>, group: Vec<u8>, consumer: Vec<u8>, min_idle: u64, ids: Vec<StreamId>, options: ClaimOptions) -> Reply {
+    let (claimed, deleted) = match state.kv.xclaim(&key, &group, &consumer, min_idle, &ids, options) {
+        Ok(claimed) => claimed,
+        Err(e) => return e.into(),
+    };
+
+    let claimed_ids: Vec<_> = claimed.iter().map(|(id, _)| *id).collect();
+    log_claims(state, &key, &group, &consumer, &claimed_ids, &deleted, options);
+
+    if options.just_id {
+        ids_reply(&claimed_ids)
+    } else {
+        entries_reply(claimed.into_iter().map(|(id, fields)| (id, Some(fields))).collect())
+    }
+}
+
+// Replies with `[next start ID, claimed entries, IDs no longer in the stream]`
+#[allow(clippy::too_many_arguments)]
+fn handle_xautoclaim(state: &mut ServerState, key: Vec<u8>, group: Vec<u8>, consumer: Vec<u8>, min_idle: u64, start: StreamId, count: usize, just_id: bool) -> Reply {
+    let AutoClaim { next, claimed, deleted } = match state.kv.xautoclaim(&key, &group, &consumer, min_idle, start, count, just_id) {
+        Ok(autoclaim) => autoclaim,
+        Err(e) => return e.into(),
+    };
+
+    let claimed_ids: Vec<_> = claimed.iter().map(|(id, _)| *id).collect();
+    let options = ClaimOptions { just_id, ..ClaimOptions::default() };
+    log_claims(state, &key, &group, &consumer, &claimed_ids, &deleted, options);
+
+    let claimed = if just_id {
+        ids_reply(&claimed_ids)
+    } else {
+        entries_reply(claimed.into_iter().map(|(id, fields)| (id, Some(fields))).collect())
+    };
+
+    Reply::Array(vec![Reply::Bulk(next.to_string().into_bytes()), claimed, ids_reply(&deleted)])
+}
+
+// Claims depend on how long entries have been idle, so rather than the command that was run,
+// the log gets a claim of exactly the entries it took, at the time it took them, and an
+// acknowledgement of the ones it dropped
+fn log_claims(state: &ServerState, key: &[u8], group: &[u8], consumer: &[u8], claimed: &[StreamId], deleted: &[StreamId], options: ClaimOptions) {
+    let mut commands = Vec::new();
+
+    if !claimed.is_empty() {
+        let mut args = vec![b"xclaim".to_vec(), key.to_vec(), group.to_vec(), consumer.to_vec(), b"0".to_vec()];
+        args.extend(claimed.iter().map(|id| id.to_string().into_bytes()));
+
+        // Everything claimed at once was given the same delivery time, so it's read back off the
+        // first of them rather than worked out again
+        let time = state
+            .kv
+            .xgroup(key, group)
+            .ok()
+            .and_then(|group| group.pending().find(|(id, _)| *id == claimed[0]))
+            .map_or(0, |(_, entry)| entry.delivered_at);
+        args.extend([b"time".to_vec(), time.to_string().into_bytes()]);
+        if let Some(retry_count) = options.retry_count {
+            args.extend([b"retrycount".to_vec(), retry_count.to_string().into_bytes()]);
+        }
+        if options.force {
+            args.push(b"force".to_vec());
+        }
+        if options.just_id {
+            args.push(b"justid".to_vec());
+        }
+        commands.push(args);
+    }
+
+    if let Some(last_id) = options.last_id {
+        let last_delivered = state.kv.xgroup(key, group).map_or(last_id, |group| group.last_delivered());
+        commands.push(vec![b"xgroup".to_vec(), b"setid".to_vec(), key.to_vec(), group.to_vec(), last_delivered.to_string().into_bytes()]);
+    }
+
+    if !deleted.is_empty() {
+        let mut args = vec![b"xack".to_vec(), key.to_vec(), group.to_vec()];
+        args.extend(deleted.iter().map(|id| id.to_string().into_bytes()));
+        commands.push(args);
+    }
+
+    log_writes(state, commands);
+}
+
+// Lists stream entries as `[id, [field, value, ...]]`, with nil in place of the fields of
+// entries that are only still around as pending IDs
+fn entries_reply(entries: Vec<(StreamId, Option<Fields>)>) -> Reply {
+    let replies = entries
+        .into_iter()
+        .map(|(id, fields)| {
+            let fields = match fields {
+                Some(fields) => Reply::Array(fields
+                    .into_iter()
+                    .flat_map(|(field, value)| [Reply::Bulk(field), Reply::Bulk(value)])
+                    .collect()),
+                None => Reply::Nil,
+            };
+            Reply::Array(vec![Reply::Bulk(id.to_string().into_bytes()), fields])
+        })
+        .collect();
+
+    Reply::Array(replies)
+}
+
+fn ids_reply(ids: &[StreamId]) -> Reply {
+    Reply::Array(ids.iter().map(|id| Reply::Bulk(id.to_string().into_bytes())).collect())
+}
+
+// Scores are sent as strings, since they may not be whole numbers
+fn score_reply(score: f64) -> Reply {
+    Reply::Bulk(score.to_string().into_bytes())
+}
+
+// Lists sorted set members, each followed by its score if `withscores` is set
+fn members_reply(members: Vec<(Vec<u8>, f64)>, withscores: bool) -> Reply {
+    let replies = members
+        .into_iter()
+        .flat_map(|(member, score)| {
+            let score = withscores.then(|| score_reply(score));
+            std::iter::once(Reply::Bulk(member)).chain(score)
+        })
+        .collect();
+
+    Reply::Array(replies)
+}
+
+fn handle_ping(message: Option<Vec<u8>>) -> Reply {
+    if let Some(message) = message {
+        Reply::Bulk(message)
+    } else {
+        Reply::Status(String::from("PONG"))
+    }
+}
+
+// RESP clients can't tell a reply from a message while subscribed, so PING gets an array
+// like messages do
+fn subscribed_ping_reply(protocol: Protocol, message: Option<Vec<u8>>) -> Reply {
+    match protocol {
+        Protocol::Resp => Reply::Array(vec![
+            Reply::Bulk(b"pong".to_vec()),
+            Reply::Bulk(message.unwrap_or_default()),
+        ]),
+        Protocol::Text | Protocol::Binary => handle_ping(message),
+    }
+}
+
+// EVAL caches the script too, so it can be run with EVALSHA afterwards
+fn handle_eval(state: &mut ServerState, script: Vec<u8>, keys: Vec<Vec<u8>>, args: Vec<Vec<u8>>) -> Reply {
+    state.scripts.lock().unwrap().load(script.clone());
+
+    run_script(state, &script, keys, args)
+}
+
+fn handle_evalsha(state: &mut ServerState, sha: String, keys: Vec<Vec<u8>>, args: Vec<Vec<u8>>) -> Reply {
+    let Some(script) = state.scripts.lock().unwrap().get(&sha).map(<[u8]>::to_vec) else {
+        return Reply::Error(String::from("NOSCRIPT No matching script. Please use EVAL."));
+    };
+
+    run_script(state, &script, keys, args)
+}
+
+// Scripts can use any key, so they run with every shard locked and every command they run
+// happens together.
+// Each of those commands is logged to the append-only file by itself.
+fn run_script(state: &mut ServerState, script: &[u8], keys: Vec<Vec<u8>>, args: Vec<Vec<u8>>) -> Reply {
+    let time_limit = state.lua_time_limit;
+
+    scripting::run(script, keys, args, time_limit, |args| {
+        match RequestPacket::from_arg_list(args) {
+            RequestPacket::Subscribe { .. }
+            | RequestPacket::PSubscribe { .. }
+            | RequestPacket::Unsubscribe { .. }
+            | RequestPacket::PUnsubscribe { .. }
+            | RequestPacket::Multi
+            | RequestPacket::Exec
+            | RequestPacket::Discard
+            | RequestPacket::Watch { .. }
+            | RequestPacket::Unwatch
+            | RequestPacket::Eval { .. }
+            | RequestPacket::EvalSha { .. }
+            | RequestPacket::ScriptLoad { .. }
+            | RequestPacket::ScriptExists { .. }
+            | RequestPacket::ScriptFlush => Reply::Error(String::from("command not allowed from scripts")),
+            packet => execute(state, packet),
+        }
+    })
+}
+
+fn handle_script_exists(state: &mut ServerState, shas: Vec<String>) -> Reply {
+    let exists = shas
+        .iter()
+        .map(|sha| Reply::Integer(state.scripts.lock().unwrap().contains(sha).into()))
+        .collect();
+
+    Reply::Array(exists)
+}
+
+fn handle_script_flush(state: &mut ServerState) -> Reply {
+    state.scripts.lock().unwrap().flush();
+    Reply::Ack("flushed")
+}
+
+fn handle_info(state: &mut ServerState) -> Reply {
+    let sections = [
+        String::from("# Server"),
+        format!("rustis_version:{}", clap::crate_version!()),
+        format!("uptime_in_seconds:{}", state.started_at.elapsed().as_secs()),
+        String::new(),
+        String::from("# Clients"),
+        format!("blocked_clients:{}", state.blocked.lock().unwrap().len()),
+        String::new(),
+        String::from("# Stats"),
+        format!("expired_keys:{}", state.kv.expired_keys()),
+        format!("pubsub_dropped_messages:{}", state.ps.lock().unwrap().dropped().values().sum::<u64>()),
+        String::new(),
+        String::from("# Keyspace"),
+        format!("keys:{}", state.kv.len()),
+        format!("expires:{}", state.kv.expires()),
+    ];
+
+    Reply::Bulk(sections.join("\r\n").into_bytes())
+}
+
+fn handle_save(state: &mut ServerState) -> Reply {
+    match state.snapshotter.save(state.kv.iter()) {
+        Ok(_) => Reply::Ack("saved"),
+        Err(e) => Reply::Error(format!("save failed: {}", e)),
+    }
+}
+
+fn handle_bgsave(state: &mut ServerState) -> Reply {
+    if state.snapshotter.bgsave(state.kv.iter()) {
+        Reply::Status(String::from("Background saving started"))
+    } else {
+        Reply::Error(String::from("background save already in progress"))
+    }
+}
+
+fn handle_bgrewriteaof(state: &mut ServerState) -> Reply {
+    match &state.aof {
+        Some(aof) if aof.bgrewrite(state.kv.iter()) => {
+            Reply::Status(String::from("Background append only file rewriting started"))
+        },
+        Some(_) => Reply::Error(String::from("append only file rewrite already in progress")),
+        None => Reply::Error(String::from("append only file is disabled")),
+    }
+}
+
+fn write_reply(output: &mut Vec<u8>, protocol: Protocol, reply: &Reply) {
+    output.extend_from_slice(&reply.encode(protocol));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pattern_message_over_text() {
+        let mut ps = PubSub::default();
+
+        let (subscriber, receiver) = ps.subscriber();
+        ps.psubscribe(&subscriber, String::from("orders.*"));
+        ps.publish(String::from("orders.new"), b"42".to_vec());
+
+        let reply = message_reply(Protocol::Text, receiver.recv().unwrap());
+        assert_eq!(reply.encode(Protocol::Text), b"orders.*\norders.new\n42\n\n");
+    }
+}
@@ -0,0 +1,423 @@
+use crate::kvstore::{Entry, KvStore, Value};
+use crate::sortedset::SortedSet;
+use crate::stream::{ConsumerGroup, PendingEntry, Stream, StreamId};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/* Snapshot file format:
+
+RUSTIS<VERSION: u16>
+<ENTRY>
+<ENTRY>
+...
+<EOF: u8 = 0xFF>
+
+Each entry is:
+
+<TYPE: u8>
+<EXPIRES AT: u64, unix time in milliseconds, 0 if the key never expires>
+<KEY LEN: u32><KEY>
+<VALUE>
+
+where the value depends on the type:
+
+String: <LEN: u32><BYTES>
+List:   <COUNT: u32>, then <LEN: u32><BYTES> for each element in order
+Hash:   <COUNT: u32>, then <LEN: u32><FIELD><LEN: u32><VALUE> for each field
+Set:    <COUNT: u32>, then <LEN: u32><BYTES> for each member
+ZSet:   <COUNT: u32>, then <LEN: u32><MEMBER><SCORE: f64> for each member, lowest score first
+Stream: <LAST ID><COUNT: u32>, then <ID><FIELD COUNT: u32> followed by <LEN: u32><FIELD><LEN: u32><VALUE>
+        for each field, for each entry oldest first. Then <GROUP COUNT: u32>, then for each group
+        <LEN: u32><NAME><LAST DELIVERED ID><PENDING COUNT: u32> followed by
+        <ID><LEN: u32><CONSUMER><DELIVERED AT: u64><DELIVERIES: u64> for each pending entry
+
+where an ID is <MS: u64><SEQ: u64>.
+
+All integers are little-endian.
+
+*/
+
+const MAGIC: &[u8] = b"RUSTIS";
+const VERSION: u16 = 1;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_HASH: u8 = 2;
+const TYPE_SET: u8 = 3;
+const TYPE_ZSET: u8 = 4;
+const TYPE_STREAM: u8 = 5;
+const EOF: u8 = 0xFF;
+
+// Writes snapshots of the keyspace to a file, and reads them back
+pub struct Snapshotter {
+    path: PathBuf,
+    saving: Arc<AtomicBool>,
+}
+
+impl Snapshotter {
+    pub fn new(path: PathBuf) -> Self {
+        Snapshotter {
+            path,
+            saving: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn save<'a>(&self, entries: impl IntoIterator<Item = Entry<'a>>) -> io::Result<()> {
+        write_file(&self.path, &encode(entries, SystemTime::now()))
+    }
+
+    // Saves a copy of the store on another thread, so the caller can release its lock while
+    // the file is written. Returns false if a background save is already running.
+    pub fn bgsave<'a>(&self, entries: impl IntoIterator<Item = Entry<'a>>) -> bool {
+        if self.saving.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+
+        let buf = encode(entries, SystemTime::now());
+        let path = self.path.clone();
+        let saving = Arc::clone(&self.saving);
+
+        thread::spawn(move || {
+            if let Err(e) = write_file(&path, &buf) {
+                println!("error: background save failed: {}", e);
+            }
+            saving.store(false, Ordering::SeqCst);
+        });
+
+        true
+    }
+
+    // Reads the snapshot file, if there is one
+    pub fn load(&self) -> io::Result<Option<KvStore>> {
+        match fs::read(&self.path) {
+            Ok(buf) => decode(&buf, SystemTime::now()).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// Writes to a temporary file first, so a crash mid-write never leaves a truncated snapshot
+fn write_file(path: &Path, buf: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(buf)?;
+    file.sync_all()?;
+
+    fs::rename(tmp_path, path)
+}
+
+pub fn encode<'a>(entries: impl IntoIterator<Item = Entry<'a>>, now: SystemTime) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&VERSION.to_le_bytes());
+
+    for (key, value, ttl) in entries {
+        let expires_at = ttl.map_or(0, |ttl| unix_millis(now + ttl).max(1));
+
+        let value_type = match value {
+            Value::Str(_) => TYPE_STRING,
+            Value::List(_) => TYPE_LIST,
+            Value::Hash(_) => TYPE_HASH,
+            Value::Set(_) => TYPE_SET,
+            Value::ZSet(_) => TYPE_ZSET,
+            Value::Stream(_) => TYPE_STREAM,
+        };
+
+        buf.push(value_type);
+        buf.extend_from_slice(&expires_at.to_le_bytes());
+        write_bytes(&mut buf, key);
+
+        match value {
+            Value::Str(value) => write_bytes(&mut buf, value),
+            Value::List(list) => {
+                buf.extend_from_slice(&(list.len() as u32).to_le_bytes());
+                for value in list {
+                    write_bytes(&mut buf, value);
+                }
+            },
+            Value::Set(set) => {
+                buf.extend_from_slice(&(set.len() as u32).to_le_bytes());
+                for member in set {
+                    write_bytes(&mut buf, member);
+                }
+            },
+            Value::Hash(hash) => {
+                buf.extend_from_slice(&(hash.len() as u32).to_le_bytes());
+                for (field, value) in hash {
+                    write_bytes(&mut buf, field);
+                    write_bytes(&mut buf, value);
+                }
+            },
+            Value::ZSet(zset) => {
+                buf.extend_from_slice(&(zset.len() as u32).to_le_bytes());
+                for (member, score) in zset.iter() {
+                    write_bytes(&mut buf, member);
+                    buf.extend_from_slice(&score.to_le_bytes());
+                }
+            },
+            Value::Stream(stream) => {
+                write_id(&mut buf, stream.last_id());
+                buf.extend_from_slice(&(stream.len() as u32).to_le_bytes());
+                for (id, fields) in stream.iter() {
+                    write_id(&mut buf, id);
+                    buf.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+                    for (field, value) in fields {
+                        write_bytes(&mut buf, field);
+                        write_bytes(&mut buf, value);
+                    }
+                }
+
+                buf.extend_from_slice(&(stream.groups().count() as u32).to_le_bytes());
+                for (name, group) in stream.groups() {
+                    write_bytes(&mut buf, name);
+                    write_id(&mut buf, group.last_delivered());
+                    buf.extend_from_slice(&(group.pending_len() as u32).to_le_bytes());
+                    for (id, entry) in group.pending() {
+                        write_id(&mut buf, id);
+                        write_bytes(&mut buf, &entry.consumer);
+                        buf.extend_from_slice(&entry.delivered_at.to_le_bytes());
+                        buf.extend_from_slice(&entry.deliveries.to_le_bytes());
+                    }
+                }
+            },
+        }
+    }
+
+    buf.push(EOF);
+    buf
+}
+
+// Rebuilds a store from a snapshot, dropping keys that expired before `now`
+pub fn decode(buf: &[u8], now: SystemTime) -> io::Result<KvStore> {
+    let mut reader = Reader { buf, pos: 0 };
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(invalid("not a snapshot file"));
+    }
+
+    let version = u16::from_le_bytes(reader.take(2)?.try_into().unwrap());
+    if version != VERSION {
+        return Err(invalid("unsupported snapshot version"));
+    }
+
+    let now = unix_millis(now);
+    let mut kv = KvStore::new();
+
+    loop {
+        let value_type = reader.take(1)?[0];
+        if value_type == EOF {
+            break;
+        }
+
+        let expires_at = reader.read_u64()?;
+        let key = reader.read_bytes()?;
+
+        let value = match value_type {
+            TYPE_STRING => Value::Str(reader.read_bytes()?),
+            TYPE_LIST => {
+                let count = reader.read_u32()?;
+                let list = (0..count)
+                    .map(|_| reader.read_bytes())
+                    .collect::<io::Result<_>>()?;
+                Value::List(list)
+            },
+            TYPE_HASH => {
+                let count = reader.read_u32()?;
+                let hash = (0..count)
+                    .map(|_| Ok((reader.read_bytes()?, reader.read_bytes()?)))
+                    .collect::<io::Result<_>>()?;
+                Value::Hash(hash)
+            },
+            TYPE_SET => {
+                let count = reader.read_u32()?;
+                let set = (0..count)
+                    .map(|_| reader.read_bytes())
+                    .collect::<io::Result<_>>()?;
+                Value::Set(set)
+            },
+            TYPE_ZSET => {
+                let count = reader.read_u32()?;
+                let mut zset = SortedSet::new();
+                for _ in 0..count {
+                    let member = reader.read_bytes()?;
+                    zset.insert(member, reader.read_f64()?);
+                }
+                Value::ZSet(zset)
+            },
+            TYPE_STREAM => {
+                let last_id = reader.read_id()?;
+                let count = reader.read_u32()?;
+                let mut stream = Stream::new();
+                for _ in 0..count {
+                    let id = reader.read_id()?;
+                    if id <= stream.last_id() {
+                        return Err(invalid("stream entries out of order"));
+                    }
+
+                    let field_count = reader.read_u32()?;
+                    let fields = (0..field_count)
+                        .map(|_| Ok((reader.read_bytes()?, reader.read_bytes()?)))
+                        .collect::<io::Result<_>>()?;
+                    stream.append(id, fields);
+                }
+                stream.set_last_id(last_id).map_err(|_| invalid("stream last ID before its entries"))?;
+
+                let group_count = reader.read_u32()?;
+                for _ in 0..group_count {
+                    let name = reader.read_bytes()?;
+                    let mut group = ConsumerGroup::new(reader.read_id()?);
+                    let pending_count = reader.read_u32()?;
+                    for _ in 0..pending_count {
+                        let id = reader.read_id()?;
+                        let consumer = reader.read_bytes()?;
+                        let delivered_at = reader.read_u64()?;
+                        let deliveries = reader.read_u64()?;
+                        group.assign(id, PendingEntry { consumer, delivered_at, deliveries });
+                    }
+                    stream.restore_group(name, group);
+                }
+                Value::Stream(stream)
+            },
+            _ => return Err(invalid("unknown entry type")),
+        };
+
+        if expires_at == 0 {
+            kv.restore(key, value, None);
+        } else if expires_at > now {
+            kv.restore(key, value, Some(Duration::from_millis(expires_at - now)));
+        }
+    }
+
+    Ok(kv)
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn write_id(buf: &mut Vec<u8>, id: StreamId) {
+    buf.extend_from_slice(&id.ms.to_le_bytes());
+    buf.extend_from_slice(&id.seq.to_le_bytes());
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn invalid(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let end = self.pos + len;
+        if end > self.buf.len() {
+            return Err(invalid("unexpected end of snapshot"));
+        }
+
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn read_id(&mut self) -> io::Result<StreamId> {
+        Ok(StreamId::new(self.read_u64()?, self.read_u64()?))
+    }
+
+    fn read_f64(&mut self) -> io::Result<f64> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn read_bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.read_u32()?;
+        Ok(self.take(len as usize)?.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::kvstore::Ttl;
+    use crate::stream::{GroupReadFrom, NewId, ReadFrom};
+
+    #[test]
+    fn round_trip() {
+        let mut kv = KvStore::new();
+        kv.set(b"plain", b"value");
+        kv.setex(b"expiring", b"\x00\xff", Duration::from_secs(100));
+        kv.push(b"list", vec![b"a".to_vec(), b"b".to_vec()], false).unwrap();
+        kv.hset(b"hash", vec![(b"field".to_vec(), b"value".to_vec())]).unwrap();
+        kv.sadd(b"set", vec![b"member".to_vec()]).unwrap();
+        kv.zadd(b"zset", vec![(1.5, b"a".to_vec()), (f64::NEG_INFINITY, b"b".to_vec())]).unwrap();
+        kv.xadd(b"stream", NewId::Exact(StreamId::new(1, 1)), vec![(b"field".to_vec(), b"value".to_vec())], None).unwrap();
+        kv.xadd(b"trimmed", NewId::Exact(StreamId::new(5, 0)), Vec::new(), Some(0)).unwrap();
+        kv.xgroup_create(b"stream", b"group", ReadFrom::After(StreamId::MIN), false).unwrap();
+        kv.xreadgroup(b"stream", b"group", b"consumer", GroupReadFrom::Undelivered, None, false).unwrap();
+
+        let buf = encode(kv.iter(), SystemTime::now());
+        let kv = decode(&buf, SystemTime::now()).unwrap();
+
+        assert_eq!(kv.get(b"plain"), Ok(Some(b"value".to_vec())));
+        assert_eq!(kv.get(b"expiring"), Ok(Some(b"\x00\xff".to_vec())));
+        assert_eq!(kv.lrange(b"list", 0, -1), Ok(vec![b"a".to_vec(), b"b".to_vec()]));
+        assert_eq!(kv.hget(b"hash", b"field"), Ok(Some(b"value".to_vec())));
+        assert_eq!(kv.sismember(b"set", b"member"), Ok(true));
+        assert_eq!(kv.zscore(b"zset", b"a"), Ok(Some(1.5)));
+        assert_eq!(kv.zrank(b"zset", b"b"), Ok(Some(0)));
+        assert_eq!(kv.xread(b"stream", StreamId::MIN, None), Ok(vec![(StreamId::new(1, 1), vec![(b"field".to_vec(), b"value".to_vec())])]));
+        assert_eq!(kv.xlen(b"trimmed"), Ok(0));
+        assert_eq!(kv.xlast_id(b"trimmed"), Ok(StreamId::new(5, 0)));
+        let group = kv.xgroup(b"stream", b"group").unwrap();
+        assert_eq!(group.last_delivered(), StreamId::new(1, 1));
+        assert_eq!(group.pending().map(|(id, entry)| (id, &entry.consumer[..], entry.deliveries)).collect::<Vec<_>>(), vec![(StreamId::new(1, 1), &b"consumer"[..], 1)]);
+        assert_eq!(kv.ttl(b"plain"), Ttl::Persistent);
+        assert!(matches!(kv.ttl(b"expiring"), Ttl::Expires(ttl) if ttl.as_secs() >= 98));
+    }
+
+    #[test]
+    fn drops_expired_on_load() {
+        let mut kv = KvStore::new();
+        kv.set(b"plain", b"value");
+        kv.setex(b"expiring", b"value", Duration::from_secs(10));
+
+        let buf = encode(kv.iter(), SystemTime::now());
+        let later = SystemTime::now() + Duration::from_secs(60);
+        let kv = decode(&buf, later).unwrap();
+
+        assert_eq!(kv.len(), 1);
+        assert_eq!(kv.get(b"expiring"), Ok(None));
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let mut kv = KvStore::new();
+        kv.set(b"key", b"value");
+
+        let buf = encode(kv.iter(), SystemTime::now());
+
+        assert!(decode(&buf[..buf.len() - 3], SystemTime::now()).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Bound;
+use std::str::FromStr;
+
+// A set of members ordered by score. Members are kept in a B-tree sorted by (score, member)
+// for range queries, with a hash index from member to score for direct lookups.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SortedSet {
+    scores: HashMap<Vec<u8>, f64>,
+    ordered: BTreeSet<Entry>,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    score: f64,
+    member: Vec<u8>,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Members with the same score are ordered by their bytes
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then_with(|| self.member.cmp(&other.member))
+    }
+}
+
+// One end of a score range, as given to ZRANGEBYSCORE: `1.5`, `(1.5` to leave the score
+// itself out, `-inf` or `+inf`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreBound {
+    pub score: f64,
+    pub exclusive: bool,
+}
+
+impl FromStr for ScoreBound {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (s, exclusive) = match s.strip_prefix('(') {
+            Some(s) => (s, true),
+            None => (s, false),
+        };
+
+        let score = parse_score(s).ok_or(())?;
+
+        Ok(ScoreBound { score, exclusive })
+    }
+}
+
+impl ScoreBound {
+    fn below(&self, score: f64) -> bool {
+        if self.exclusive {
+            self.score < score
+        } else {
+            self.score <= score
+        }
+    }
+
+    fn above(&self, score: f64) -> bool {
+        if self.exclusive {
+            self.score > score
+        } else {
+            self.score >= score
+        }
+    }
+}
+
+// Parses a score, which may be `inf`, `+inf` or `-inf` but never NaN
+pub fn parse_score(s: &str) -> Option<f64> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|score| !score.is_nan())
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        SortedSet::default()
+    }
+
+    // Adds a member or changes its score, returning true if it's new
+    pub fn insert(&mut self, member: Vec<u8>, score: f64) -> bool {
+        let old = self.scores.insert(member.clone(), score);
+
+        if let Some(old) = old {
+            self.ordered.remove(&Entry { score: old, member: member.clone() });
+        }
+        self.ordered.insert(Entry { score, member });
+
+        old.is_none()
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.ordered.remove(&Entry { score, member: member.to_vec() });
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    // Position of a member counting up from the lowest score
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+        let entry = Entry { score, member: member.to_vec() };
+
+        Some(self.ordered.range(..entry).count())
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    // Members from lowest to highest score
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&[u8], f64)> {
+        self.ordered
+            .iter()
+            .map(|entry| (&entry.member[..], entry.score))
+    }
+
+    // Members with scores between `min` and `max`, from lowest to highest
+    pub fn range_by_score(&self, min: ScoreBound, max: ScoreBound) -> impl Iterator<Item = (&[u8], f64)> {
+        let start = Entry { score: min.score, member: Vec::new() };
+
+        self.ordered
+            .range((Bound::Included(start), Bound::Unbounded))
+            .skip_while(move |entry| !min.below(entry.score))
+            .take_while(move |entry| max.above(entry.score))
+            .map(|entry| (&entry.member[..], entry.score))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bound(s: &str) -> ScoreBound {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ordering() {
+        let mut set = SortedSet::new();
+
+        assert!(set.insert(b"b".to_vec(), 2.0));
+        assert!(set.insert(b"a".to_vec(), 2.0));
+        assert!(set.insert(b"c".to_vec(), -1.0));
+        assert!(!set.insert(b"c".to_vec(), 5.0));
+
+        let members: Vec<_> = set.iter().map(|(member, _)| member.to_vec()).collect();
+        assert_eq!(members, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+
+        assert_eq!(set.rank(b"c"), Some(2));
+        assert_eq!(set.score(b"c"), Some(5.0));
+        assert_eq!(set.len(), 3);
+
+        assert!(set.remove(b"a"));
+        assert!(!set.remove(b"a"));
+        assert_eq!(set.rank(b"b"), Some(0));
+    }
+
+    #[test]
+    fn score_ranges() {
+        let mut set = SortedSet::new();
+        for (i, member) in [b"a", b"b", b"c", b"d"].iter().enumerate() {
+            set.insert(member.to_vec(), i as f64);
+        }
+
+        let range = |min, max| -> Vec<_> {
+            set.range_by_score(bound(min), bound(max))
+                .map(|(_, score)| score)
+                .collect()
+        };
+
+        assert_eq!(range("1", "2"), vec![1.0, 2.0]);
+        assert_eq!(range("(1", "2"), vec![2.0]);
+        assert_eq!(range("-inf", "(2"), vec![0.0, 1.0]);
+        assert_eq!(range("2", "+inf"), vec![2.0, 3.0]);
+        assert!(range("5", "10").is_empty());
+
+        assert!("nan".parse::<ScoreBound>().is_err());
+    }
+}
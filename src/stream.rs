@@ -0,0 +1,650 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::Bound;
+use std::str::FromStr;
+
+pub type Fields = Vec<(Vec<u8>, Vec<u8>)>;
+
+// Identifies an entry in a stream: the unix time in milliseconds it was added at, and a
+// sequence number telling apart entries added in the same millisecond
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId { ms: u64::MAX, seq: u64::MAX };
+
+    pub fn new(ms: u64, seq: u64) -> Self {
+        StreamId { ms, seq }
+    }
+
+    // The smallest ID after this one
+    pub fn next(self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => Some(StreamId::new(self.ms.checked_add(1)?, 0)),
+        }
+    }
+
+    // Parses `ms-seq`, or just `ms` with `seq` standing in for the missing sequence number
+    fn parse(s: &str, seq: u64) -> Option<StreamId> {
+        match s.split_once('-') {
+            Some((ms, given)) => Some(StreamId::new(ms.parse().ok()?, given.parse().ok()?)),
+            None => Some(StreamId::new(s.parse().ok()?, seq)),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+impl FromStr for StreamId {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        StreamId::parse(s, 0).ok_or(())
+    }
+}
+
+// The start of an ID range, as given to XRANGE: `-` for the first entry, `ms-seq`, `ms` for
+// the first entry in that millisecond, or any of those after `(` to leave the ID itself out
+pub fn parse_start(s: &str) -> Option<Bound<StreamId>> {
+    match s {
+        "-" => Some(Bound::Unbounded),
+        "+" => Some(Bound::Included(StreamId::MAX)),
+        _ => parse_bound(s, 0),
+    }
+}
+
+// The end of an ID range: `+` for the last entry, or `ms` for the last one in that millisecond
+pub fn parse_end(s: &str) -> Option<Bound<StreamId>> {
+    match s {
+        "+" => Some(Bound::Unbounded),
+        "-" => Some(Bound::Included(StreamId::MIN)),
+        _ => parse_bound(s, u64::MAX),
+    }
+}
+
+fn parse_bound(s: &str, seq: u64) -> Option<Bound<StreamId>> {
+    match s.strip_prefix('(') {
+        Some(s) => StreamId::parse(s, seq).map(Bound::Excluded),
+        None => StreamId::parse(s, seq).map(Bound::Included),
+    }
+}
+
+// The ID asked for a new entry: `*` to have one made up from the clock, `ms-*` to pick only
+// the sequence number, or an exact `ms-seq`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NewId {
+    Auto,
+    AutoSeq(u64),
+    Exact(StreamId),
+}
+
+impl FromStr for NewId {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            return Ok(NewId::Auto);
+        }
+
+        match s.strip_suffix("-*") {
+            Some(ms) => ms.parse().map(NewId::AutoSeq).map_err(|_| ()),
+            None => s.parse().map(NewId::Exact),
+        }
+    }
+}
+
+// Where XREAD starts reading a stream: after an ID, or `$` for only the entries added from
+// the time of the read on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadFrom {
+    After(StreamId),
+    New,
+}
+
+impl FromStr for ReadFrom {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "$" => Ok(ReadFrom::New),
+            _ => s.parse().map(ReadFrom::After),
+        }
+    }
+}
+
+impl fmt::Display for ReadFrom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadFrom::After(id) => id.fmt(f),
+            ReadFrom::New => write!(f, "$"),
+        }
+    }
+}
+
+// Where XREADGROUP reads a stream from: `>` for entries no consumer in the group has been
+// given yet, or an ID to go back over the consumer's own pending entries after it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GroupReadFrom {
+    Undelivered,
+    Pending(StreamId),
+}
+
+impl FromStr for GroupReadFrom {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            ">" => Ok(GroupReadFrom::Undelivered),
+            _ => s.parse().map(GroupReadFrom::Pending),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum IdError {
+    // New entries can't use 0-0
+    Zero,
+    // New entries have to go after every entry the stream has ever had
+    TooSmall,
+}
+
+// An append-only log of entries, each a list of field/value pairs, ordered by ID. The stream
+// remembers the last ID it handed out, so IDs keep going up even after the entries holding
+// them are trimmed away.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, Fields>,
+    last_id: StreamId,
+    groups: BTreeMap<Vec<u8>, ConsumerGroup>,
+}
+
+// An entry that has been given to a consumer, which stays pending until it's acknowledged
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingEntry {
+    pub consumer: Vec<u8>,
+    // Unix time in milliseconds the entry was last given to a consumer
+    pub delivered_at: u64,
+    // How many times the entry has been given to a consumer
+    pub deliveries: u64,
+}
+
+// Readers sharing a stream, where each entry is given to only one of the group's consumers
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConsumerGroup {
+    // The newest entry the group has handed out
+    last_delivered: StreamId,
+    pending: BTreeMap<StreamId, PendingEntry>,
+    // The pending entries each consumer holds
+    consumers: BTreeMap<Vec<u8>, BTreeSet<StreamId>>,
+}
+
+// How XCLAIM changes the entries it claims, beyond giving them to the new consumer
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ClaimOptions {
+    // Set the entries' idle time to this many milliseconds
+    pub idle: Option<u64>,
+    // Set when the entries were delivered, as a unix time in milliseconds
+    pub time: Option<u64>,
+    // Set the entries' delivery count
+    pub retry_count: Option<u64>,
+    // Claim entries even if they aren't pending, as long as they're still in the stream
+    pub force: bool,
+    // Leave the delivery count alone, for claims that don't hand the entries to anyone yet
+    pub just_id: bool,
+    // Move the group's last delivered ID forward to this
+    pub last_id: Option<StreamId>,
+}
+
+// Which pending entries the extended form of XPENDING lists
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingRange {
+    // Only entries idle for at least this many milliseconds
+    pub min_idle: Option<u64>,
+    pub start: Bound<StreamId>,
+    pub end: Bound<StreamId>,
+    pub count: usize,
+    // Only entries pending for this consumer
+    pub consumer: Option<Vec<u8>>,
+}
+
+impl ConsumerGroup {
+    pub fn new(last_delivered: StreamId) -> Self {
+        ConsumerGroup { last_delivered, ..ConsumerGroup::default() }
+    }
+
+    pub fn last_delivered(&self) -> StreamId {
+        self.last_delivered
+    }
+
+    pub fn set_last_delivered(&mut self, id: StreamId) {
+        self.last_delivered = id;
+    }
+
+    pub fn pending(&self) -> impl DoubleEndedIterator<Item = (StreamId, &PendingEntry)> {
+        self.pending.iter().map(|(id, entry)| (*id, entry))
+    }
+
+    // The pending entries in `range`, given the current unix time in milliseconds
+    pub fn pending_range(&self, range: &PendingRange, now: u64) -> Vec<(StreamId, &PendingEntry)> {
+        let (start, end) = match (range.start, range.end) {
+            (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end) | Bound::Excluded(end)) if start > end => return Vec::new(),
+            (Bound::Excluded(start), Bound::Excluded(end)) if start == end => return Vec::new(),
+            bounds => bounds,
+        };
+
+        self.pending
+            .range((start, end))
+            .filter(|(_, entry)| range.consumer.as_ref().is_none_or(|consumer| entry.consumer == *consumer))
+            .filter(|(_, entry)| range.min_idle.is_none_or(|min_idle| now.saturating_sub(entry.delivered_at) >= min_idle))
+            .take(range.count)
+            .map(|(id, entry)| (*id, entry))
+            .collect()
+    }
+
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    // Consumers holding pending entries, with how many they hold
+    pub fn consumers(&self) -> impl Iterator<Item = (&[u8], usize)> {
+        self.consumers
+            .iter()
+            .filter(|(_, ids)| !ids.is_empty())
+            .map(|(consumer, ids)| (&consumer[..], ids.len()))
+    }
+
+    // Makes an entry pending for `consumer`, taking it away from whichever consumer had it
+    pub fn assign(&mut self, id: StreamId, entry: PendingEntry) {
+        if let Some(old) = self.pending.get(&id) {
+            if let Some(ids) = self.consumers.get_mut(&old.consumer) {
+                ids.remove(&id);
+            }
+        }
+
+        self.consumers.entry(entry.consumer.clone()).or_default().insert(id);
+        self.pending.insert(id, entry);
+    }
+
+    // Removes an entry from the pending list, returning whether it was there
+    pub fn ack(&mut self, id: StreamId) -> bool {
+        let Some(entry) = self.pending.remove(&id) else {
+            return false;
+        };
+
+        if let Some(ids) = self.consumers.get_mut(&entry.consumer) {
+            ids.remove(&id);
+        }
+        true
+    }
+
+    fn add_consumer(&mut self, consumer: &[u8]) {
+        if !self.consumers.contains_key(consumer) {
+            self.consumers.insert(consumer.to_vec(), BTreeSet::new());
+        }
+    }
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Stream::default()
+    }
+
+    // Works out the ID of the next entry, given the current unix time in milliseconds
+    pub fn next_id(&self, id: NewId, now: u64) -> Result<StreamId, IdError> {
+        let last = self.last_id;
+
+        let id = match id {
+            NewId::Auto if now > last.ms => StreamId::new(now, 0),
+            NewId::Auto => match last.seq.checked_add(1) {
+                Some(seq) => StreamId::new(last.ms, seq),
+                None => StreamId::new(last.ms.checked_add(1).ok_or(IdError::TooSmall)?, 0),
+            },
+            NewId::AutoSeq(ms) if ms > last.ms => StreamId::new(ms, if ms == 0 { 1 } else { 0 }),
+            NewId::AutoSeq(ms) if ms == last.ms => StreamId::new(ms, last.seq.checked_add(1).ok_or(IdError::TooSmall)?),
+            NewId::AutoSeq(_) => return Err(IdError::TooSmall),
+            NewId::Exact(id) => id,
+        };
+
+        if id == StreamId::MIN {
+            return Err(IdError::Zero);
+        }
+        if id <= last {
+            return Err(IdError::TooSmall);
+        }
+
+        Ok(id)
+    }
+
+    // Appends an entry, whose ID must come from `next_id`
+    pub fn append(&mut self, id: StreamId, fields: Fields) {
+        debug_assert!(id > self.last_id);
+
+        self.entries.insert(id, fields);
+        self.last_id = id;
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    // Moves the last ID forward without adding an entry, as when restoring a stream whose
+    // newest entries were trimmed
+    pub fn set_last_id(&mut self, id: StreamId) -> Result<(), IdError> {
+        match self.entries.last_key_value() {
+            Some((last, _)) if id < *last => Err(IdError::TooSmall),
+            _ => {
+                self.last_id = id;
+                Ok(())
+            },
+        }
+    }
+
+    // Removes the oldest entries until at most `maxlen` are left, returning how many went
+    pub fn trim(&mut self, maxlen: usize) -> usize {
+        let excess = self.entries.len().saturating_sub(maxlen);
+
+        for _ in 0..excess {
+            self.entries.pop_first();
+        }
+
+        excess
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, id: StreamId) -> Option<&Fields> {
+        self.entries.get(&id)
+    }
+
+    // Entries with IDs between `start` and `end`, oldest first
+    pub fn range(&self, start: Bound<StreamId>, end: Bound<StreamId>) -> impl DoubleEndedIterator<Item = (StreamId, &Fields)> {
+        let empty = match (start, end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end) | Bound::Excluded(end)) => start >= end,
+            _ => false,
+        };
+
+        // BTreeMap::range panics on backwards ranges, so those are swapped for an empty one
+        let (start, end) = if empty {
+            (Bound::Excluded(StreamId::MAX), Bound::Unbounded)
+        } else {
+            (start, end)
+        };
+
+        self.entries
+            .range((start, end))
+            .map(|(id, fields)| (*id, fields))
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (StreamId, &Fields)> {
+        self.range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    // Adds a consumer group that will hand out entries after `last_delivered`, returning false
+    // if there's already a group with that name
+    pub fn create_group(&mut self, name: &[u8], last_delivered: StreamId) -> bool {
+        if self.groups.contains_key(name) {
+            return false;
+        }
+
+        self.groups.insert(name.to_vec(), ConsumerGroup::new(last_delivered));
+        true
+    }
+
+    pub fn destroy_group(&mut self, name: &[u8]) -> bool {
+        self.groups.remove(name).is_some()
+    }
+
+    pub fn group(&self, name: &[u8]) -> Option<&ConsumerGroup> {
+        self.groups.get(name)
+    }
+
+    pub fn group_mut(&mut self, name: &[u8]) -> Option<&mut ConsumerGroup> {
+        self.groups.get_mut(name)
+    }
+
+    // Puts back a group read from a snapshot
+    pub fn restore_group(&mut self, name: Vec<u8>, group: ConsumerGroup) {
+        self.groups.insert(name, group);
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = (&[u8], &ConsumerGroup)> {
+        self.groups.iter().map(|(name, group)| (&name[..], group))
+    }
+
+    // Gives `consumer` up to `count` entries that no one in the group has been given yet, which
+    // stay pending until acknowledged unless `noack` is set. `None` if there's no such group.
+    pub fn read_group(&mut self, group: &[u8], consumer: &[u8], count: Option<usize>, noack: bool, now: u64) -> Option<Vec<(StreamId, Fields)>> {
+        let group = self.groups.get_mut(group)?;
+        group.add_consumer(consumer);
+
+        let entries: Vec<_> = self.entries
+            .range((Bound::Excluded(group.last_delivered), Bound::Unbounded))
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| (*id, fields.clone()))
+            .collect();
+
+        for (id, _) in &entries {
+            group.last_delivered = *id;
+            if !noack {
+                group.assign(*id, PendingEntry { consumer: consumer.to_vec(), delivered_at: now, deliveries: 1 });
+            }
+        }
+
+        Some(entries)
+    }
+
+    // The entries pending for `consumer` after `after`, with `None` in place of the fields of
+    // entries that have been trimmed from the stream since they were delivered
+    pub fn read_pending(&mut self, group: &[u8], consumer: &[u8], after: StreamId, count: Option<usize>) -> Option<Vec<(StreamId, Option<Fields>)>> {
+        let group = self.groups.get_mut(group)?;
+        group.add_consumer(consumer);
+
+        let entries = group.consumers[consumer]
+            .range((Bound::Excluded(after), Bound::Unbounded))
+            .take(count.unwrap_or(usize::MAX))
+            .map(|id| (*id, self.entries.get(id).cloned()))
+            .collect();
+
+        Some(entries)
+    }
+
+    // Acknowledges entries, returning how many were pending
+    pub fn ack(&mut self, group: &[u8], ids: &[StreamId]) -> Option<usize> {
+        let group = self.groups.get_mut(group)?;
+
+        Some(ids.iter().filter(|id| group.ack(**id)).count())
+    }
+
+    // Gives pending entries that have been idle for at least `min_idle` milliseconds to
+    // `consumer`, returning the IDs it got. Entries no longer in the stream can't be claimed,
+    // and are dropped from the pending list, which is returned second.
+    pub fn claim(&mut self, group: &[u8], consumer: &[u8], min_idle: u64, ids: &[StreamId], options: ClaimOptions, now: u64) -> Option<(Vec<StreamId>, Vec<StreamId>)> {
+        let group = self.groups.get_mut(group)?;
+        let mut claimed = Vec::new();
+        let mut deleted = Vec::new();
+
+        for id in ids {
+            if !self.entries.contains_key(id) {
+                if group.ack(*id) {
+                    deleted.push(*id);
+                }
+                continue;
+            }
+
+            let deliveries = match group.pending.get(id) {
+                Some(entry) if now.saturating_sub(entry.delivered_at) < min_idle => continue,
+                Some(entry) => entry.deliveries,
+                None if options.force => 0,
+                None => continue,
+            };
+
+            let delivered_at = match (options.time, options.idle) {
+                (Some(time), _) => time,
+                (None, Some(idle)) => now.saturating_sub(idle),
+                (None, None) => now,
+            };
+            let deliveries = match options.retry_count {
+                Some(count) => count,
+                None if options.just_id => deliveries,
+                None => deliveries + 1,
+            };
+
+            group.assign(*id, PendingEntry { consumer: consumer.to_vec(), delivered_at, deliveries });
+            claimed.push(*id);
+        }
+
+        if let Some(last_id) = options.last_id {
+            group.last_delivered = group.last_delivered.max(last_id);
+        }
+
+        Some((claimed, deleted))
+    }
+
+    // Walks the pending list from `start`, claiming up to `count` entries idle for at least
+    // `min_idle` milliseconds. Returns the ID to carry on from (0-0 once the whole list has been
+    // seen), the claimed IDs, and the IDs dropped because they're no longer in the stream.
+    #[allow(clippy::too_many_arguments)]
+    pub fn autoclaim(&mut self, group: &[u8], consumer: &[u8], min_idle: u64, start: StreamId, count: usize, just_id: bool, now: u64) -> Option<(StreamId, Vec<StreamId>, Vec<StreamId>)> {
+        let group = self.groups.get_mut(group)?;
+        let mut claimed = Vec::new();
+        let mut deleted = Vec::new();
+
+        let mut next = StreamId::MIN;
+
+        for (id, entry) in group.pending.range(start..) {
+            if claimed.len() == count {
+                next = *id;
+                break;
+            }
+
+            if !self.entries.contains_key(id) {
+                deleted.push(*id);
+            } else if now.saturating_sub(entry.delivered_at) >= min_idle {
+                claimed.push(*id);
+            }
+        }
+
+        for id in &deleted {
+            group.ack(*id);
+        }
+        for id in &claimed {
+            let deliveries = group.pending[id].deliveries + u64::from(!just_id);
+            group.assign(*id, PendingEntry { consumer: consumer.to_vec(), delivered_at: now, deliveries });
+        }
+
+        Some((next, claimed, deleted))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fields(value: &str) -> Fields {
+        vec![(b"f".to_vec(), value.as_bytes().to_vec())]
+    }
+
+    #[test]
+    fn ids() {
+        let mut stream = Stream::new();
+
+        assert_eq!(stream.next_id(NewId::Exact(StreamId::MIN), 100), Err(IdError::Zero));
+        assert_eq!(stream.next_id(NewId::AutoSeq(0), 100), Ok(StreamId::new(0, 1)));
+
+        let id = stream.next_id(NewId::Auto, 100).unwrap();
+        assert_eq!(id, StreamId::new(100, 0));
+        stream.append(id, fields("a"));
+
+        // The clock going backwards doesn't make IDs go backwards
+        assert_eq!(stream.next_id(NewId::Auto, 50), Ok(StreamId::new(100, 1)));
+        assert_eq!(stream.next_id(NewId::AutoSeq(100), 50), Ok(StreamId::new(100, 1)));
+        assert_eq!(stream.next_id(NewId::AutoSeq(99), 50), Err(IdError::TooSmall));
+        assert_eq!(stream.next_id(NewId::Exact(StreamId::new(100, 0)), 50), Err(IdError::TooSmall));
+        assert_eq!(stream.next_id(NewId::Exact(StreamId::new(100, 5)), 50), Ok(StreamId::new(100, 5)));
+
+        assert_eq!("5-*".parse(), Ok(NewId::AutoSeq(5)));
+        assert_eq!("5".parse(), Ok(NewId::Exact(StreamId::new(5, 0))));
+        assert!("5-x".parse::<NewId>().is_err());
+    }
+
+    #[test]
+    fn consumer_groups() {
+        let mut stream = Stream::new();
+        for seq in 1..=3 {
+            stream.append(StreamId::new(1, seq), fields("value"));
+        }
+
+        assert!(stream.create_group(b"workers", StreamId::MIN));
+        assert!(!stream.create_group(b"workers", StreamId::MIN));
+        assert_eq!(stream.read_group(b"missing", b"alice", None, false, 0), None);
+
+        // Each entry goes to one consumer
+        let ids = |entries: Vec<(StreamId, Fields)>| -> Vec<_> { entries.into_iter().map(|(id, _)| id.seq).collect() };
+        assert_eq!(ids(stream.read_group(b"workers", b"alice", Some(2), false, 100).unwrap()), vec![1, 2]);
+        assert_eq!(ids(stream.read_group(b"workers", b"bob", None, false, 100).unwrap()), vec![3]);
+        assert!(stream.read_group(b"workers", b"bob", None, false, 100).unwrap().is_empty());
+
+        let pending = stream.read_pending(b"workers", b"alice", StreamId::MIN, None).unwrap();
+        assert_eq!(pending.len(), 2);
+
+        assert_eq!(stream.ack(b"workers", &[StreamId::new(1, 1), StreamId::new(1, 1)]), Some(1));
+        assert_eq!(stream.group(b"workers").unwrap().pending_len(), 2);
+
+        // Only entries idle long enough can be claimed
+        let options = ClaimOptions::default();
+        assert_eq!(stream.claim(b"workers", b"bob", 50, &[StreamId::new(1, 2)], options, 120), Some((vec![], vec![])));
+        assert_eq!(stream.claim(b"workers", b"bob", 50, &[StreamId::new(1, 2)], options, 200), Some((vec![StreamId::new(1, 2)], vec![])));
+
+        let group = stream.group(b"workers").unwrap();
+        let consumers: Vec<_> = group.consumers().collect();
+        assert_eq!(consumers, vec![(&b"bob"[..], 2)]);
+        assert_eq!(group.pending().next().unwrap().1.deliveries, 2);
+
+        // Trimmed entries are dropped from the pending list rather than claimed
+        stream.trim(1);
+        let (next, claimed, deleted) = stream.autoclaim(b"workers", b"alice", 0, StreamId::MIN, 10, false, 300).unwrap();
+        assert_eq!((next, claimed, deleted), (StreamId::MIN, vec![StreamId::new(1, 3)], vec![StreamId::new(1, 2)]));
+    }
+
+    #[test]
+    fn ranges_and_trimming() {
+        let mut stream = Stream::new();
+        for (ms, seq) in [(1, 0), (2, 1), (2, 2), (3, 3)] {
+            stream.append(StreamId::new(ms, seq), fields("value"));
+        }
+
+        let range = |start, end| -> Vec<_> {
+            stream.range(parse_start(start).unwrap(), parse_end(end).unwrap())
+                .map(|(id, _)| id.to_string())
+                .collect()
+        };
+
+        assert_eq!(range("-", "+").len(), 4);
+        assert_eq!(range("2", "2"), vec!["2-1", "2-2"]);
+        assert_eq!(range("(2-1", "+"), vec!["2-2", "3-3"]);
+        assert!(range("3", "1").is_empty());
+        assert!(range("(2-1", "(2-1").is_empty());
+
+        assert_eq!(stream.trim(3), 1);
+        assert_eq!(stream.trim(3), 0);
+        assert_eq!(stream.trim(0), 3);
+        assert!(stream.is_empty());
+        assert_eq!(stream.last_id(), StreamId::new(3, 3));
+    }
+}